@@ -0,0 +1,53 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use eth_state_fold_types::ethers;
+use ethers::core::types::{
+    transaction::eip2718::TypedTransaction, Address, BlockId, Bytes, H256, U64,
+};
+use ethers::providers::Middleware;
+
+use async_trait::async_trait;
+
+/// Blocks fetched per batch when the transport doesn't say otherwise.
+pub const DEFAULT_BLOCK_BATCH_SIZE: usize = 50;
+
+/// Read request of a batch.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BatchRequest {
+    GetStorageAt { address: Address, slot: H256 },
+    Call { tx: Box<TypedTransaction> },
+    GetBlockByNumber { number: U64 },
+}
+
+/// Response to a `BatchRequest`, of the matching variant. `Block` is `None`
+/// for blocks the node doesn't have.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BatchResponse {
+    Storage(H256),
+    Call(Bytes),
+    Block(Option<Box<ethers::types::Block<H256>>>),
+}
+
+/// Transport able to send many requests as a single JSON-RPC batch request.
+/// Used by the batches of the access layers of `eth-state-fold`, and by
+/// `BlockArchive` to fetch the blocks of large gaps, such as when a
+/// subscription resumes far behind the latest block. Without one, those are
+/// sent one request at a time.
+#[async_trait]
+pub trait BatchTransport<M: Middleware>: std::fmt::Debug + Send + Sync {
+    /// Sends `requests` as a single batch, answering one response per
+    /// request, in order. Storage reads and calls are pinned to `block`,
+    /// while `GetBlockByNumber` requests name their own block.
+    async fn send_batch(
+        &self,
+        middleware: &M,
+        block: BlockId,
+        requests: &[BatchRequest],
+    ) -> std::result::Result<Vec<BatchResponse>, M::Error>;
+
+    /// Most blocks `BlockArchive` requests in a single batch.
+    fn batch_size(&self) -> usize {
+        DEFAULT_BLOCK_BATCH_SIZE
+    }
+}
@@ -2,6 +2,8 @@
 // SPDX-License-Identifier: Apache-2.0 (see LICENSE)
 
 use crate::block_tree::BlockTree;
+use crate::{BatchRequest, BatchResponse, BatchTransport};
+use crate::{Clock, TokioClock};
 
 use eth_state_fold_types::BlocksSince;
 
@@ -12,7 +14,9 @@ use ethers::providers::Middleware;
 use ethers::types::{BlockId, BlockNumber};
 
 use std::convert::TryInto;
+use std::future::Future;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
 
 use snafu::{ensure, ResultExt, Snafu};
@@ -47,31 +51,76 @@ pub enum BlockArchiveError<M: ethers::providers::Middleware + 'static> {
 
     #[snafu(display("Depth of `{}` higher than latest block `{}`", depth, latest))]
     DepthTooHigh { depth: usize, latest: usize },
+
+    #[snafu(display("Reorg deeper than `{}` blocks", max_depth))]
+    ReorgTooDeep { max_depth: usize },
 }
 
 pub type Result<T, M> = std::result::Result<T, BlockArchiveError<M>>;
 
+/// Outcome of `BlockArchive::push`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BlockPush {
+    /// The pushed block descends from the previous latest block.
+    Extended,
+
+    /// The pushed block is on another branch than the previous latest block.
+    Reorg(Reorg),
+}
+
+/// Switch between the branches of two heads.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reorg {
+    /// Latest block shared by both branches.
+    pub ancestor: Arc<Block>,
+
+    /// Blocks of the old branch after `ancestor`, oldest first.
+    pub orphaned: Vec<Arc<Block>>,
+
+    /// Blocks of the new branch after `ancestor`, oldest first.
+    pub adopted: Vec<Arc<Block>>,
+}
+
+/// Extra blocks retained beyond `max_depth`, so that reorgs slightly deeper
+/// than the deepest subscription can still be resolved from memory.
+const RETENTION_MARGIN: usize = 64;
+
 pub struct BlockArchive<M: Middleware> {
     middleware: Arc<M>,
     block_tree: RwLock<BlockTree>,
     max_depth: usize,
+    block_batch: std::sync::RwLock<Option<Arc<dyn BatchTransport<M>>>>,
 }
 
 impl<M: Middleware + 'static> BlockArchive<M> {
-    pub(crate) async fn new(middleware: Arc<M>, max_depth: usize) -> Result<Self, M> {
+    /// Creates an archive retaining `max_depth` blocks of history, plus a small
+    /// margin. Older blocks are dropped, and depths beyond `max_depth` are
+    /// rejected with `BlockOutOfRange`. It starts at the latest block of
+    /// `middleware`, and is advanced with `push`.
+    pub async fn new(middleware: Arc<M>, max_depth: usize) -> Result<Self, M> {
         let block_tree = {
             let latest_block = fetch_block(middleware.as_ref(), BlockNumber::Latest).await?;
 
-            RwLock::new(BlockTree::new(Arc::new(latest_block)))
+            RwLock::new(BlockTree::new(
+                Arc::new(latest_block),
+                max_depth.saturating_add(RETENTION_MARGIN),
+            ))
         };
 
         Ok(Self {
             middleware,
             block_tree,
             max_depth,
+            block_batch: std::sync::RwLock::new(None),
         })
     }
 
+    /// Sets the transport batching the fetches of the blocks of large gaps, or
+    /// unsets it with `None`, falling back to fetching them one at a time.
+    pub fn set_block_batch_transport(&self, transport: Option<Arc<dyn BatchTransport<M>>>) {
+        *self.block_batch.write().unwrap() = transport;
+    }
+
     pub(crate) async fn update_latest_block(&self, block: Arc<Block>) -> Result<(), M> {
         let mut block_tree = self.block_tree.write().await;
 
@@ -92,6 +141,96 @@ impl<M: Middleware + 'static> BlockArchive<M> {
 
         Ok(())
     }
+
+    /// Whether `block` is the latest block or one of its retained ancestors.
+    pub(crate) async fn on_current_chain(&self, block: &Block) -> bool {
+        let block_tree = self.block_tree.read().await;
+
+        block.number <= block_tree.latest_block().number
+            && block_tree
+                .block_with_number(&block.number)
+                .is_some_and(|b| b.hash == block.hash)
+    }
+
+    /// Makes `block` the latest block, fetching its missing ancestors, and
+    /// reports whether it extends the previous latest block or reorgs it. The
+    /// archive is locked throughout, so concurrent pushes are ordered. Fails
+    /// with `ReorgTooDeep`, leaving the latest block unchanged, if `block`
+    /// shares no block with it within the retained history.
+    pub async fn push(&self, block: Arc<Block>) -> Result<BlockPush, M> {
+        let mut block_tree = self.block_tree.write().await;
+        let previous = block_tree.latest_block();
+
+        let reorg = {
+            let block_tree = &*block_tree;
+            let block_with_hash = |hash: H256| {
+                let retained = block_tree.block_with_hash(&hash);
+                async move {
+                    match retained {
+                        Some(b) => Ok(b),
+                        None => self.fetch_block(hash).await,
+                    }
+                }
+            };
+
+            let max_depth = self.retained_depth();
+            let ancestor = common_ancestor(&previous.hash, &block.hash, max_depth, block_with_hash)
+                .await?
+                .ok_or(snafu::NoneError)
+                .context(ReorgTooDeepSnafu { max_depth })?;
+
+            Reorg {
+                orphaned: branch(&previous, ancestor.number, block_with_hash).await?,
+                adopted: branch(&block, ancestor.number, block_with_hash).await?,
+                ancestor,
+            }
+        };
+
+        for b in &reorg.adopted {
+            block_tree.insert_block(Arc::clone(b));
+        }
+        block_tree.update_latest_block(block);
+
+        Ok(if reorg.ancestor.hash == previous.hash {
+            BlockPush::Extended
+        } else {
+            BlockPush::Reorg(reorg)
+        })
+    }
+
+    /// Branches replaced when going from `old_head` to `new_head`, or `None`
+    /// if `new_head` descends from `old_head`. Fails with `ReorgTooDeep` if
+    /// they share no block within the retained history.
+    pub async fn reorg(
+        &self,
+        old_head: &Arc<Block>,
+        new_head: &Arc<Block>,
+    ) -> Result<Option<Reorg>, M> {
+        let max_depth = self.retained_depth();
+        let ancestor = self
+            .common_ancestor(&old_head.hash, &new_head.hash, max_depth)
+            .await?
+            .ok_or(snafu::NoneError)
+            .context(ReorgTooDeepSnafu { max_depth })?;
+
+        if ancestor.hash == old_head.hash {
+            return Ok(None);
+        }
+
+        Ok(Some(Reorg {
+            orphaned: self.branch(old_head, ancestor.number).await?,
+            adopted: self.branch(new_head, ancestor.number).await?,
+            ancestor,
+        }))
+    }
+
+    /// Blocks of the branch of `head` numbered after `after`, oldest first.
+    pub async fn branch(&self, head: &Arc<Block>, after: U64) -> Result<Vec<Arc<Block>>, M> {
+        branch(head, after, |hash| async move {
+            self.block_with_hash(&hash).await
+        })
+        .await
+    }
 }
 
 impl<M: Middleware + 'static> BlockArchive<M> {
@@ -130,9 +269,19 @@ impl<M: Middleware + 'static> BlockArchive<M> {
         Ok(b)
     }
 
-    pub async fn blocks_since(&self, depth: usize, previous: Arc<Block>) -> Result<BlocksSince, M> {
-        let latest = self.latest_block().await;
+    /// Deepest block reachable from the latest block.
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
 
+    /// Blocks of history retained behind the latest block, `max_depth` plus a
+    /// small margin.
+    pub fn retained_depth(&self) -> usize {
+        self.max_depth.saturating_add(RETENTION_MARGIN)
+    }
+
+    /// Fails with `BlockOutOfRange` if `depth` exceeds the retained history.
+    pub fn check_depth(&self, depth: usize) -> Result<(), M> {
         ensure!(
             depth <= self.max_depth,
             BlockOutOfRangeSnafu {
@@ -141,6 +290,14 @@ impl<M: Middleware + 'static> BlockArchive<M> {
             }
         );
 
+        Ok(())
+    }
+
+    pub async fn blocks_since(&self, depth: usize, previous: Arc<Block>) -> Result<BlocksSince, M> {
+        let latest = self.latest_block().await;
+
+        self.check_depth(depth)?;
+
         ensure!(
             previous.number <= latest.number,
             PreviousAheadOfLatestSnafu {
@@ -172,9 +329,29 @@ impl<M: Middleware + 'static> BlockArchive<M> {
 
         Ok(b)
     }
+
+    /// Latest block shared by the branches of `a` and `b`, walking their
+    /// parents through the archive, and the node for blocks it doesn't hold.
+    /// Returns `None` if no shared block is found within `max_depth` blocks
+    /// of the higher of the two.
+    pub async fn common_ancestor(
+        &self,
+        a: &H256,
+        b: &H256,
+        max_depth: usize,
+    ) -> Result<Option<Arc<Block>>, M> {
+        common_ancestor(a, b, max_depth, |hash| async move {
+            self.block_with_hash(&hash).await
+        })
+        .await
+    }
 }
 
 impl<M: Middleware + 'static> BlockArchive<M> {
+    pub(crate) async fn fetch_latest_block(&self) -> Result<Arc<Block>, M> {
+        self.fetch_block(BlockNumber::Latest).await
+    }
+
     async fn fetch_block<T: Into<BlockId> + Send + Sync>(
         &self,
         block_id: T,
@@ -210,8 +387,10 @@ impl<M: Middleware + 'static> BlockArchive<M> {
                 self.extend_stack_to_ancestor(&mut stack, previous).await?;
                 stack.reverse();
 
+                // Unlike the normal case, the stack keeps the block replacing
+                // `previous`, on top of the ones replacing its ancestors.
                 let spillover = stack.len() - len;
-                stack.truncate(number_of_new_blocks + spillover);
+                stack.truncate(number_of_new_blocks + 1 + spillover);
 
                 Ok(BlocksSince::Reorg(stack))
             }
@@ -225,6 +404,8 @@ impl<M: Middleware + 'static> BlockArchive<M> {
         ancestor_number: U64,
         leaf: Arc<Block>,
     ) -> Result<Vec<Arc<Block>>, M> {
+        self.prefetch_branch(ancestor_number, &leaf).await?;
+
         let mut stack = Vec::new();
         let mut current = leaf.clone();
 
@@ -238,6 +419,76 @@ impl<M: Middleware + 'static> BlockArchive<M> {
         Ok(stack)
     }
 
+    /// Fetches in batches the blocks of the branch of `leaf` between
+    /// `ancestor_number` and `leaf`, exclusive, that the archive doesn't hold,
+    /// if a `BatchTransport` is set. Batched blocks are fetched by number,
+    /// so each must be the parent of the block above it; past a block that
+    /// isn't, as when the chain reorgs during the catch up, or one that is
+    /// incomplete, the rest are left to be fetched one at a time by hash.
+    async fn prefetch_branch(&self, ancestor_number: U64, leaf: &Block) -> Result<(), M> {
+        let Some(transport) = self.block_batch.read().unwrap().clone() else {
+            return Ok(());
+        };
+
+        let batch_size = transport.batch_size().max(1) as u64;
+        let mut next = leaf.number;
+        let mut expected = leaf.parent_hash;
+
+        while next > ancestor_number + 1 {
+            if let Some(b) = self.block_tree.read().await.block_with_hash(&expected) {
+                next = b.number;
+                expected = b.parent_hash;
+                continue;
+            }
+
+            let low = next
+                .as_u64()
+                .saturating_sub(batch_size)
+                .max(ancestor_number.as_u64() + 1);
+            let requests: Vec<_> = (low..next.as_u64())
+                .rev()
+                .map(|number| BatchRequest::GetBlockByNumber {
+                    number: number.into(),
+                })
+                .collect();
+            let responses = transport
+                .send_batch(self.middleware.as_ref(), leaf.hash.into(), &requests)
+                .await
+                .context(EthersProviderSnafu)?;
+
+            let fetched = responses.len();
+            for response in responses {
+                let block: Block = match response {
+                    BatchResponse::Block(Some(block)) => match (*block).try_into() {
+                        Ok(block) => block,
+                        Err(_) => return Ok(()),
+                    },
+                    _ => return Ok(()),
+                };
+
+                if block.hash != expected {
+                    tracing::debug!(
+                        "Batched block `{}` off the branch of `{}`, fetching the rest by hash",
+                        block.number,
+                        leaf.hash
+                    );
+
+                    return Ok(());
+                }
+
+                next = block.number;
+                expected = block.parent_hash;
+                self.insert_block(Arc::new(block)).await;
+            }
+
+            if fetched < requests.len() {
+                return Ok(());
+            }
+        }
+
+        Ok(())
+    }
+
     async fn extend_stack_to_ancestor(
         &self,
         stack: &mut Vec<Arc<Block>>,
@@ -267,19 +518,74 @@ impl<M: Middleware + 'static> BlockArchive<M> {
     }
 }
 
+/// Retrying of blocks answered with missing fields (e.g. a `null` hash or
+/// number), as nodes occasionally do for blocks at the tip that aren't fully
+/// available yet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IncompleteBlockRetry {
+    /// Retries after the first attempt. `0` fails on the first incomplete
+    /// block.
+    pub max_retries: usize,
+
+    /// Delay between attempts.
+    pub delay: Duration,
+}
+
+impl Default for IncompleteBlockRetry {
+    /// Three retries, 100ms apart.
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            delay: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Fetches a block, retrying incomplete ones with the default
+/// `IncompleteBlockRetry`.
 pub async fn fetch_block<M: Middleware + 'static, T: Into<BlockId> + Send + Sync>(
     middleware: &M,
     block_id: T,
 ) -> Result<Block, M> {
-    middleware
-        .get_block(block_id)
-        .await
-        .context(EthersProviderSnafu)?
-        .ok_or(snafu::NoneError)
-        .context(BlockUnavailableSnafu)?
-        .try_into()
-        .map_err(|_| snafu::NoneError)
-        .context(BlockIncompleteSnafu)
+    fetch_block_with_retry(
+        middleware,
+        block_id,
+        IncompleteBlockRetry::default(),
+        &TokioClock,
+    )
+    .await
+}
+
+/// Same as `fetch_block`, retrying incomplete blocks according to `retry`,
+/// waiting between attempts on `clock`. Fails with `BlockIncomplete` once
+/// retries are exhausted.
+pub async fn fetch_block_with_retry<M: Middleware + 'static, T: Into<BlockId> + Send + Sync>(
+    middleware: &M,
+    block_id: T,
+    retry: IncompleteBlockRetry,
+    clock: &dyn Clock,
+) -> Result<Block, M> {
+    let block_id = block_id.into();
+    let mut attempt = 0;
+
+    loop {
+        let block: std::result::Result<Block, _> = middleware
+            .get_block(block_id)
+            .await
+            .context(EthersProviderSnafu)?
+            .ok_or(snafu::NoneError)
+            .context(BlockUnavailableSnafu)?
+            .try_into();
+
+        match block {
+            Ok(block) => return Ok(block),
+            Err(_) if attempt < retry.max_retries => {
+                attempt += 1;
+                clock.sleep(retry.delay).await;
+            }
+            Err(_) => return BlockIncompleteSnafu {}.fail(),
+        }
+    }
 }
 
 pub async fn current_block_number<M: Middleware + 'static>(middleware: &M) -> Result<U64, M> {
@@ -293,6 +599,25 @@ pub async fn fetch_block_at_depth<M: Middleware + 'static>(
     middleware: &M,
     current: U64,
     depth: usize,
+) -> Result<Block, M> {
+    fetch_block_at_depth_with_retry(
+        middleware,
+        current,
+        depth,
+        IncompleteBlockRetry::default(),
+        &TokioClock,
+    )
+    .await
+}
+
+/// Same as `fetch_block_at_depth`, retrying incomplete blocks according to
+/// `retry`, waiting between attempts on `clock`.
+pub async fn fetch_block_at_depth_with_retry<M: Middleware + 'static>(
+    middleware: &M,
+    current: U64,
+    depth: usize,
+    retry: IncompleteBlockRetry,
+    clock: &dyn Clock,
 ) -> Result<Block, M> {
     ensure!(
         current > depth.into(),
@@ -302,16 +627,78 @@ pub async fn fetch_block_at_depth<M: Middleware + 'static>(
         }
     );
 
-    fetch_block(middleware, current - depth).await
+    fetch_block_with_retry(middleware, current - depth, retry, clock).await
+}
+
+/// Blocks of the branch of `head` numbered after `after`, oldest first,
+/// walking its parents with `block_with_hash`.
+async fn branch<M: Middleware + 'static, Fut>(
+    head: &Arc<Block>,
+    after: U64,
+    mut block_with_hash: impl FnMut(H256) -> Fut,
+) -> Result<Vec<Arc<Block>>, M>
+where
+    Fut: Future<Output = Result<Arc<Block>, M>>,
+{
+    let mut branch = vec![];
+    let mut current = Arc::clone(head);
+
+    while current.number > after {
+        let parent = block_with_hash(current.parent_hash).await?;
+        branch.push(current);
+        current = parent;
+    }
+
+    branch.reverse();
+    Ok(branch)
+}
+
+/// Latest block shared by the branches of `a` and `b`, walking their parents
+/// with `block_with_hash`. Returns `None` if no shared block is found within
+/// `max_depth` blocks of the higher of the two. See
+/// `BlockArchive::common_ancestor`.
+pub async fn common_ancestor<M: Middleware + 'static, Fut>(
+    a: &H256,
+    b: &H256,
+    max_depth: usize,
+    mut block_with_hash: impl FnMut(H256) -> Fut,
+) -> Result<Option<Arc<Block>>, M>
+where
+    Fut: Future<Output = Result<Arc<Block>, M>>,
+{
+    let mut a = block_with_hash(*a).await?;
+    let mut b = block_with_hash(*b).await?;
+    let floor = a.number.max(b.number).saturating_sub(max_depth.into());
+
+    while a.hash != b.hash {
+        let number = a.number.max(b.number);
+        if number <= floor || number.is_zero() {
+            return Ok(None);
+        }
+
+        if a.number == number {
+            a = block_with_hash(a.parent_hash).await?;
+        }
+        if b.number == number {
+            b = block_with_hash(b.parent_hash).await?;
+        }
+    }
+
+    Ok(Some(a))
 }
 
 #[cfg(test)]
 mod tests {
-    use super::BlockArchive;
-    use eth_state_fold_test::mock_middleware::MockMiddleware;
-    use eth_state_fold_types::BlocksSince;
+    use super::{BlockArchive, BlockArchiveError, BlockPush};
+    use crate::{BatchRequest, BatchResponse, BatchTransport};
+    use eth_state_fold_test::mock_middleware::{MockError, MockMiddleware};
+    use eth_state_fold_types::ethereum_types::{H256, U64};
+    use eth_state_fold_types::ethers;
+    use eth_state_fold_types::{Block, BlocksSince};
+    use ethers::types::BlockId;
 
-    use std::sync::Arc;
+    use async_trait::async_trait;
+    use std::sync::{Arc, Mutex};
 
     async fn instantiate_all() -> (Arc<MockMiddleware>, BlockArchive<MockMiddleware>) {
         let max_depth = 100;
@@ -346,6 +733,33 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn retention_test() {
+        let m = MockMiddleware::new(128).await;
+        let archive = BlockArchive::new(Arc::clone(&m), 8).await.unwrap();
+        let retention = 8 + super::RETENTION_MARGIN;
+
+        let mut latest = m.get_latest_block().await.unwrap().hash;
+        for _ in 0..(2 * retention) {
+            latest = m.add_block(latest).await.unwrap();
+            update_archive_with_latest(&m, &archive).await;
+            archive.block_at_depth(8).await.unwrap();
+        }
+
+        // Blocks older than the retained history are dropped, or never kept.
+        archive.block_with_number(1.into()).await.unwrap();
+        assert_eq!(archive.block_tree.read().await.len(), retention + 1);
+
+        assert!(matches!(
+            archive.check_depth(9),
+            Err(super::BlockArchiveError::BlockOutOfRange {
+                depth: 9,
+                max_depth: 8
+            })
+        ));
+        assert!(archive.check_depth(8).is_ok());
+    }
+
     #[tokio::test]
     async fn straight_test() {
         let (m, archive) = instantiate_all().await;
@@ -477,6 +891,38 @@ mod tests {
         };
     }
 
+    #[tokio::test]
+    async fn common_ancestor_test() {
+        let (m, archive) = instantiate_all().await;
+
+        let fork = m.get_block_with_number(120.into()).await.unwrap().hash;
+        let mut a = fork;
+        let mut b = fork;
+        for _ in 0..4 {
+            a = m.add_block(a).await.unwrap();
+        }
+        for _ in 0..6 {
+            b = m.add_block(b).await.unwrap();
+        }
+
+        let ancestor = archive.common_ancestor(&a, &b, 16).await.unwrap();
+        assert_eq!(ancestor.unwrap().hash, fork);
+        let ancestor = archive.common_ancestor(&b, &a, 16).await.unwrap();
+        assert_eq!(ancestor.unwrap().hash, fork);
+
+        // Same branch.
+        let ancestor = archive.common_ancestor(&fork, &b, 16).await.unwrap();
+        assert_eq!(ancestor.unwrap().hash, fork);
+        let ancestor = archive.common_ancestor(&a, &a, 0).await.unwrap();
+        assert_eq!(ancestor.unwrap().hash, a);
+
+        // Fork deeper than the bound.
+        let ancestor = archive.common_ancestor(&a, &b, 5).await.unwrap();
+        assert!(ancestor.is_none());
+        let ancestor = archive.common_ancestor(&a, &b, 6).await.unwrap();
+        assert_eq!(ancestor.unwrap().hash, fork);
+    }
+
     #[tokio::test]
     async fn branching_reorg_test() {
         let (m, archive) = instantiate_all().await;
@@ -530,10 +976,199 @@ mod tests {
             }
 
             BlocksSince::Reorg(v) => {
-                assert_eq!(v.len(), 1);
-                let p = v[0].clone();
-                assert_eq!(p.number, 129.into());
+                assert_eq!(v.len(), 2);
+                assert_eq!(v[0].number, 129.into());
+                assert_eq!(v[1].number, 130.into());
+                assert_eq!(v[1].hash, archive.block_at_depth(8).await.unwrap().hash);
             }
         };
     }
+
+    #[tokio::test]
+    async fn push_test() {
+        let (m, archive) = instantiate_all().await;
+        let fork = archive.block_with_number(126.into()).await.unwrap();
+        let old_head = archive.latest_block().await;
+
+        let hash = m.add_block(old_head.hash).await.unwrap();
+        let block = Arc::new(m.get_block(hash).await.unwrap());
+        assert_eq!(archive.push(block).await.unwrap(), BlockPush::Extended);
+        let old_head = archive.latest_block().await;
+
+        let mut new_head = fork.hash;
+        for _ in 0..4 {
+            new_head = m.add_block(new_head).await.unwrap();
+        }
+        let new_head = Arc::new(m.get_block(new_head).await.unwrap());
+
+        let reorg = match archive.push(Arc::clone(&new_head)).await.unwrap() {
+            BlockPush::Reorg(reorg) => reorg,
+            BlockPush::Extended => panic!("expected reorg"),
+        };
+        assert_eq!(reorg.ancestor.hash, fork.hash);
+
+        let numbers = |blocks: &[Arc<eth_state_fold_types::Block>]| {
+            blocks.iter().map(|b| b.number.as_u64()).collect::<Vec<_>>()
+        };
+        assert_eq!(numbers(&reorg.orphaned), vec![127, 128, 129]);
+        assert_eq!(reorg.orphaned.last().unwrap().hash, old_head.hash);
+        assert_eq!(numbers(&reorg.adopted), vec![127, 128, 129, 130]);
+        assert_eq!(reorg.adopted.last().unwrap().hash, new_head.hash);
+
+        // The archive follows the new branch.
+        assert_eq!(archive.latest_block().await.hash, new_head.hash);
+        assert_eq!(
+            archive.block_with_number(127.into()).await.unwrap().hash,
+            reorg.adopted[0].hash
+        );
+
+        // A branch deeper than the retained history is rejected, and the
+        // latest block kept.
+        let archive = BlockArchive::new(Arc::clone(&m), 0).await.unwrap();
+        let latest = archive.latest_block().await;
+        let fork = m.add_block(m.get_block_with_number(10.into()).await.unwrap().hash);
+        let fork = Arc::new(m.get_block(fork.await.unwrap()).await.unwrap());
+        assert!(matches!(
+            archive.push(fork).await,
+            Err(BlockArchiveError::ReorgTooDeep { .. })
+        ));
+        assert_eq!(archive.latest_block().await.hash, latest.hash);
+    }
+
+    /// Records every batch of block numbers, answering from the branch of
+    /// `tip`, or from the latest block if `None`.
+    #[derive(Debug, Default)]
+    struct RecordingTransport {
+        batches: Mutex<Vec<Vec<U64>>>,
+        tip: Option<H256>,
+    }
+
+    #[async_trait]
+    impl BatchTransport<MockMiddleware> for RecordingTransport {
+        async fn send_batch(
+            &self,
+            middleware: &MockMiddleware,
+            _block: BlockId,
+            requests: &[BatchRequest],
+        ) -> Result<Vec<BatchResponse>, MockError> {
+            let numbers: Vec<U64> = requests
+                .iter()
+                .map(|request| match request {
+                    BatchRequest::GetBlockByNumber { number } => *number,
+                    _ => panic!("expected block requests"),
+                })
+                .collect();
+            self.batches.lock().unwrap().push(numbers.clone());
+
+            let tip = match self.tip {
+                Some(tip) => tip,
+                None => middleware.get_latest_block().await.unwrap().hash,
+            };
+
+            let mut responses = vec![];
+            for number in numbers {
+                let block = middleware
+                    .get_block_with_number_from(number, tip)
+                    .await
+                    .unwrap();
+                let block = ethers::providers::Middleware::get_block(middleware, block.hash)
+                    .await?
+                    .map(Box::new);
+                responses.push(BatchResponse::Block(block));
+            }
+
+            Ok(responses)
+        }
+    }
+
+    fn assert_continuous(previous: &Block, blocks: &[Arc<Block>]) {
+        let mut parent = previous;
+        for block in blocks {
+            assert_eq!(block.parent_hash, parent.hash);
+            assert_eq!(block.number, parent.number + 1);
+            parent = block;
+        }
+    }
+
+    async fn catch_up(
+        m: &MockMiddleware,
+        archive: &BlockArchive<MockMiddleware>,
+        previous: Arc<Block>,
+    ) -> Vec<Arc<Block>> {
+        update_archive_with_latest(m, archive).await;
+
+        match archive
+            .blocks_since(0, Arc::clone(&previous))
+            .await
+            .unwrap()
+        {
+            BlocksSince::Normal(blocks) => {
+                assert_eq!(blocks.len(), 100);
+                assert_continuous(&previous, &blocks);
+                blocks
+            }
+
+            BlocksSince::Reorg(_) => panic!("expected no reorg"),
+        }
+    }
+
+    #[tokio::test]
+    async fn batched_catch_up_test() {
+        let (m, archive) = instantiate_all().await;
+        let transport = Arc::new(RecordingTransport::default());
+        archive.set_block_batch_transport(Some(transport.clone()));
+
+        let previous = archive.latest_block().await;
+        let mut latest = previous.hash;
+        for _ in 0..100 {
+            latest = m.add_block(latest).await.unwrap();
+        }
+
+        let blocks = catch_up(&m, &archive, previous).await;
+        assert_eq!(blocks.last().unwrap().hash, latest);
+
+        // The blocks between `previous` and the new latest block, newest first.
+        let batches: Vec<Vec<u64>> = transport
+            .batches
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|batch| batch.iter().map(U64::as_u64).collect())
+            .collect();
+        assert_eq!(
+            batches,
+            vec![
+                (178..228).rev().collect(),
+                (129..178).rev().collect::<Vec<_>>()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn batched_catch_up_reorg_test() {
+        let (m, archive) = instantiate_all().await;
+        let previous = archive.latest_block().await;
+
+        // Batches answered from a competing branch, as if the chain reorged
+        // while catching up.
+        let mut fork = previous.hash;
+        let mut latest = previous.hash;
+        for _ in 0..120 {
+            fork = m.add_block(fork).await.unwrap();
+        }
+        for _ in 0..100 {
+            latest = m.add_block(latest).await.unwrap();
+        }
+
+        let transport = Arc::new(RecordingTransport {
+            tip: Some(fork),
+            ..Default::default()
+        });
+        archive.set_block_batch_transport(Some(transport.clone()));
+
+        // The blocks off the branch are dropped, and fetched by hash instead.
+        let blocks = catch_up(&m, &archive, previous).await;
+        assert_eq!(blocks.last().unwrap().hash, latest);
+        assert_eq!(transport.batches.lock().unwrap().len(), 1);
+    }
 }
@@ -1,13 +1,18 @@
 // (c) Cartesi and individual authors (see AUTHORS)
 // SPDX-License-Identifier: Apache-2.0 (see LICENSE)
 
-use crate::block_archive::{self, BlockArchive};
+use crate::block_archive::{self, BlockArchive, BlockPush};
+use crate::poll_interval::{PollInterval, PollTracker};
+use crate::{BatchTransport, Clock, TokioClock};
 
 use eth_state_fold_types::{
+    ethereum_types::{H256, U64},
     ethers::providers::{Middleware, Provider, ProviderError, Ws},
     Block, BlockError, BlockStreamItem, BlocksSince,
 };
 
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use tokio::sync::{oneshot, watch};
 use tokio_stream::{Stream, StreamExt};
@@ -45,25 +50,151 @@ pub enum SubscriptionError<M: Middleware + 'static> {
     ArchiveError {
         source: block_archive::BlockArchiveError<M>,
     },
+
+    #[snafu(display("Reached maximum of {} subscribers", max_subscribers))]
+    TooManySubscribers { max_subscribers: usize },
 }
 pub type SubscriptionResult<T, M> = std::result::Result<T, SubscriptionError<M>>;
 
+/// Health signal of the chain followed by a `BlockSubscriber`. See
+/// `BlockSubscriber::health`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChainHealth {
+    /// No new block arrived within the stall threshold. `since` is when the
+    /// last block arrived, or when watching started if none has.
+    Stalled {
+        since: tokio::time::Instant,
+        last_block: Arc<Block>,
+    },
+
+    /// Blocks flow again after a stall; `block` is the first new one.
+    Resumed { block: Arc<Block> },
+}
+
 pub struct BlockSubscriber<M: Middleware + 'static> {
     pub handle: tokio::task::JoinHandle<Result<(), Provider<Ws>>>,
     pub block_archive: Arc<BlockArchive<M>>,
     pub shutdown_notifier: watch::Receiver<Option<Result<(), Provider<Ws>>>>,
 
     new_block_alarm: watch::Receiver<()>,
-    _kill_switch: oneshot::Sender<()>,
+    subscribers: Arc<AtomicUsize>,
+    max_subscribers: Option<usize>,
+    kill_switch: std::sync::Mutex<Option<oneshot::Sender<()>>>,
+    poll: Arc<std::sync::Mutex<PollTracker>>,
+    deduplicate: Arc<AtomicBool>,
+    clamp_depth: AtomicBool,
+    clock: SharedClock,
+}
+
+// Clock of a subscriber, shared with its background task, which can be
+// replaced after it started.
+type SharedClock = Arc<std::sync::Mutex<Arc<dyn Clock>>>;
+
+fn current_clock(clock: &SharedClock) -> Arc<dyn Clock> {
+    Arc::clone(&clock.lock().unwrap())
 }
 
 impl<M: Middleware + 'static> BlockSubscriber<M> {
+    /// Starts a subscriber listening to the Ws subscription of new blocks at
+    /// `ws_url`. When no block arrives within `poll_interval`, either a fixed
+    /// duration or a `PollInterval`, the node is polled for missed blocks.
     pub async fn start(
         middleware: Arc<M>,
         ws_url: String,
-        subscriber_timeout: std::time::Duration,
+        poll_interval: impl Into<PollInterval>,
         max_depth: usize,
+        max_subscribers: Option<usize>,
     ) -> crate::block_archive::Result<Self, M> {
+        Self::spawn(
+            middleware,
+            max_depth,
+            max_subscribers,
+            poll_interval.into(),
+            move |archive, new_block_tx, poll, deduplicate, clock| {
+                background_process(ws_url, archive, new_block_tx, poll, deduplicate, clock)
+            },
+        )
+        .await
+    }
+
+    /// Starts a subscriber listening to the given stream of new blocks,
+    /// instead of establishing a Ws subscription.
+    #[cfg(test)]
+    pub(crate) async fn start_with_subscription<S>(
+        middleware: Arc<M>,
+        max_depth: usize,
+        max_subscribers: Option<usize>,
+        subscription: S,
+    ) -> crate::block_archive::Result<Self, M>
+    where
+        S: Stream<Item = Result<Arc<Block>, M>> + Send + Unpin + 'static,
+    {
+        Self::spawn(
+            middleware,
+            max_depth,
+            max_subscribers,
+            PollInterval::Fixed(std::time::Duration::MAX),
+            move |archive, new_block_tx, _, deduplicate, _| async move {
+                let listen =
+                    listen_and_broadcast(archive, &new_block_tx, subscription, deduplicate);
+                if let Err(e) = listen.await {
+                    tracing::debug!("`listen_and_broadcast` stopped: `{}`", e);
+                }
+
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    /// Same as `start_with_subscription`, timing out on the blocks of the
+    /// stream after `poll_interval`, as the Ws subscription does.
+    #[cfg(test)]
+    pub(crate) async fn start_with_polled_subscription<S>(
+        middleware: Arc<M>,
+        max_depth: usize,
+        poll_interval: PollInterval,
+        subscription: S,
+    ) -> crate::block_archive::Result<Self, M>
+    where
+        S: Stream<Item = Result<Arc<Block>, M>> + Send + Unpin + 'static,
+    {
+        Self::spawn(
+            middleware,
+            max_depth,
+            None,
+            poll_interval,
+            move |archive, new_block_tx, poll, deduplicate, clock| async move {
+                let subscription = Box::pin(timed_blocks(subscription, poll, clock));
+                let listen =
+                    listen_and_broadcast(archive, &new_block_tx, subscription, deduplicate);
+                if let Err(e) = listen.await {
+                    tracing::debug!("`listen_and_broadcast` stopped: `{}`", e);
+                }
+
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    async fn spawn<P, Fut>(
+        middleware: Arc<M>,
+        max_depth: usize,
+        max_subscribers: Option<usize>,
+        poll_interval: PollInterval,
+        process: P,
+    ) -> crate::block_archive::Result<Self, M>
+    where
+        P: FnOnce(
+            Arc<BlockArchive<M>>,
+            watch::Sender<()>,
+            Arc<std::sync::Mutex<PollTracker>>,
+            Arc<AtomicBool>,
+            SharedClock,
+        ) -> Fut,
+        Fut: Future<Output = Result<(), Provider<Ws>>> + Send + 'static,
+    {
         let archive = Arc::new(BlockArchive::new(middleware.clone(), max_depth).await?);
 
         let (kill_tx, kill_rx) = oneshot::channel();
@@ -71,12 +202,22 @@ impl<M: Middleware + 'static> BlockSubscriber<M> {
         let (new_block_tx, new_block_alarm) = watch::channel(());
 
         let block_archive = archive.clone();
+        let poll = Arc::new(std::sync::Mutex::new(PollTracker::new(poll_interval)));
+        let deduplicate = Arc::new(AtomicBool::new(true));
+        let clock: SharedClock = Arc::new(std::sync::Mutex::new(Arc::new(TokioClock)));
+
+        // Create future of `background_process` main loop. This future will
+        // run against the kill_switch.
+        let task = process(
+            archive,
+            new_block_tx,
+            Arc::clone(&poll),
+            Arc::clone(&deduplicate),
+            Arc::clone(&clock),
+        );
 
         // Create background task and detach it.
         let handle = tokio::spawn(async move {
-            // Create future of `background_process` main loop. This
-            // future will run against the kill_switch.
-            let task = background_process(ws_url, archive, new_block_tx, subscriber_timeout);
             tokio::pin!(task);
 
             tokio::select! {
@@ -90,7 +231,10 @@ impl<M: Middleware + 'static> BlockSubscriber<M> {
                     res
                 },
 
+                // Either `shutdown` was called or the subscriber was dropped.
                 _ = kill_rx => {
+                    tracing::debug!("Shutting down BlockSubscriber");
+                    let _ = shutdown_sender.send(Some(Ok(())));
                     Ok(())
                 }
             }
@@ -101,10 +245,68 @@ impl<M: Middleware + 'static> BlockSubscriber<M> {
             block_archive,
             shutdown_notifier,
             new_block_alarm,
-            _kill_switch: kill_tx,
+            subscribers: Arc::new(AtomicUsize::new(0)),
+            max_subscribers,
+            kill_switch: std::sync::Mutex::new(Some(kill_tx)),
+            poll,
+            deduplicate,
+            clamp_depth: AtomicBool::new(false),
+            clock,
         })
     }
 
+    /// How long the subscriber currently waits for a new block before polling
+    /// the node. Changes over time under `PollInterval::Adaptive`.
+    pub fn poll_interval(&self) -> std::time::Duration {
+        self.poll.lock().unwrap().interval()
+    }
+
+    /// Whether notifications of blocks already on the current chain, such as
+    /// those repeated by providers or overlapping a reconnection, are dropped
+    /// instead of rewinding the latest block to them. A block of another
+    /// branch is never dropped, as it's a reorg. Enabled by default.
+    pub fn set_deduplication(&self, enabled: bool) {
+        self.deduplicate.store(enabled, Ordering::SeqCst);
+    }
+
+    /// Sets the clock timing the polls for missed blocks and the stall
+    /// detection of `health`, `TokioClock` by default. Streams of `health`
+    /// already returned keep their clock.
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        *self.clock.lock().unwrap() = clock;
+    }
+
+    /// Whether subscriptions deeper than the chain (e.g. a fresh devnet) start
+    /// right away from its genesis block, with a warning, as if their depth
+    /// were clamped to its height, instead of waiting for a block at their
+    /// depth. Disabled by default.
+    pub fn set_depth_clamping(&self, enabled: bool) {
+        self.clamp_depth.store(enabled, Ordering::SeqCst);
+    }
+
+    /// Sets the transport batching the block fetches of subscriptions catching
+    /// up on large gaps. See `BlockArchive::set_block_batch_transport`.
+    pub fn set_block_batch_transport(&self, transport: Option<Arc<dyn BatchTransport<M>>>) {
+        self.block_archive.set_block_batch_transport(transport);
+    }
+
+    /// Stops the background task and waits for it to finish. Subscriptions end
+    /// after yielding their pending items. Dropping the `BlockSubscriber` also
+    /// stops the background task. Calling it more than once is a no-op.
+    pub async fn shutdown(&self) {
+        let kill_switch = self
+            .kill_switch
+            .lock()
+            .expect("`kill_switch` lock should not be poisoned")
+            .take();
+
+        if let Some(kill_switch) = kill_switch {
+            let _ = kill_switch.send(());
+        }
+
+        let _ = self.wait_for_completion().await;
+    }
+
     pub async fn wait_for_completion(&self) -> Result<(), Provider<Ws>> {
         let mut notifier = self.shutdown_notifier.clone();
 
@@ -130,44 +332,307 @@ impl<M: Middleware + 'static> BlockSubscriber<M> {
         }
     }
 
+    /// Number of live subscriptions. A subscription is live until its stream
+    /// is dropped.
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers.load(Ordering::SeqCst)
+    }
+
+    /// Subscribes to blocks at the given depth from the latest block. The first
+    /// item is always a snapshot of the block currently at `depth`, followed by
+    /// live updates. If the chain is shorter than `depth`, the stream waits
+    /// until there is a block at that depth, unless depth clamping is enabled
+    /// with `set_depth_clamping`.
+    ///
+    /// Fails with `ArchiveError` if `depth` exceeds the `max_depth` set at
+    /// `start`, beyond which history is not retained, and with
+    /// `TooManySubscribers` if the maximum number of subscribers set at `start`
+    /// has been reached.
     pub async fn subscribe_new_blocks_at_depth(
         &self,
         depth: usize,
-    ) -> block_archive::Result<impl Stream<Item = SubscriptionResult<BlockStreamItem, M>> + Unpin, M>
+    ) -> SubscriptionResult<impl Stream<Item = SubscriptionResult<BlockStreamItem, M>> + Unpin, M>
     {
+        self.subscribe(depth, None, false)
+    }
+
+    /// Same as `subscribe_new_blocks_at_depth`, but on reorgs also emits each
+    /// previously emitted block dropped by the reorg as an `Orphaned` item,
+    /// oldest first, before the `Reorg` item. Useful to record orphaned blocks,
+    /// e.g. to measure reorg rates.
+    pub async fn subscribe_new_blocks_at_depth_with_orphans(
+        &self,
+        depth: usize,
+    ) -> SubscriptionResult<impl Stream<Item = SubscriptionResult<BlockStreamItem, M>> + Unpin, M>
+    {
+        self.subscribe(depth, None, true)
+    }
+
+    /// Resumes a subscription to blocks at the given depth from the latest
+    /// block. The stream first replays every block after `last_seen` up to the
+    /// block currently at `depth`, then continues with live updates, without
+    /// gaps or duplicates. If `last_seen` has been reorged out, the first item
+    /// is a `Reorg`.
+    ///
+    /// Fails with `ArchiveError` if `depth` exceeds the `max_depth` set at
+    /// `start`, beyond which history is not retained, and with
+    /// `TooManySubscribers` if the maximum number of subscribers set at `start`
+    /// has been reached.
+    pub async fn subscribe_from(
+        &self,
+        last_seen: Arc<Block>,
+        depth: usize,
+    ) -> SubscriptionResult<impl Stream<Item = SubscriptionResult<BlockStreamItem, M>> + Unpin, M>
+    {
+        self.subscribe(depth, Some(last_seen), false)
+    }
+
+    /// Watches the tip, yielding `Stalled` once no new block has arrived for
+    /// `stall_threshold`, and `Resumed` when blocks flow again. The threshold
+    /// should be a comfortable multiple of the chain's expected block time, so
+    /// that sporadic slow blocks aren't reported. The stream ends when the
+    /// subscriber is shut down.
+    pub fn health(
+        &self,
+        stall_threshold: std::time::Duration,
+    ) -> impl Stream<Item = ChainHealth> + Unpin {
         let archive = self.block_archive.clone();
         let mut alarm = self.new_block_alarm.clone();
+        let clock = current_clock(&self.clock);
+
+        Box::pin(async_stream::stream! {
+            let mut since = clock.now();
+            let mut stalled = false;
+
+            loop {
+                // A block arriving right at the threshold isn't a stall.
+                let changed = tokio::select! {
+                    biased;
+                    changed = alarm.changed() => Some(changed),
+                    _ = clock.sleep(stall_threshold) => None,
+                };
+
+                match changed {
+                    Some(Ok(())) => {
+                        since = clock.now();
+
+                        if stalled {
+                            stalled = false;
+                            let block = archive.latest_block().await;
+                            yield ChainHealth::Resumed { block };
+                        }
+                    }
+
+                    // Subscriber shut down.
+                    Some(Err(_)) => break,
+
+                    None if !stalled => {
+                        stalled = true;
+                        let last_block = archive.latest_block().await;
+                        tracing::warn!(
+                            "No new block since `{}` for over {:?}",
+                            last_block.number,
+                            stall_threshold
+                        );
+
+                        yield ChainHealth::Stalled { since, last_block };
+                    }
+
+                    None => {}
+                }
+            }
+        })
+    }
+
+    /// Latest block shared by the branches of `a` and `b`, or `None` if there's
+    /// none within `max_depth` blocks of the higher of the two. Blocks are
+    /// taken from the tracked history when possible, and fetched otherwise.
+    pub async fn common_ancestor(
+        &self,
+        a: &H256,
+        b: &H256,
+        max_depth: usize,
+    ) -> crate::block_archive::Result<Option<Arc<Block>>, M> {
+        self.block_archive.common_ancestor(a, b, max_depth).await
+    }
+}
+
+/// Internals
+impl<M: Middleware + 'static> BlockSubscriber<M> {
+    fn subscribe(
+        &self,
+        depth: usize,
+        last_seen: Option<Arc<Block>>,
+        with_orphans: bool,
+    ) -> SubscriptionResult<impl Stream<Item = SubscriptionResult<BlockStreamItem, M>> + Unpin, M>
+    {
+        self.block_archive
+            .check_depth(depth)
+            .context(ArchiveSnafu)?;
 
-        let mut previous = archive.block_at_depth(depth).await?;
+        let guard = self.new_subscription()?;
+        let clamp_depth = self.clamp_depth.load(Ordering::SeqCst);
+        let archive = self.block_archive.clone();
+        let mut alarm = self.new_block_alarm.clone();
+        let shutdown = self.shutdown_notifier.clone();
 
         Ok(Box::pin(async_stream::try_stream! {
-            while let () = alarm.changed().await.context(SubscriptionDroppedSnafu)? {
-                let diff = archive
-                    .blocks_since(depth, Arc::clone(&previous))
-                    .await
-                    .context(ArchiveSnafu)?;
-
-                match diff {
-                    BlocksSince::Normal(blocks) => {
-                        if let Some(p) = blocks.last() {
-                            previous = p.clone();
-                            for b in blocks {
-                                yield BlockStreamItem::NewBlock(b);
+            // Keep the subscription counted until the stream is dropped.
+            let _guard = guard;
+
+            let mut catching_up = last_seen.is_some();
+            let mut start = last_seen;
+
+            if start.is_none() {
+                while start.is_none() {
+                    match archive.block_at_depth(depth).await {
+                        Ok(block) => start = Some(block),
+
+                        Err(block_archive::BlockArchiveError::DepthTooHigh { latest, .. })
+                            if clamp_depth =>
+                        {
+                            tracing::warn!(
+                                "Subscription depth `{}` clamped to the height `{}` of the chain",
+                                depth,
+                                latest
+                            );
+                            start = Some(archive.block_at_depth(latest).await.context(ArchiveSnafu)?);
+                        }
+
+                        Err(block_archive::BlockArchiveError::DepthTooHigh { .. }) => {
+                            if !new_block(&mut alarm, &shutdown).await? {
+                                break;
                             }
                         }
+
+                        Err(e) => Err(e).context(ArchiveSnafu)?,
                     }
+                }
 
-                    BlocksSince::Reorg(blocks) => {
-                        if let Some(p) = blocks.last() {
-                            previous = p.clone();
+                if let Some(snapshot) = &start {
+                    yield BlockStreamItem::NewBlock(Arc::clone(snapshot));
+                }
+            }
+
+            if let Some(mut previous) = start {
+                // When resuming, catch up before waiting for new blocks.
+                while catching_up || new_block(&mut alarm, &shutdown).await? {
+                    catching_up = false;
+
+                    // Past the retained history, the blocks since `previous`
+                    // can't be resolved reliably, so skip to `depth`.
+                    if history_gap(&archive, &previous).await.context(ArchiveSnafu)? {
+                        let to = archive.block_at_depth(depth).await.context(ArchiveSnafu)?;
+                        let from = std::mem::replace(&mut previous, Arc::clone(&to));
+
+                        yield BlockStreamItem::HistoryGap { from, to };
+                        continue;
+                    }
+
+                    let diff = archive
+                        .blocks_since(depth, Arc::clone(&previous))
+                        .await
+                        .context(ArchiveSnafu)?;
+
+                    match diff {
+                        BlocksSince::Normal(blocks) => {
+                            if let Some(p) = blocks.last() {
+                                previous = p.clone();
+                                for b in blocks {
+                                    yield BlockStreamItem::NewBlock(b);
+                                }
+                            }
                         }
 
-                        yield BlockStreamItem::Reorg(blocks);
+                        BlocksSince::Reorg(blocks) => {
+                            if with_orphans {
+                                let orphaned = orphaned_blocks(&archive, &previous, &blocks)
+                                    .await
+                                    .context(ArchiveSnafu)?;
+
+                                for b in orphaned {
+                                    yield BlockStreamItem::Orphaned(b);
+                                }
+                            }
+
+                            if let Some(p) = blocks.last() {
+                                previous = p.clone();
+                            }
+
+                            yield BlockStreamItem::Reorg(blocks);
+                        }
                     }
                 }
             }
         }))
     }
+
+    fn new_subscription(&self) -> SubscriptionResult<SubscriptionGuard, M> {
+        let max_subscribers = self.max_subscribers.unwrap_or(usize::MAX);
+
+        self.subscribers
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                (count < max_subscribers).then_some(count + 1)
+            })
+            .map_err(|_| TooManySubscribersSnafu { max_subscribers }.build())?;
+
+        Ok(SubscriptionGuard(Arc::clone(&self.subscribers)))
+    }
+}
+
+/// Decrements the subscriber count when dropped.
+struct SubscriptionGuard(Arc<AtomicUsize>);
+
+impl Drop for SubscriptionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Blocks of the branch of `previous` replaced by `reorg`, oldest first, taken
+/// from the archive while it still holds them.
+async fn orphaned_blocks<M: Middleware + 'static>(
+    archive: &BlockArchive<M>,
+    previous: &Arc<Block>,
+    reorg: &[Arc<Block>],
+) -> crate::block_archive::Result<Vec<Arc<Block>>, M> {
+    match reorg.first() {
+        Some(first) => archive.branch(previous, first.number - 1).await,
+        None => Ok(vec![]),
+    }
+}
+
+/// Whether the blocks since `previous` are out of the history retained by
+/// `archive`: it's further behind the latest block than is retained, or on a
+/// branch forking off the current one further down than that.
+async fn history_gap<M: Middleware + 'static>(
+    archive: &BlockArchive<M>,
+    previous: &Block,
+) -> block_archive::Result<bool, M> {
+    let latest = archive.latest_block().await;
+    let retained_depth = archive.retained_depth();
+    if latest.number.saturating_sub(previous.number) > U64::from(retained_depth) {
+        return Ok(true);
+    }
+
+    let ancestor = archive
+        .common_ancestor(&latest.hash, &previous.hash, retained_depth)
+        .await?;
+
+    Ok(ancestor.is_none())
+}
+
+/// Waits for a new block. Returns `false` if the subscriber was shut down, so
+/// subscriptions end cleanly instead of erroring.
+async fn new_block<M: Middleware + 'static>(
+    alarm: &mut watch::Receiver<()>,
+    shutdown: &watch::Receiver<Option<Result<(), Provider<Ws>>>>,
+) -> SubscriptionResult<bool, M> {
+    match alarm.changed().await {
+        Ok(()) => Ok(true),
+        Err(_) if matches!(*shutdown.borrow(), Some(Ok(()))) => Ok(false),
+        Err(e) => Err(e).context(SubscriptionDroppedSnafu),
+    }
 }
 
 #[tracing::instrument(skip_all)]
@@ -175,7 +640,9 @@ async fn background_process<M: Middleware + 'static>(
     ws_url: String,
     block_archive: Arc<BlockArchive<M>>,
     new_block_alarm: watch::Sender<()>,
-    subscriber_timeout: std::time::Duration,
+    poll: Arc<std::sync::Mutex<PollTracker>>,
+    deduplicate: Arc<AtomicBool>,
+    clock: SharedClock,
 ) -> Result<(), Provider<Ws>> {
     loop {
         tracing::trace!("Starting Ws connection at {}", ws_url);
@@ -190,20 +657,25 @@ async fn background_process<M: Middleware + 'static>(
             .await
             .context(EthersProviderSnafu)
             .map(|subscription| {
-                Box::pin(subscription.timeout(subscriber_timeout).map(|x| {
-                    let block_header = x
-                        .map_err(|e| Arc::new(e.into()))
-                        .context(NewBlockSubscriberTimeoutSnafu)?;
-
+                let blocks = subscription.map(|block_header| {
                     let block = block_header
                         .try_into()
                         .map_err(|err| BlockIncompleteSnafu { err }.build())?;
 
                     Ok(Arc::new(block))
-                }))
+                });
+
+                Box::pin(timed_blocks(blocks, Arc::clone(&poll), Arc::clone(&clock)))
             })?;
 
-        match listen_and_broadcast(block_archive.clone(), &new_block_alarm, subscription).await {
+        let listen = listen_and_broadcast(
+            block_archive.clone(),
+            &new_block_alarm,
+            subscription,
+            Arc::clone(&deduplicate),
+        );
+
+        match listen.await {
             Err(e) => {
                 tracing::warn!(
                     "`listen_and_broadcast` error `{}`, retrying subscription",
@@ -221,29 +693,149 @@ async fn background_process<M: Middleware + 'static>(
     }
 }
 
+/// Blocks of `blocks`, or `NewBlockSubscriberTimeout` whenever none arrives
+/// within the current interval of `poll`, on `clock`.
+fn timed_blocks<M: Middleware + 'static, S>(
+    blocks: S,
+    poll: Arc<std::sync::Mutex<PollTracker>>,
+    clock: SharedClock,
+) -> impl Stream<Item = Result<Arc<Block>, M>>
+where
+    S: Stream<Item = Result<Arc<Block>, M>> + Unpin,
+{
+    poll_timeout(blocks, poll, clock).map(|x| {
+        x.map_err(Arc::new)
+            .context(NewBlockSubscriberTimeoutSnafu)?
+    })
+}
+
+/// Items of `stream`, or a `TimedOut` error whenever none arrives within the
+/// current interval of `poll`, which is tuned on their arrival.
+fn poll_timeout<S: Stream + Unpin>(
+    mut stream: S,
+    poll: Arc<std::sync::Mutex<PollTracker>>,
+    clock: SharedClock,
+) -> impl Stream<Item = std::result::Result<S::Item, std::io::Error>> {
+    async_stream::stream! {
+        loop {
+            let interval = poll.lock().unwrap().interval();
+            let clock = current_clock(&clock);
+
+            let next = tokio::select! {
+                item = stream.next() => Some(item),
+                _ = clock.sleep(interval) => None,
+            };
+
+            match next {
+                Some(Some(item)) => {
+                    poll.lock().unwrap().observe_block(clock.now());
+                    yield Ok(item);
+                }
+
+                Some(None) => break,
+
+                None => {
+                    poll.lock().unwrap().observe_idle(clock.now());
+                    yield Err(std::io::ErrorKind::TimedOut.into());
+                }
+            }
+        }
+    }
+}
+
+/// Makes `block` the latest block of `archive`. If it can't be linked to the
+/// previous latest block (e.g. after a reorg deeper than the retained
+/// history), the archive restarts from it, so that it doesn't get stuck.
+async fn push_block<M: Middleware + 'static>(archive: &BlockArchive<M>, block: Arc<Block>) {
+    match archive.push(Arc::clone(&block)).await {
+        Ok(BlockPush::Extended) => {}
+
+        Ok(BlockPush::Reorg(reorg)) => tracing::debug!(
+            "Reorg of `{}` blocks from block `{}`",
+            reorg.orphaned.len(),
+            reorg.ancestor.number
+        ),
+
+        Err(e) => {
+            tracing::warn!("Restarting archive from block `{}`: `{}`", block.number, e);
+            let _ = archive.update_latest_block(block).await;
+        }
+    }
+}
+
+/// Polls in a row finding blocks missed by the subscription after which it's
+/// deemed stale, and retried.
+const MAX_MISSED_POLLS: usize = 3;
+
 #[tracing::instrument(skip_all)]
 async fn listen_and_broadcast<M: Middleware + 'static>(
     block_archive: Arc<BlockArchive<M>>,
     new_block_alarm: &watch::Sender<()>,
     mut subscription: impl Stream<Item = Result<Arc<Block>, M>> + Send + Unpin,
+    deduplicate: Arc<AtomicBool>,
 ) -> Result<(), M> {
+    // Polls in a row that found blocks the subscription didn't deliver. A
+    // live subscription may miss the odd block; one missing many is stale.
+    let mut missed_polls = 0;
+
     // Listen to new blocks and notify subscribers.
     loop {
         // Block on waiting for new block.
-        let new_head = subscription
+        let new_head = match subscription
             .next()
             .await
             .ok_or(snafu::NoneError)
-            .context(EthersSubscriptionDroppedSnafu)??;
+            .context(EthersSubscriptionDroppedSnafu)?
+        {
+            Ok(new_head) => new_head,
+
+            // Blocks may be produced sporadically (e.g. instant-mine dev
+            // nodes), so a timeout alone doesn't mean the subscription is
+            // stale. Blocks found by polling are broadcast, and we only retry
+            // the subscription if it keeps missing them.
+            Err(e) if matches!(*e, BlockSubscriberError::NewBlockSubscriberTimeout { .. }) => {
+                let latest = block_archive.latest_block().await;
+
+                match block_archive.fetch_latest_block().await {
+                    Ok(new_head) if new_head.number <= latest.number => {
+                        tracing::trace!("No new blocks since `{}`", latest.number);
+                        continue;
+                    }
+
+                    Ok(new_head) => {
+                        tracing::debug!("Polled missed block `{}`", new_head.number);
+                        push_block(&block_archive, new_head).await;
+                        let _ = new_block_alarm.send(());
+
+                        missed_polls += 1;
+                        if missed_polls >= MAX_MISSED_POLLS {
+                            return Err(e);
+                        }
+
+                        continue;
+                    }
+
+                    Err(_) => return Err(e),
+                }
+            }
+
+            Err(e) => return Err(e),
+        };
 
         tracing::trace!(
             "Subscriber received block with number `{}` and hash `{}`",
             new_head.number,
             new_head.hash
         );
+        missed_polls = 0;
+
+        if deduplicate.load(Ordering::SeqCst) && block_archive.on_current_chain(&new_head).await {
+            tracing::trace!("Dropping duplicate block `{}`", new_head.hash);
+            continue;
+        }
 
         // Insert in archive
-        let _ = block_archive.update_latest_block(new_head).await;
+        push_block(&block_archive, new_head).await;
 
         // Send new block to subscribers.
         if new_block_alarm.send(()).is_err() {
@@ -255,3 +847,598 @@ async fn listen_and_broadcast<M: Middleware + 'static>(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        BlockSubscriber, BlockSubscriberError, ChainHealth, SubscriptionError, SubscriptionResult,
+    };
+    use crate::block_archive::BlockArchiveError;
+    use crate::{Clock, ManualClock, PollInterval};
+    use eth_state_fold_test::mock_middleware::MockMiddleware;
+    use eth_state_fold_types::{Block, BlockStreamItem};
+
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+
+    type Sender = mpsc::Sender<super::Result<Arc<Block>, MockMiddleware>>;
+
+    async fn instantiate(m: &Arc<MockMiddleware>) -> (BlockSubscriber<MockMiddleware>, Sender) {
+        let (tx, rx) = mpsc::channel(16);
+        let subscriber = BlockSubscriber::start_with_subscription(
+            Arc::clone(m),
+            64,
+            Some(4),
+            ReceiverStream::new(rx),
+        )
+        .await
+        .unwrap();
+
+        (subscriber, tx)
+    }
+
+    async fn add_block(m: &Arc<MockMiddleware>, tx: &Sender) {
+        let block = new_block(m).await;
+        tx.send(Ok(block)).await.unwrap();
+    }
+
+    async fn new_block(m: &Arc<MockMiddleware>) -> Arc<Block> {
+        let latest = m.get_latest_block().await.unwrap();
+        let hash = m.add_block(latest.hash).await.unwrap();
+        Arc::new(m.get_block(hash).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn snapshot_test() {
+        let m = MockMiddleware::new(128).await;
+        let (subscriber, tx) = instantiate(&m).await;
+
+        for depth in [0, 1, 10] {
+            let expected = subscriber
+                .block_archive
+                .block_at_depth(depth)
+                .await
+                .unwrap();
+            let mut s = subscriber
+                .subscribe_new_blocks_at_depth(depth)
+                .await
+                .unwrap();
+
+            match s.next().await.unwrap().unwrap() {
+                BlockStreamItem::NewBlock(b) => assert_eq!(b, expected),
+                BlockStreamItem::Reorg(_)
+                | BlockStreamItem::Orphaned(_)
+                | BlockStreamItem::HistoryGap { .. } => panic!("expected snapshot"),
+            }
+
+            add_block(&m, &tx).await;
+
+            match s.next().await.unwrap().unwrap() {
+                BlockStreamItem::NewBlock(b) => assert_eq!(b.parent_hash, expected.hash),
+                BlockStreamItem::Reorg(_)
+                | BlockStreamItem::Orphaned(_)
+                | BlockStreamItem::HistoryGap { .. } => panic!("expected new block"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn short_chain_snapshot_test() {
+        let m = MockMiddleware::new(2).await;
+        let (subscriber, tx) = instantiate(&m).await;
+
+        let mut s = subscriber.subscribe_new_blocks_at_depth(4).await.unwrap();
+        let first = tokio::spawn(async move { s.next().await.unwrap().unwrap() });
+
+        // Chain goes from block 2 to block 4, reaching depth 4 at genesis.
+        for _ in 0..2 {
+            add_block(&m, &tx).await;
+        }
+
+        match first.await.unwrap() {
+            BlockStreamItem::NewBlock(b) => assert_eq!(b.number, 0.into()),
+            BlockStreamItem::Reorg(_)
+            | BlockStreamItem::Orphaned(_)
+            | BlockStreamItem::HistoryGap { .. } => panic!("expected snapshot"),
+        }
+
+        // Clamped, deeper subscriptions start from genesis right away, and
+        // move on once the chain reaches their depth.
+        subscriber.set_depth_clamping(true);
+        let mut s = subscriber.subscribe_new_blocks_at_depth(10).await.unwrap();
+        assert_eq!(next_number(&mut s).await, 0);
+
+        for _ in 4..11 {
+            add_block(&m, &tx).await;
+        }
+        assert_eq!(next_number(&mut s).await, 1);
+    }
+
+    #[tokio::test]
+    async fn drop_test() {
+        let m = MockMiddleware::new(128).await;
+        let (subscriber, _tx) = instantiate(&m).await;
+
+        let mut notifier = subscriber.shutdown_notifier.clone();
+        let mut s = subscriber.subscribe_new_blocks_at_depth(0).await.unwrap();
+        assert!(s.next().await.unwrap().is_ok());
+
+        drop(subscriber);
+
+        notifier.changed().await.unwrap();
+        assert!(matches!(*notifier.borrow(), Some(Ok(()))));
+        assert!(s.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn shutdown_test() {
+        let m = MockMiddleware::new(128).await;
+        let (subscriber, tx) = instantiate(&m).await;
+
+        let mut s = subscriber.subscribe_new_blocks_at_depth(0).await.unwrap();
+        assert!(s.next().await.unwrap().is_ok());
+
+        subscriber.shutdown().await;
+        subscriber.shutdown().await;
+
+        assert!(subscriber.wait_for_completion().await.is_ok());
+        assert!(s.next().await.is_none());
+        assert!(tx.is_closed());
+    }
+
+    #[tokio::test]
+    async fn max_subscribers_test() {
+        let m = MockMiddleware::new(128).await;
+        let (subscriber, _tx) = instantiate(&m).await;
+
+        let mut subscriptions = vec![];
+        for i in 0..4 {
+            assert_eq!(subscriber.subscriber_count(), i);
+            subscriptions.push(subscriber.subscribe_new_blocks_at_depth(0).await.unwrap());
+        }
+
+        assert_eq!(subscriber.subscriber_count(), 4);
+        assert!(matches!(
+            subscriber.subscribe_new_blocks_at_depth(0).await,
+            Err(SubscriptionError::TooManySubscribers { max_subscribers: 4 })
+        ));
+
+        subscriptions.pop();
+        assert_eq!(subscriber.subscriber_count(), 3);
+        assert!(subscriber.subscribe_new_blocks_at_depth(0).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn retention_test() {
+        let m = MockMiddleware::new(128).await;
+        let (_tx, rx) = mpsc::channel(16);
+        let subscriber = BlockSubscriber::start_with_subscription(
+            Arc::clone(&m),
+            8,
+            None,
+            ReceiverStream::new(rx),
+        )
+        .await
+        .unwrap();
+
+        assert!(matches!(
+            subscriber.subscribe_new_blocks_at_depth(9).await,
+            Err(SubscriptionError::ArchiveError {
+                source: BlockArchiveError::BlockOutOfRange {
+                    depth: 9,
+                    max_depth: 8
+                }
+            })
+        ));
+
+        let latest = m.get_latest_block().await.unwrap();
+        assert!(matches!(
+            subscriber.subscribe_from(Arc::new(latest), 9).await,
+            Err(SubscriptionError::ArchiveError { .. })
+        ));
+        assert_eq!(subscriber.subscriber_count(), 0);
+
+        let mut s = subscriber.subscribe_new_blocks_at_depth(8).await.unwrap();
+        assert_eq!(next_number(&mut s).await, 120);
+    }
+
+    fn timeout_error() -> Arc<BlockSubscriberError<MockMiddleware>> {
+        let source = Arc::new(std::io::ErrorKind::TimedOut.into());
+        Arc::new(BlockSubscriberError::NewBlockSubscriberTimeout { source })
+    }
+
+    async fn next_number(
+        s: &mut (impl Stream<Item = SubscriptionResult<BlockStreamItem, MockMiddleware>> + Unpin),
+    ) -> u64 {
+        match s.next().await.unwrap().unwrap() {
+            BlockStreamItem::NewBlock(b) => b.number.as_u64(),
+            BlockStreamItem::Reorg(_)
+            | BlockStreamItem::Orphaned(_)
+            | BlockStreamItem::HistoryGap { .. } => panic!("expected new block"),
+        }
+    }
+
+    #[tokio::test]
+    async fn bursty_test() {
+        let m = MockMiddleware::new(128).await;
+        let (subscriber, tx) = instantiate(&m).await;
+
+        let mut s = subscriber.subscribe_new_blocks_at_depth(0).await.unwrap();
+        assert_eq!(next_number(&mut s).await, 128);
+
+        // A while with no blocks, then a burst of five.
+        for _ in 0..16 {
+            tokio::task::yield_now().await;
+        }
+        for _ in 0..5 {
+            add_block(&m, &tx).await;
+        }
+
+        for n in 129..=133 {
+            assert_eq!(next_number(&mut s).await, n);
+        }
+
+        // A burst where only the tip is notified.
+        for _ in 0..4 {
+            new_block(&m).await;
+        }
+        add_block(&m, &tx).await;
+
+        for n in 134..=138 {
+            assert_eq!(next_number(&mut s).await, n);
+        }
+    }
+
+    #[tokio::test]
+    async fn timeout_test() {
+        let m = MockMiddleware::new(128).await;
+        let (subscriber, tx) = instantiate(&m).await;
+
+        let mut s = subscriber.subscribe_new_blocks_at_depth(0).await.unwrap();
+        assert_eq!(next_number(&mut s).await, 128);
+
+        // Timeout without new blocks keeps the subscription.
+        tx.send(Err(timeout_error())).await.unwrap();
+        // Let the subscriber handle the timeout before the chain advances.
+        while tx.capacity() < tx.max_capacity() {
+            tokio::task::yield_now().await;
+        }
+        tokio::task::yield_now().await;
+        add_block(&m, &tx).await;
+        assert_eq!(next_number(&mut s).await, 129);
+
+        // Timeout with a missed block still broadcasts it, and keeps the
+        // subscription.
+        new_block(&m).await;
+        tx.send(Err(timeout_error())).await.unwrap();
+        assert_eq!(next_number(&mut s).await, 130);
+        add_block(&m, &tx).await;
+        assert_eq!(next_number(&mut s).await, 131);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn adaptive_poll_test() {
+        let m = MockMiddleware::new(128).await;
+        let (tx, rx) = mpsc::channel(16);
+        let poll_interval = PollInterval::Adaptive {
+            min: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+        };
+        let subscriber = BlockSubscriber::start_with_polled_subscription(
+            Arc::clone(&m),
+            64,
+            poll_interval,
+            ReceiverStream::new(rx),
+        )
+        .await
+        .unwrap();
+
+        let mut s = subscriber.subscribe_new_blocks_at_depth(0).await.unwrap();
+        assert_eq!(next_number(&mut s).await, 128);
+
+        // Blocks every 2s.
+        for n in 129..=138 {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            add_block(&m, &tx).await;
+            assert_eq!(next_number(&mut s).await, n);
+        }
+        let interval = subscriber.poll_interval();
+        assert!(interval > Duration::from_secs(3) && interval < Duration::from_secs(5));
+
+        // A block missed by the subscription isn't polled for while merely
+        // late...
+        new_block(&m).await;
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        let late = tokio::time::timeout(Duration::from_millis(500), s.next()).await;
+        assert!(late.is_err());
+
+        // ...then is, and the subscription is kept.
+        assert_eq!(next_number(&mut s).await, 139);
+        add_block(&m, &tx).await;
+        assert_eq!(next_number(&mut s).await, 140);
+    }
+
+    #[tokio::test]
+    async fn subscribe_from_test() {
+        let m = MockMiddleware::new(128).await;
+        let (subscriber, tx) = instantiate(&m).await;
+
+        // Consumer went offline at block 120, while the chain advanced.
+        let last_seen = subscriber.block_archive.block_with_number(120.into()).await;
+        for _ in 0..3 {
+            add_block(&m, &tx).await;
+        }
+        while subscriber.block_archive.latest_block().await.number < 131.into() {
+            tokio::task::yield_now().await;
+        }
+
+        let mut s = subscriber
+            .subscribe_from(last_seen.unwrap(), 0)
+            .await
+            .unwrap();
+
+        for n in 121..=131 {
+            assert_eq!(next_number(&mut s).await, n);
+        }
+
+        for _ in 0..2 {
+            add_block(&m, &tx).await;
+        }
+        for n in 132..=133 {
+            assert_eq!(next_number(&mut s).await, n);
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribe_from_reorg_test() {
+        let m = MockMiddleware::new(128).await;
+        let (subscriber, tx) = instantiate(&m).await;
+
+        let base = subscriber.block_archive.block_with_number(122.into()).await;
+        let base = base.unwrap();
+        let last_seen = subscriber.block_archive.block_with_number(125.into()).await;
+
+        // Reorg from block 123, while the consumer was offline.
+        let mut tip = base.hash;
+        for _ in 0..10 {
+            tip = m.add_block(tip).await.unwrap();
+        }
+        tx.send(Ok(Arc::new(m.get_block(tip).await.unwrap())))
+            .await
+            .unwrap();
+        while subscriber.block_archive.latest_block().await.hash != tip {
+            tokio::task::yield_now().await;
+        }
+
+        let mut s = subscriber
+            .subscribe_from(last_seen.unwrap(), 0)
+            .await
+            .unwrap();
+
+        match s.next().await.unwrap().unwrap() {
+            BlockStreamItem::Reorg(blocks) => {
+                assert_eq!(blocks.first().unwrap().parent_hash, base.hash);
+                assert_eq!(blocks.last().unwrap().hash, tip);
+            }
+            BlockStreamItem::NewBlock(_)
+            | BlockStreamItem::Orphaned(_)
+            | BlockStreamItem::HistoryGap { .. } => panic!("expected reorg"),
+        }
+
+        add_block(&m, &tx).await;
+        assert_eq!(next_number(&mut s).await, 133);
+    }
+
+    #[tokio::test]
+    async fn orphans_test() {
+        let m = MockMiddleware::new(128).await;
+        let (subscriber, tx) = instantiate(&m).await;
+
+        let mut s = subscriber
+            .subscribe_new_blocks_at_depth_with_orphans(0)
+            .await
+            .unwrap();
+        let base = m.get_latest_block().await.unwrap();
+        assert_eq!(next_number(&mut s).await, 128);
+
+        let mut emitted = vec![];
+        for i in 129..=131 {
+            add_block(&m, &tx).await;
+            assert_eq!(next_number(&mut s).await, i);
+            emitted.push(m.get_latest_block().await.unwrap().hash);
+        }
+
+        // Reorg from block 129.
+        let mut tip = base.hash;
+        for _ in 0..4 {
+            tip = m.add_block(tip).await.unwrap();
+        }
+        tx.send(Ok(Arc::new(m.get_block(tip).await.unwrap())))
+            .await
+            .unwrap();
+
+        for hash in emitted {
+            match s.next().await.unwrap().unwrap() {
+                BlockStreamItem::Orphaned(b) => assert_eq!(b.hash, hash),
+                BlockStreamItem::NewBlock(_)
+                | BlockStreamItem::Reorg(_)
+                | BlockStreamItem::HistoryGap { .. } => panic!("expected orphaned block"),
+            }
+        }
+
+        match s.next().await.unwrap().unwrap() {
+            BlockStreamItem::Reorg(blocks) => {
+                assert_eq!(blocks.first().unwrap().parent_hash, base.hash);
+                assert_eq!(blocks.last().unwrap().hash, tip);
+            }
+            BlockStreamItem::NewBlock(_)
+            | BlockStreamItem::Orphaned(_)
+            | BlockStreamItem::HistoryGap { .. } => panic!("expected reorg"),
+        }
+
+        add_block(&m, &tx).await;
+        assert_eq!(next_number(&mut s).await, 133);
+    }
+
+    #[tokio::test]
+    async fn duplicate_test() {
+        let m = MockMiddleware::new(128).await;
+        let (subscriber, tx) = instantiate(&m).await;
+
+        let mut s = subscriber.subscribe_new_blocks_at_depth(0).await.unwrap();
+        assert_eq!(next_number(&mut s).await, 128);
+
+        let mut blocks = vec![];
+        for n in 129..=131 {
+            let block = new_block(&m).await;
+            tx.send(Ok(Arc::clone(&block))).await.unwrap();
+            assert_eq!(next_number(&mut s).await, n);
+            blocks.push(block);
+        }
+
+        // Repeated notifications of the tip and of an ancestor are dropped.
+        for block in [&blocks[2], &blocks[0]] {
+            tx.send(Ok(Arc::clone(block))).await.unwrap();
+        }
+        add_block(&m, &tx).await;
+        assert_eq!(next_number(&mut s).await, 132);
+        let tip = m.get_latest_block().await.unwrap();
+
+        // Reorg to a fork, and back to the first branch, re-emitting its
+        // blocks as part of the `Reorg`.
+        let mut fork = blocks[2].hash;
+        for _ in 0..2 {
+            fork = m.add_block(fork).await.unwrap();
+        }
+        tx.send(Ok(Arc::new(m.get_block(fork).await.unwrap())))
+            .await
+            .unwrap();
+        assert!(matches!(
+            s.next().await.unwrap().unwrap(),
+            BlockStreamItem::Reorg(blocks) if blocks.last().unwrap().hash == fork
+        ));
+
+        let mut back = tip.hash;
+        for _ in 0..2 {
+            back = m.add_block(back).await.unwrap();
+            tx.send(Ok(Arc::new(m.get_block(back).await.unwrap())))
+                .await
+                .unwrap();
+        }
+        match s.next().await.unwrap().unwrap() {
+            BlockStreamItem::Reorg(blocks) => {
+                assert_eq!(blocks.first().unwrap().hash, tip.hash);
+                assert_eq!(blocks.last().unwrap().hash, back);
+            }
+            BlockStreamItem::NewBlock(_)
+            | BlockStreamItem::Orphaned(_)
+            | BlockStreamItem::HistoryGap { .. } => panic!("expected reorg"),
+        }
+
+        // Without deduplication, a repeated ancestor rewinds the chain.
+        subscriber.set_deduplication(false);
+        tx.send(Ok(Arc::clone(&blocks[0]))).await.unwrap();
+        assert!(s.next().await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn history_gap_test() {
+        let m = MockMiddleware::new(128).await;
+        let (subscriber, tx) = instantiate(&m).await;
+
+        let mut s = subscriber.subscribe_new_blocks_at_depth(0).await.unwrap();
+        let from = m.get_latest_block().await.unwrap();
+        assert_eq!(next_number(&mut s).await, 128);
+
+        // The tip jumps past the retained history, as after a long disconnect.
+        let retained = subscriber.block_archive.retained_depth() as u64;
+        for _ in 0..retained {
+            new_block(&m).await;
+        }
+        add_block(&m, &tx).await;
+
+        match s.next().await.unwrap().unwrap() {
+            BlockStreamItem::HistoryGap { from: f, to } => {
+                assert_eq!(f.hash, from.hash);
+                assert_eq!(to.number.as_u64(), 128 + retained + 1);
+            }
+            BlockStreamItem::NewBlock(_)
+            | BlockStreamItem::Reorg(_)
+            | BlockStreamItem::Orphaned(_) => panic!("expected history gap"),
+        }
+
+        add_block(&m, &tx).await;
+        assert_eq!(next_number(&mut s).await, 128 + retained + 2);
+
+        // A reorg forking off further down than the retained history, with
+        // the subscription's last block still within it by number.
+        let previous = m.get_latest_block().await.unwrap();
+        let base = m
+            .get_block_with_number(previous.number - retained - 1)
+            .await
+            .unwrap();
+        let mut tip = base.hash;
+        for _ in 0..=retained + 1 {
+            tip = m.add_block(tip).await.unwrap();
+        }
+        tx.send(Ok(Arc::new(m.get_block(tip).await.unwrap())))
+            .await
+            .unwrap();
+
+        match s.next().await.unwrap().unwrap() {
+            BlockStreamItem::HistoryGap { from, to } => {
+                assert_eq!(from.hash, previous.hash);
+                assert_eq!(to.hash, tip);
+            }
+            BlockStreamItem::NewBlock(_)
+            | BlockStreamItem::Reorg(_)
+            | BlockStreamItem::Orphaned(_) => panic!("expected history gap"),
+        }
+    }
+
+    #[tokio::test]
+    async fn health_test() {
+        let m = MockMiddleware::new(128).await;
+        let (subscriber, tx) = instantiate(&m).await;
+        let threshold = Duration::from_secs(60);
+        let clock = Arc::new(ManualClock::new());
+        subscriber.set_clock(Arc::clone(&clock) as Arc<dyn Clock>);
+
+        let mut health = subscriber.health(threshold);
+        let mut s = subscriber.subscribe_new_blocks_at_depth(0).await.unwrap();
+        assert_eq!(next_number(&mut s).await, 128);
+        add_block(&m, &tx).await;
+        assert_eq!(next_number(&mut s).await, 129);
+        let last = m.get_latest_block().await.unwrap();
+
+        // Stalls once the clock reaches the threshold, however long it took.
+        let stalled = {
+            let next = health.next();
+            tokio::pin!(next);
+            loop {
+                tokio::select! {
+                    item = &mut next => break item.unwrap(),
+                    _ = tokio::task::yield_now() => clock.advance(threshold / 4),
+                }
+            }
+        };
+        match stalled {
+            ChainHealth::Stalled { since, last_block } => {
+                assert_eq!(last_block.hash, last.hash);
+                assert!(clock.now() - since >= threshold);
+            }
+            ChainHealth::Resumed { .. } => panic!("expected stall"),
+        }
+
+        add_block(&m, &tx).await;
+        match health.next().await.unwrap() {
+            ChainHealth::Resumed { block } => assert_eq!(block.parent_hash, last.hash),
+            ChainHealth::Stalled { .. } => panic!("expected resume"),
+        }
+
+        subscriber.shutdown().await;
+        assert!(health.next().await.is_none());
+    }
+}
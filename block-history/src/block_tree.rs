@@ -7,18 +7,22 @@ use eth_state_fold_types::Block;
 use std::collections::HashMap;
 use std::sync::Arc;
 
+/// Tree of recent blocks. Only blocks at most `retention` blocks behind the
+/// latest block are kept; older blocks are dropped as the latest advances.
 pub(crate) struct BlockTree {
     tree: HashMap<H256, Arc<Block>>,
     number_map: HashMap<U64, H256>,
     latest: Arc<Block>,
+    retention: usize,
 }
 
 impl BlockTree {
-    pub fn new(start_block: Arc<Block>) -> Self {
+    pub fn new(start_block: Arc<Block>, retention: usize) -> Self {
         Self {
             latest: Arc::clone(&start_block),
             number_map: HashMap::from([(start_block.number, start_block.hash)]),
             tree: HashMap::from([(start_block.hash, start_block)]),
+            retention,
         }
     }
 
@@ -32,6 +36,10 @@ impl BlockTree {
     }
 
     pub fn insert_block(&mut self, block: Arc<Block>) {
+        if block.number < self.oldest_retained() {
+            return;
+        }
+
         self.number_map.insert(block.number, block.hash);
         self.tree.insert(block.hash, block);
     }
@@ -41,7 +49,28 @@ impl BlockTree {
     }
 
     pub fn update_latest_block(&mut self, block: Arc<Block>) {
+        let advanced = block.number > self.latest.number;
+
         self.latest = Arc::clone(&block);
         self.insert_block(block);
+
+        if advanced {
+            self.prune();
+        }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    fn oldest_retained(&self) -> U64 {
+        self.latest.number.saturating_sub(U64::from(self.retention))
+    }
+
+    fn prune(&mut self) {
+        let oldest = self.oldest_retained();
+        self.tree.retain(|_, b| b.number >= oldest);
+        self.number_map.retain(|n, _| *n >= oldest);
     }
 }
@@ -0,0 +1,104 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use async_trait::async_trait;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// Source of time, for retry backoffs, circuit breaker cooldowns, polling and
+/// stall detection. Can be replaced by a `ManualClock`, so tests of
+/// time-dependent logic are deterministic.
+#[async_trait]
+pub trait Clock: std::fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+    async fn sleep(&self, duration: Duration);
+}
+
+/// Clock of the tokio runtime, the default. Tests can pause and advance it with
+/// `tokio::time::pause` and `tokio::time::advance`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioClock;
+
+#[async_trait]
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+}
+
+/// Clock that only moves when `advance`d, waking the sleeps it moves past.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: watch::Sender<Instant>,
+}
+
+impl ManualClock {
+    /// Clock starting at the current time of the runtime.
+    pub fn new() -> Self {
+        Self {
+            now: watch::channel(Instant::now()).0,
+        }
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        self.now.send_modify(|now| *now += duration);
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.borrow()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        let mut now = self.now.subscribe();
+        let Some(deadline) = now.borrow_and_update().checked_add(duration) else {
+            return std::future::pending().await;
+        };
+
+        while *now.borrow_and_update() < deadline {
+            // The sender lives as long as `self`.
+            let _ = now.changed().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Clock, ManualClock};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn manual_clock_test() {
+        let clock = Arc::new(ManualClock::new());
+        let start = clock.now();
+
+        let sleeper = Arc::clone(&clock);
+        let sleep = tokio::spawn(async move { sleeper.sleep(Duration::from_secs(10)).await });
+        // Lets the sleep start before the clock moves.
+        tokio::task::yield_now().await;
+
+        clock.advance(Duration::from_secs(4));
+        tokio::task::yield_now().await;
+        assert!(!sleep.is_finished());
+        assert_eq!(clock.now() - start, Duration::from_secs(4));
+
+        clock.advance(Duration::from_secs(6));
+        sleep.await.unwrap();
+        assert_eq!(clock.now() - start, Duration::from_secs(10));
+    }
+}
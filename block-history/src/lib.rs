@@ -1,17 +1,26 @@
 // (c) Cartesi and individual authors (see AUTHORS)
 // SPDX-License-Identifier: Apache-2.0 (see LICENSE)
 
+mod batch;
 mod block_archive;
 mod block_subscriber;
 mod block_tree;
+mod clock;
+mod poll_interval;
 
 pub mod config;
 
-pub use block_archive::BlockArchive;
-pub use block_subscriber::BlockSubscriber;
+pub use batch::{BatchRequest, BatchResponse, BatchTransport, DEFAULT_BLOCK_BATCH_SIZE};
+pub use block_archive::{BlockArchive, BlockPush, Reorg};
+pub use block_subscriber::{BlockSubscriber, ChainHealth};
+pub use clock::{Clock, ManualClock, TokioClock};
+pub use poll_interval::PollInterval;
 
 pub use block_archive::BlockArchiveError;
 pub use block_subscriber::BlockSubscriberError;
 pub use block_subscriber::SubscriptionError;
 
-pub use block_archive::{current_block_number, fetch_block, fetch_block_at_depth};
+pub use block_archive::{
+    common_ancestor, current_block_number, fetch_block, fetch_block_at_depth,
+    fetch_block_at_depth_with_retry, fetch_block_with_retry, IncompleteBlockRetry,
+};
@@ -0,0 +1,142 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How long a `BlockSubscriber` waits for a new block before polling the node
+/// for one its subscription may have missed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PollInterval {
+    /// Always the same interval.
+    Fixed(Duration),
+
+    /// Twice the observed block time, bounded by `min` and `max`, so that
+    /// fast chains are polled often and idle ones rarely, while blocks merely
+    /// late don't trigger polls. It's `max` until two blocks have been
+    /// observed.
+    Adaptive { min: Duration, max: Duration },
+}
+
+impl From<Duration> for PollInterval {
+    fn from(interval: Duration) -> Self {
+        Self::Fixed(interval)
+    }
+}
+
+/// Weight of the latest sample in the moving average of block times.
+const SMOOTHING: f64 = 0.25;
+
+/// Multiple of the average block time waited for a block before polling, so
+/// that the jitter of block times doesn't trigger polls.
+const BLOCK_TIME_MULTIPLE: u32 = 2;
+
+/// Current interval of a `PollInterval`, tuned on block arrivals.
+#[derive(Debug)]
+pub(crate) struct PollTracker {
+    policy: PollInterval,
+    block_time: Option<Duration>,
+    last_block: Option<Instant>,
+}
+
+impl PollTracker {
+    pub fn new(policy: PollInterval) -> Self {
+        Self {
+            policy,
+            block_time: None,
+            last_block: None,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        match self.policy {
+            PollInterval::Fixed(interval) => interval,
+
+            PollInterval::Adaptive { min, max } => self.block_time.map_or(max, |block_time| {
+                (block_time * BLOCK_TIME_MULTIPLE).max(min).min(max)
+            }),
+        }
+    }
+
+    /// Records a block arriving at `now`.
+    pub fn observe_block(&mut self, now: Instant) {
+        if let Some(last_block) = self.last_block {
+            self.sample(now - last_block);
+        }
+
+        self.last_block = Some(now);
+    }
+
+    /// Records a poll interval elapsing at `now` without blocks. The time since
+    /// the last block is a lower bound of the current block time.
+    pub fn observe_idle(&mut self, now: Instant) {
+        let Some(last_block) = self.last_block else {
+            return;
+        };
+
+        let idle = now - last_block;
+        if self.block_time.is_none_or(|block_time| idle > block_time) {
+            self.sample(idle);
+        }
+    }
+
+    fn sample(&mut self, block_time: Duration) {
+        self.block_time = Some(match self.block_time {
+            Some(average) => average.mul_f64(1.0 - SMOOTHING) + block_time.mul_f64(SMOOTHING),
+            None => block_time,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PollInterval, PollTracker};
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    const MIN: Duration = Duration::from_secs(1);
+    const MAX: Duration = Duration::from_secs(30);
+
+    fn observe_blocks(tracker: &mut PollTracker, start: Instant, block_time: Duration, n: u32) {
+        for i in 0..n {
+            tracker.observe_block(start + block_time * i);
+        }
+    }
+
+    #[test]
+    fn fixed_test() {
+        let mut tracker = PollTracker::new(Duration::from_secs(5).into());
+        observe_blocks(&mut tracker, Instant::now(), Duration::from_millis(10), 10);
+        assert_eq!(tracker.interval(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn adaptive_test() {
+        let mut tracker = PollTracker::new(PollInterval::Adaptive { min: MIN, max: MAX });
+        let start = Instant::now();
+        assert_eq!(tracker.interval(), MAX);
+
+        // Blocks every 12s.
+        observe_blocks(&mut tracker, start, Duration::from_secs(12), 20);
+        let interval = tracker.interval();
+        assert!(interval > Duration::from_secs(23) && interval < Duration::from_secs(25));
+
+        // Blocks much faster than `min`.
+        let start = start + Duration::from_secs(12 * 20);
+        observe_blocks(&mut tracker, start, Duration::from_millis(100), 40);
+        assert_eq!(tracker.interval(), MIN);
+
+        // Idle chain, slower than `max`.
+        let last_block = start + Duration::from_millis(100 * 39);
+        for i in 1..=20 {
+            tracker.observe_idle(last_block + Duration::from_secs(60 * i));
+        }
+        assert_eq!(tracker.interval(), MAX);
+
+        // Blocks speed up again.
+        let start = last_block + Duration::from_secs(60 * 21);
+        observe_blocks(&mut tracker, start, Duration::from_secs(2), 40);
+        let interval = tracker.interval();
+        assert!(interval > Duration::from_secs(3) && interval < Duration::from_secs(5));
+    }
+}
@@ -60,7 +60,9 @@ where
 
         let query_block: QueryBlock = query_block.into();
         let request = Request::new(QueryBlockRequest {
-            query_block: Some(query_block.into()),
+            query_block: Some(query_block.try_into().context(MessageConversionSnafu {
+                context: "`get_block` request".to_owned(),
+            })?),
         });
 
         let block = client
@@ -161,7 +163,9 @@ where
 
         let request = Request::new(QueryStateRequest {
             initial_state: Some(initial_state_json),
-            query_block: Some(query_block.into()),
+            query_block: Some(query_block.try_into().context(MessageConversionSnafu {
+                context: "`get_state` request".to_owned(),
+            })?),
         });
 
         let state = client
@@ -3,13 +3,17 @@
 
 use eth_state_fold_types::ethers;
 use eth_state_fold_types::Block;
-use ethers::providers::{FromErr, Middleware, MockProvider};
-use ethers::types::{BlockId, BlockNumber, Bloom, H256, U256, U64};
+use ethers::providers::{FromErr, Middleware, MockProvider, Provider};
+use ethers::types::{
+    transaction::eip2718::TypedTransaction, Address, BlockId, BlockNumber, Bloom, Bytes, Filter,
+    FilterBlockOption, Log, NameOrAddress, Trace, TransactionReceipt, TxHash, H256, U256, U64,
+};
 
 use async_trait::async_trait;
 use std::collections::HashMap;
 use std::fmt;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
 
 #[derive(Debug, Clone)]
@@ -26,25 +30,118 @@ impl FromErr<MockError> for MockError {
     }
 }
 
+/// Time taken to answer a `get_logs` request, by its filter.
+pub type LogLatency = fn(&Filter) -> Duration;
+
 #[derive(Debug)]
 pub struct MockMiddleware {
     chain: Mutex<HashMap<H256, Block>>,
     block_count: Mutex<U64>,
     latest_block: Mutex<H256>,
     deepest_block: Mutex<U64>,
+    finalized_block: Mutex<Option<U64>>,
+    safe_block: Mutex<Option<U64>>,
+
+    /// If set, hashes are derived from `(seed, height, branch)`, where
+    /// `branch` is the number of blocks previously added at that height.
+    seed: Option<u64>,
+    branches: Mutex<HashMap<U64, u64>>,
+
+    /// Logs answered by `get_logs`, and the number of upcoming `get_logs`
+    /// requests that fail with `MockError`.
+    logs: Mutex<Vec<Log>>,
+    failing_requests: Mutex<usize>,
+
+    /// Logs answered by `get_logs` for the `pending` tag, whatever the
+    /// pending block.
+    pending_logs: Mutex<Vec<Log>>,
+
+    /// Filters of every `get_logs` request received.
+    log_requests: Mutex<Vec<Filter>>,
+
+    /// Time taken to answer each `get_logs` request, by its filter. Answered
+    /// right away if `None`, the default.
+    log_latency: Mutex<Option<LogLatency>>,
+
+    /// Receipts answered by `get_transaction_receipt`, by transaction hash.
+    receipts: Mutex<HashMap<H256, TransactionReceipt>>,
+
+    /// Outputs answered by `call`, by contract address, and the transaction
+    /// and block of every `call` received.
+    call_outputs: Mutex<HashMap<Address, Bytes>>,
+    call_requests: Mutex<Vec<(TypedTransaction, Option<BlockId>)>>,
+
+    /// Traces answered by `trace_block` and `trace_transaction`. If `None`,
+    /// the default, tracing is unsupported, and they fail with `MockError`.
+    traces: Mutex<Option<Vec<Trace>>>,
+
+    /// Id answered by `get_chainid`, `1337` unless set.
+    chain_id: Mutex<U256>,
+
+    /// Block from which each contract set by `self_destruct` has no code.
+    self_destructs: Mutex<HashMap<Address, U64>>,
+
+    /// Number of upcoming `get_block` requests answered with a block missing
+    /// its hash and number, like nodes do for blocks not yet fully available.
+    incomplete_blocks: Mutex<usize>,
+
+    /// Provider answering raw requests, with the responses pushed to its
+    /// `MockProvider`.
+    provider: Provider<MockProvider>,
 }
 
 impl MockMiddleware {
     pub async fn new(initial_block_count: u64) -> Arc<Self> {
-        assert!(initial_block_count > 0);
+        Self::build(None, initial_block_count).await
+    }
 
-        let latest_block = H256::zero();
+    /// Creates a chain whose hashes are derived from `seed`, such that chains
+    /// built with the same seed and in the same order have the same hashes.
+    /// See `seeded_hash`.
+    pub async fn new_seeded(seed: u64, initial_block_count: u64) -> Arc<Self> {
+        Self::build(Some(seed), initial_block_count).await
+    }
+
+    /// Hash of the block at `height` on `branch` in a seeded chain. The first
+    /// block added at each height is on branch `0`, the second on branch `1`,
+    /// and so on.
+    pub fn seeded_hash(seed: u64, height: u64, branch: u64) -> H256 {
+        let mut bytes = [0u8; 24];
+        bytes[..8].copy_from_slice(&seed.to_be_bytes());
+        bytes[8..16].copy_from_slice(&height.to_be_bytes());
+        bytes[16..].copy_from_slice(&branch.to_be_bytes());
+
+        H256::from(ethers::utils::keccak256(bytes))
+    }
+
+    async fn build(seed: Option<u64>, initial_block_count: u64) -> Arc<Self> {
+        let latest_block = match seed {
+            Some(seed) => Self::seeded_hash(seed, 0, 0),
+            None => H256::zero(),
+        };
 
         let this = Self {
             chain: Mutex::new(HashMap::new()),
             block_count: Mutex::new(U64::from(0)),
             latest_block: Mutex::new(latest_block),
             deepest_block: Mutex::new(U64::from(0)),
+            finalized_block: Mutex::new(None),
+            safe_block: Mutex::new(None),
+            seed,
+            branches: Mutex::new(HashMap::from([(U64::from(0), 1)])),
+            logs: Mutex::new(vec![]),
+            failing_requests: Mutex::new(0),
+            pending_logs: Mutex::new(vec![]),
+            log_requests: Mutex::new(vec![]),
+            log_latency: Mutex::new(None),
+            receipts: Mutex::new(HashMap::new()),
+            call_outputs: Mutex::new(HashMap::new()),
+            call_requests: Mutex::new(vec![]),
+            traces: Mutex::new(None),
+            chain_id: Mutex::new(U256::from(1337)),
+            self_destructs: Mutex::new(HashMap::new()),
+            incomplete_blocks: Mutex::new(0),
+            provider: Provider::new(MockProvider::new()),
         };
 
         this.chain.lock().await.insert(
@@ -56,6 +153,8 @@ impl MockMiddleware {
 
                 timestamp: U256::zero(),
                 logs_bloom: Bloom::zero(),
+                base_fee_per_gas: None,
+                gas_used: Some(U256::zero()),
             },
         );
 
@@ -69,7 +168,7 @@ impl MockMiddleware {
 
     pub async fn add_block(&self, parent_hash: H256) -> Option<H256> {
         let new_number = self.chain.lock().await.get(&parent_hash)?.number + U64::from(1);
-        let new_hash = self.new_hash().await;
+        let new_hash = self.new_hash(new_number).await;
         let new_block = Block {
             number: new_number,
             hash: new_hash,
@@ -77,6 +176,8 @@ impl MockMiddleware {
 
             timestamp: U256::zero(),
             logs_bloom: Bloom::zero(),
+            base_fee_per_gas: None,
+            gas_used: Some(U256::zero()),
         };
         self.chain.lock().await.insert(new_hash, new_block);
         *self.latest_block.lock().await = new_hash;
@@ -122,9 +223,110 @@ impl MockMiddleware {
             .cloned()
     }
 
-    async fn new_hash(&self) -> H256 {
+    /// Sets the block number answered for the `finalized` tag.
+    pub async fn set_finalized_block(&self, number: U64) {
+        *self.finalized_block.lock().await = Some(number);
+    }
+
+    /// Sets the block number answered for the `safe` tag.
+    pub async fn set_safe_block(&self, number: U64) {
+        *self.safe_block.lock().await = Some(number);
+    }
+
+    /// Sets the logs answered by `get_logs`.
+    pub async fn set_logs(&self, logs: Vec<Log>) {
+        *self.logs.lock().await = logs;
+    }
+
+    /// Sets the logs answered by `get_logs` for the pending block.
+    pub async fn set_pending_logs(&self, logs: Vec<Log>) {
+        *self.pending_logs.lock().await = logs;
+    }
+
+    /// Adds a receipt answered by `get_transaction_receipt`.
+    pub async fn add_receipt(&self, receipt: TransactionReceipt) {
+        self.receipts
+            .lock()
+            .await
+            .insert(receipt.transaction_hash, receipt);
+    }
+
+    /// Sets the output answered by `call` to calls of `address`.
+    pub async fn set_call_output(&self, address: Address, output: Bytes) {
+        self.call_outputs.lock().await.insert(address, output);
+    }
+
+    /// Transaction and block of every `call` received so far.
+    pub async fn call_requests(&self) -> Vec<(TypedTransaction, Option<BlockId>)> {
+        self.call_requests.lock().await.clone()
+    }
+
+    /// Enables tracing, answering `traces` by their block number and
+    /// transaction hash.
+    pub async fn set_traces(&self, traces: Vec<Trace>) {
+        *self.traces.lock().await = Some(traces);
+    }
+
+    pub async fn set_chain_id(&self, chain_id: U256) {
+        *self.chain_id.lock().await = chain_id;
+    }
+
+    /// Makes `get_code` answer no code at `address` from block `number` on.
+    pub async fn self_destruct(&self, address: Address, number: U64) {
+        self.self_destructs.lock().await.insert(address, number);
+    }
+
+    /// Makes each `get_logs` request take `latency(filter)` to be answered,
+    /// such that concurrent requests may complete out of order.
+    pub async fn set_log_latency(&self, latency: LogLatency) {
+        *self.log_latency.lock().await = Some(latency);
+    }
+
+    /// Makes the next `n` `get_logs` requests fail with `MockError`.
+    pub async fn fail_next_requests(&self, n: usize) {
+        *self.failing_requests.lock().await = n;
+    }
+
+    /// Sets the base fee and gas used of the block `hash`, answered by
+    /// `get_block`.
+    pub async fn set_block_fees(&self, hash: H256, base_fee_per_gas: Option<U256>, gas_used: U256) {
+        if let Some(block) = self.chain.lock().await.get_mut(&hash) {
+            block.base_fee_per_gas = base_fee_per_gas;
+            block.gas_used = Some(gas_used);
+        }
+    }
+
+    /// Sets the logs bloom of the block `hash`, answered by `get_block`.
+    pub async fn set_logs_bloom(&self, hash: H256, logs_bloom: Bloom) {
+        if let Some(block) = self.chain.lock().await.get_mut(&hash) {
+            block.logs_bloom = logs_bloom;
+        }
+    }
+
+    /// Makes the next `n` `get_block` requests answer incomplete blocks.
+    pub async fn return_incomplete_blocks(&self, n: usize) {
+        *self.incomplete_blocks.lock().await = n;
+    }
+
+    /// Filters of every `get_logs` request received so far.
+    pub async fn log_requests(&self) -> Vec<Filter> {
+        self.log_requests.lock().await.clone()
+    }
+
+    async fn new_hash(&self, number: U64) -> H256 {
         *self.block_count.lock().await += U64::from(1);
-        H256::from_low_u64_be(self.block_count.lock().await.as_u64())
+
+        match self.seed {
+            Some(seed) => {
+                let mut branches = self.branches.lock().await;
+                let branch = branches.entry(number).or_default();
+                let hash = Self::seeded_hash(seed, number.as_u64(), *branch);
+                *branch += 1;
+                hash
+            }
+
+            None => H256::from_low_u64_be(self.block_count.lock().await.as_u64()),
+        }
     }
 }
 
@@ -138,6 +340,10 @@ impl Middleware for MockMiddleware {
         unreachable!()
     }
 
+    fn provider(&self) -> &Provider<Self::Provider> {
+        &self.provider
+    }
+
     async fn get_block_number(&self) -> Result<U64, Self::Error> {
         Ok(MockMiddleware::get_latest_block(self).await.unwrap().number)
     }
@@ -159,18 +365,244 @@ impl Middleware for MockMiddleware {
                 MockMiddleware::get_latest_block(self).await.unwrap()
             }
 
+            BlockId::Number(BlockNumber::Finalized) => match *self.finalized_block.lock().await {
+                Some(n) => MockMiddleware::get_block_with_number(self, n)
+                    .await
+                    .unwrap(),
+                None => return Ok(None),
+            },
+
+            BlockId::Number(BlockNumber::Safe) => match *self.safe_block.lock().await {
+                Some(n) => MockMiddleware::get_block_with_number(self, n)
+                    .await
+                    .unwrap(),
+                None => return Ok(None),
+            },
+
+            // Pending block on top of the latest one, without a hash nor a
+            // logs bloom, like geth's.
+            BlockId::Number(BlockNumber::Pending) => {
+                let latest = MockMiddleware::get_latest_block(self).await.unwrap();
+                return Ok(Some(ethers::types::Block {
+                    number: Some(latest.number + 1),
+                    parent_hash: latest.hash,
+                    ..Default::default()
+                }));
+            }
+
             x => panic!("get_block not number {:?}", x),
         };
 
+        let complete = {
+            let mut incomplete_blocks = self.incomplete_blocks.lock().await;
+            let complete = *incomplete_blocks == 0;
+            *incomplete_blocks = incomplete_blocks.saturating_sub(1);
+            complete
+        };
+
         let block = ethers::types::Block {
-            hash: Some(block.hash),
-            number: Some(block.number),
+            hash: complete.then_some(block.hash),
+            number: complete.then_some(block.number),
             parent_hash: block.parent_hash,
             timestamp: U256::zero(),
-            logs_bloom: Some(Bloom::zero()),
+            logs_bloom: Some(block.logs_bloom),
+            base_fee_per_gas: block.base_fee_per_gas,
+            gas_used: block.gas_used.unwrap_or_default(),
             ..Default::default()
         };
 
         Ok(Some(block))
     }
+
+    /// Storage of the mock chain holds the slot number in every slot.
+    async fn get_storage_at<T: Into<NameOrAddress> + Send + Sync>(
+        &self,
+        _from: T,
+        location: H256,
+        _block: Option<BlockId>,
+    ) -> Result<H256, Self::Error> {
+        Ok(location)
+    }
+
+    /// Code of the mock chain is a single `STOP` at every address, except at
+    /// those set by `self_destruct`, from their block on.
+    async fn get_code<T: Into<NameOrAddress> + Send + Sync>(
+        &self,
+        at: T,
+        block: Option<BlockId>,
+    ) -> Result<Bytes, Self::Error> {
+        let address = match at.into() {
+            NameOrAddress::Address(address) => address,
+            NameOrAddress::Name(name) => panic!("get_code of name {:?}", name),
+        };
+
+        let number = match block {
+            Some(BlockId::Hash(h)) => MockMiddleware::get_block(self, h).await.unwrap().number,
+            Some(BlockId::Number(BlockNumber::Number(n))) => n,
+            Some(BlockId::Number(BlockNumber::Latest)) | None => {
+                MockMiddleware::get_latest_block(self).await.unwrap().number
+            }
+            Some(x) => panic!("get_code not number {:?}", x),
+        };
+
+        match self.self_destructs.lock().await.get(&address) {
+            Some(destruction) if number >= *destruction => Ok(Bytes::default()),
+            _ => Ok(Bytes::from(vec![0x00])),
+        }
+    }
+
+    async fn get_chainid(&self) -> Result<U256, Self::Error> {
+        Ok(*self.chain_id.lock().await)
+    }
+
+    async fn trace_block(&self, block: BlockNumber) -> Result<Vec<Trace>, Self::Error> {
+        let number = match block {
+            BlockNumber::Number(n) => n,
+            BlockNumber::Latest => MockMiddleware::get_latest_block(self).await.unwrap().number,
+            x => panic!("trace_block not number {:?}", x),
+        };
+
+        let traces = self.traces.lock().await;
+        let traces = traces.as_ref().ok_or(MockError)?;
+        Ok(traces
+            .iter()
+            .filter(|trace| trace.block_number == number.as_u64())
+            .cloned()
+            .collect())
+    }
+
+    async fn trace_transaction(&self, hash: H256) -> Result<Vec<Trace>, Self::Error> {
+        let traces = self.traces.lock().await;
+        let traces = traces.as_ref().ok_or(MockError)?;
+        Ok(traces
+            .iter()
+            .filter(|trace| trace.transaction_hash == Some(hash))
+            .cloned()
+            .collect())
+    }
+
+    /// Answers the output set by `set_call_output` for the called address,
+    /// at any block, or fails with `MockError`.
+    async fn call(
+        &self,
+        tx: &TypedTransaction,
+        block: Option<BlockId>,
+    ) -> Result<Bytes, Self::Error> {
+        self.call_requests.lock().await.push((tx.clone(), block));
+
+        let address = match tx.to() {
+            Some(NameOrAddress::Address(address)) => address,
+            x => panic!("call not to address {:?}", x),
+        };
+
+        self.call_outputs
+            .lock()
+            .await
+            .get(address)
+            .cloned()
+            .ok_or(MockError)
+    }
+
+    async fn get_transaction_receipt<T: Send + Sync + Into<TxHash>>(
+        &self,
+        transaction_hash: T,
+    ) -> Result<Option<TransactionReceipt>, Self::Error> {
+        let receipts = self.receipts.lock().await;
+        Ok(receipts.get(&transaction_hash.into()).cloned())
+    }
+
+    /// Answers the logs set by `set_logs` within the filter's block range, in
+    /// reverse order, or fails if set by `fail_next_requests`.
+    async fn get_logs(&self, filter: &Filter) -> Result<Vec<Log>, Self::Error> {
+        self.log_requests.lock().await.push(filter.clone());
+
+        let latency = *self.log_latency.lock().await;
+        if let Some(latency) = latency {
+            tokio::time::sleep(latency(filter)).await;
+        }
+
+        {
+            let mut failing_requests = self.failing_requests.lock().await;
+            if *failing_requests > 0 {
+                *failing_requests -= 1;
+                return Err(MockError);
+            }
+        }
+
+        let in_range: Box<dyn Fn(&Log) -> bool + Send> = match filter.block_option {
+            FilterBlockOption::Range {
+                from_block: Some(BlockNumber::Pending),
+                to_block: Some(BlockNumber::Pending),
+            } => return Ok(self.pending_logs.lock().await.clone()),
+
+            FilterBlockOption::Range {
+                from_block: Some(BlockNumber::Number(from)),
+                to_block: Some(BlockNumber::Number(to)),
+            } => Box::new(move |log| log.block_number.is_some_and(|n| from <= n && n <= to)),
+
+            FilterBlockOption::AtBlockHash(hash) => {
+                Box::new(move |log| log.block_hash == Some(hash))
+            }
+
+            _ => Box::new(|_| true),
+        };
+
+        let logs = self.logs.lock().await;
+        Ok(logs
+            .iter()
+            .rev()
+            .filter(|log| in_range(log))
+            .cloned()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MockMiddleware;
+    use eth_state_fold_types::ethers::types::H256;
+    use std::sync::Arc;
+
+    const SEED: u64 = 7;
+
+    async fn build_forked_chain() -> (Arc<MockMiddleware>, Vec<H256>) {
+        let m = MockMiddleware::new_seeded(SEED, 8).await;
+
+        let base = m.get_block_with_number(4.into()).await.unwrap().hash;
+        let mut tip = base;
+        for _ in 0..6 {
+            tip = m.add_block(tip).await.unwrap();
+        }
+
+        let mut hashes = vec![];
+        for n in 0u64..=10 {
+            hashes.push(m.get_block_with_number(n.into()).await.unwrap().hash);
+        }
+
+        (m, hashes)
+    }
+
+    #[tokio::test]
+    async fn seeded_test() {
+        let (m1, hashes1) = build_forked_chain().await;
+        let (m2, hashes2) = build_forked_chain().await;
+        assert_eq!(hashes1, hashes2);
+
+        // Canonical chain up to the fork is on branch 0, the fork on branch 1.
+        for n in 0u64..=4 {
+            assert_eq!(hashes1[n as usize], MockMiddleware::seeded_hash(SEED, n, 0));
+        }
+        for n in 5u64..=8 {
+            assert_eq!(hashes1[n as usize], MockMiddleware::seeded_hash(SEED, n, 1));
+        }
+        for n in 9u64..=10 {
+            assert_eq!(hashes1[n as usize], MockMiddleware::seeded_hash(SEED, n, 0));
+        }
+
+        let old_tip = MockMiddleware::seeded_hash(SEED, 8, 0);
+        assert_eq!(m1.get_block(old_tip).await, m2.get_block(old_tip).await);
+
+        let other = MockMiddleware::new_seeded(SEED + 1, 8).await;
+        assert_ne!(other.get_latest_block().await.unwrap().hash, old_tip);
+    }
 }
@@ -2,10 +2,12 @@
 // SPDX-License-Identifier: Apache-2.0 (see LICENSE)
 
 use anyhow::{anyhow, Result};
-use ethers::contract::Abigen;
+use ethers::contract::{Abigen, Contract};
+use ethers::core::abi::Abi;
+use ethers::providers::Middleware;
 use proc_macro2::token_stream::IntoIter;
 use proc_macro2::{Delimiter, Group, TokenStream, TokenTree};
-use quote::quote;
+use quote::{format_ident, quote};
 use serde_json::Value;
 use std::error;
 use std::io::{Read, Write};
@@ -51,6 +53,13 @@ macro_rules! contract_include {
     };
 }
 
+/// Contract bindings generated by [`write`], which can be instantiated on any
+/// middleware given only the contract's address.
+pub trait ContractBinding<M: Middleware>: From<Contract<M>> {
+    /// The contract's ABI.
+    fn abi() -> Abi;
+}
+
 /// Generates type-safe contract bindings from a contract's ABI. Uses [`Abigen`]
 /// under the hood.
 ///
@@ -83,7 +92,8 @@ where
     let abi_source = serde_json::to_string(&source)?;
 
     let bindings = Abigen::new(contract_name, abi_source)?.generate()?;
-    let tokens = bindings.into_tokens();
+    let mut tokens = bindings.into_tokens();
+    tokens.extend(self::contract_binding(contract_name));
 
     let tokens = self::replace_ethers_crates(tokens);
     let raw = tokens.to_string();
@@ -94,6 +104,24 @@ where
     Ok(())
 }
 
+/// Implements [`ContractBinding`] for the bindings generated by [`Abigen`].
+///
+/// [`Abigen`]: ethers::contract::Abigen
+fn contract_binding(contract_name: &str) -> TokenStream {
+    let name = format_ident!("{}", contract_name);
+    let abi = format_ident!("{}_ABI", contract_name.to_uppercase());
+
+    quote!(
+        impl<M: eth_state_fold_types::ethers::providers::Middleware>
+            eth_state_fold_types::contract::ContractBinding<M> for #name<M>
+        {
+            fn abi() -> eth_state_fold_types::ethers::core::abi::Abi {
+                #abi.clone()
+            }
+        }
+    )
+}
+
 /// Formats the raw input source string and return formatted output using
 /// locally installed `rustfmt`.
 fn format<S>(source: S) -> Result<String>
@@ -268,4 +296,22 @@ mod tests {
 
         assert_eq!(expected_output, actual_output);
     }
+
+    #[test]
+    fn test_contract_binding_uses_generated_abi() {
+        let expected_output = quote! {
+            impl<M: eth_state_fold_types::ethers::providers::Middleware>
+                eth_state_fold_types::contract::ContractBinding<M> for SimpleStorage<M>
+            {
+                fn abi() -> eth_state_fold_types::ethers::core::abi::Abi {
+                    SIMPLESTORAGE_ABI.clone()
+                }
+            }
+        }
+        .to_string();
+
+        let actual_output = contract_binding("SimpleStorage").to_string();
+
+        assert_eq!(expected_output, actual_output);
+    }
 }
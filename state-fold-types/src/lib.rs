@@ -24,6 +24,15 @@ pub struct Block {
     pub parent_hash: H256,
     pub timestamp: U256,
     pub logs_bloom: Bloom,
+
+    /// EIP-1559 base fee, `None` for pre-London blocks.
+    #[serde(default)]
+    pub base_fee_per_gas: Option<U256>,
+
+    /// Gas used by the block's transactions. `None` if unknown, as for blocks
+    /// received through the gRPC interface, which doesn't carry it.
+    #[serde(default)]
+    pub gas_used: Option<U256>,
 }
 
 impl PartialEq for Block {
@@ -40,10 +49,17 @@ impl std::hash::Hash for Block {
     }
 }
 
+/// State computed at a block, along with the block's header, so its
+/// timestamp, parent or logs bloom don't need to be fetched again. The header
+/// is shared with the environment's caches, costing no extra memory.
 #[derive(Debug)]
 pub struct BlockState<State> {
     pub block: Arc<Block>,
     pub state: Arc<State>,
+
+    /// Id of the chain the state was computed on, if known. States of other
+    /// chains are rejected by environments, such as when seeding their cache.
+    pub chain_id: Option<U256>,
 }
 
 impl<State> Clone for BlockState<State> {
@@ -51,6 +67,7 @@ impl<State> Clone for BlockState<State> {
         Self {
             block: Arc::clone(&self.block),
             state: Arc::clone(&self.state),
+            chain_id: self.chain_id,
         }
     }
 }
@@ -65,6 +82,21 @@ pub enum BlocksSince {
 pub enum BlockStreamItem {
     NewBlock(Arc<Block>),
     Reorg(Vec<Arc<Block>>),
+
+    /// A previously emitted block dropped by a reorg. Only emitted by
+    /// subscriptions opting into orphaned blocks, before the `Reorg` item
+    /// replacing them.
+    Orphaned(Arc<Block>),
+
+    /// The subscription fell behind by more blocks than the retained history,
+    /// e.g. after a long disconnect, so the blocks between `from`, the last
+    /// block emitted, and `to`, the block now at the subscription's depth,
+    /// can't be reliably emitted, nor can a reorg of `from` be resolved. The
+    /// subscription continues from `to`; consumers should resync from it.
+    HistoryGap {
+        from: Arc<Block>,
+        to: Arc<Block>,
+    },
 }
 
 #[derive(Clone, Debug)]
@@ -82,9 +114,35 @@ pub enum StateStreamItem<T> {
 #[derive(Clone, Debug)]
 pub enum QueryBlock {
     Latest,
+
+    /// The node's `safe` head, between `Latest` and finalized.
+    Safe,
+
+    /// The node's pending block. Its state isn't reorg-stable, so the
+    /// environment rejects it.
+    Pending,
+
     BlockHash(H256),
     BlockNumber(U64),
+
+    /// The block with this number, which must have this hash. Queries fail,
+    /// instead of racing a reorg, if the canonical block at that number has
+    /// another hash.
+    BlockNumberAndHash(U64, H256),
+
     BlockDepth(usize),
+
+    /// The block that currently has exactly `n` confirmations, counting the
+    /// latest block as its first, so the same block as `BlockDepth(n - 1)`.
+    /// It is resolved against the tip at query time; the resolved block is
+    /// what gets cached. A block with no confirmations is pending, which the
+    /// environment rejects.
+    Confirmations(u64),
+
+    /// A chain-specific target, such as a layer-2's latest verified batch,
+    /// resolved into a block by the environment's `BlockResolver`.
+    Custom(String),
+
     Block(Arc<Block>),
 }
 
@@ -114,6 +172,8 @@ impl<T> std::convert::TryFrom<self::ethers::types::Block<T>> for Block {
             parent_hash: H256::from(b.parent_hash.0),
             timestamp: U256(b.timestamp.0),
             logs_bloom: Bloom::from(b.logs_bloom.ok_or(BlockError::MissingLogsBloom)?.0),
+            base_fee_per_gas: b.base_fee_per_gas.map(|f| U256(f.0)),
+            gas_used: Some(U256(b.gas_used.0)),
         })
     }
 }
@@ -142,6 +202,12 @@ impl From<&U64> for QueryBlock {
     }
 }
 
+impl From<(U64, H256)> for QueryBlock {
+    fn from((n, h): (U64, H256)) -> Self {
+        QueryBlock::BlockNumberAndHash(n, h)
+    }
+}
+
 impl From<Block> for QueryBlock {
     fn from(b: Block) -> Self {
         QueryBlock::Block(Arc::new(b))
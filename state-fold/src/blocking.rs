@@ -0,0 +1,103 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+//! Synchronous facade of `StateFoldEnvironment`, for callers that aren't
+//! async, such as CLI tools, scripts and FFI boundaries.
+
+use crate::error::FoldableError;
+use crate::{Foldable, StateFoldEnvironment};
+
+use eth_state_fold_types::ethers;
+use eth_state_fold_types::{BlockState, QueryBlock};
+use ethers::providers::Middleware;
+
+use std::sync::Arc;
+use tokio::runtime::{Builder, Handle, Runtime};
+
+/// Environment whose queries block the calling thread, driving a dedicated
+/// runtime internally. It must not be used from within an async context,
+/// where it panics; await the inner environment there instead.
+pub struct BlockingStateFoldEnvironment<M: Middleware, UD> {
+    env: Arc<StateFoldEnvironment<M, UD>>,
+    runtime: Runtime,
+}
+
+impl<M: Middleware + 'static, UD> BlockingStateFoldEnvironment<M, UD> {
+    /// Wraps `env`, building the runtime driving its queries.
+    pub fn new(env: Arc<StateFoldEnvironment<M, UD>>) -> std::io::Result<Self> {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        Ok(Self { env, runtime })
+    }
+
+    pub fn inner(&self) -> &Arc<StateFoldEnvironment<M, UD>> {
+        &self.env
+    }
+
+    /// Blocking version of `StateFoldEnvironment::get_state_for_block`.
+    ///
+    /// # Panics
+    ///
+    /// If called from within an async context.
+    pub fn get_state_for_block<F: Foldable<UserData = UD> + Send + Sync + 'static>(
+        &self,
+        initial_state: &F::InitialState,
+        fold_block: impl Into<QueryBlock>,
+    ) -> Result<BlockState<F>, FoldableError<M, F>> {
+        assert!(
+            Handle::try_current().is_err(),
+            "`BlockingStateFoldEnvironment` called from within an async context; \
+             await `StateFoldEnvironment::get_state_for_block` instead"
+        );
+
+        self.runtime.block_on(
+            self.env
+                .get_state_for_block(initial_state, fold_block.into()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlockingStateFoldEnvironment;
+    use crate::test_utils::mocks::IncrementFold;
+    use crate::StateFoldEnvironment;
+    use std::sync::Arc;
+
+    use eth_state_fold_test::mock_middleware::MockMiddleware;
+    use eth_state_fold_types::QueryBlock;
+
+    fn blocking_env() -> BlockingStateFoldEnvironment<MockMiddleware, ()> {
+        let m = futures::executor::block_on(MockMiddleware::new(128));
+        let env = StateFoldEnvironment::new(m, None, 8, 0.into(), vec![], 1, usize::MAX, ());
+        BlockingStateFoldEnvironment::new(Arc::new(env)).unwrap()
+    }
+
+    #[test]
+    fn blocking_test() {
+        let env = blocking_env();
+
+        let block_state = env
+            .get_state_for_block::<IncrementFold>(&42, QueryBlock::Latest)
+            .unwrap();
+        assert_eq!(block_state.block.number, 128.into());
+        assert_eq!(block_state.state.n, 128 + 42);
+
+        let block_state = env
+            .get_state_for_block::<IncrementFold>(&42, QueryBlock::BlockNumber(100.into()))
+            .unwrap();
+        assert_eq!(block_state.state.n, 100 + 42);
+    }
+
+    #[test]
+    #[should_panic(expected = "called from within an async context")]
+    fn async_context_test() {
+        let env = blocking_env();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let _ = env.get_state_for_block::<IncrementFold>(&42, QueryBlock::Latest);
+        });
+    }
+}
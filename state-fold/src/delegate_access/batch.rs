@@ -0,0 +1,177 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use super::error::*;
+use super::requests::Requests;
+
+use eth_state_fold_types::ethers;
+use ethers::core::types::{transaction::eip2718::TypedTransaction, Address, BlockId, H256, U64};
+use ethers::providers::Middleware;
+
+/// Shared with `eth-block-history`, so a single transport serves both the
+/// batches of the access layers and the block fetches of `BlockArchive`.
+pub use eth_block_history::{BatchRequest, BatchResponse, BatchTransport};
+
+/// Batch of reads pinned to the block of the access layer that created it.
+/// See `FoldMiddleware::batch` and `SyncMiddleware::batch`.
+#[derive(Debug)]
+pub struct Batch<'a, M: Middleware> {
+    access: &'a Requests<M>,
+    block: BlockId,
+    requests: Vec<BatchRequest>,
+}
+
+impl<'a, M: Middleware + 'static> Batch<'a, M> {
+    pub(crate) fn new(access: &'a Requests<M>, block: BlockId) -> Self {
+        Self {
+            access,
+            block,
+            requests: vec![],
+        }
+    }
+
+    pub fn get_storage_at(mut self, address: Address, slot: H256) -> Self {
+        self.requests
+            .push(BatchRequest::GetStorageAt { address, slot });
+        self
+    }
+
+    pub fn call(mut self, tx: TypedTransaction) -> Self {
+        self.requests.push(BatchRequest::Call { tx: Box::new(tx) });
+        self
+    }
+
+    pub fn get_block(mut self, number: U64) -> Self {
+        self.requests
+            .push(BatchRequest::GetBlockByNumber { number });
+        self
+    }
+
+    /// Sends all requests, as a single batch if a `BatchTransport` is
+    /// configured, or sequentially otherwise. Responses are in the order the
+    /// requests were added.
+    pub async fn execute(self) -> Result<Vec<BatchResponse>, M> {
+        if self.requests.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let middleware = self.access.inner.as_ref();
+        if let Some(transport) = &self.access.batch_transport {
+            return self
+                .access
+                .request("batch", || {
+                    transport.send_batch(middleware, self.block, &self.requests)
+                })
+                .await;
+        }
+
+        let mut responses = Vec::with_capacity(self.requests.len());
+        for request in &self.requests {
+            let response = match request {
+                BatchRequest::GetStorageAt { address, slot } => self
+                    .access
+                    .request("eth_getStorageAt", || {
+                        middleware.get_storage_at(*address, *slot, Some(self.block))
+                    })
+                    .await
+                    .map(BatchResponse::Storage),
+
+                BatchRequest::Call { tx } => self
+                    .access
+                    .request("eth_call", || middleware.call(tx, Some(self.block)))
+                    .await
+                    .map(BatchResponse::Call),
+
+                BatchRequest::GetBlockByNumber { number } => self
+                    .access
+                    .request("eth_getBlockByNumber", || middleware.get_block(*number))
+                    .await
+                    .map(|block| BatchResponse::Block(block.map(Box::new))),
+            };
+
+            responses.push(response?);
+        }
+
+        Ok(responses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BatchRequest, BatchResponse, BatchTransport};
+    use crate::StateFoldEnvironment;
+
+    use eth_state_fold_test::mock_middleware::{MockError, MockMiddleware};
+    use eth_state_fold_types::ethers::types::{Address, BlockId, H256};
+
+    use async_trait::async_trait;
+    use std::sync::{Arc, Mutex};
+
+    /// Records every batch sent, answering from the mock chain.
+    #[derive(Debug, Default)]
+    struct RecordingTransport {
+        batches: Mutex<Vec<(BlockId, Vec<BatchRequest>)>>,
+    }
+
+    #[async_trait]
+    impl BatchTransport<MockMiddleware> for RecordingTransport {
+        async fn send_batch(
+            &self,
+            _middleware: &MockMiddleware,
+            block: BlockId,
+            requests: &[BatchRequest],
+        ) -> Result<Vec<BatchResponse>, MockError> {
+            self.batches
+                .lock()
+                .unwrap()
+                .push((block, requests.to_vec()));
+
+            Ok(requests
+                .iter()
+                .map(|r| match r {
+                    BatchRequest::GetStorageAt { slot, .. } => BatchResponse::Storage(*slot),
+                    BatchRequest::Call { .. } => BatchResponse::Call(Default::default()),
+                    BatchRequest::GetBlockByNumber { .. } => BatchResponse::Block(None),
+                })
+                .collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn batch_test() {
+        let m = MockMiddleware::new(128).await;
+        let mut env =
+            StateFoldEnvironment::new(Arc::clone(&m), None, 8, 0.into(), vec![], 1, usize::MAX, ());
+
+        let block = m.get_latest_block().await.unwrap();
+        let slots: Vec<_> = (0..4).map(H256::from_low_u64_be).collect();
+        let expected: Vec<_> = slots.iter().copied().map(BatchResponse::Storage).collect();
+
+        let batch = |env: &StateFoldEnvironment<MockMiddleware, ()>| {
+            let access = env.fold_access(&block);
+            let slots = slots.clone();
+            async move {
+                slots
+                    .into_iter()
+                    .fold(access.batch(), |batch, slot| {
+                        batch.get_storage_at(Address::zero(), slot)
+                    })
+                    .execute()
+                    .await
+                    .unwrap()
+            }
+        };
+
+        // Without a transport, requests are sent sequentially.
+        assert_eq!(batch(&env).await, expected);
+
+        let transport = Arc::new(RecordingTransport::default());
+        env.batch_transport = Some(transport.clone());
+        assert_eq!(batch(&env).await, expected);
+
+        let batches = transport.batches.lock().unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].0, BlockId::Hash(block.hash));
+        assert_eq!(batches[0].1.len(), 4);
+    }
+}
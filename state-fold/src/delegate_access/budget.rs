@@ -0,0 +1,87 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use super::error::*;
+
+use eth_state_fold_types::ethers;
+use ethers::providers::Middleware;
+
+use snafu::ensure;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Maximum number of requests a single query may send, shared by all of its
+/// access layers, and by the blocks the environment fetches for it.
+#[derive(Debug)]
+pub(crate) struct RpcBudget {
+    limit: usize,
+    spent: AtomicUsize,
+}
+
+impl RpcBudget {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            spent: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Whether a request was attempted past the limit.
+    pub fn exceeded(&self) -> bool {
+        self.spent.load(Ordering::Relaxed) > self.limit
+    }
+
+    /// Spends one request, returning whether it is within the limit.
+    fn spend(&self) -> bool {
+        self.spent.fetch_add(1, Ordering::Relaxed) < self.limit
+    }
+
+    /// Runs `query` with `budget` as the budget of the blocks the environment
+    /// fetches for it. See `spend_current`.
+    pub async fn scope<T>(budget: Option<Arc<Self>>, query: impl Future<Output = T>) -> T {
+        QUERY_BUDGET.scope(budget, query).await
+    }
+}
+
+tokio::task_local! {
+    // Budget of the query running in the current task.
+    static QUERY_BUDGET: Option<Arc<RpcBudget>>;
+}
+
+/// Spends one request of the budget of the query running in the current task,
+/// if any. The request is sent regardless, and the query fails once the
+/// budget is checked.
+pub(crate) fn spend_current() {
+    let _ = QUERY_BUDGET.try_with(try_spend);
+}
+
+/// Budget of the query running in the current task, if any.
+pub(crate) fn current() -> Option<Arc<RpcBudget>> {
+    QUERY_BUDGET.try_with(Clone::clone).ok().flatten()
+}
+
+/// Spends one request of `budget`, if any, returning whether it may be sent.
+pub(crate) fn try_spend(budget: &Option<Arc<RpcBudget>>) -> bool {
+    budget.as_ref().is_none_or(|budget| budget.spend())
+}
+
+/// Same as `try_spend`, failing with `RpcBudgetExceeded` if the request may
+/// not be sent.
+pub(crate) fn spend<M: Middleware>(budget: &Option<Arc<RpcBudget>>) -> Result<(), M> {
+    ensure!(try_spend(budget), RpcBudgetExceededSnafu);
+    Ok(())
+}
+
+/// Fails with `RpcBudgetExceeded` if a request was attempted past `budget`.
+pub(crate) fn check<M: Middleware>(budget: &Option<Arc<RpcBudget>>) -> Result<(), M> {
+    ensure!(
+        !budget.as_ref().is_some_and(|budget| budget.exceeded()),
+        RpcBudgetExceededSnafu
+    );
+    Ok(())
+}
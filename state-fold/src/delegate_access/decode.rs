@@ -0,0 +1,223 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use super::error::*;
+
+use eth_state_fold_types::ethers;
+use ethers::abi::{AbiDecode, RawLog};
+use ethers::contract::{EthLogDecode, LogMeta};
+use ethers::core::types::{Action, Log, Trace};
+use ethers::providers::Middleware;
+
+use snafu::OptionExt;
+
+/// What to do with logs that can't be decoded into the requested event type.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnrecognizedLogs {
+    /// Drop them.
+    #[default]
+    Skip,
+
+    /// Return them in `DecodedLogs::unrecognized`.
+    Collect,
+}
+
+/// Logs decoded into events of type `E`, in the order logs are handed to
+/// folds, each with the metadata of its log.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodedLogs<E> {
+    pub events: Vec<(E, LogMeta)>,
+
+    /// Logs that couldn't be decoded, empty unless collected.
+    pub unrecognized: Vec<Log>,
+}
+
+/// Decodes `log` into `E` (e.g. the events enum generated by `abigen` for a
+/// contract), or `None` if it doesn't match any of its events.
+pub fn decode_event<E: EthLogDecode>(log: &Log) -> Option<E> {
+    let raw = RawLog {
+        topics: log.topics.clone(),
+        data: log.data.to_vec(),
+    };
+
+    E::decode_log(&raw).ok()
+}
+
+/// Decodes the input of the call traced by `trace` into `C` (e.g. a call
+/// struct, or the calls enum generated by `abigen` for a contract), or `None`
+/// if it isn't a call, or doesn't match any of its functions. Traces of
+/// `FoldMiddleware::get_block_traces` are as the node answers them, with
+/// their inputs and outputs undecoded.
+pub fn decode_call<C: AbiDecode>(trace: &Trace) -> Option<C> {
+    match &trace.action {
+        Action::Call(call) => C::decode(&call.input).ok(),
+        _ => None,
+    }
+}
+
+/// Fails if a decoded log lacks any of its metadata, as pending logs do.
+pub(crate) fn decode_logs<M: Middleware, E>(
+    logs: Vec<Log>,
+    decode: impl Fn(&Log) -> Option<E>,
+    unrecognized: UnrecognizedLogs,
+) -> Result<DecodedLogs<E>, M> {
+    let mut decoded = DecodedLogs {
+        events: Vec::with_capacity(logs.len()),
+        unrecognized: vec![],
+    };
+
+    for log in logs {
+        match decode(&log) {
+            Some(event) => decoded.events.push((event, log_meta(&log)?)),
+            None if unrecognized == UnrecognizedLogs::Collect => decoded.unrecognized.push(log),
+            None => {}
+        }
+    }
+
+    Ok(decoded)
+}
+
+fn log_meta<M: Middleware>(log: &Log) -> Result<LogMeta, M> {
+    Ok(LogMeta {
+        address: log.address,
+        block_number: log.block_number.context(LogUnavailableSnafu)?,
+        block_hash: log.block_hash.context(LogUnavailableSnafu)?,
+        transaction_hash: log.transaction_hash.context(LogUnavailableSnafu)?,
+        transaction_index: log.transaction_index.context(LogUnavailableSnafu)?,
+        log_index: log.log_index.context(LogUnavailableSnafu)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_call, UnrecognizedLogs};
+    use crate::StateFoldEnvironment;
+    use std::sync::Arc;
+
+    use eth_state_fold_test::mock_middleware::MockMiddleware;
+    use eth_state_fold_test::simple_storage::{GetValueCall, SetValueCall};
+    use eth_state_fold_types::ethers;
+    use ethers::abi::{AbiEncode, RawLog};
+    use ethers::contract::EthLogDecode;
+    use ethers::types::{
+        Action, ActionType, Address, Call, Create, Filter, Log, Trace, H256, U256, U64,
+    };
+
+    const TRANSFER: u64 = 1;
+    const APPROVAL: u64 = 2;
+
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    enum TokenEvents {
+        Transfer(U256),
+        Approval(U256),
+    }
+
+    impl EthLogDecode for TokenEvents {
+        fn decode_log(log: &RawLog) -> Result<Self, ethers::abi::Error> {
+            let value = U256::from_big_endian(&log.data);
+
+            match log.topics.first().map(|t| t.to_low_u64_be()) {
+                Some(TRANSFER) => Ok(Self::Transfer(value)),
+                Some(APPROVAL) => Ok(Self::Approval(value)),
+                _ => Err(ethers::abi::Error::InvalidData),
+            }
+        }
+    }
+
+    fn log(event: u64, value: u64, block: u64, index: u64) -> Log {
+        let mut data = [0u8; 32];
+        U256::from(value).to_big_endian(&mut data);
+
+        Log {
+            address: Address::from_low_u64_be(42),
+            topics: vec![H256::from_low_u64_be(event)],
+            data: data.to_vec().into(),
+            block_number: Some(block.into()),
+            block_hash: Some(H256::from_low_u64_be(block)),
+            transaction_hash: Some(H256::from_low_u64_be(index)),
+            transaction_index: Some(index.into()),
+            log_index: Some(index.into()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn get_events_test() {
+        let m = MockMiddleware::new(16).await;
+        let env = StateFoldEnvironment::new(Arc::clone(&m), None, 4, 0.into(), vec![], 1, 8, ());
+
+        m.set_logs(vec![
+            log(TRANSFER, 10, 2, 0),
+            log(3, 0, 2, 1),
+            log(APPROVAL, 20, 3, 2),
+            log(TRANSFER, 30, 5, 3),
+        ])
+        .await;
+
+        let block = m.get_block_with_number(U64::from(16)).await.unwrap();
+        let access = env.sync_access(&block);
+        let filter = Filter::new().address(Address::from_low_u64_be(42));
+
+        let decoded = access
+            .get_events::<TokenEvents>(&filter, UnrecognizedLogs::Collect)
+            .await
+            .unwrap();
+
+        let events: Vec<_> = decoded
+            .events
+            .iter()
+            .map(|(event, meta)| (event.clone(), meta.block_number.as_u64()))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (TokenEvents::Transfer(10.into()), 2),
+                (TokenEvents::Approval(20.into()), 3),
+                (TokenEvents::Transfer(30.into()), 5),
+            ]
+        );
+        assert_eq!(decoded.unrecognized.len(), 1);
+        assert_eq!(decoded.unrecognized[0].log_index, Some(1.into()));
+
+        let decoded = access
+            .get_events_with(
+                &filter,
+                |log| (log.topics[0].to_low_u64_be() == APPROVAL).then_some(()),
+                UnrecognizedLogs::Skip,
+            )
+            .await
+            .unwrap();
+        assert_eq!(decoded.events.len(), 1);
+        assert!(decoded.unrecognized.is_empty());
+    }
+
+    #[test]
+    fn decode_call_test() {
+        let trace = |action: Action| Trace {
+            action,
+            result: None,
+            trace_address: vec![],
+            subtraces: 0,
+            transaction_position: Some(0),
+            transaction_hash: Some(H256::zero()),
+            block_number: 0,
+            block_hash: H256::zero(),
+            action_type: ActionType::Call,
+            error: None,
+        };
+
+        let set_value = SetValueCall {
+            value: "hello".to_owned(),
+        };
+        let call = trace(Action::Call(Call {
+            input: set_value.clone().encode().into(),
+            ..Default::default()
+        }));
+        assert_eq!(decode_call::<SetValueCall>(&call), Some(set_value));
+
+        // Calls of other functions, and other actions, aren't decoded.
+        assert_eq!(decode_call::<GetValueCall>(&call), None);
+        let create = trace(Action::Create(Create::default()));
+        assert_eq!(decode_call::<SetValueCall>(&create), None);
+    }
+}
@@ -2,32 +2,121 @@
 // SPDX-License-Identifier: Apache-2.0 (see LICENSE)
 
 use eth_state_fold_types::ethers;
+use ethers::core::types::{H256, U64};
 use ethers::providers::{FromErr, Middleware};
 
 use snafu::Snafu;
 
+/// Blocks read by the access layer a provider error comes from, for
+/// debugging.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AccessedBlocks {
+    /// Errors raised outside of an access layer.
+    #[default]
+    Unknown,
+
+    /// The block being folded.
+    Block(H256),
+
+    /// The pending block being folded.
+    Pending,
+
+    /// The range of blocks being synced, inclusive.
+    Range { from: U64, to: U64 },
+}
+
+impl std::fmt::Display for AccessedBlocks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unknown => write!(f, "unknown blocks"),
+            Self::Block(hash) => write!(f, "block {:?}", hash),
+            Self::Pending => write!(f, "the pending block"),
+            Self::Range { from, to } => write!(f, "blocks {} to {}", from, to),
+        }
+    }
+}
+
 #[derive(Debug, Snafu)]
 #[snafu(visibility(pub))]
 pub enum AccessError<M: Middleware + 'static> {
-    #[snafu(display("Ethers provider error: {}", source))]
-    EthersProviderError { source: M::Error },
+    #[snafu(display("Ethers provider error at {}: {}", blocks, source))]
+    EthersProviderError {
+        source: M::Error,
+        blocks: AccessedBlocks,
+    },
 
     #[snafu(display("Requested log unavailable"))]
     LogUnavailable {},
 
+    #[snafu(display("Requested receipt unavailable"))]
+    ReceiptUnavailable {},
+
+    #[snafu(display("Requested receipt outside of the blocks being accessed"))]
+    ReceiptOutOfRange {},
+
+    #[snafu(display("Requested trace outside of the block being accessed"))]
+    TraceOutOfRange {},
+
     #[snafu(display("Requested block incomplete"))]
     BlockIncomplete {},
 
     #[snafu(display("Requested block unavailable"))]
     BlockUnavailable {},
 
-    #[snafu(display("Partition error: {:?}", sources))]
-    PartitionError { sources: Vec<M::Error> },
+    #[snafu(display("Partition error at {}: {:?}", blocks, sources))]
+    PartitionError {
+        sources: Vec<M::Error>,
+        blocks: AccessedBlocks,
+    },
+
+    #[snafu(display("RPC budget of the query exceeded"))]
+    RpcBudgetExceeded {},
+
+    #[snafu(display("Request gate dropped the request"))]
+    RequestGateClosed {},
 }
 pub type Result<T, M> = std::result::Result<T, AccessError<M>>;
 
 impl<M: Middleware> FromErr<M::Error> for AccessError<M> {
     fn from(source: M::Error) -> Self {
-        AccessError::EthersProviderError { source }
+        AccessError::EthersProviderError {
+            source,
+            blocks: AccessedBlocks::Unknown,
+        }
+    }
+}
+
+/// `AccessError` with its middleware type erased, for the errors of folds,
+/// which can't be generic over the middleware. Displays as, and has the
+/// `source` of, the original error, along with the blocks it was accessing,
+/// and can be recovered with `downcast_ref`. See `foldable_error!`.
+#[derive(Debug)]
+pub struct ErasedAccessError(Box<dyn std::error::Error + Send + Sync>);
+
+impl ErasedAccessError {
+    /// The original error, if its middleware is `M`.
+    pub fn downcast_ref<M: Middleware + 'static>(&self) -> Option<&AccessError<M>> {
+        self.0.downcast_ref()
+    }
+}
+
+impl<M: Middleware + 'static> From<AccessError<M>> for ErasedAccessError
+where
+    M::Error: 'static,
+{
+    fn from(error: AccessError<M>) -> Self {
+        Self(Box::new(error))
+    }
+}
+
+impl std::fmt::Display for ErasedAccessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for ErasedAccessError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.source()
     }
 }
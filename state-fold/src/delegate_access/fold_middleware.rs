@@ -1,29 +1,357 @@
 // (c) Cartesi and individual authors (see AUTHORS)
 // SPDX-License-Identifier: Apache-2.0 (see LICENSE)
 
+#[cfg(feature = "profiling")]
+use crate::profiling::BlockProfile;
+use crate::Clock;
+
+use super::batch::{Batch, BatchTransport};
+use super::budget::RpcBudget;
+use super::decode::{decode_event, decode_logs, DecodedLogs, UnrecognizedLogs};
 use super::error::*;
+use super::log_coalescer::LogCoalescer;
+use super::log_pages::LogPagination;
+use super::request_gate::RequestGate;
+use super::requests::Requests;
+use super::retry::RetryPolicy;
 
+use eth_state_fold_types::contract::ContractBinding;
 use eth_state_fold_types::ethers;
+use ethers::contract::{Contract, EthLogDecode};
 use ethers::core::types::{
-    transaction::eip2718::TypedTransaction, BlockId, Bytes, Filter, Log, H256,
+    transaction::eip2718::TypedTransaction, Address, BlockId, BlockNumber, Bytes, Filter, Log,
+    Trace, TransactionReceipt, H256, U64,
 };
-use ethers::providers::{FromErr, Middleware};
+use ethers::providers::Middleware;
 
 use async_trait::async_trait;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+
+use snafu::{ensure, ResultExt};
 
 #[derive(Debug)]
-pub struct FoldMiddleware<M> {
-    inner: Arc<M>,
+pub struct FoldMiddleware<M: Middleware> {
+    requests: Requests<M>,
     block_hash: H256,
+
+    // Number of the block being folded, fetched when needed if not given.
+    block_number: Option<U64>,
+
+    // Whether the block being folded is the node's pending block, which
+    // requests are pinned to by tag, as it has no stable hash.
+    pending: bool,
+    log_coalescer: Option<LogCoalescer>,
+
+    // Logs returned by `get_logs`, when replaying for a `FoldTrace`.
+    log_recorder: Option<Arc<Mutex<Vec<Log>>>>,
 }
 
 impl<M> FoldMiddleware<M>
 where
     M: Middleware,
 {
-    pub fn new(inner: Arc<M>, block_hash: H256) -> Self {
-        Self { inner, block_hash }
+    pub fn new(
+        inner: Arc<M>,
+        block_hash: H256,
+        retry_policy: RetryPolicy<M>,
+        batch_transport: Option<Arc<dyn BatchTransport<M>>>,
+    ) -> Self {
+        let mut requests = Requests::new(inner, retry_policy, batch_transport);
+        requests.blocks = AccessedBlocks::Block(block_hash);
+
+        Self {
+            requests,
+            block_hash,
+            block_number: None,
+            pending: false,
+            log_coalescer: None,
+            log_recorder: None,
+        }
+    }
+
+    pub(crate) fn with_block_number(mut self, block_number: U64) -> Self {
+        self.block_number = Some(block_number);
+        self
+    }
+
+    pub(crate) fn at_pending_block(mut self) -> Self {
+        self.pending = true;
+        self.requests.blocks = AccessedBlocks::Pending;
+        self
+    }
+
+    pub(crate) fn with_budget(mut self, budget: Option<Arc<RpcBudget>>) -> Self {
+        self.requests.budget = budget;
+        self
+    }
+
+    pub(crate) fn with_gate(mut self, gate: Option<RequestGate>) -> Self {
+        self.requests.gate = gate;
+        self
+    }
+
+    pub(crate) fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.requests.clock = clock;
+        self
+    }
+
+    pub(crate) fn with_log_pagination(
+        mut self,
+        log_pagination: Option<Arc<dyn LogPagination<M>>>,
+    ) -> Self {
+        self.requests.log_pagination = log_pagination;
+        self
+    }
+
+    pub(crate) fn with_log_coalescer(mut self, log_coalescer: Option<LogCoalescer>) -> Self {
+        self.log_coalescer = log_coalescer;
+        self
+    }
+
+    pub(crate) fn with_log_recorder(mut self, recorder: Arc<Mutex<Vec<Log>>>) -> Self {
+        self.log_recorder = Some(recorder);
+        self
+    }
+
+    #[cfg(feature = "profiling")]
+    pub(crate) fn with_profile(mut self, profile: Arc<BlockProfile>) -> Self {
+        self.requests.profile = Some(profile);
+        self
+    }
+
+    /// Underlying middleware, for capabilities this access layer doesn't wrap
+    /// (e.g. custom RPC methods). Calls through it are not pinned to the
+    /// block being folded; the caller is responsible for pinning them, as
+    /// otherwise the resulting state may be non-deterministic.
+    pub fn raw_middleware(&self) -> &M {
+        self.requests.inner.as_ref()
+    }
+
+    /// Starts a batch of reads pinned to the block being folded, sent as a
+    /// single JSON-RPC batch request if a `BatchTransport` is configured.
+    pub fn batch(&self) -> Batch<'_, M>
+    where
+        M: 'static,
+    {
+        self.requests.batch(self.block_id())
+    }
+
+    /// Instantiates the contract bindings `C` at `address`, with calls pinned
+    /// to the block being folded.
+    pub fn contract<C>(self: &Arc<Self>, address: Address) -> C
+    where
+        M: 'static,
+        C: ContractBinding<Self>,
+    {
+        Contract::new(address, C::abi(), Arc::clone(self)).into()
+    }
+
+    /// Fetches the logs matching `filter`, like `get_logs`, decoding each into
+    /// the event type `E`, such as the events enum generated by `abigen` for a
+    /// contract. Logs of other events are skipped or collected according to
+    /// `unrecognized`. Fails with `LogUnavailable` if a decoded log lacks its
+    /// metadata.
+    pub async fn get_events<E: EthLogDecode>(
+        &self,
+        filter: &Filter,
+        unrecognized: UnrecognizedLogs,
+    ) -> std::result::Result<DecodedLogs<E>, AccessError<M>>
+    where
+        M: 'static,
+    {
+        self.get_events_with(filter, decode_event, unrecognized)
+            .await
+    }
+
+    /// Same as `get_events`, decoding logs with `decode`, which returns `None`
+    /// for unrecognized logs.
+    pub async fn get_events_with<E>(
+        &self,
+        filter: &Filter,
+        decode: impl Fn(&Log) -> Option<E> + Send,
+        unrecognized: UnrecognizedLogs,
+    ) -> std::result::Result<DecodedLogs<E>, AccessError<M>>
+    where
+        M: 'static,
+    {
+        let logs = Middleware::get_logs(self, filter).await?;
+        decode_logs(logs, decode, unrecognized)
+    }
+
+    /// Receipt of the transaction `tx_hash`, or `None` if the node doesn't
+    /// know it. Fails with `ReceiptOutOfRange` if the transaction isn't in the
+    /// block being folded, as the state would otherwise depend on other blocks.
+    pub async fn get_receipt(
+        &self,
+        tx_hash: H256,
+    ) -> std::result::Result<Option<TransactionReceipt>, AccessError<M>>
+    where
+        M: 'static,
+    {
+        let receipt = self
+            .requests
+            .request("eth_getTransactionReceipt", || {
+                self.requests.inner.get_transaction_receipt(tx_hash)
+            })
+            .await?;
+
+        if let Some(receipt) = &receipt {
+            ensure!(
+                receipt.block_hash == Some(self.block_hash),
+                ReceiptOutOfRangeSnafu
+            );
+        }
+
+        Ok(receipt)
+    }
+
+    /// Receipt of the transaction that emitted `log`, e.g. to check its status
+    /// or its other logs. Fails with `LogUnavailable` if `log` lacks its
+    /// transaction hash, and with `ReceiptUnavailable` if the node doesn't
+    /// know the transaction.
+    pub async fn get_log_receipt(
+        &self,
+        log: &Log,
+    ) -> std::result::Result<TransactionReceipt, AccessError<M>>
+    where
+        M: 'static,
+    {
+        let tx_hash = log
+            .transaction_hash
+            .ok_or(snafu::NoneError)
+            .context(LogUnavailableSnafu)?;
+
+        self.get_receipt(tx_hash)
+            .await?
+            .ok_or(snafu::NoneError)
+            .context(ReceiptUnavailableSnafu)
+    }
+
+    /// Whether there's code at `address` as of the block being folded, e.g.
+    /// to find out whether the contract of a fold has self-destructed. See
+    /// `Foldable::terminal`.
+    pub async fn is_contract_alive(
+        &self,
+        address: Address,
+    ) -> std::result::Result<bool, AccessError<M>>
+    where
+        M: 'static,
+    {
+        let code = self
+            .requests
+            .request("eth_getCode", || {
+                self.requests.inner.get_code(address, Some(self.block_id()))
+            })
+            .await?;
+
+        Ok(!code.is_empty())
+    }
+
+    /// Traces of the calls of every transaction of the block being folded,
+    /// including internal ones, such as value transfers within contract
+    /// execution, which aren't visible in logs. Requires a node supporting the
+    /// `trace` namespace, see `ProviderCapabilities::tracing`. Fails with
+    /// `TraceOutOfRange` if the node answers the traces of another block of
+    /// the same number, as after a reorg. Traces are as the node answers them,
+    /// and their call inputs can be decoded with `decode_call`.
+    pub async fn get_block_traces(&self) -> std::result::Result<Vec<Trace>, AccessError<M>>
+    where
+        M: 'static,
+    {
+        let block_number = match self.block_number {
+            Some(block_number) => block_number,
+            None => self
+                .requests
+                .request("eth_getBlockByHash", || {
+                    self.requests.inner.get_block(self.block_hash)
+                })
+                .await?
+                .and_then(|block| block.number)
+                .ok_or(snafu::NoneError)
+                .context(BlockUnavailableSnafu)?,
+        };
+
+        let traces = self
+            .requests
+            .request("trace_block", || {
+                self.requests
+                    .inner
+                    .trace_block(BlockNumber::Number(block_number))
+            })
+            .await?;
+
+        self.check_traces(traces)
+    }
+
+    /// Traces of the calls of the transaction `tx_hash`, like
+    /// `get_block_traces`. Fails with `TraceOutOfRange` if the transaction
+    /// isn't in the block being folded.
+    pub async fn get_transaction_traces(
+        &self,
+        tx_hash: H256,
+    ) -> std::result::Result<Vec<Trace>, AccessError<M>>
+    where
+        M: 'static,
+    {
+        let traces = self
+            .requests
+            .request("trace_transaction", || {
+                self.requests.inner.trace_transaction(tx_hash)
+            })
+            .await?;
+
+        self.check_traces(traces)
+    }
+
+    fn check_traces(&self, traces: Vec<Trace>) -> std::result::Result<Vec<Trace>, AccessError<M>>
+    where
+        M: 'static,
+    {
+        ensure!(
+            traces
+                .iter()
+                .all(|trace| trace.block_hash == self.block_hash),
+            TraceOutOfRangeSnafu
+        );
+
+        Ok(traces)
+    }
+
+    /// Block requests are pinned to.
+    fn block_id(&self) -> BlockId {
+        if self.pending {
+            BlockNumber::Pending.into()
+        } else {
+            self.block_hash.into()
+        }
+    }
+
+    /// Fetches every page of the logs matching `filter`, in order.
+    async fn get_log_pages(
+        &self,
+        pagination: &dyn LogPagination<M>,
+        filter: &Filter,
+    ) -> Result<Vec<Log>, M>
+    where
+        M: 'static,
+    {
+        let mut logs = vec![];
+        let mut cursor = None;
+
+        loop {
+            let page = self
+                .requests
+                .request("eth_getLogs", || {
+                    pagination.get_logs_page(&self.requests.inner, filter, cursor.as_deref())
+                })
+                .await?;
+            logs.extend(page.logs);
+
+            match page.cursor {
+                Some(next) => cursor = Some(next),
+                None => return Ok(logs),
+            }
+        }
     }
 }
 
@@ -37,7 +365,7 @@ where
     type Inner = M;
 
     fn inner(&self) -> &M {
-        Arc::as_ref(&self.inner)
+        Arc::as_ref(&self.requests.inner)
     }
 
     async fn call(
@@ -47,22 +375,61 @@ where
     ) -> std::result::Result<Bytes, Self::Error> {
         // If user provides a block, we use it. Otherwise, we use the default
         // block given during instantiation.
-        let block = block.or_else(|| Some(self.block_hash.into()));
-        self.inner().call(tx, block).await.map_err(FromErr::from)
+        let block = block.or_else(|| Some(self.block_id()));
+        self.requests
+            .request("eth_call", || self.inner().call(tx, block))
+            .await
     }
 
     async fn get_logs(&self, filter: &Filter) -> std::result::Result<Vec<Log>, Self::Error> {
         // Unlike call, we always override user provided range. This is a
         // limitation of ethers, because the type that holds the range is
         // private.
-        let filter = filter.clone().at_block_hash(self.block_hash);
-        let mut logs = self
-            .inner()
-            .get_logs(&filter)
-            .await
-            .map_err(FromErr::from)?;
+        let filter = if self.pending {
+            filter
+                .clone()
+                .from_block(BlockNumber::Pending)
+                .to_block(BlockNumber::Pending)
+        } else {
+            filter.clone().at_block_hash(self.block_hash)
+        };
+
+        let fetch = |filter: Filter| async move {
+            match &self.requests.log_pagination {
+                Some(pagination) => self.get_log_pages(pagination.as_ref(), &filter).await,
+                None => {
+                    self.requests
+                        .request("eth_getLogs", || self.inner().get_logs(&filter))
+                        .await
+                }
+            }
+        };
+
+        let mut logs = match &self.log_coalescer {
+            Some(coalescer) if !self.pending => {
+                coalescer
+                    .get_logs(
+                        self.requests.clock.as_ref(),
+                        self.block_hash,
+                        &filter,
+                        fetch,
+                    )
+                    .await?
+            }
+
+            _ => fetch(filter).await?,
+        };
+
+        // Logs of the pending block may lack their number and index, and are
+        // answered in order, being those of a single block.
+        if !self.pending {
+            super::utils::sort_logs(&mut logs)?;
+        }
+
+        if let Some(recorder) = &self.log_recorder {
+            recorder.lock().unwrap().extend(logs.iter().cloned());
+        }
 
-        super::utils::sort_logs(&mut logs)?;
         Ok(logs)
     }
 }
@@ -78,6 +445,74 @@ pub mod tests {
 
     use eth_state_fold_test::simple_storage::SimpleStorage;
 
+    #[tokio::test]
+    async fn trace_test() {
+        use crate::AccessError;
+        use eth_state_fold_test::mock_middleware::MockMiddleware;
+        use ethers::types::{Action, ActionType, Call, CallType, Trace, H256, U64};
+        use std::sync::Arc;
+
+        let m = MockMiddleware::new(16).await;
+        let env = StateFoldEnvironment::new(Arc::clone(&m), None, 4, 0.into(), vec![], 4, 2, ());
+        let block = |n: u64| m.get_block_with_number(U64::from(n));
+
+        // A transaction calling a contract, which transfers value to
+        // `recipient` in an internal call.
+        let tx_hash = H256::repeat_byte(1);
+        let (contract, recipient) = (Address::repeat_byte(2), Address::repeat_byte(3));
+        let mined = block(10).await.unwrap();
+        let trace = |trace_address: Vec<usize>, call: Call| Trace {
+            action: Action::Call(call),
+            result: None,
+            trace_address,
+            subtraces: 0,
+            transaction_position: Some(0),
+            transaction_hash: Some(tx_hash),
+            block_number: mined.number.as_u64(),
+            block_hash: mined.hash,
+            action_type: ActionType::Call,
+            error: None,
+        };
+        let call = |to: Address, value: u64| Call {
+            to,
+            value: value.into(),
+            call_type: CallType::Call,
+            ..Default::default()
+        };
+        m.set_traces(vec![
+            trace(vec![], call(contract, 0)),
+            trace(vec![0], call(recipient, 5)),
+        ])
+        .await;
+        m.provider().as_ref().push::<Vec<Trace>, _>(vec![]).unwrap();
+        assert!(env.capabilities().await.tracing);
+
+        let internal_transfers = |traces: Vec<Trace>| {
+            traces
+                .into_iter()
+                .filter(|trace| !trace.trace_address.is_empty())
+                .filter_map(|trace| match trace.action {
+                    Action::Call(call) if !call.value.is_zero() => Some((call.to, call.value)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let access = env.fold_access(&mined);
+        let traces = access.get_block_traces().await.unwrap();
+        assert_eq!(internal_transfers(traces), vec![(recipient, U256::from(5))]);
+
+        let traces = access.get_transaction_traces(tx_hash).await.unwrap();
+        assert_eq!(traces.len(), 2);
+
+        // Other blocks.
+        let access = env.fold_access(&block(11).await.unwrap());
+        assert!(access.get_block_traces().await.unwrap().is_empty());
+
+        let err = access.get_transaction_traces(tx_hash).await.unwrap_err();
+        assert!(matches!(err, AccessError::TraceOutOfRange {}));
+    }
+
     pub async fn fold_query_test<M: Middleware + 'static>(
         account: Address,
         deployed_address: Address,
@@ -208,4 +643,64 @@ pub mod tests {
             assert!(utils::contains_topic(&bloom, &U256::from(3)));
         }
     }
+
+    #[tokio::test]
+    async fn contract_test() {
+        use eth_state_fold_test::mock_middleware::MockMiddleware;
+        use ethers::abi::Token;
+        use ethers::types::{BlockId, BlockNumber, Bytes, NameOrAddress, U64};
+        use std::sync::Arc;
+
+        let m = MockMiddleware::new(16).await;
+        let env = StateFoldEnvironment::new(Arc::clone(&m), None, 4, 0.into(), vec![], 1, 2, ());
+        let block = m.get_block_with_number(U64::from(10)).await.unwrap();
+
+        let address = Address::repeat_byte(1);
+        let output = ethers::abi::encode(&[Token::String("this".to_owned())]);
+        m.set_call_output(address, Bytes::from(output)).await;
+
+        // Calls of bindings instantiated through either access layer are
+        // decoded, and sent to the contract at the block being accessed.
+        let fold_storage = env
+            .fold_access(&block)
+            .contract::<SimpleStorage<_>>(address);
+        assert_eq!(fold_storage.get_value().call().await.unwrap(), "this");
+
+        let sync_storage = env
+            .sync_access(&block)
+            .contract::<SimpleStorage<_>>(address);
+        assert_eq!(sync_storage.get_value().call().await.unwrap(), "this");
+
+        let requests = m.call_requests().await;
+        assert_eq!(requests.len(), 2);
+        for (tx, _) in &requests {
+            assert_eq!(tx.to(), Some(&NameOrAddress::Address(address)));
+        }
+        assert_eq!(requests[0].1, Some(BlockId::Hash(block.hash)));
+        assert_eq!(
+            requests[1].1,
+            Some(BlockId::Number(BlockNumber::Number(block.number)))
+        );
+    }
+
+    #[tokio::test]
+    async fn raw_middleware_test() {
+        use crate::test_utils::mocks::TipFold;
+        use eth_state_fold_test::mock_middleware::MockMiddleware;
+        use eth_state_fold_types::QueryBlock;
+        use std::sync::Arc;
+
+        let m = MockMiddleware::new(128).await;
+        let env =
+            StateFoldEnvironment::new(Arc::clone(&m), None, 8, 0.into(), vec![], 1, usize::MAX, ());
+
+        // Folding block 124 from block 120, reaching the unpinned tip.
+        let block_state = env
+            .get_state_for_block::<TipFold>(&(), QueryBlock::BlockNumber(124.into()))
+            .await
+            .unwrap();
+
+        assert_eq!(block_state.block.number, 124.into());
+        assert_eq!(block_state.state.tip, 128);
+    }
 }
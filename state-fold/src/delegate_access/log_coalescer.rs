@@ -0,0 +1,340 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use crate::Clock;
+
+use eth_state_fold_types::ethers;
+use ethers::core::types::{Filter, Log, Topic, ValueOrArray, H256};
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{oneshot, Notify};
+
+/// Merger of the `get_logs` requests of folds of the same block sent within a
+/// short window of each other into a single request, for the union of their
+/// addresses and topics, answering each with the logs matching its own
+/// filter. Trades up to `window` of latency per request for fewer requests,
+/// when many folds run on the same blocks concurrently. Can be shared with
+/// other environments of the same node.
+#[derive(Clone, Debug)]
+pub struct LogCoalescer {
+    window: Duration,
+    max_batch: usize,
+
+    // Batch still open to requests, by block hash.
+    pending: Arc<Mutex<HashMap<H256, Arc<PendingBatch>>>>,
+}
+
+#[derive(Debug, Default)]
+struct PendingBatch {
+    requests: Mutex<BatchRequests>,
+    full: Notify,
+}
+
+#[derive(Debug, Default)]
+struct BatchRequests {
+    filters: Vec<Filter>,
+
+    // Requests joining the first one, which sends the batch. They are
+    // answered `None`, or dropped, if it fails or is cancelled, and then send
+    // their own requests.
+    waiting: Vec<oneshot::Sender<Option<Arc<Vec<Log>>>>>,
+}
+
+impl LogCoalescer {
+    /// Coalescer merging the requests sent within `window` of the first one of
+    /// a batch, up to `max_batch` of them, after which the batch is sent right
+    /// away. A `max_batch` of `1` disables coalescing.
+    pub fn new(window: Duration, max_batch: usize) -> Self {
+        Self {
+            window,
+            max_batch,
+            pending: Default::default(),
+        }
+    }
+
+    /// Logs of the block `block_hash` matching `filter`, fetched with `fetch`,
+    /// along with the other requests of the block within the window.
+    pub(crate) async fn get_logs<E, Fut>(
+        &self,
+        clock: &dyn Clock,
+        block_hash: H256,
+        filter: &Filter,
+        fetch: impl FnOnce(Filter) -> Fut,
+    ) -> Result<Vec<Log>, E>
+    where
+        Fut: Future<Output = Result<Vec<Log>, E>>,
+    {
+        if self.max_batch <= 1 {
+            return fetch(filter.clone()).await;
+        }
+
+        let batch = match self.join(block_hash, filter) {
+            Ok(batch) => batch,
+
+            Err(rx) => {
+                return match rx.await {
+                    Ok(Some(logs)) => Ok(matching_logs(filter, &logs)),
+                    _ => fetch(filter.clone()).await,
+                }
+            }
+        };
+
+        // Seals the batch if this request is cancelled, so that the ones
+        // waiting on it send their own.
+        let sealer = Sealer {
+            coalescer: self,
+            block_hash,
+            batch,
+        };
+
+        tokio::select! {
+            _ = clock.sleep(self.window) => {}
+            _ = sealer.batch.full.notified() => {}
+        }
+
+        let BatchRequests { filters, waiting } = sealer.seal();
+        match fetch(merge_filters(&filters)).await {
+            Ok(logs) => {
+                let logs = Arc::new(logs);
+                for tx in waiting {
+                    let _ = tx.send(Some(Arc::clone(&logs)));
+                }
+
+                Ok(matching_logs(filter, &logs))
+            }
+
+            Err(e) => {
+                for tx in waiting {
+                    let _ = tx.send(None);
+                }
+
+                Err(e)
+            }
+        }
+    }
+
+    /// Adds `filter` to the open batch of `block_hash`, returning the receiver
+    /// of its logs, or opens a new batch, returned for this request to send.
+    #[allow(clippy::type_complexity)]
+    fn join(
+        &self,
+        block_hash: H256,
+        filter: &Filter,
+    ) -> Result<Arc<PendingBatch>, oneshot::Receiver<Option<Arc<Vec<Log>>>>> {
+        let mut pending = self.pending.lock().unwrap();
+
+        let Some(batch) = pending.get(&block_hash).cloned() else {
+            let batch = Arc::new(PendingBatch::default());
+            batch.requests.lock().unwrap().filters.push(filter.clone());
+            pending.insert(block_hash, Arc::clone(&batch));
+            return Ok(batch);
+        };
+
+        let (tx, rx) = oneshot::channel();
+        let mut requests = batch.requests.lock().unwrap();
+        requests.filters.push(filter.clone());
+        requests.waiting.push(tx);
+
+        if requests.filters.len() >= self.max_batch {
+            pending.remove(&block_hash);
+            batch.full.notify_one();
+        }
+
+        Err(rx)
+    }
+}
+
+/// Closes a batch to new requests when sent, or when its sender is dropped.
+struct Sealer<'a> {
+    coalescer: &'a LogCoalescer,
+    block_hash: H256,
+    batch: Arc<PendingBatch>,
+}
+
+impl Sealer<'_> {
+    fn seal(&self) -> BatchRequests {
+        let mut pending = self.coalescer.pending.lock().unwrap();
+        if pending
+            .get(&self.block_hash)
+            .is_some_and(|batch| Arc::ptr_eq(batch, &self.batch))
+        {
+            pending.remove(&self.block_hash);
+        }
+
+        std::mem::take(&mut *self.batch.requests.lock().unwrap())
+    }
+}
+
+impl Drop for Sealer<'_> {
+    fn drop(&mut self) {
+        self.seal();
+    }
+}
+
+/// Filter matching the logs of every filter of `filters`, all of the same
+/// block.
+fn merge_filters(filters: &[Filter]) -> Filter {
+    let mut merged = filters[0].clone();
+    if filters.len() == 1 {
+        return merged;
+    }
+
+    merged.address = filters
+        .iter()
+        .map(|filter| filter.address.clone())
+        .collect::<Option<Vec<_>>>()
+        .map(|addresses| ValueOrArray::Array(union(addresses)));
+
+    for (i, topic) in merged.topics.iter_mut().enumerate() {
+        *topic = filters
+            .iter()
+            .map(|filter| match &filter.topics[i] {
+                Some(topic) if !values(topic).contains(&None) => Some(topic.clone()),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(|topics| ValueOrArray::Array(union(topics)));
+    }
+
+    merged
+}
+
+fn values<T: Clone>(value: &ValueOrArray<T>) -> Vec<T> {
+    match value {
+        ValueOrArray::Value(value) => vec![value.clone()],
+        ValueOrArray::Array(values) => values.clone(),
+    }
+}
+
+fn union<T: Clone + PartialEq>(values_or_arrays: Vec<ValueOrArray<T>>) -> Vec<T> {
+    let mut union = vec![];
+    for value in values_or_arrays.iter().flat_map(values) {
+        if !union.contains(&value) {
+            union.push(value);
+        }
+    }
+
+    union
+}
+
+/// Logs of `logs` matching the addresses and topics of `filter`.
+fn matching_logs(filter: &Filter, logs: &[Log]) -> Vec<Log> {
+    let topic_matches = |topic: &Option<Topic>, log_topic: Option<&H256>| match topic {
+        Some(topic) => values(topic)
+            .iter()
+            .any(|t| t.is_none() || t.as_ref() == log_topic),
+        None => true,
+    };
+
+    logs.iter()
+        .filter(|log| {
+            filter
+                .address
+                .as_ref()
+                .is_none_or(|address| values(address).contains(&log.address))
+        })
+        .filter(|log| {
+            filter
+                .topics
+                .iter()
+                .enumerate()
+                .all(|(i, topic)| topic_matches(topic, log.topics.get(i)))
+        })
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LogCoalescer;
+    use crate::StateFoldEnvironment;
+
+    use eth_state_fold_test::mock_middleware::MockMiddleware;
+    use eth_state_fold_types::ethers;
+    use ethers::providers::Middleware;
+    use ethers::types::{Address, Filter, Log, ValueOrArray, H256, U64};
+
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn coalescing_test() {
+        let m = MockMiddleware::new(16).await;
+        let mut env =
+            StateFoldEnvironment::new(Arc::clone(&m), None, 4, 0.into(), vec![], 4, 2, ());
+        let block = m.get_block_with_number(U64::from(10)).await.unwrap();
+
+        // A log of each address, one of them under a topic.
+        let addresses: Vec<_> = (1..=4).map(Address::repeat_byte).collect();
+        let topic = H256::repeat_byte(0xff);
+        let logs: Vec<_> = addresses
+            .iter()
+            .enumerate()
+            .map(|(i, address)| Log {
+                address: *address,
+                topics: if i == 0 { vec![topic] } else { vec![] },
+                block_hash: Some(block.hash),
+                block_number: Some(block.number),
+                log_index: Some(i.into()),
+                ..Default::default()
+            })
+            .collect();
+        m.set_logs(logs.clone()).await;
+
+        let filters = vec![
+            Filter::new().address(addresses[0]).topic0(topic),
+            Filter::new().address(addresses[1]),
+            Filter::new().address(vec![addresses[2], addresses[1]]),
+        ];
+        let expected = vec![
+            vec![logs[0].clone()],
+            vec![logs[1].clone()],
+            vec![logs[1].clone(), logs[2].clone()],
+        ];
+
+        let get_logs = |env: &StateFoldEnvironment<MockMiddleware, ()>| {
+            let accesses: Vec<_> = filters.iter().map(|_| env.fold_access(&block)).collect();
+            let filters = filters.clone();
+            async move {
+                let requests = accesses
+                    .iter()
+                    .zip(&filters)
+                    .map(|(access, filter)| access.get_logs(filter));
+                futures::future::join_all(requests).await
+            }
+        };
+
+        // Near-simultaneous requests of the same block are merged, and each
+        // is answered its own logs.
+        env.log_coalescer = Some(LogCoalescer::new(Duration::from_millis(10), 8));
+        let ok = |results: Vec<Result<Vec<Log>, _>>| -> Vec<_> {
+            results.into_iter().map(Result::unwrap).collect()
+        };
+        assert_eq!(ok(get_logs(&env).await), expected);
+
+        let requests = m.log_requests().await;
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            requests[0].address,
+            Some(ValueOrArray::Array(addresses[..3].to_vec()))
+        );
+        assert_eq!(requests[0].topics[0], None);
+
+        // Up to the maximum batch size.
+        env.log_coalescer = Some(LogCoalescer::new(Duration::from_millis(10), 2));
+        assert_eq!(ok(get_logs(&env).await), expected);
+        assert_eq!(m.log_requests().await.len(), 3);
+
+        // Only the request sending a failed batch fails, the others joining it
+        // are sent on their own.
+        m.fail_next_requests(1).await;
+        let before = m.log_requests().await.len();
+        let results = get_logs(&env).await;
+        assert!(results[0].is_err());
+        assert!(results[1..].iter().all(Result::is_ok));
+        assert_eq!(m.log_requests().await.len(), before + 3);
+    }
+}
@@ -0,0 +1,34 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use eth_state_fold_types::ethers;
+use ethers::core::types::{Filter, Log};
+use ethers::providers::Middleware;
+
+use async_trait::async_trait;
+
+/// Page of the logs matching a filter, answered by `LogPagination`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LogPage {
+    /// Logs of this page, in the order of the whole result.
+    pub logs: Vec<Log>,
+
+    /// Cursor of the next page, `None` if this is the last one.
+    pub cursor: Option<String>,
+}
+
+/// Transport able to fetch large log sets in pages, for providers whose
+/// `eth_getLogs` takes a pagination token. Without one, log queries answered
+/// with too many logs are split into smaller block ranges instead, which can't
+/// retrieve a single block with more logs than the provider's cap.
+#[async_trait]
+pub trait LogPagination<M: Middleware>: std::fmt::Debug + Send + Sync {
+    /// Fetches the page of the logs matching `filter` at `cursor`, the first
+    /// one if `None`.
+    async fn get_logs_page(
+        &self,
+        middleware: &M,
+        filter: &Filter,
+        cursor: Option<&str>,
+    ) -> std::result::Result<LogPage, M::Error>;
+}
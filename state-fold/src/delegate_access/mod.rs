@@ -1,12 +1,26 @@
 // (c) Cartesi and individual authors (see AUTHORS)
 // SPDX-License-Identifier: Apache-2.0 (see LICENSE)
 
+pub mod batch;
+pub(crate) mod budget;
+pub mod decode;
 pub mod error;
 pub mod fold_middleware;
+pub mod log_coalescer;
+pub mod log_pages;
+pub(crate) mod request_gate;
+pub(crate) mod requests;
+pub mod retry;
 pub mod sync_middleware;
 
-pub use error::AccessError;
+pub use batch::{Batch, BatchRequest, BatchResponse, BatchTransport};
+pub use decode::{decode_call, decode_event, DecodedLogs, UnrecognizedLogs};
+pub use error::{AccessError, AccessedBlocks, ErasedAccessError};
 pub use fold_middleware::FoldMiddleware;
+pub use log_coalescer::LogCoalescer;
+pub use log_pages::{LogPage, LogPagination};
+pub use request_gate::{Priority, RequestGate};
+pub use retry::{default_classifier, RetryPolicy, Retryability};
 pub use sync_middleware::SyncMiddleware;
 
 mod partition_events;
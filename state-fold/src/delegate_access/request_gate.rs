@@ -0,0 +1,222 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use super::error::*;
+
+use eth_state_fold_types::ethers::providers::Middleware;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+/// Priority of a query's requests at a `RequestGate`. See
+/// `StateFoldEnvironment::get_state_for_block_with_priority`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Latency-sensitive queries, such as interactive ones.
+    High,
+
+    #[default]
+    Normal,
+
+    /// Background work, such as long cold syncs.
+    Low,
+}
+
+tokio::task_local! {
+    // Priority of the query running in the current task.
+    static QUERY_PRIORITY: Priority;
+}
+
+impl Priority {
+    /// Priority of the query running in the current task, `Normal` if none.
+    pub(crate) fn current() -> Self {
+        QUERY_PRIORITY
+            .try_with(|priority| *priority)
+            .unwrap_or_default()
+    }
+
+    /// Runs `query` with this priority, including the queries nested in its
+    /// folds.
+    pub(crate) async fn scope<T>(self, query: impl std::future::Future<Output = T>) -> T {
+        QUERY_PRIORITY.scope(self, query).await
+    }
+}
+
+/// Limit on the requests the access layers send concurrently, shared by every
+/// query, and possibly by several environments. When the limit is reached,
+/// waiting requests are let through by `Priority`, and in arrival order within
+/// the same priority, so background syncs don't starve interactive queries.
+#[derive(Clone, Debug)]
+pub struct RequestGate {
+    state: Arc<Mutex<GateState>>,
+}
+
+#[derive(Debug)]
+struct GateState {
+    available: usize,
+
+    // Waiting requests of each priority, indexed by `Priority as usize`.
+    waiting: [VecDeque<oneshot::Sender<GatePermit>>; 3],
+}
+
+/// Permission to send a request, returned to its `RequestGate` when dropped.
+#[derive(Debug)]
+pub(crate) struct GatePermit {
+    gate: Option<RequestGate>,
+}
+
+impl RequestGate {
+    /// Gate letting at most `max_in_flight` requests through at once.
+    pub fn new(max_in_flight: usize) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(GateState {
+                available: max_in_flight,
+                waiting: Default::default(),
+            })),
+        }
+    }
+
+    /// Waits for permission to send a request of `priority`, or `None` if
+    /// the gate drops the request while it waits.
+    pub(crate) async fn acquire(&self, priority: Priority) -> Option<GatePermit> {
+        let rx = {
+            let mut state = self.state.lock().unwrap();
+
+            if state.available > 0 && state.waiting.iter().all(VecDeque::is_empty) {
+                state.available -= 1;
+                return Some(GatePermit {
+                    gate: Some(self.clone()),
+                });
+            }
+
+            let (tx, rx) = oneshot::channel();
+            state.waiting[priority as usize].push_back(tx);
+            rx
+        };
+
+        rx.await.ok()
+    }
+
+    /// Number of requests waiting for permission.
+    #[cfg(test)]
+    pub(crate) fn waiting(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.waiting.iter().map(VecDeque::len).sum()
+    }
+
+    /// Hands the permit of a finished request to the next waiting request.
+    fn release(&self) {
+        loop {
+            let tx = {
+                let mut state = self.state.lock().unwrap();
+
+                match state.waiting.iter_mut().find_map(VecDeque::pop_front) {
+                    Some(tx) => tx,
+                    None => {
+                        state.available += 1;
+                        return;
+                    }
+                }
+            };
+
+            // If the waiting request was cancelled, try the next one. A permit
+            // sent to a request cancelled afterwards is dropped, and released,
+            // along with its channel.
+            let permit = GatePermit {
+                gate: Some(self.clone()),
+            };
+
+            match tx.send(permit) {
+                Ok(()) => return,
+                Err(mut permit) => permit.gate = None,
+            }
+        }
+    }
+}
+
+impl Drop for GatePermit {
+    fn drop(&mut self) {
+        if let Some(gate) = self.gate.take() {
+            gate.release();
+        }
+    }
+}
+
+/// Waits for permission to send a request of the current query's priority at
+/// `gate`, if any. Fails with `RequestGateClosed` if it isn't granted.
+pub(crate) async fn acquire<M: Middleware + 'static>(
+    gate: &Option<RequestGate>,
+) -> Result<Option<GatePermit>, M> {
+    match gate {
+        Some(gate) => match gate.acquire(Priority::current()).await {
+            Some(permit) => Ok(Some(permit)),
+            None => RequestGateClosedSnafu.fail(),
+        },
+
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Priority, RequestGate};
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn priority_test() {
+        let gate = RequestGate::new(1);
+        let order = Arc::new(Mutex::new(vec![]));
+        let permit = gate.acquire(Priority::Normal).await;
+
+        let waiter = |priority: Priority, label: &'static str| {
+            let gate = gate.clone();
+            let order = Arc::clone(&order);
+            tokio::spawn(async move {
+                let _permit = gate.acquire(priority).await;
+                order.lock().unwrap().push(label);
+            })
+        };
+
+        let waiters = [
+            waiter(Priority::Low, "low"),
+            waiter(Priority::Normal, "normal 1"),
+            waiter(Priority::High, "high"),
+            waiter(Priority::Normal, "normal 2"),
+        ];
+        while gate.waiting() < 4 {
+            tokio::task::yield_now().await;
+        }
+
+        drop(permit);
+        for waiter in waiters {
+            waiter.await.unwrap();
+        }
+
+        assert_eq!(
+            *order.lock().unwrap(),
+            vec!["high", "normal 1", "normal 2", "low"]
+        );
+        assert_eq!(gate.state.lock().unwrap().available, 1);
+    }
+
+    #[tokio::test]
+    async fn cancelled_waiter_test() {
+        let gate = RequestGate::new(1);
+        let permit = gate.acquire(Priority::Normal).await;
+
+        let cancelled = tokio::spawn({
+            let gate = gate.clone();
+            async move { gate.acquire(Priority::High).await }
+        });
+        while gate.waiting() < 1 {
+            tokio::task::yield_now().await;
+        }
+        cancelled.abort();
+        let _ = cancelled.await;
+
+        drop(permit);
+        let _permit = gate.acquire(Priority::Low).await;
+        assert_eq!(gate.state.lock().unwrap().available, 0);
+    }
+}
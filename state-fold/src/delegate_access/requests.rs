@@ -0,0 +1,141 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+#[cfg(feature = "profiling")]
+use crate::profiling::BlockProfile;
+use crate::{Clock, TokioClock};
+
+use super::batch::{Batch, BatchTransport};
+use super::budget::{self, RpcBudget};
+use super::error::*;
+use super::log_pages::LogPagination;
+use super::request_gate::{self, RequestGate};
+use super::retry::RetryPolicy;
+
+use eth_state_fold_types::ethers;
+use ethers::core::types::BlockId;
+use ethers::providers::Middleware;
+
+use snafu::ResultExt;
+use std::future::Future;
+use std::sync::Arc;
+
+/// Middleware of an access layer, along with how its requests are sent:
+/// retried, counted against the budget of the query, let through its gate, and
+/// timed. Shared by `FoldMiddleware` and `SyncMiddleware`.
+#[derive(Debug)]
+pub(crate) struct Requests<M: Middleware> {
+    pub inner: Arc<M>,
+    pub retry_policy: RetryPolicy<M>,
+    pub batch_transport: Option<Arc<dyn BatchTransport<M>>>,
+    pub budget: Option<Arc<RpcBudget>>,
+    pub gate: Option<RequestGate>,
+    pub clock: Arc<dyn Clock>,
+    pub blocks: AccessedBlocks,
+    pub log_pagination: Option<Arc<dyn LogPagination<M>>>,
+
+    #[cfg(feature = "profiling")]
+    pub profile: Option<Arc<BlockProfile>>,
+}
+
+impl<M: Middleware> Requests<M> {
+    pub fn new(
+        inner: Arc<M>,
+        retry_policy: RetryPolicy<M>,
+        batch_transport: Option<Arc<dyn BatchTransport<M>>>,
+    ) -> Self {
+        Self {
+            inner,
+            retry_policy,
+            batch_transport,
+            budget: None,
+            gate: None,
+            clock: Arc::new(TokioClock),
+            blocks: AccessedBlocks::Unknown,
+            log_pagination: None,
+
+            #[cfg(feature = "profiling")]
+            profile: None,
+        }
+    }
+
+    /// Spends one request of the budget, failing with `RpcBudgetExceeded` if
+    /// it may not be sent.
+    pub fn spend(&self) -> Result<(), M> {
+        budget::spend(&self.budget)
+    }
+
+    /// Spends one request of the budget, returning whether it may be sent.
+    pub fn try_spend(&self) -> bool {
+        budget::try_spend(&self.budget)
+    }
+
+    /// Starts a batch of reads pinned to `block`.
+    pub fn batch(&self, block: BlockId) -> Batch<'_, M>
+    where
+        M: 'static,
+    {
+        Batch::new(self, block)
+    }
+
+    /// Same as `send`, spending the budget on the first attempt too, and
+    /// failing with `RpcBudgetExceeded` if it runs out before a successful
+    /// attempt.
+    pub async fn request<T, Fut>(
+        &self,
+        method: &'static str,
+        request: impl FnMut() -> Fut,
+    ) -> Result<T, M>
+    where
+        M: 'static,
+        Fut: Future<Output = std::result::Result<T, M::Error>>,
+    {
+        self.spend()?;
+
+        match self.send(method, request).await {
+            Err(AccessError::EthersProviderError { .. })
+                if self.budget.as_ref().is_some_and(|budget| budget.exceeded()) =>
+            {
+                RpcBudgetExceededSnafu.fail()
+            }
+
+            res => res,
+        }
+    }
+
+    /// Sends `request`, of the RPC `method`, with the retry policy, timing it
+    /// when profiling, and counting it with the `metrics` feature. Its errors
+    /// carry the accessed `blocks`. Each retry
+    /// spends the budget, and isn't attempted past it. Each attempt waits for
+    /// the gate, whose permit isn't held through the backoffs.
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub async fn send<T, Fut>(
+        &self,
+        method: &'static str,
+        mut request: impl FnMut() -> Fut,
+    ) -> Result<T, M>
+    where
+        M: 'static,
+        Fut: Future<Output = std::result::Result<T, M::Error>>,
+    {
+        #[cfg(feature = "metrics")]
+        crate::metrics::rpc_call(method);
+
+        let (gate, blocks) = (&self.gate, self.blocks);
+        let attempt = || {
+            let request = request();
+            async move {
+                let _permit = request_gate::acquire(gate).await?;
+                request.await.context(EthersProviderSnafu { blocks })
+            }
+        };
+        let request = self
+            .retry_policy
+            .retry(self.clock.as_ref(), attempt, || self.try_spend());
+
+        #[cfg(feature = "profiling")]
+        let request = crate::profiling::time_rpc(&self.profile, self.clock.as_ref(), request);
+
+        request.await
+    }
+}
@@ -0,0 +1,263 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use super::error::*;
+use crate::Clock;
+
+use eth_state_fold_types::ethers;
+use ethers::providers::{Middleware, ProviderError};
+
+use std::error::Error;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+const DEFAULT_MAX_RETRIES: usize = 3;
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// HTTP statuses deemed transient by `default_classifier`: too many requests,
+/// bad gateway, service unavailable and gateway timeout.
+const TRANSIENT_STATUSES: [u16; 4] = [429, 502, 503, 504];
+
+/// How the access layer handles a provider error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retryability {
+    /// The error is transient, and the request is retried after a backoff.
+    Retry,
+
+    /// The request fails. Queries for events may still be partitioned, as
+    /// configured by the query limit error codes.
+    Fail,
+
+    /// The request fails immediately, without partitioning queries for events.
+    FailFast,
+}
+
+/// Policy for retrying requests made through the access layer.
+#[derive(Debug)]
+pub struct RetryPolicy<M: Middleware> {
+    /// Classifies provider errors. Defaults to `default_classifier`, and may be
+    /// overridden to match the errors of a specific node.
+    pub classifier: fn(&M::Error) -> Retryability,
+
+    /// Maximum number of retries of a single request. Defaults to `3`.
+    pub max_retries: usize,
+
+    /// Delay before the first retry, doubled on each subsequent retry.
+    /// Defaults to 100 milliseconds.
+    pub backoff: Duration,
+}
+
+/// Classifies errors by the types found along their chain of sources:
+/// transport timeouts and connection failures, and HTTP responses of rate
+/// limits or unavailable servers, are transient. Other errors, such as JSON-RPC
+/// error responses, fail.
+pub fn default_classifier<M: Middleware>(err: &M::Error) -> Retryability
+where
+    M::Error: 'static,
+{
+    let mut source: Option<&(dyn Error + 'static)> = Some(err);
+    while let Some(err) = source {
+        if is_transient(err) {
+            return Retryability::Retry;
+        }
+
+        source = err.source();
+    }
+
+    Retryability::Fail
+}
+
+fn is_transient(err: &(dyn Error + 'static)) -> bool {
+    if let Some(err) = err.downcast_ref::<ProviderError>() {
+        return match err {
+            ProviderError::HTTPError(err) => {
+                err.is_timeout()
+                    || err.is_connect()
+                    || err
+                        .status()
+                        .is_some_and(|status| TRANSIENT_STATUSES.contains(&status.as_u16()))
+            }
+
+            // Transparent, so its source skips the client's own error.
+            ProviderError::JsonRpcClientError(err) => is_transient(err.as_ref()),
+
+            _ => false,
+        };
+    }
+
+    if let Some(err) = err.downcast_ref::<std::io::Error>() {
+        return matches!(
+            err.kind(),
+            ErrorKind::TimedOut
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionRefused
+                | ErrorKind::ConnectionAborted
+        );
+    }
+
+    false
+}
+
+impl<M: Middleware> Default for RetryPolicy<M>
+where
+    M::Error: 'static,
+{
+    fn default() -> Self {
+        Self {
+            classifier: default_classifier::<M>,
+            max_retries: DEFAULT_MAX_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+        }
+    }
+}
+
+impl<M: Middleware> Clone for RetryPolicy<M> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<M: Middleware> Copy for RetryPolicy<M> {}
+
+impl<M: Middleware> RetryPolicy<M> {
+    pub fn classify(&self, err: &M::Error) -> Retryability {
+        (self.classifier)(err)
+    }
+
+    /// Runs `request`, retrying it while it fails with provider errors
+    /// classified as `Retry`, up to `max_retries` times, and as long as
+    /// `may_retry` allows, sleeping the backoffs on `clock`.
+    pub(crate) async fn retry<T, Fut>(
+        &self,
+        clock: &dyn Clock,
+        mut request: impl FnMut() -> Fut,
+        mut may_retry: impl FnMut() -> bool,
+    ) -> Result<T, M>
+    where
+        M: 'static,
+        Fut: Future<Output = Result<T, M>>,
+    {
+        let mut backoff = self.backoff;
+        let mut retries = 0;
+
+        loop {
+            match request().await {
+                Err(AccessError::EthersProviderError { source, .. })
+                    if retries < self.max_retries
+                        && self.classify(&source) == Retryability::Retry
+                        && may_retry() =>
+                {
+                    clock.sleep(backoff).await;
+                    backoff = backoff.saturating_mul(2);
+                    retries += 1;
+                }
+
+                res => return res,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Retryability;
+    use crate::{RequestGate, StateFoldEnvironment};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    use eth_state_fold_test::mock_middleware::{MockError, MockMiddleware};
+    use eth_state_fold_types::ethers::providers::Middleware;
+    use eth_state_fold_types::ethers::types::Filter;
+
+    #[tokio::test]
+    async fn classifier_test() {
+        let m = MockMiddleware::new(128).await;
+        let mut env =
+            StateFoldEnvironment::new(Arc::clone(&m), None, 8, 0.into(), vec![], 1, usize::MAX, ());
+        env.retry_policy.backoff = Duration::ZERO;
+
+        let block = m.get_latest_block().await.unwrap();
+        let filter = Filter::new();
+
+        // `MockError` is not deemed transient by default.
+        m.fail_next_requests(1).await;
+        assert!(env.fold_access(&block).get_logs(&filter).await.is_err());
+        assert!(env.fold_access(&block).get_logs(&filter).await.is_ok());
+
+        env.retry_policy.classifier = |_: &MockError| Retryability::Retry;
+
+        m.fail_next_requests(3).await;
+        assert!(env.fold_access(&block).get_logs(&filter).await.is_ok());
+        m.fail_next_requests(3).await;
+        assert!(env.sync_access(&block).get_logs(&filter).await.is_ok());
+
+        // Exceeding the maximum number of retries.
+        m.fail_next_requests(4).await;
+        assert!(env.fold_access(&block).get_logs(&filter).await.is_err());
+    }
+
+    #[test]
+    fn default_classifier_test() {
+        use super::default_classifier;
+        use eth_state_fold_types::ethers::providers::{Http, Provider, ProviderError};
+
+        let classify = default_classifier::<Provider<Http>>;
+
+        let io = |kind| ProviderError::JsonRpcClientError(Box::new(std::io::Error::from(kind)));
+        assert_eq!(
+            classify(&io(std::io::ErrorKind::TimedOut)),
+            Retryability::Retry
+        );
+        assert_eq!(
+            classify(&io(std::io::ErrorKind::ConnectionRefused)),
+            Retryability::Retry
+        );
+        assert_eq!(
+            classify(&io(std::io::ErrorKind::InvalidData)),
+            Retryability::Fail
+        );
+
+        // Messages mentioning transient failures don't make errors transient.
+        let custom = ProviderError::CustomError("429 rate limit timed out".to_owned());
+        assert_eq!(classify(&custom), Retryability::Fail);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backoff_test() {
+        let m = MockMiddleware::new(128).await;
+        let mut env =
+            StateFoldEnvironment::new(Arc::clone(&m), None, 8, 0.into(), vec![], 1, usize::MAX, ());
+        env.retry_policy.classifier = |_: &MockError| Retryability::Retry;
+        env.retry_policy.backoff = Duration::from_millis(100);
+
+        let block = m.get_latest_block().await.unwrap();
+        let access = env.fold_access(&block);
+
+        // Backoffs of 100, 200 and 400 milliseconds.
+        m.fail_next_requests(3).await;
+        let start = Instant::now();
+        assert!(access.get_logs(&Filter::new()).await.is_ok());
+        assert_eq!(start.elapsed(), Duration::from_millis(700));
+
+        // No backoff after the last retry.
+        m.fail_next_requests(4).await;
+        let start = Instant::now();
+        assert!(access.get_logs(&Filter::new()).await.is_err());
+        assert_eq!(start.elapsed(), Duration::from_millis(700));
+
+        // The permit of the gate is released during backoffs.
+        env.request_gate = Some(RequestGate::new(1));
+        let (retried, other) = (env.fold_access(&block), env.fold_access(&block));
+        let filter = Filter::new();
+        m.fail_next_requests(1).await;
+        let start = Instant::now();
+        let (retried, elapsed) = tokio::join!(retried.get_logs(&filter), async {
+            other.get_logs(&filter).await.unwrap();
+            start.elapsed()
+        });
+        assert!(retried.is_ok());
+        assert!(elapsed < Duration::from_millis(100));
+    }
+}
@@ -1,29 +1,45 @@
 // (c) Cartesi and individual authors (see AUTHORS)
 // SPDX-License-Identifier: Apache-2.0 (see LICENSE)
 
+#[cfg(feature = "profiling")]
+use crate::profiling::BlockProfile;
+use crate::Clock;
+
+use super::batch::{Batch, BatchTransport};
+use super::budget::{self, RpcBudget};
+use super::decode::{decode_event, decode_logs, DecodedLogs, UnrecognizedLogs};
 use super::error::*;
+use super::log_pages::LogPagination;
 use super::partition_events::*;
+use super::request_gate::RequestGate;
+use super::requests::Requests;
+use super::retry::{RetryPolicy, Retryability};
 
+use eth_state_fold_types::contract::ContractBinding;
 use eth_state_fold_types::ethers;
+use ethers::contract::{Contract, EthLogDecode};
 use ethers::core::types::{
-    transaction::eip2718::TypedTransaction, BlockId, BlockNumber, Bytes, Filter, FilterBlockOption,
-    Log, U64,
+    transaction::eip2718::TypedTransaction, Address, BlockId, BlockNumber, Bytes, Filter,
+    FilterBlockOption, Log, TransactionReceipt, H256, U64,
 };
-use ethers::providers::{FromErr, Middleware};
+use ethers::providers::Middleware;
 
 use async_trait::async_trait;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use snafu::ResultExt;
+use snafu::{ensure, ResultExt};
 
 #[derive(Debug)]
-pub struct SyncMiddleware<M> {
-    inner: Arc<M>,
+pub struct SyncMiddleware<M: Middleware> {
+    requests: Requests<M>,
     genesis: U64,
     block_number: U64,
     query_limit_error_codes: Vec<i32>,
     concurrent_events_fetch: usize,
     maximum_events_per_response: usize,
+
+    // Logs returned by `get_logs`, when replaying for a `FoldTrace`.
+    log_recorder: Option<Arc<Mutex<Vec<Log>>>>,
 }
 
 impl<M> SyncMiddleware<M>
@@ -37,19 +53,236 @@ where
         query_limit_error_codes: Vec<i32>,
         concurrent_events_fetch: usize,
         maximum_events_per_response: usize,
+        retry_policy: RetryPolicy<M>,
+        batch_transport: Option<Arc<dyn BatchTransport<M>>>,
     ) -> Self {
+        let mut requests = Requests::new(inner, retry_policy, batch_transport);
+        requests.blocks = AccessedBlocks::Range {
+            from: genesis,
+            to: block_number,
+        };
+
         Self {
-            inner,
+            requests,
             genesis,
             block_number,
             query_limit_error_codes,
             concurrent_events_fetch,
             maximum_events_per_response,
+            log_recorder: None,
         }
     }
 
+    pub(crate) fn with_budget(mut self, budget: Option<Arc<RpcBudget>>) -> Self {
+        self.requests.budget = budget;
+        self
+    }
+
+    pub(crate) fn with_gate(mut self, gate: Option<RequestGate>) -> Self {
+        self.requests.gate = gate;
+        self
+    }
+
+    pub(crate) fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.requests.clock = clock;
+        self
+    }
+
+    pub(crate) fn with_log_pagination(
+        mut self,
+        log_pagination: Option<Arc<dyn LogPagination<M>>>,
+    ) -> Self {
+        self.requests.log_pagination = log_pagination;
+        self
+    }
+
+    pub(crate) fn with_log_recorder(mut self, recorder: Arc<Mutex<Vec<Log>>>) -> Self {
+        self.log_recorder = Some(recorder);
+        self
+    }
+
+    #[cfg(feature = "profiling")]
+    pub(crate) fn with_profile(mut self, profile: Arc<BlockProfile>) -> Self {
+        self.requests.profile = Some(profile);
+        self
+    }
+
     pub fn get_inner(&self) -> Arc<M> {
-        Arc::clone(&self.inner)
+        Arc::clone(&self.requests.inner)
+    }
+
+    /// Underlying middleware, for capabilities this access layer doesn't wrap
+    /// (e.g. custom RPC methods). Calls through it are not pinned to the
+    /// block being synced; the caller is responsible for pinning them, as
+    /// otherwise the resulting state may be non-deterministic.
+    pub fn raw_middleware(&self) -> &M {
+        self.requests.inner.as_ref()
+    }
+
+    /// Starts a batch of reads pinned to the block being synced, sent as a
+    /// single JSON-RPC batch request if a `BatchTransport` is configured.
+    pub fn batch(&self) -> Batch<'_, M>
+    where
+        M: 'static,
+    {
+        self.requests.batch(self.block_number.into())
+    }
+
+    /// Instantiates the contract bindings `C` at `address`, with calls pinned
+    /// to the block being synced.
+    pub fn contract<C>(self: &Arc<Self>, address: Address) -> C
+    where
+        M: 'static,
+        C: ContractBinding<Self>,
+    {
+        Contract::new(address, C::abi(), Arc::clone(self)).into()
+    }
+
+    /// Fetches the logs matching `filter`, like `get_logs`, decoding each into
+    /// the event type `E`, such as the events enum generated by `abigen` for a
+    /// contract. Logs of other events are skipped or collected according to
+    /// `unrecognized`. Fails with `LogUnavailable` if a decoded log lacks its
+    /// metadata.
+    pub async fn get_events<E: EthLogDecode>(
+        &self,
+        filter: &Filter,
+        unrecognized: UnrecognizedLogs,
+    ) -> std::result::Result<DecodedLogs<E>, AccessError<M>>
+    where
+        M: 'static,
+    {
+        self.get_events_with(filter, decode_event, unrecognized)
+            .await
+    }
+
+    /// Same as `get_events`, decoding logs with `decode`, which returns `None`
+    /// for unrecognized logs.
+    pub async fn get_events_with<E>(
+        &self,
+        filter: &Filter,
+        decode: impl Fn(&Log) -> Option<E> + Send,
+        unrecognized: UnrecognizedLogs,
+    ) -> std::result::Result<DecodedLogs<E>, AccessError<M>>
+    where
+        M: 'static,
+    {
+        let logs = Middleware::get_logs(self, filter).await?;
+        decode_logs(logs, decode, unrecognized)
+    }
+
+    /// Receipt of the transaction `tx_hash`, or `None` if the node doesn't
+    /// know it. Fails with `ReceiptOutOfRange` if the transaction is after the
+    /// block being synced, pending, or in a block no longer on the chain, as
+    /// the state would otherwise depend on other blocks.
+    pub async fn get_receipt(
+        &self,
+        tx_hash: H256,
+    ) -> std::result::Result<Option<TransactionReceipt>, AccessError<M>>
+    where
+        M: 'static,
+    {
+        let receipt = self
+            .requests
+            .request("eth_getTransactionReceipt", || {
+                self.requests.inner.get_transaction_receipt(tx_hash)
+            })
+            .await?;
+
+        let Some(receipt) = receipt else {
+            return Ok(None);
+        };
+
+        let (Some(number), Some(hash)) = (receipt.block_number, receipt.block_hash) else {
+            return ReceiptOutOfRangeSnafu.fail();
+        };
+        ensure!(number <= self.block_number, ReceiptOutOfRangeSnafu);
+
+        // Blocks are read by number, on the chain the node currently follows,
+        // which must still hold the block of the receipt.
+        let block = self
+            .requests
+            .request("eth_getBlockByNumber", || {
+                self.requests.inner.get_block(number)
+            })
+            .await?
+            .ok_or(snafu::NoneError)
+            .context(BlockUnavailableSnafu)?;
+        ensure!(block.hash == Some(hash), ReceiptOutOfRangeSnafu);
+
+        Ok(Some(receipt))
+    }
+
+    /// Receipt of the transaction that emitted `log`, e.g. to check its status
+    /// or its other logs. Fails with `LogUnavailable` if `log` lacks its
+    /// transaction hash, and with `ReceiptUnavailable` if the node doesn't
+    /// know the transaction.
+    pub async fn get_log_receipt(
+        &self,
+        log: &Log,
+    ) -> std::result::Result<TransactionReceipt, AccessError<M>>
+    where
+        M: 'static,
+    {
+        let tx_hash = log
+            .transaction_hash
+            .ok_or(snafu::NoneError)
+            .context(LogUnavailableSnafu)?;
+
+        self.get_receipt(tx_hash)
+            .await?
+            .ok_or(snafu::NoneError)
+            .context(ReceiptUnavailableSnafu)
+    }
+
+    /// Whether there's code at `address` as of the block being synced, e.g.
+    /// to find out whether the contract of a fold has self-destructed. See
+    /// `Foldable::terminal`.
+    pub async fn is_contract_alive(
+        &self,
+        address: Address,
+    ) -> std::result::Result<bool, AccessError<M>>
+    where
+        M: 'static,
+    {
+        let code = self
+            .requests
+            .request("eth_getCode", || {
+                self.requests
+                    .inner
+                    .get_code(address, Some(self.block_number.into()))
+            })
+            .await?;
+
+        Ok(!code.is_empty())
+    }
+
+    /// Fetches every page of the logs matching `filter`, in order. Pages past
+    /// the budget are skipped, like partitions.
+    async fn get_log_pages(
+        &self,
+        pagination: &dyn LogPagination<M>,
+        filter: &Filter,
+    ) -> Result<Vec<Log>, M>
+    where
+        M: 'static,
+    {
+        let mut logs = vec![];
+        let mut cursor = None;
+
+        loop {
+            let page = self
+                .requests
+                .send("eth_getLogs", || {
+                    pagination.get_logs_page(&self.requests.inner, filter, cursor.as_deref())
+                })
+                .await?;
+            logs.extend(page.logs);
+
+            match page.cursor {
+                Some(next) if self.requests.try_spend() => cursor = Some(next),
+                _ => return Ok(logs),
+            }
+        }
     }
 }
 
@@ -63,7 +296,7 @@ where
     type Inner = M;
 
     fn inner(&self) -> &M {
-        Arc::as_ref(&self.inner)
+        Arc::as_ref(&self.requests.inner)
     }
 
     async fn call(
@@ -74,7 +307,9 @@ where
         // If user provides a block, we use it. Otherwise, we use the default
         // blocks given during instantiation.
         let block = block.or_else(|| Some(self.block_number.into()));
-        self.inner().call(tx, block).await.map_err(FromErr::from)
+        self.requests
+            .request("eth_call", || self.inner().call(tx, block))
+            .await
     }
 
     async fn get_logs(&self, filter: &Filter) -> std::result::Result<Vec<Log>, Self::Error> {
@@ -98,10 +333,9 @@ where
 
             FilterBlockOption::AtBlockHash(h) => {
                 let b = self
-                    .inner
-                    .get_block(h)
-                    .await
-                    .context(EthersProviderSnafu)?
+                    .requests
+                    .request("eth_getBlockByHash", || self.requests.inner.get_block(h))
+                    .await?
                     .ok_or(snafu::NoneError)
                     .context(BlockUnavailableSnafu)?
                     .number
@@ -115,12 +349,21 @@ where
             _ => (self.genesis.as_u64(), self.block_number.as_u64()),
         };
 
-        let mut logs = partition_events
+        let logs = partition_events
             .get_events(start, end)
             .await
-            .map_err(|err_arr| PartitionSnafu { sources: err_arr }.build())?;
+            .map_err(partition_error);
+
+        // Partitions past the budget are skipped, so their logs are missing.
+        budget::check(&self.requests.budget)?;
+        let mut logs = logs?;
 
         super::utils::sort_logs(&mut logs)?;
+
+        if let Some(recorder) = &self.log_recorder {
+            recorder.lock().unwrap().extend(logs.iter().cloned());
+        }
+
         Ok(logs)
     }
 }
@@ -130,7 +373,7 @@ impl<M> PartitionProvider<Log, Filter> for SyncMiddleware<M>
 where
     M: Middleware + 'static,
 {
-    type ProviderErr = <<Self as Middleware>::Inner as Middleware>::Error;
+    type ProviderErr = AccessError<M>;
 
     async fn fetch_events_with_range_inner(
         &self,
@@ -138,12 +381,34 @@ where
         from_block: u64,
         to_block: u64,
     ) -> std::result::Result<Vec<Log>, Self::ProviderErr> {
+        // Past the budget, the whole query fails with `RpcBudgetExceeded`,
+        // so skipping the request is harmless.
+        if !self.requests.try_spend() {
+            return Ok(vec![]);
+        }
+
         let filter = data.clone().from_block(from_block).to_block(to_block);
-        let logs = self.inner().get_logs(&filter).await?;
+        let logs = match &self.requests.log_pagination {
+            Some(pagination) => self.get_log_pages(pagination.as_ref(), &filter).await?,
+            None => {
+                self.requests
+                    .send("eth_getLogs", || self.inner().get_logs(&filter))
+                    .await?
+            }
+        };
+
         Ok(logs)
     }
 
     fn should_retry_with_partition(&self, err: &Self::ProviderErr) -> bool {
+        let AccessError::EthersProviderError { source: err, .. } = err else {
+            return false;
+        };
+
+        if self.requests.retry_policy.classify(err) == Retryability::FailFast {
+            return false;
+        }
+
         for code in &self.query_limit_error_codes {
             let s = format!("{:?}", err);
             if s.contains(&code.to_string()) {
@@ -155,8 +420,34 @@ where
     }
 
     fn maximum_events_per_response(&self) -> usize {
-        self.maximum_events_per_response
+        // The maximum applies to each page, not to the reassembled logs.
+        match self.requests.log_pagination {
+            Some(_) => usize::MAX,
+            None => self.maximum_events_per_response,
+        }
+    }
+}
+
+/// `PartitionError` of the provider errors of the partitions, or the first of
+/// their other errors, which partitioning doesn't apply to.
+fn partition_error<M: Middleware + 'static>(errors: Vec<AccessError<M>>) -> AccessError<M> {
+    let mut sources = vec![];
+    let mut blocks = AccessedBlocks::Unknown;
+    for error in errors {
+        match error {
+            AccessError::EthersProviderError {
+                source,
+                blocks: accessed,
+            } => {
+                sources.push(source);
+                blocks = accessed;
+            }
+
+            error => return error,
+        }
     }
+
+    PartitionSnafu { sources, blocks }.build()
 }
 
 #[cfg(test)]
@@ -170,6 +461,239 @@ pub mod tests {
 
     use eth_state_fold_test::simple_storage::SimpleStorage;
 
+    #[tokio::test]
+    async fn log_order_test() {
+        use eth_state_fold_test::mock_middleware::MockMiddleware;
+        use ethers::types::{Filter, Log, U64};
+        use std::sync::Arc;
+
+        let m = MockMiddleware::new(16).await;
+        let env = StateFoldEnvironment::new(Arc::clone(&m), None, 4, 0.into(), vec![], 4, 2, ());
+
+        // Two logs per block, answered in reverse order, such that the query
+        // is partitioned.
+        let key = |log: &Log| (log.block_number.unwrap(), log.log_index.unwrap());
+        let logs: Vec<_> = (1..=8u64)
+            .flat_map(|n| {
+                (0..2u64).map(move |i| Log {
+                    block_number: Some(n.into()),
+                    log_index: Some((2 * n + i).into()),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        m.set_logs(logs.clone()).await;
+
+        let block = m.get_block_with_number(U64::from(16)).await.unwrap();
+        let fetched = env
+            .sync_access(&block)
+            .get_logs(&Filter::new())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            fetched.iter().map(key).collect::<Vec<_>>(),
+            logs.iter().map(key).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn log_order_out_of_order_completion_test() {
+        use eth_state_fold_test::mock_middleware::MockMiddleware;
+        use ethers::types::{BlockNumber, Filter, FilterBlockOption, Log, U64};
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let m = MockMiddleware::new(16).await;
+        let env = StateFoldEnvironment::new(Arc::clone(&m), None, 4, 0.into(), vec![], 4, 2, ());
+
+        // Later ranges are answered first, so concurrent partitions complete
+        // in reverse order.
+        m.set_log_latency(|filter| match filter.block_option {
+            FilterBlockOption::Range {
+                from_block: Some(BlockNumber::Number(from)),
+                ..
+            } => Duration::from_millis(100 - from.as_u64()),
+            _ => Duration::ZERO,
+        })
+        .await;
+
+        // Two logs per block, stored with their indices scrambled.
+        let key = |log: &Log| (log.block_number.unwrap(), log.log_index.unwrap());
+        let logs: Vec<_> = (1..=8u64)
+            .flat_map(|n| {
+                [1, 0].map(|i: u64| Log {
+                    block_number: Some(n.into()),
+                    log_index: Some((2 * n + i).into()),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        m.set_logs(logs.clone()).await;
+
+        let block = m.get_block_with_number(U64::from(16)).await.unwrap();
+        let fetched = env
+            .sync_access(&block)
+            .get_logs(&Filter::new())
+            .await
+            .unwrap();
+
+        assert!(m.log_requests().await.len() > 1);
+
+        let mut expected: Vec<_> = logs.iter().map(key).collect();
+        expected.sort();
+        assert_eq!(fetched.iter().map(key).collect::<Vec<_>>(), expected);
+    }
+
+    #[tokio::test]
+    async fn pagination_test() {
+        use crate::{LogPage, LogPagination};
+        use async_trait::async_trait;
+        use eth_state_fold_test::mock_middleware::{MockError, MockMiddleware};
+        use ethers::types::{Filter, Log, U64};
+        use std::sync::Arc;
+
+        // Pages of `get_logs`, with the offset of the next page as cursor.
+        #[derive(Debug)]
+        struct Pages(usize);
+
+        #[async_trait]
+        impl LogPagination<MockMiddleware> for Pages {
+            async fn get_logs_page(
+                &self,
+                middleware: &MockMiddleware,
+                filter: &Filter,
+                cursor: Option<&str>,
+            ) -> Result<LogPage, MockError> {
+                let logs = middleware.get_logs(filter).await?;
+                let start = cursor.map_or(0, |cursor| cursor.parse().unwrap());
+                let end = logs.len().min(start + self.0);
+
+                Ok(LogPage {
+                    logs: logs[start..end].to_vec(),
+                    cursor: (end < logs.len()).then(|| end.to_string()),
+                })
+            }
+        }
+
+        // More logs in block 10 than fit in a response.
+        let m = MockMiddleware::new(16).await;
+        let block = m.get_block_with_number(U64::from(10)).await.unwrap();
+        let logs: Vec<_> = (0..10u64)
+            .map(|i| Log {
+                block_hash: Some(block.hash),
+                block_number: Some(block.number),
+                log_index: Some(i.into()),
+                ..Default::default()
+            })
+            .collect();
+        m.set_logs(logs.clone()).await;
+
+        let mut env =
+            StateFoldEnvironment::new(Arc::clone(&m), None, 4, 0.into(), vec![], 4, 3, ());
+        env.log_pagination = Some(Arc::new(Pages(3)));
+
+        let requests = m.log_requests().await.len();
+        let latest = m.get_block_with_number(U64::from(16)).await.unwrap();
+        let fetched = env
+            .sync_access(&latest)
+            .get_logs(&Filter::new())
+            .await
+            .unwrap();
+        assert_eq!(fetched, logs);
+        assert_eq!(m.log_requests().await.len() - requests, 4);
+
+        let fetched = env
+            .fold_access(&block)
+            .get_logs(&Filter::new())
+            .await
+            .unwrap();
+        assert_eq!(fetched, logs);
+    }
+
+    #[tokio::test]
+    async fn receipt_test() {
+        use crate::AccessError;
+        use eth_state_fold_test::mock_middleware::MockMiddleware;
+        use ethers::types::{Log, TransactionReceipt, H256, U64};
+        use std::sync::Arc;
+
+        let m = MockMiddleware::new(16).await;
+        let env = StateFoldEnvironment::new(Arc::clone(&m), None, 4, 0.into(), vec![], 4, 2, ());
+        let block = |n: u64| m.get_block_with_number(U64::from(n));
+
+        let tx_hash = H256::repeat_byte(1);
+        let mined = block(10).await.unwrap();
+        let log = Log {
+            block_hash: Some(mined.hash),
+            block_number: Some(mined.number),
+            transaction_hash: Some(tx_hash),
+            ..Default::default()
+        };
+        m.add_receipt(TransactionReceipt {
+            transaction_hash: tx_hash,
+            block_hash: Some(mined.hash),
+            block_number: Some(mined.number),
+            status: Some(1.into()),
+            logs: vec![log.clone(), log.clone()],
+            ..Default::default()
+        })
+        .await;
+
+        // Within the block being folded, or up to the block being synced.
+        let receipt = env.fold_access(&mined).get_log_receipt(&log).await.unwrap();
+        assert_eq!(receipt.status, Some(1.into()));
+        assert_eq!(receipt.logs.len(), 2);
+
+        let access = env.sync_access(&block(12).await.unwrap());
+        let receipt = access.get_receipt(tx_hash).await.unwrap().unwrap();
+        assert_eq!(receipt.block_hash, Some(mined.hash));
+
+        // Outside of them.
+        let err = env
+            .fold_access(&block(11).await.unwrap())
+            .get_receipt(tx_hash)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AccessError::ReceiptOutOfRange {}));
+
+        let err = env
+            .sync_access(&block(9).await.unwrap())
+            .get_receipt(tx_hash)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AccessError::ReceiptOutOfRange {}));
+
+        // On another branch, which the chain doesn't follow.
+        let orphaned_hash = H256::repeat_byte(3);
+        let latest = m.get_latest_block().await.unwrap();
+        let orphaned = m.add_block(block(9).await.unwrap().hash).await.unwrap();
+        m.add_block(latest.hash).await.unwrap();
+        m.add_receipt(TransactionReceipt {
+            transaction_hash: orphaned_hash,
+            block_hash: Some(orphaned),
+            block_number: Some(mined.number),
+            ..Default::default()
+        })
+        .await;
+        let err = access.get_receipt(orphaned_hash).await.unwrap_err();
+        assert!(matches!(err, AccessError::ReceiptOutOfRange {}));
+
+        // Unknown transactions.
+        let unknown = H256::repeat_byte(2);
+        assert!(access.get_receipt(unknown).await.unwrap().is_none());
+
+        let log = Log {
+            transaction_hash: Some(unknown),
+            ..Default::default()
+        };
+        let err = access.get_log_receipt(&log).await.unwrap_err();
+        assert!(matches!(err, AccessError::ReceiptUnavailable {}));
+
+        let err = access.get_log_receipt(&Log::default()).await.unwrap_err();
+        assert!(matches!(err, AccessError::LogUnavailable {}));
+    }
+
     pub async fn sync_query_test<M: Middleware + 'static>(
         account: Address,
         deployed_address: Address,
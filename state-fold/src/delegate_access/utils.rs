@@ -7,6 +7,9 @@ use eth_state_fold_types::ethers;
 use ethers::core::types::Log;
 use ethers::providers::Middleware;
 
+/// Sorts logs by `(block_number, log_index)`, which is the order logs are
+/// handed to folds, regardless of how partitions were fetched and reassembled.
+/// The sort is stable. Fails if any log lacks its block number or index.
 pub fn sort_logs<M: Middleware>(logs: &mut [Log]) -> Result<(), M> {
     for log in logs.iter() {
         if !(log.block_number.is_some() && log.log_index.is_some()) {
@@ -14,14 +17,7 @@ pub fn sort_logs<M: Middleware>(logs: &mut [Log]) -> Result<(), M> {
         }
     }
 
-    logs.sort_by(|a, b| {
-        let c = a.block_number.unwrap().cmp(&b.block_number.unwrap());
-        if let std::cmp::Ordering::Equal = c {
-            a.log_index.unwrap().cmp(&b.log_index.unwrap())
-        } else {
-            c
-        }
-    });
+    logs.sort_by_key(|log| (log.block_number, log.log_index));
 
     Ok(())
 }
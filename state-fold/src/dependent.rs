@@ -0,0 +1,306 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use crate::error::FoldableError;
+use crate::{FoldMiddleware, Foldable, StateFoldEnvironment, SyncMiddleware};
+
+use eth_state_fold_types::ethers;
+use eth_state_fold_types::{Block, QueryBlock};
+use ethers::providers::Middleware;
+use ethers::types::U64;
+
+use async_trait::async_trait;
+use snafu::Snafu;
+use std::sync::Arc;
+
+/// State derived from the state of another fold, its `Dependency`, at the
+/// same block, such as an aggregate over a lower-level fold. Queried through
+/// `Dependent<Self>`, which queries the state of the dependency at each block
+/// before syncing or folding on it, and passes it in. The dependency's states
+/// are cached as those of any other query, and shared with its other users.
+/// Its queries count against the budget of the enclosing query, and keep its
+/// priority. Dependencies may themselves be `Dependent`, forming a DAG; cycles
+/// fail with `CycleDetected`, as the source of a `DependencyQuery` error.
+#[async_trait]
+pub trait DependentFoldable: Send + Sync + std::fmt::Debug + Sized {
+    type InitialState: Clone + PartialEq + Eq + std::hash::Hash + Send + Sync;
+    type Error: std::error::Error;
+    type UserData: Send + Sync;
+
+    /// Fold depended on, whose errors are kept as the sources of `Dependency`
+    /// and `DependencyQuery` errors.
+    type Dependency: Foldable<UserData = Self::UserData, Error: Send + Sync> + Send + Sync + 'static;
+
+    /// Initial state of the dependency of `initial_state`.
+    fn dependency_initial_state(
+        initial_state: &Self::InitialState,
+    ) -> <Self::Dependency as Foldable>::InitialState;
+
+    /// Same as `Foldable::genesis_block`. Defaults to the genesis of the
+    /// dependency.
+    fn genesis_block(initial_state: &Self::InitialState) -> Option<U64> {
+        Self::Dependency::genesis_block(&Self::dependency_initial_state(initial_state))
+    }
+
+    /// Same as `Foldable::relevant`. Defaults to `true`. The dependency isn't
+    /// queried on the blocks skipped.
+    fn relevant<M: Middleware + 'static>(
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, Self::UserData>,
+    ) -> bool {
+        true
+    }
+
+    /// Same as `Foldable::terminal`. Defaults to `false`.
+    fn terminal(&self) -> bool {
+        false
+    }
+
+    /// Same as `Foldable::sync`, with the state of the dependency at `block`.
+    async fn sync<M: Middleware + 'static>(
+        initial_state: &Self::InitialState,
+        dependency: &Self::Dependency,
+        block: &Block,
+        env: &StateFoldEnvironment<M, Self::UserData>,
+        access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error>;
+
+    /// Same as `Foldable::fold`, with the state of the dependency at `block`.
+    async fn fold<M: Middleware + 'static>(
+        previous_state: &Self,
+        dependency: &Self::Dependency,
+        block: &Block,
+        env: &StateFoldEnvironment<M, Self::UserData>,
+        access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error>;
+}
+
+/// Error of a `Dependent` fold.
+#[derive(Debug, Snafu)]
+pub enum DependencyError<F: DependentFoldable + 'static> {
+    #[snafu(display("{}", source))]
+    Inner { source: F::Error },
+
+    #[snafu(display("Dependency fold error: {}", source))]
+    Dependency {
+        source: <F::Dependency as Foldable>::Error,
+    },
+
+    /// Other errors of the dependency's query, a `FoldableError` of the
+    /// dependency, with its middleware type erased.
+    #[snafu(display("Dependency query error: {}", source))]
+    DependencyQuery {
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+}
+
+/// Adapter folding a `DependentFoldable` on the state of its dependency.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Dependent<F: DependentFoldable> {
+    pub state: F,
+    pub initial_state: F::InitialState,
+}
+
+impl<F: DependentFoldable> std::fmt::Debug for Dependent<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Dependent")
+            .field("state", &self.state)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<F> Dependent<F>
+where
+    F: DependentFoldable + 'static,
+{
+    /// State of the dependency of `initial_state` at `block`.
+    async fn dependency<M: Middleware + 'static>(
+        initial_state: &F::InitialState,
+        block: &Block,
+        env: &StateFoldEnvironment<M, F::UserData>,
+    ) -> Result<Arc<F::Dependency>, DependencyError<F>> {
+        let query = QueryBlock::Block(Arc::new(block.clone()));
+
+        match env
+            .get_nested_state_for_block::<F::Dependency>(
+                &F::dependency_initial_state(initial_state),
+                query,
+            )
+            .await
+        {
+            Ok(block_state) => Ok(block_state.state),
+            Err(FoldableError::InnerError { source }) => {
+                Err(DependencyError::Dependency { source })
+            }
+            Err(e) => Err(DependencyError::DependencyQuery { source: e.into() }),
+        }
+    }
+}
+
+#[async_trait]
+impl<F> Foldable for Dependent<F>
+where
+    F: DependentFoldable + 'static,
+{
+    type InitialState = F::InitialState;
+    type Error = DependencyError<F>;
+    type UserData = F::UserData;
+
+    fn genesis_block(initial_state: &Self::InitialState) -> Option<U64> {
+        F::genesis_block(initial_state)
+    }
+
+    fn relevant<M: Middleware + 'static>(
+        block: &Block,
+        env: &StateFoldEnvironment<M, Self::UserData>,
+    ) -> bool {
+        F::relevant(block, env)
+    }
+
+    fn terminal(&self) -> bool {
+        self.state.terminal()
+    }
+
+    async fn sync<M: Middleware + 'static>(
+        initial_state: &Self::InitialState,
+        block: &Block,
+        env: &StateFoldEnvironment<M, Self::UserData>,
+        access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        let dependency = Self::dependency(initial_state, block, env).await?;
+        let state = F::sync(initial_state, &dependency, block, env, access)
+            .await
+            .map_err(|source| DependencyError::Inner { source })?;
+
+        Ok(Self {
+            state,
+            initial_state: initial_state.clone(),
+        })
+    }
+
+    async fn fold<M: Middleware + 'static>(
+        previous_state: &Self,
+        block: &Block,
+        env: &StateFoldEnvironment<M, Self::UserData>,
+        access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        let initial_state = &previous_state.initial_state;
+        let dependency = Self::dependency(initial_state, block, env).await?;
+        let state = F::fold(&previous_state.state, &dependency, block, env, access)
+            .await
+            .map_err(|source| DependencyError::Inner { source })?;
+
+        Ok(Self {
+            state,
+            initial_state: initial_state.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Dependent;
+    use crate::error::FoldableError;
+    use crate::test_utils::mocks::{CycleFold, CyclicFold, DoubledFold, IncrementFold, OffsetFold};
+    use crate::{ComputeSource, Foldable, StateFoldEnvironment};
+
+    use eth_state_fold_test::mock_middleware::MockMiddleware;
+    use eth_state_fold_types::ethers::types::{H256, U64};
+    use eth_state_fold_types::QueryBlock;
+    use std::sync::Arc;
+
+    const INITIAL_VALUE: u64 = 42;
+
+    #[tokio::test]
+    async fn dependency_test() {
+        let m = MockMiddleware::new(128).await;
+        let env =
+            StateFoldEnvironment::new(Arc::clone(&m), None, 8, 0.into(), vec![], 1, usize::MAX, ());
+
+        // Synced at block 120, folded up to 128, on the states of both levels
+        // of dependencies.
+        let state = env
+            .get_state_for_block::<Dependent<OffsetFold>>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap()
+            .state;
+        assert_eq!(state.state.value, 2 * (128 + INITIAL_VALUE) + 1);
+
+        // Dependencies are cached at every block folded on, and reused.
+        let numbers = |cached: Vec<(U64, H256)>| -> Vec<u64> {
+            cached.into_iter().map(|(n, _)| n.as_u64()).collect()
+        };
+        let cached = env.cached_blocks::<IncrementFold>(&INITIAL_VALUE).await;
+        assert_eq!(numbers(cached), (120..=128).collect::<Vec<_>>());
+        let cached = env
+            .cached_blocks::<Dependent<DoubledFold>>(&INITIAL_VALUE)
+            .await;
+        assert_eq!(numbers(cached), (120..=128).collect::<Vec<_>>());
+
+        for n in [120, 124, 128] {
+            let (block_state, source) = env
+                .get_state_for_block_instrumented::<Dependent<DoubledFold>>(
+                    &INITIAL_VALUE,
+                    QueryBlock::BlockNumber(n.into()),
+                )
+                .await
+                .unwrap();
+            assert_eq!(source, ComputeSource::CacheHit);
+            assert_eq!(block_state.state.state.value, 2 * (n + INITIAL_VALUE));
+        }
+    }
+
+    #[tokio::test]
+    async fn dependency_budget_test() {
+        let m = MockMiddleware::new(128).await;
+        let new_env = || {
+            StateFoldEnvironment::new(Arc::clone(&m), None, 8, 0.into(), vec![], 1, usize::MAX, ())
+        };
+
+        // Smallest budget the dependency can be queried within on its own.
+        let mut budget = 1;
+        while new_env()
+            .get_state_for_block_with_budget::<IncrementFold>(
+                &INITIAL_VALUE,
+                QueryBlock::Latest,
+                Some(budget),
+            )
+            .await
+            .is_err()
+        {
+            budget += 1;
+        }
+
+        // Not enough for the dependent as well, as the requests of its
+        // dependency count against its budget.
+        let err = new_env()
+            .get_state_for_block_with_budget::<Dependent<DoubledFold>>(
+                &INITIAL_VALUE,
+                QueryBlock::Latest,
+                Some(budget),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, FoldableError::RpcBudgetExceeded { .. }));
+    }
+
+    #[tokio::test]
+    async fn dependency_cycle_test() {
+        let m = MockMiddleware::new(128).await;
+        let env =
+            StateFoldEnvironment::new(Arc::clone(&m), None, 8, 0.into(), vec![], 1, usize::MAX, ());
+
+        // Relevance is forwarded.
+        let block = env.block_with_number(128.into()).await.unwrap();
+        assert!(!Dependent::<CyclicFold>::relevant(&block, &env));
+
+        // The cycle is kept as the source of the error of the dependency
+        // query that closes it.
+        let state = env
+            .get_state_for_block::<CycleFold>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap()
+            .state;
+        assert!(state.cycle_detected);
+    }
+}
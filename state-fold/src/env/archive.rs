@@ -3,43 +3,106 @@
 
 use crate::Foldable;
 
+use super::cache_key::CacheKey;
+use super::state_cache::{MemoryStateCache, StateCache};
 use super::train::Train;
 
+use eth_state_fold_types::ethers::types::{H256, U64};
+use eth_state_fold_types::{Block, BlockState};
+
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
+pub(crate) type KeyExtractor<F> =
+    Arc<dyn Fn(&<F as Foldable>::InitialState) -> CacheKey + Send + Sync>;
+
+pub(crate) type CacheFactory<F> =
+    Arc<dyn Fn(&<F as Foldable>::InitialState) -> Box<dyn StateCache<F>> + Send + Sync>;
+
+pub(crate) type ReorgHook<F> =
+    Arc<dyn Fn(&<F as Foldable>::InitialState, &Block, &[BlockState<F>]) + Send + Sync>;
+
+pub(crate) type InvalidationHook<F> =
+    Arc<dyn Fn(&<F as Foldable>::InitialState, &[(U64, H256)]) + Send + Sync>;
+
 pub(crate) struct Archive<F>
 where
     F: Foldable,
 {
-    safety_margin: usize,
-    trains: RwLock<HashMap<F::InitialState, Arc<Train<F>>>>,
+    trains: RwLock<HashMap<CacheKey, Arc<Train<F>>>>,
+    key: KeyExtractor<F>,
+    cache: CacheFactory<F>,
+    reorg_hook: std::sync::RwLock<Option<ReorgHook<F>>>,
+    invalidation_hook: std::sync::RwLock<Option<InvalidationHook<F>>>,
 }
 
 impl<F> Archive<F>
 where
     F: Foldable + 'static,
 {
-    pub fn new(safety_margin: usize) -> Self {
+    pub fn new() -> Self {
         Self {
-            safety_margin,
             trains: RwLock::new(HashMap::new()),
+            key: Arc::new(|initial_state: &F::InitialState| {
+                CacheKey::new::<F, _>(initial_state.clone())
+            }),
+            cache: Arc::new(|_: &F::InitialState| Box::new(MemoryStateCache::new())),
+            reorg_hook: Default::default(),
+            invalidation_hook: Default::default(),
         }
     }
 
+    /// Creates an empty archive like this one, with the same hooks, but whose
+    /// trains are keyed on `key`, and store their states in the caches built
+    /// by `cache`, where given.
+    pub fn rebuild(&self, key: Option<KeyExtractor<F>>, cache: Option<CacheFactory<F>>) -> Self {
+        Self {
+            trains: RwLock::new(HashMap::new()),
+            key: key.unwrap_or_else(|| Arc::clone(&self.key)),
+            cache: cache.unwrap_or_else(|| Arc::clone(&self.cache)),
+            reorg_hook: self.reorg_hook().into(),
+            invalidation_hook: self.invalidation_hook().into(),
+        }
+    }
+
+    /// Calls `hook` on the states orphaned by reorgs.
+    pub fn set_reorg_hook(&self, hook: ReorgHook<F>) {
+        *self.reorg_hook.write().unwrap() = Some(hook);
+    }
+
+    /// Calls `hook` with the blocks of the states dropped from the cache when
+    /// orphaned by reorgs.
+    pub fn set_invalidation_hook(&self, hook: InvalidationHook<F>) {
+        *self.invalidation_hook.write().unwrap() = Some(hook);
+    }
+
+    pub fn reorg_hook(&self) -> Option<ReorgHook<F>> {
+        self.reorg_hook.read().unwrap().clone()
+    }
+
+    pub fn invalidation_hook(&self) -> Option<InvalidationHook<F>> {
+        self.invalidation_hook.read().unwrap().clone()
+    }
+
+    /// Returns the train of `initial_state`, without creating one if missing.
+    pub async fn train(&self, initial_state: &F::InitialState) -> Option<Arc<Train<F>>> {
+        let key = (self.key)(initial_state);
+        self.trains.read().await.get(&key).cloned()
+    }
+
     pub async fn get_train(&self, initial_state: &F::InitialState) -> Arc<Train<F>> {
-        if let Some(train) = self.trains.read().await.get(initial_state) {
+        let key = (self.key)(initial_state);
+
+        if let Some(train) = self.trains.read().await.get(&key) {
             return Arc::clone(train);
         }
 
-        let train = Arc::new(Train::new(initial_state.clone(), self.safety_margin));
-
-        self.trains
-            .write()
-            .await
-            .insert(initial_state.clone(), Arc::clone(&train));
+        let states = (self.cache)(initial_state);
+        let train = Arc::new(Train::with_cache(initial_state.clone(), states).await);
 
-        train
+        // Another call may have created the train in the meantime.
+        let mut trains = self.trains.write().await;
+        Arc::clone(trains.entry(key).or_insert(train))
     }
 }
@@ -0,0 +1,46 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use async_trait::async_trait;
+
+use eth_state_fold_types::ethers;
+use ethers::core::types::{BlockId, BlockNumber};
+use ethers::providers::Middleware;
+
+/// Resolver of `QueryBlock::Custom` targets into concrete blocks. It lets
+/// folds target chain-specific confirmation concepts, such as a layer-2's
+/// latest verified batch, while the environment stays chain-agnostic.
+#[async_trait]
+pub trait BlockResolver<M: Middleware>: Send + Sync {
+    /// Block of `target`, or `None` if this resolver doesn't know `target`,
+    /// in which case the query fails with `UnknownQueryTarget`.
+    async fn resolve(
+        &self,
+        middleware: &M,
+        target: &str,
+    ) -> std::result::Result<Option<BlockId>, M::Error>;
+}
+
+/// Built-in resolver for standard Ethereum, mapping the `latest`, `safe`,
+/// `finalized` and `earliest` targets onto their block tags.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StandardResolver;
+
+#[async_trait]
+impl<M: Middleware> BlockResolver<M> for StandardResolver {
+    async fn resolve(
+        &self,
+        _middleware: &M,
+        target: &str,
+    ) -> std::result::Result<Option<BlockId>, M::Error> {
+        let tag = match target {
+            "latest" => BlockNumber::Latest,
+            "safe" => BlockNumber::Safe,
+            "finalized" => BlockNumber::Finalized,
+            "earliest" => BlockNumber::Earliest,
+            _ => return Ok(None),
+        };
+
+        Ok(Some(tag.into()))
+    }
+}
@@ -0,0 +1,145 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use eth_state_fold_types::ethereum_types::U256;
+use eth_state_fold_types::Block;
+use eth_state_fold_types::BlockState;
+
+use serde::{de::DeserializeOwned, Serialize};
+use snafu::{ensure, Snafu};
+
+/// Prefix of every export, followed by the version and format tag bytes.
+/// Exports of version 1 don't carry the chain id, and are still read.
+const MAGIC: &[u8] = b"SFCACHE";
+const VERSION: u8 = 2;
+const UNTAGGED_VERSION: u8 = 1;
+
+/// Serialization format of `StateFoldEnvironment::export_cache`. Each format
+/// is behind its own feature. Exports are tagged with their format, so
+/// importing with another one fails instead of yielding garbage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CacheFormat {
+    /// Human-readable, for debugging. Requires the `json` feature.
+    #[cfg(feature = "json")]
+    Json,
+
+    /// Compact, for storage. Requires the `bincode` feature.
+    #[cfg(feature = "bincode")]
+    Bincode,
+}
+
+impl CacheFormat {
+    fn tag(&self) -> u8 {
+        match self {
+            #[cfg(feature = "json")]
+            Self::Json => 1,
+
+            #[cfg(feature = "bincode")]
+            Self::Bincode => 2,
+        }
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum CacheExportError {
+    #[snafu(display("Data is not a cache export of a supported version"))]
+    NotACacheExport {},
+
+    #[snafu(display("Cache export tagged `{}` cannot be read as `{:?}`", found, expected))]
+    FormatMismatch { expected: CacheFormat, found: u8 },
+
+    #[snafu(display("Cache export of chain `{}` imported on chain `{}`", found, expected))]
+    ChainMismatch { expected: U256, found: U256 },
+
+    #[snafu(display("Chain id unavailable: {}", source))]
+    ChainIdUnavailable {
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[cfg(feature = "json")]
+    #[snafu(display("JSON error: {}", source))]
+    JsonError { source: serde_json::Error },
+
+    #[cfg(feature = "bincode")]
+    #[snafu(display("Bincode error: {}", source))]
+    BincodeError { source: bincode::Error },
+}
+
+/// Encodes `states`, computed on the chain `chain_id`.
+pub(crate) fn encode<F: Serialize>(
+    states: &[BlockState<F>],
+    chain_id: U256,
+    format: CacheFormat,
+) -> Result<Vec<u8>, CacheExportError> {
+    let entries: Vec<(&Block, &F)> = states
+        .iter()
+        .map(|s| (s.block.as_ref(), s.state.as_ref()))
+        .collect();
+    let entries = (chain_id, entries);
+
+    let mut data = MAGIC.to_vec();
+    data.extend([VERSION, format.tag()]);
+
+    match format {
+        #[cfg(feature = "json")]
+        CacheFormat::Json => serde_json::to_writer(&mut data, &entries)
+            .map_err(|source| CacheExportError::JsonError { source })?,
+
+        #[cfg(feature = "bincode")]
+        CacheFormat::Bincode => bincode::serialize_into(&mut data, &entries)
+            .map_err(|source| CacheExportError::BincodeError { source })?,
+    }
+
+    Ok(data)
+}
+
+/// Decodes the states of an export, along with the id of their chain, unless
+/// of version 1.
+#[allow(clippy::type_complexity)]
+pub(crate) fn decode<F: DeserializeOwned>(
+    data: &[u8],
+    format: CacheFormat,
+) -> Result<(Option<U256>, Vec<(Block, F)>), CacheExportError> {
+    let header = MAGIC.len() + 2;
+    ensure!(
+        data.len() >= header
+            && data.starts_with(MAGIC)
+            && [UNTAGGED_VERSION, VERSION].contains(&data[MAGIC.len()]),
+        NotACacheExportSnafu {}
+    );
+
+    let found = data[MAGIC.len() + 1];
+    ensure!(
+        found == format.tag(),
+        FormatMismatchSnafu {
+            expected: format,
+            found
+        }
+    );
+
+    let payload = &data[header..];
+    if data[MAGIC.len()] == UNTAGGED_VERSION {
+        let entries = deserialize(payload, format)?;
+        return Ok((None, entries));
+    }
+
+    let (chain_id, entries) = deserialize::<(U256, _)>(payload, format)?;
+    Ok((Some(chain_id), entries))
+}
+
+fn deserialize<T: DeserializeOwned>(
+    payload: &[u8],
+    format: CacheFormat,
+) -> Result<T, CacheExportError> {
+    Ok(match format {
+        #[cfg(feature = "json")]
+        CacheFormat::Json => serde_json::from_slice(payload)
+            .map_err(|source| CacheExportError::JsonError { source })?,
+
+        #[cfg(feature = "bincode")]
+        CacheFormat::Bincode => bincode::deserialize(payload)
+            .map_err(|source| CacheExportError::BincodeError { source })?,
+    })
+}
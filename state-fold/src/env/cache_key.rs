@@ -0,0 +1,73 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use std::any::{Any, TypeId};
+use std::hash::{Hash, Hasher};
+
+/// Type-erased key of a train inside an archive. It allows the archive to be
+/// keyed on any type extracted from the `InitialState`. Keys are namespaced by
+/// the `Foldable` type, so states of different fold types never collide, even
+/// with equal initial states.
+pub(crate) struct CacheKey {
+    fold: TypeId,
+    key: Box<dyn DynKey>,
+}
+
+impl CacheKey {
+    pub fn new<F: 'static, K: Hash + Eq + Send + Sync + 'static>(key: K) -> Self {
+        Self {
+            fold: TypeId::of::<F>(),
+            key: Box::new(key),
+        }
+    }
+}
+
+impl PartialEq for CacheKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.fold == other.fold && self.key.eq_key(other.key.as_ref())
+    }
+}
+
+impl Eq for CacheKey {}
+
+impl Hash for CacheKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.fold.hash(state);
+        self.key.hash_key(state)
+    }
+}
+
+trait DynKey: Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+    fn eq_key(&self, other: &dyn DynKey) -> bool;
+    fn hash_key(&self, state: &mut dyn Hasher);
+}
+
+impl<K: Hash + Eq + Send + Sync + 'static> DynKey for K {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn eq_key(&self, other: &dyn DynKey) -> bool {
+        other.as_any().downcast_ref::<K>() == Some(self)
+    }
+
+    fn hash_key(&self, mut state: &mut dyn Hasher) {
+        self.hash(&mut state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CacheKey;
+    use crate::test_utils::mocks::{IncrementFold, ScaledFold};
+
+    #[test]
+    fn namespace_test() {
+        let key = CacheKey::new::<IncrementFold, u64>(42);
+
+        assert!(key == CacheKey::new::<IncrementFold, u64>(42));
+        assert!(key != CacheKey::new::<IncrementFold, u64>(43));
+        assert!(key != CacheKey::new::<ScaledFold, u64>(42));
+    }
+}
@@ -0,0 +1,203 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use crate::delegate_access::{BatchRequest, BatchTransport};
+
+use eth_state_fold_types::ethers;
+use ethers::core::types::{Address, BlockId, BlockNumber, Trace, H256, U256};
+use ethers::providers::Middleware;
+
+/// Capabilities of the node behind the environment's provider, probed by
+/// `StateFoldEnvironment::capabilities`. A capability whose probe fails for
+/// any reason is reported as unsupported.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProviderCapabilities {
+    /// Whether the node resolves the `finalized` block tag.
+    pub finalized_tag: bool,
+
+    /// Whether the node resolves the `safe` block tag.
+    pub safe_tag: bool,
+
+    /// Whether the node accepts blocks referenced by hash (EIP-1898), which
+    /// the access layer uses to pin requests to a block.
+    pub eip1898: bool,
+
+    /// Whether the environment's `batch_transport` is set and accepted by the
+    /// node.
+    pub batch_requests: bool,
+
+    /// Whether the node supports `eth_subscribe`.
+    pub subscriptions: bool,
+
+    /// Whether the node supports the `trace` namespace, used by
+    /// `FoldMiddleware::get_block_traces`. Probed by tracing a transaction
+    /// that doesn't exist, which is cheap, unlike tracing a block.
+    pub tracing: bool,
+}
+
+impl ProviderCapabilities {
+    pub(crate) async fn probe<M: Middleware>(
+        middleware: &M,
+        batch_transport: Option<&dyn BatchTransport<M>>,
+    ) -> Self {
+        let finalized_tag = matches!(
+            middleware.get_block(BlockNumber::Finalized).await,
+            Ok(Some(_))
+        );
+
+        let safe_tag = matches!(middleware.get_block(BlockNumber::Safe).await, Ok(Some(_)));
+
+        let latest = match middleware.get_block(BlockNumber::Latest).await {
+            Ok(Some(block)) => block.hash.map(BlockId::Hash),
+            _ => None,
+        };
+
+        let eip1898 = match latest {
+            Some(block) => middleware
+                .get_storage_at(Address::zero(), H256::zero(), Some(block))
+                .await
+                .is_ok(),
+            None => false,
+        };
+
+        let batch_requests = match (batch_transport, latest) {
+            (Some(transport), Some(block)) => {
+                let requests = [BatchRequest::GetStorageAt {
+                    address: Address::zero(),
+                    slot: H256::zero(),
+                }];
+
+                matches!(
+                    transport.send_batch(middleware, block, &requests).await,
+                    Ok(responses) if responses.len() == requests.len()
+                )
+            }
+
+            _ => false,
+        };
+
+        let tracing = Self::probe_tracing(middleware).await;
+
+        let subscriptions = Self::probe_subscriptions(middleware).await;
+
+        Self {
+            finalized_tag,
+            safe_tag,
+            eip1898,
+            batch_requests,
+            subscriptions,
+            tracing,
+        }
+    }
+
+    /// Nodes answer the traces of an unknown transaction with `null` or an
+    /// empty list, and fail only if they don't support tracing.
+    async fn probe_tracing<M: Middleware>(middleware: &M) -> bool {
+        middleware
+            .provider()
+            .request::<_, Option<Vec<Trace>>>("trace_transaction", [H256::zero()])
+            .await
+            .is_ok()
+    }
+
+    async fn probe_subscriptions<M: Middleware>(middleware: &M) -> bool {
+        let provider = middleware.provider();
+
+        match provider
+            .request::<_, U256>("eth_subscribe", ["newHeads"])
+            .await
+        {
+            Ok(id) => {
+                let _ = provider.request::<_, bool>("eth_unsubscribe", [id]).await;
+                true
+            }
+
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProviderCapabilities;
+    use crate::delegate_access::{BatchRequest, BatchResponse, BatchTransport};
+    use crate::StateFoldEnvironment;
+    use std::sync::Arc;
+
+    use eth_state_fold_test::mock_middleware::{MockError, MockMiddleware};
+    use eth_state_fold_types::ethers;
+    use ethers::core::types::{BlockId, Trace, H256, U256};
+    use ethers::providers::Middleware;
+
+    use async_trait::async_trait;
+
+    /// Transport answering zeroed storage, or failing if not `supported`.
+    #[derive(Debug)]
+    struct ProbedTransport {
+        supported: bool,
+    }
+
+    #[async_trait]
+    impl BatchTransport<MockMiddleware> for ProbedTransport {
+        async fn send_batch(
+            &self,
+            _middleware: &MockMiddleware,
+            _block: BlockId,
+            requests: &[BatchRequest],
+        ) -> Result<Vec<BatchResponse>, MockError> {
+            if !self.supported {
+                return Err(MockError);
+            }
+
+            Ok(requests
+                .iter()
+                .map(|_| BatchResponse::Storage(H256::zero()))
+                .collect())
+        }
+    }
+
+    fn new_env(m: &Arc<MockMiddleware>) -> StateFoldEnvironment<MockMiddleware, ()> {
+        StateFoldEnvironment::new(Arc::clone(m), None, 8, 0.into(), vec![], 1, usize::MAX, ())
+    }
+
+    #[tokio::test]
+    async fn capabilities_test() {
+        let m = MockMiddleware::new(128).await;
+        m.set_safe_block(120.into()).await;
+
+        let mut env = new_env(&m);
+        env.batch_transport = Some(Arc::new(ProbedTransport { supported: false }));
+        assert_eq!(
+            env.capabilities().await,
+            ProviderCapabilities {
+                finalized_tag: false,
+                safe_tag: true,
+                eip1898: true,
+                batch_requests: false,
+                subscriptions: false,
+                tracing: false,
+            }
+        );
+
+        // Capabilities are probed only once.
+        m.set_finalized_block(110.into()).await;
+        assert!(!env.capabilities().await.finalized_tag);
+
+        // Answers to the probes, the last pushed answered first.
+        m.provider().as_ref().push(U256::one()).unwrap();
+        m.provider().as_ref().push::<Vec<Trace>, _>(vec![]).unwrap();
+        let mut env = new_env(&m);
+        env.batch_transport = Some(Arc::new(ProbedTransport { supported: true }));
+        assert_eq!(
+            env.capabilities().await,
+            ProviderCapabilities {
+                finalized_tag: true,
+                safe_tag: true,
+                eip1898: true,
+                batch_requests: true,
+                subscriptions: true,
+                tracing: true,
+            }
+        );
+    }
+}
@@ -0,0 +1,237 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use crate::Clock;
+
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Configuration of the circuit breaker of each initial state. After
+/// `failure_threshold` consecutive failed folds, the circuit opens, and queries
+/// needing to fold fail with `CircuitOpen` until `cooldown` elapses. Then a
+/// single query is let through; the circuit closes if it succeeds, and opens
+/// again otherwise.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CircuitBreakerConfig {
+    pub failure_threshold: usize,
+    pub cooldown: Duration,
+}
+
+/// State of the circuit breaker of an initial state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Folds are attempted normally.
+    Closed,
+
+    /// Folds fail immediately, for `retry_in` more.
+    Open { retry_in: Duration },
+
+    /// The cooldown elapsed, and the next fold is attempted as a trial, the
+    /// only one let through until it completes.
+    HalfOpen,
+}
+
+#[derive(Debug, Default)]
+struct Breaker {
+    consecutive_failures: usize,
+    opened_at: Option<Instant>,
+
+    // Whether the trial query of the half-open circuit is running.
+    trial: bool,
+}
+
+impl Breaker {
+    fn state(&self, config: &CircuitBreakerConfig, clock: &dyn Clock) -> CircuitState {
+        match self.opened_at {
+            None => CircuitState::Closed,
+
+            Some(opened_at) => match config
+                .cooldown
+                .checked_sub(clock.now().saturating_duration_since(opened_at))
+            {
+                Some(retry_in) if !retry_in.is_zero() => CircuitState::Open { retry_in },
+                _ => CircuitState::HalfOpen,
+            },
+        }
+    }
+}
+
+/// Circuit breaker of a train.
+#[derive(Debug, Default)]
+pub(crate) struct CircuitBreaker(Mutex<Breaker>);
+
+/// Query let through by a `CircuitBreaker`, ending its trial, if it is one,
+/// when dropped, whatever its outcome.
+pub(crate) struct Admission<'a> {
+    breaker: &'a CircuitBreaker,
+    trial: bool,
+}
+
+impl Drop for Admission<'_> {
+    fn drop(&mut self) {
+        if self.trial {
+            self.breaker.0.lock().unwrap().trial = false;
+        }
+    }
+}
+
+impl CircuitBreaker {
+    pub fn state(&self, config: &CircuitBreakerConfig, clock: &dyn Clock) -> CircuitState {
+        self.0.lock().unwrap().state(config, clock)
+    }
+
+    /// Lets a query through, unless the circuit is open, failing with the
+    /// time left until it half-opens. A half-open circuit lets a single trial
+    /// through; other queries fail with no time left while it runs.
+    pub fn admit(
+        &self,
+        config: &CircuitBreakerConfig,
+        clock: &dyn Clock,
+    ) -> Result<Admission<'_>, Duration> {
+        let mut breaker = self.0.lock().unwrap();
+
+        let trial = match breaker.state(config, clock) {
+            CircuitState::Closed => false,
+            CircuitState::Open { retry_in } => return Err(retry_in),
+            CircuitState::HalfOpen if breaker.trial => return Err(Duration::ZERO),
+            CircuitState::HalfOpen => {
+                breaker.trial = true;
+                true
+            }
+        };
+
+        Ok(Admission {
+            breaker: self,
+            trial,
+        })
+    }
+
+    pub fn record_success(&self) {
+        *self.0.lock().unwrap() = Breaker::default();
+    }
+
+    pub fn record_failure(&self, config: &CircuitBreakerConfig, clock: &dyn Clock) {
+        let mut breaker = self.0.lock().unwrap();
+        breaker.consecutive_failures += 1;
+
+        // A failed trial reopens the circuit right away.
+        if breaker.opened_at.is_some() || breaker.consecutive_failures >= config.failure_threshold {
+            breaker.opened_at = Some(clock.now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CircuitBreaker, CircuitBreakerConfig, CircuitState};
+    use crate::error::FoldableError;
+    use crate::test_utils::mocks::FlakyFold;
+    use crate::{ManualClock, StateFoldEnvironment};
+
+    use eth_state_fold_test::mock_middleware::MockMiddleware;
+    use eth_state_fold_types::QueryBlock;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const CONFIG: CircuitBreakerConfig = CircuitBreakerConfig {
+        failure_threshold: 3,
+        cooldown: Duration::from_millis(50),
+    };
+
+    #[tokio::test]
+    async fn circuit_breaker_test() {
+        let m = MockMiddleware::new(128).await;
+        let failing = Arc::new(AtomicBool::new(true));
+        let mut env = StateFoldEnvironment::new(
+            Arc::clone(&m),
+            None,
+            8,
+            0.into(),
+            vec![],
+            1,
+            usize::MAX,
+            Arc::clone(&failing),
+        );
+        env.circuit_breaker = Some(CONFIG);
+        let clock = Arc::new(ManualClock::new());
+        env.clock = Arc::clone(&clock) as _;
+
+        let query = || env.get_state_for_block::<FlakyFold>(&(), QueryBlock::Latest);
+
+        for _ in 0..3 {
+            assert_eq!(
+                env.circuit_state::<FlakyFold>(&()).await,
+                CircuitState::Closed
+            );
+            assert!(matches!(
+                query().await,
+                Err(FoldableError::InnerError { .. })
+            ));
+        }
+
+        assert!(matches!(
+            env.circuit_state::<FlakyFold>(&()).await,
+            CircuitState::Open { .. }
+        ));
+        assert!(matches!(
+            query().await,
+            Err(FoldableError::CircuitOpen { .. })
+        ));
+
+        // A failed trial reopens the circuit.
+        clock.advance(CONFIG.cooldown);
+        assert_eq!(
+            env.circuit_state::<FlakyFold>(&()).await,
+            CircuitState::HalfOpen
+        );
+        assert!(matches!(
+            query().await,
+            Err(FoldableError::InnerError { .. })
+        ));
+        assert!(matches!(
+            query().await,
+            Err(FoldableError::CircuitOpen { .. })
+        ));
+
+        // A successful trial closes it.
+        failing.store(false, Ordering::SeqCst);
+        clock.advance(CONFIG.cooldown);
+        assert_eq!(
+            env.circuit_state::<FlakyFold>(&()).await,
+            CircuitState::HalfOpen
+        );
+        assert!(query().await.is_ok());
+        assert_eq!(
+            env.circuit_state::<FlakyFold>(&()).await,
+            CircuitState::Closed
+        );
+    }
+
+    #[tokio::test]
+    async fn single_trial_test() {
+        let breaker = CircuitBreaker::default();
+        let clock = ManualClock::new();
+        for _ in 0..3 {
+            breaker.record_failure(&CONFIG, &clock);
+        }
+        clock.advance(CONFIG.cooldown);
+
+        // Only one query is let through while half-open.
+        let trial = breaker.admit(&CONFIG, &clock).unwrap();
+        assert_eq!(breaker.admit(&CONFIG, &clock).err(), Some(Duration::ZERO));
+        assert_eq!(breaker.state(&CONFIG, &clock), CircuitState::HalfOpen);
+
+        // Another once it ends without an outcome, e.g. cancelled.
+        drop(trial);
+        let trial = breaker.admit(&CONFIG, &clock).unwrap();
+
+        // Every query once closed.
+        breaker.record_success();
+        drop(trial);
+        let _first = breaker.admit(&CONFIG, &clock).unwrap();
+        let _second = breaker.admit(&CONFIG, &clock).unwrap();
+    }
+}
@@ -0,0 +1,19 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use eth_state_fold_types::ethers::types::U64;
+
+/// How a state returned by `get_state_for_block_instrumented` was obtained.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ComputeSource {
+    /// The state was already cached.
+    CacheHit,
+
+    /// The state was folded from the cached state of the ancestor block
+    /// `from_block`.
+    IncrementalFold { from_block: U64 },
+
+    /// No usable ancestor was cached, so the state was synced from scratch,
+    /// querying from the `from_genesis` block, and then folded.
+    ColdSync { from_genesis: U64 },
+}
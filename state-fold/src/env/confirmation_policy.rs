@@ -0,0 +1,124 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+/// Policy deciding which blocks are considered safe from reorgs. It is
+/// evaluated on every query that needs to `sync`, and the resulting safe block
+/// is where the train is anchored.
+///
+/// States are cached by block hash, so caching a state of a block that is later
+/// reorged out is harmless; the anchor, however, is assumed immutable. If a
+/// reorg goes deeper than the anchor, the train has to `sync` again. As such, a
+/// stricter policy means fewer re-syncs at the cost of more `fold` calls from
+/// the anchor to the queried block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfirmationPolicy {
+    /// Blocks at least `n` blocks deep from the latest block.
+    Depth(usize),
+
+    /// Blocks at or before the node's `finalized` block.
+    Finalized,
+
+    /// Blocks at least `n` blocks deep, and at or before the `finalized` block.
+    /// Useful on chains where the finalized tag occasionally jumps ahead.
+    Both(usize),
+
+    /// Blocks at least `n` blocks deep, or at or before the `finalized` block.
+    /// Useful on chains where the finalized tag occasionally lags behind.
+    Either(usize),
+}
+
+impl ConfirmationPolicy {
+    /// Depth required by this policy, if any.
+    pub fn depth(&self) -> Option<usize> {
+        match self {
+            Self::Depth(n) | Self::Both(n) | Self::Either(n) => Some(*n),
+            Self::Finalized => None,
+        }
+    }
+
+    /// Whether this policy depends on the node's `finalized` block.
+    pub fn uses_finalized(&self) -> bool {
+        !matches!(self, Self::Depth(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConfirmationPolicy;
+    use crate::error::FoldableError;
+    use crate::test_utils::mocks::IncrementFold;
+    use crate::StateFoldEnvironment;
+    use std::sync::Arc;
+
+    use eth_state_fold_test::mock_middleware::MockMiddleware;
+    use eth_state_fold_types::ethers::types::U64;
+
+    async fn safe_block_number(
+        m: &Arc<MockMiddleware>,
+        policy: ConfirmationPolicy,
+    ) -> Result<U64, FoldableError<MockMiddleware, IncrementFold>> {
+        let mut env =
+            StateFoldEnvironment::new(Arc::clone(m), None, 8, 0.into(), vec![], 1, usize::MAX, ());
+        env.confirmation_policy = Some(policy);
+
+        env.safe_block_number::<IncrementFold>(&0).await
+    }
+
+    #[tokio::test]
+    async fn policy_test() {
+        let m = MockMiddleware::new(128).await;
+
+        // Finalized pointer lagging behind the depth.
+        m.set_finalized_block(100.into()).await;
+
+        let safe = safe_block_number(&m, ConfirmationPolicy::Depth(8)).await;
+        assert_eq!(safe.unwrap(), 120.into());
+        let safe = safe_block_number(&m, ConfirmationPolicy::Finalized).await;
+        assert_eq!(safe.unwrap(), 100.into());
+        let safe = safe_block_number(&m, ConfirmationPolicy::Both(8)).await;
+        assert_eq!(safe.unwrap(), 100.into());
+        let safe = safe_block_number(&m, ConfirmationPolicy::Either(8)).await;
+        assert_eq!(safe.unwrap(), 120.into());
+
+        // Finalized pointer jumping ahead of the depth.
+        m.set_finalized_block(125.into()).await;
+
+        let safe = safe_block_number(&m, ConfirmationPolicy::Depth(8)).await;
+        assert_eq!(safe.unwrap(), 120.into());
+        let safe = safe_block_number(&m, ConfirmationPolicy::Finalized).await;
+        assert_eq!(safe.unwrap(), 125.into());
+        let safe = safe_block_number(&m, ConfirmationPolicy::Both(8)).await;
+        assert_eq!(safe.unwrap(), 120.into());
+        let safe = safe_block_number(&m, ConfirmationPolicy::Either(8)).await;
+        assert_eq!(safe.unwrap(), 125.into());
+
+        // Depth larger than the chain.
+        let safe = safe_block_number(&m, ConfirmationPolicy::Depth(256)).await;
+        assert!(matches!(
+            safe,
+            Err(FoldableError::SafetyMarginTooLarge { .. })
+        ));
+        let safe = safe_block_number(&m, ConfirmationPolicy::Both(256)).await;
+        assert!(matches!(
+            safe,
+            Err(FoldableError::SafetyMarginTooLarge { .. })
+        ));
+        let safe = safe_block_number(&m, ConfirmationPolicy::Either(256)).await;
+        assert_eq!(safe.unwrap(), 125.into());
+    }
+
+    #[tokio::test]
+    async fn default_policy_test() {
+        let m = MockMiddleware::new(128).await;
+        let mut env =
+            StateFoldEnvironment::new(Arc::clone(&m), None, 8, 0.into(), vec![], 1, usize::MAX, ());
+
+        // Follows the safety margin, even when changed after construction.
+        assert_eq!(env.confirmation_policy(), ConfirmationPolicy::Depth(8));
+        env.safety_margin = 16;
+        assert_eq!(env.confirmation_policy(), ConfirmationPolicy::Depth(16));
+
+        let safe = env.safe_block_number::<IncrementFold>(&0).await;
+        assert_eq!(safe.unwrap(), 112.into());
+    }
+}
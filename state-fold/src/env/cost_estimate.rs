@@ -0,0 +1,27 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use super::ComputeSource;
+
+/// Approximate cost of a query, computed by `StateFoldEnvironment::estimate_cost`
+/// from the cached states, without running it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CostEstimate {
+    /// How the state would be obtained. Cached states are assumed to be on the
+    /// branch of the target.
+    pub source: ComputeSource,
+
+    /// Blocks to fold, from the cached or synced state to the target.
+    pub fold_blocks: u64,
+
+    /// Blocks covered by `sync`, from genesis to the sync block, or `0` if the
+    /// query doesn't sync.
+    pub sync_blocks: u64,
+
+    /// Rough number of RPC calls of the query, assuming each `fold` makes a
+    /// single call, and the events queried by `sync` are partitioned into at
+    /// most `concurrent_events_fetch + 1` ranges. Not a bound: queries send
+    /// more when the cached ancestor turns out to be on another branch, or
+    /// when partitions are split further on failure.
+    pub calls: u64,
+}
@@ -1,24 +1,75 @@
 // (c) Cartesi and individual authors (see AUTHORS)
 // SPDX-License-Identifier: Apache-2.0 (see LICENSE)
 
-use crate::delegate_access::{FoldMiddleware, SyncMiddleware};
+use crate::delegate_access::budget::{self, RpcBudget};
+use crate::delegate_access::request_gate::{self, GatePermit};
+use crate::delegate_access::{
+    BatchTransport, FoldMiddleware, LogCoalescer, LogPagination, Priority, RequestGate,
+    RetryPolicy, SyncMiddleware,
+};
 use crate::error::*;
-use crate::Foldable;
+#[cfg(feature = "profiling")]
+use crate::profiling::{BlockTiming, Profiler};
+use crate::{Clock, Foldable, TokioClock};
 
+use super::archive::Archive;
+#[cfg(any(feature = "json", feature = "bincode"))]
+use super::cache_export::{self, CacheExportError, CacheFormat};
+use super::cache_key::CacheKey;
 use super::global_archive::GlobalArchive;
+use super::state_cache::StateCache;
+use super::train::Train;
+use super::{
+    BlockResolver, CircuitBreakerConfig, CircuitState, ComputeSource, ConfirmationPolicy,
+    CostEstimate, FoldStep, FoldTrace, PendingBlockState, ProviderCapabilities, SampleSpec,
+    StandardResolver, Tracker, UserDataRef, Validity,
+};
 
 use eth_block_history::{
-    current_block_number, fetch_block, fetch_block_at_depth, BlockArchive, BlockArchiveError,
+    common_ancestor, current_block_number, fetch_block_at_depth_with_retry, fetch_block_with_retry,
+    BlockArchive, BlockArchiveError, IncompleteBlockRetry,
 };
-use eth_state_fold_types::{BlockState, QueryBlock};
+use eth_state_fold_types::{BlockState, BlockStreamItem, QueryBlock};
 
 use eth_state_fold_types::ethers;
 use eth_state_fold_types::Block;
-use ethers::core::types::{BlockId, BlockNumber, H256, U64};
+use ethers::core::types::{BlockId, BlockNumber, Bloom, H256, U256, U64};
 use ethers::providers::Middleware;
 
-use snafu::ResultExt;
+use futures::stream::FuturesOrdered;
+use futures::{Stream, StreamExt};
+use snafu::{ensure, ResultExt};
+use std::any::{Any, TypeId};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use tokio::sync::mpsc;
+
+const DEFAULT_FOLD_YIELD_INTERVAL: usize = 64;
+const DEFAULT_SYNC_PARTITIONS: usize = 4;
+const DEFAULT_MAX_QUERY_DEPTH: usize = 16;
+
+// Environment address, fold type, and initial state of a query.
+#[derive(Clone)]
+struct QueryKey {
+    env: usize,
+    fold: TypeId,
+    initial_state: Arc<dyn Any + Send + Sync>,
+}
+
+type UserDataSnapshotter<UD> = Arc<dyn Fn(&UD) -> Arc<dyn Any + Send + Sync> + Send + Sync>;
+
+// Recovers the user data from the snapshot taken by its snapshotter.
+type UserDataDowncaster<UD> = fn(Arc<dyn Any + Send + Sync>) -> Option<Arc<UD>>;
+
+tokio::task_local! {
+    // Snapshot of the user data of the environment at this address, taken
+    // when the outermost query of the current task started.
+    static USER_DATA_SNAPSHOT: (usize, Arc<dyn Any + Send + Sync>);
+
+    // Queries running in the current task, outermost first.
+    static QUERY_STACK: Vec<QueryKey>;
+}
 
 pub struct StateFoldEnvironment<M: Middleware, UD> {
     inner_middleware: Arc<M>,
@@ -27,6 +78,96 @@ pub struct StateFoldEnvironment<M: Middleware, UD> {
     genesis_block: U64,
     pub safety_margin: usize,
 
+    /// Policy deciding which blocks are safe to `sync` on. Defaults to
+    /// `None`, meaning `ConfirmationPolicy::Depth(safety_margin)` at the time
+    /// of each query, as returned by `confirmation_policy()`.
+    pub confirmation_policy: Option<ConfirmationPolicy>,
+
+    /// Whether, on chains shorter than the depth of the confirmation policy
+    /// (e.g. a fresh devnet), the depth is clamped to the chain height, with
+    /// a warning the first time, making the genesis block of the queried fold
+    /// safe, instead of failing with `SafetyMarginTooLarge`. Defaults to
+    /// `false`.
+    pub clamp_safety_margin: bool,
+
+    /// Number of blocks below the latest computed state of each initial state
+    /// whose states are kept. Deeper states are dropped from the cache with
+    /// `StateCache::invalidate_below`, and queries of them sync again.
+    /// Defaults to `None`, keeping every state.
+    pub state_retention: Option<usize>,
+
+    /// Number of blocks folded before yielding to the runtime, so long syncs
+    /// don't starve other tasks. Defaults to `64`.
+    pub fold_yield_interval: usize,
+
+    /// Number of ranges the syncs of `Merged` folds are split into and synced
+    /// concurrently. `1` syncs the whole range at once.
+    /// Defaults to `4`.
+    pub sync_partitions: usize,
+
+    /// Maximum number of queries nested in folds, i.e. of `get_state_for_block`
+    /// calls made by a `Foldable::fold` or `sync`, counting the outermost.
+    /// Deeper queries fail with `DepthExceeded`, and queries of a fold state
+    /// being computed in an enclosing query with `CycleDetected`, instead of
+    /// deadlocking. Queries are only tracked within a task: those of tasks a
+    /// fold spawns start over from an empty stack, so their cycles still
+    /// deadlock. Defaults to `16`.
+    pub max_query_depth: usize,
+
+    /// Policy for retrying requests of the access layer on transient errors.
+    /// Its `classifier` can be overridden to match the errors of a specific
+    /// node.
+    pub retry_policy: RetryPolicy<M>,
+
+    /// Transport sending batches of the access layer as single JSON-RPC batch
+    /// requests. If `None`, the default, batches are sent sequentially. The
+    /// same transport can batch the block fetches of the `block_archive`, see
+    /// `BlockArchive::set_block_batch_transport`.
+    pub batch_transport: Option<Arc<dyn BatchTransport<M>>>,
+
+    /// Transport fetching logs in pages, for providers supporting pagination
+    /// tokens. If `None`, the default, large log queries are split by block
+    /// range.
+    pub log_pagination: Option<Arc<dyn LogPagination<M>>>,
+
+    /// Coalescer merging the concurrent `get_logs` requests of folds of the
+    /// same block into single requests. If `None`, the default, each is sent
+    /// on its own.
+    pub log_coalescer: Option<LogCoalescer>,
+
+    /// Circuit breaker of each initial state, pausing folds that keep
+    /// failing. If `None`, the default, folds are always attempted.
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+
+    /// Clock of the retry backoffs, circuit breaker cooldowns, log coalescing
+    /// windows and incomplete block retries. Defaults to `TokioClock`.
+    pub clock: Arc<dyn Clock>,
+
+    /// Maximum number of requests the access layers may send while answering
+    /// a single query, retries and the blocks the environment fetches for it
+    /// included, after which it fails with `RpcBudgetExceeded`. If
+    /// `None`, the default, queries are unbounded. Can be overridden per query
+    /// with `get_state_for_block_with_budget`.
+    pub rpc_budget: Option<usize>,
+
+    /// Limit on the requests the access layers send concurrently, letting
+    /// waiting requests through by the priority of their query. Can be shared
+    /// with other environments. If `None`, the default, requests are sent
+    /// right away.
+    pub request_gate: Option<RequestGate>,
+
+    /// Retrying of blocks the node answers with missing fields, such as a
+    /// `null` hash at the tip. Only used when there's no `block_archive`.
+    /// Defaults to three retries, 100ms apart.
+    pub incomplete_block_retry: IncompleteBlockRetry,
+
+    // Block the chain tip is frozen at. See `with_pinned_tip`.
+    pinned_tip: Option<Arc<Block>>,
+
+    /// Resolver of `QueryBlock::Custom` targets. Defaults to
+    /// `StandardResolver`, which only knows the standard Ethereum tags.
+    pub block_resolver: Arc<dyn BlockResolver<M>>,
+
     // If the Ethereum node has a limit on the number of events returned by the
     // method `eth_getLogs` (such as Infura, with a 10k events limit and <10s
     // query limit), `query_limit_error_codes` contains the error codes of when
@@ -49,7 +190,17 @@ pub struct StateFoldEnvironment<M: Middleware, UD> {
 
     global_archive: GlobalArchive,
 
+    capabilities: tokio::sync::OnceCell<ProviderCapabilities>,
+    chain_id: tokio::sync::OnceCell<U256>,
+
+    // Whether the clamping of the safety margin was logged.
+    clamp_warned: AtomicBool,
+
+    #[cfg(feature = "profiling")]
+    profiler: Profiler,
+
     user_data: UD,
+    user_data_snapshotter: Option<(UserDataSnapshotter<UD>, UserDataDowncaster<UD>)>,
 }
 
 impl<M: Middleware + 'static, UD> StateFoldEnvironment<M, UD> {
@@ -62,38 +213,493 @@ impl<M: Middleware + 'static, UD> StateFoldEnvironment<M, UD> {
         concurrent_events_fetch: usize,
         maximum_events_per_response: usize,
         user_data: UD,
-    ) -> Self {
-        let global_archive = GlobalArchive::new(safety_margin);
+    ) -> Self
+    where
+        M::Error: 'static,
+    {
+        let global_archive = GlobalArchive::new();
 
         Self {
             inner_middleware,
             block_archive,
             safety_margin,
+            confirmation_policy: None,
+            clamp_safety_margin: false,
+            state_retention: None,
+            fold_yield_interval: DEFAULT_FOLD_YIELD_INTERVAL,
+            sync_partitions: DEFAULT_SYNC_PARTITIONS,
+            max_query_depth: DEFAULT_MAX_QUERY_DEPTH,
+            retry_policy: RetryPolicy::default(),
+            batch_transport: None,
+            log_pagination: None,
+            log_coalescer: None,
+            circuit_breaker: None,
+            clock: Arc::new(TokioClock),
+            rpc_budget: None,
+            request_gate: None,
+            incomplete_block_retry: IncompleteBlockRetry::default(),
+            pinned_tip: None,
+            block_resolver: Arc::new(StandardResolver),
             genesis_block,
             query_limit_error_codes,
             concurrent_events_fetch,
             maximum_events_per_response,
             global_archive,
+            capabilities: tokio::sync::OnceCell::new(),
+            chain_id: tokio::sync::OnceCell::new(),
+            clamp_warned: AtomicBool::new(false),
+
+            #[cfg(feature = "profiling")]
+            profiler: Profiler::default(),
+
             user_data,
+            user_data_snapshotter: None,
         }
     }
 
-    pub fn user_data(&self) -> &UD {
+    /// User data given to `new`. Within a query, the snapshot taken as it
+    /// started, if enabled with `enable_user_data_snapshots`.
+    pub fn user_data(&self) -> UserDataRef<'_, UD> {
+        match self.current_user_data_snapshot() {
+            Some(snapshot) => UserDataRef::Snapshot(snapshot),
+            None => UserDataRef::Live(&self.user_data),
+        }
+    }
+
+    /// User data given to `new`, as it is now, even within a query taking
+    /// snapshots.
+    pub fn live_user_data(&self) -> &UD {
         &self.user_data
     }
 
+    /// Error codes of `eth_getLogs` responses signalling too many logs, on
+    /// which log queries are split into smaller ranges, as given to `new`.
+    /// The environment doesn't hold a list of contracts: each fold picks the
+    /// contracts it filters from its initial state, so tracking a newly
+    /// discovered contract is querying with a new initial state.
+    pub fn query_limit_error_codes(&self) -> &[i32] {
+        &self.query_limit_error_codes
+    }
+
+    /// Makes each query snapshot the user data as it starts, so that its folds
+    /// see a consistent view through `user_data`, even if the user data is
+    /// mutated concurrently through interior mutability. Snapshots are clones,
+    /// so `Clone` must copy whatever mutable data folds read.
+    pub fn enable_user_data_snapshots(&mut self)
+    where
+        UD: Clone + Send + Sync + 'static,
+    {
+        self.user_data_snapshotter = Some((
+            Arc::new(|user_data: &UD| Arc::new(user_data.clone()) as Arc<dyn Any + Send + Sync>),
+            |snapshot| snapshot.downcast().ok(),
+        ));
+    }
+
+    /// Snapshot of the user data taken as the current query started,
+    /// including queries nested in its folds.
+    fn current_user_data_snapshot(&self) -> Option<Arc<UD>> {
+        let (_, downcast) = self.user_data_snapshotter.as_ref()?;
+        let id = self.id();
+
+        USER_DATA_SNAPSHOT
+            .try_with(|(env, snapshot)| (*env == id).then(|| Arc::clone(snapshot)))
+            .ok()
+            .flatten()
+            .and_then(*downcast)
+    }
+
+    /// Snapshot of the user data for a query starting now, unless snapshots
+    /// are disabled or the query is nested in another query of this
+    /// environment, whose snapshot it keeps.
+    fn start_user_data_snapshot(&self) -> Option<(usize, Arc<dyn Any + Send + Sync>)> {
+        let (snapshotter, _) = self.user_data_snapshotter.as_ref()?;
+        let id = self.id();
+
+        if USER_DATA_SNAPSHOT.try_with(|(env, _)| *env == id) == Ok(true) {
+            return None;
+        }
+
+        Some((id, snapshotter(&self.user_data)))
+    }
+
+    fn id(&self) -> usize {
+        self as *const Self as usize
+    }
+
     pub fn inner_middleware(&self) -> Arc<M> {
         self.inner_middleware.clone()
     }
 
+    /// Capabilities of the connected node. They are probed on the first call
+    /// and cached, so later changes to `batch_transport` aren't reflected.
+    pub async fn capabilities(&self) -> ProviderCapabilities {
+        *self
+            .capabilities
+            .get_or_init(|| {
+                ProviderCapabilities::probe(
+                    self.inner_middleware.as_ref(),
+                    self.batch_transport.as_deref(),
+                )
+            })
+            .await
+    }
+
+    /// Id of the connected chain, fetched with `eth_chainId` on the first call
+    /// and cached. States returned by queries are tagged with it, and states
+    /// of other chains are rejected by `fold_from` and `import_cache`.
+    pub async fn chain_id(&self) -> std::result::Result<U256, M::Error> {
+        self.chain_id
+            .get_or_try_init(|| self.inner_middleware.get_chainid())
+            .await
+            .copied()
+    }
+
     pub async fn get_state_for_block<F: Foldable<UserData = UD> + Send + Sync + 'static>(
         &self,
         initial_state: &F::InitialState,
         fold_block: QueryBlock,
     ) -> Result<BlockState<F>, FoldableError<M, F>> {
+        let (block_state, _) = self
+            .get_state_for_block_instrumented(initial_state, fold_block)
+            .await?;
+
+        Ok(block_state)
+    }
+
+    /// Same as `get_state_for_block`, also returning whether the state was
+    /// cached, folded from a cached ancestor, or synced from scratch.
+    pub async fn get_state_for_block_instrumented<
+        F: Foldable<UserData = UD> + Send + Sync + 'static,
+    >(
+        &self,
+        initial_state: &F::InitialState,
+        fold_block: QueryBlock,
+    ) -> Result<(BlockState<F>, ComputeSource), FoldableError<M, F>> {
+        let budget = self.rpc_budget.map(|limit| Arc::new(RpcBudget::new(limit)));
+        self.fetch_state_for_block(initial_state, fold_block, budget)
+            .await
+    }
+
+    /// Same as `get_state_for_block`, overriding `rpc_budget` for this query.
+    pub async fn get_state_for_block_with_budget<
+        F: Foldable<UserData = UD> + Send + Sync + 'static,
+    >(
+        &self,
+        initial_state: &F::InitialState,
+        fold_block: QueryBlock,
+        rpc_budget: Option<usize>,
+    ) -> Result<BlockState<F>, FoldableError<M, F>> {
+        let budget = rpc_budget.map(|limit| Arc::new(RpcBudget::new(limit)));
+        let (block_state, _) = self
+            .fetch_state_for_block(initial_state, fold_block, budget)
+            .await?;
+
+        Ok(block_state)
+    }
+
+    /// Same as `get_state_for_block`, for queries nested in the folds of
+    /// another query, such as those of `Dependent`: their requests count
+    /// against the budget of the enclosing query instead of one of their own.
+    pub(crate) async fn get_nested_state_for_block<
+        F: Foldable<UserData = UD> + Send + Sync + 'static,
+    >(
+        &self,
+        initial_state: &F::InitialState,
+        fold_block: QueryBlock,
+    ) -> Result<BlockState<F>, FoldableError<M, F>> {
+        let (block_state, _) = self
+            .fetch_state_for_block(initial_state, fold_block, budget::current())
+            .await?;
+
+        Ok(block_state)
+    }
+
+    /// Same as `get_state_for_block`, with the requests of this query, and of
+    /// the queries nested in its folds, let through the `request_gate` with
+    /// `priority`. Other queries have `Priority::Normal`.
+    pub async fn get_state_for_block_with_priority<
+        F: Foldable<UserData = UD> + Send + Sync + 'static,
+    >(
+        &self,
+        initial_state: &F::InitialState,
+        fold_block: QueryBlock,
+        priority: Priority,
+    ) -> Result<BlockState<F>, FoldableError<M, F>> {
+        priority
+            .scope(self.get_state_for_block(initial_state, fold_block))
+            .await
+    }
+
+    /// Best-effort state at the node's pending block, folded on top of the
+    /// state of its parent, for uses such as mempool monitoring. Unlike every
+    /// other query, the result is not reorg-stable, nor even deterministic:
+    /// the pending block changes as transactions arrive, and may never be
+    /// mined. As such, it's never cached, and it's returned apart from the
+    /// states of confirmed blocks. The fold's requests are pinned to the
+    /// `pending` tag, which the node may move to a block on another parent as
+    /// one is mined meanwhile, so fails with `PendingBlockMoved` if the
+    /// parent changed by the end of the fold; receipts and traces of the
+    /// pending block are unavailable.
+    pub async fn get_state_for_pending_block<F: Foldable<UserData = UD> + Send + Sync + 'static>(
+        &self,
+        initial_state: &F::InitialState,
+    ) -> Result<PendingBlockState<F>, FoldableError<M, F>> {
+        let pending = self
+            .inner_middleware
+            .get_block(BlockNumber::Pending)
+            .await
+            .context(MiddlewareSnafu)?
+            .ok_or(snafu::NoneError)
+            .context(BlockUnavailableSnafu)?;
+
+        let parent = self
+            .get_state_for_block::<F>(initial_state, QueryBlock::BlockHash(pending.parent_hash))
+            .await?;
+
+        // Nodes may leave out the hash and logs bloom of the pending block.
+        // A full bloom keeps `relevant` from skipping it.
+        let block = Arc::new(Block {
+            hash: pending.hash.unwrap_or_default(),
+            number: pending.number.unwrap_or(parent.block.number + 1),
+            parent_hash: pending.parent_hash,
+            timestamp: pending.timestamp,
+            logs_bloom: pending.logs_bloom.unwrap_or(Bloom::repeat_byte(0xff)),
+            base_fee_per_gas: pending.base_fee_per_gas,
+            gas_used: Some(pending.gas_used),
+        });
+
+        let state = if parent.state.terminal() || !F::relevant(&block, self) {
+            Arc::clone(&parent.state)
+        } else {
+            let access = self.fold_access_pending(&block);
+            let state = F::fold(&parent.state, &block, self, access)
+                .await
+                .context(InnerSnafu)?;
+
+            let found = self
+                .inner_middleware
+                .get_block(BlockNumber::Pending)
+                .await
+                .context(MiddlewareSnafu)?
+                .ok_or(snafu::NoneError)
+                .context(BlockUnavailableSnafu)?
+                .parent_hash;
+            ensure!(
+                found == block.parent_hash,
+                PendingBlockMovedSnafu {
+                    expected: block.parent_hash,
+                    found,
+                }
+            );
+
+            Arc::new(state)
+        };
+
+        Ok(PendingBlockState {
+            parent,
+            block,
+            state,
+        })
+    }
+
+    /// States of each of `initial_states` at the same block, resolving
+    /// `fold_block` once, so they're consistent with each other even if the
+    /// tip advances meanwhile. States of other fold types can be made
+    /// consistent with them by querying the `QueryBlock::Block` of their
+    /// `block`.
+    pub async fn consistent_snapshot<F: Foldable<UserData = UD> + Send + Sync + 'static>(
+        &self,
+        initial_states: &[F::InitialState],
+        fold_block: QueryBlock,
+    ) -> Result<Vec<BlockState<F>>, FoldableError<M, F>> {
+        let Some(first) = initial_states.first() else {
+            return Ok(vec![]);
+        };
+
+        let block = self.resolve_query_block::<F>(first, fold_block).await?;
+        let mut queries: FuturesOrdered<_> = initial_states
+            .iter()
+            .map(|initial_state| {
+                self.get_state_for_block::<F>(initial_state, QueryBlock::Block(Arc::clone(&block)))
+            })
+            .collect();
+
+        let mut states = Vec::with_capacity(initial_states.len());
+        while let Some(state) = queries.next().await {
+            states.push(state?);
+        }
+
+        Ok(states)
+    }
+
+    /// Estimates the cost of querying the state of `fold_block`, without
+    /// running the query. Only resolving `fold_block` makes RPC calls. Since
+    /// the head isn't fetched, syncs are assumed to be on the latest block;
+    /// under `ConfirmationPolicy::Finalized`, `safety_margin` is taken as the
+    /// depth of the sync block.
+    pub async fn estimate_cost<F: Foldable<UserData = UD> + Send + Sync + 'static>(
+        &self,
+        initial_state: &F::InitialState,
+        fold_block: QueryBlock,
+    ) -> Result<CostEstimate, FoldableError<M, F>> {
+        let block = self
+            .resolve_query_block::<F>(initial_state, fold_block)
+            .await?;
+
+        let archive = self.global_archive.get_archive::<F>().await;
+        let cached = match archive.train(initial_state).await {
+            Some(train) => train.cached_blocks().await,
+            None => vec![],
+        };
+
+        if cached.contains(&(block.number, block.hash)) {
+            return Ok(CostEstimate {
+                source: ComputeSource::CacheHit,
+                fold_blocks: 0,
+                sync_blocks: 0,
+                calls: 0,
+            });
+        }
+
+        let ancestor = cached
+            .iter()
+            .rev()
+            .find(|(number, _)| *number < block.number);
+
+        let estimate = match ancestor {
+            Some((from_block, _)) => {
+                let fold_blocks = (block.number - from_block).as_u64();
+
+                CostEstimate {
+                    source: ComputeSource::IncrementalFold {
+                        from_block: *from_block,
+                    },
+                    fold_blocks,
+                    sync_blocks: 0,
+                    // A parent and a fold per block, and a parent per block
+                    // checking the new head for reorgs.
+                    calls: 3 * fold_blocks,
+                }
+            }
+
+            None => {
+                let genesis = self.fold_genesis_block::<F>(initial_state);
+                let depth = self
+                    .confirmation_policy()
+                    .depth()
+                    .unwrap_or(self.safety_margin) as u64;
+                let fold_blocks = std::cmp::min((block.number - genesis).as_u64(), depth);
+                let sync_blocks = (block.number - genesis).as_u64() - fold_blocks;
+
+                CostEstimate {
+                    source: ComputeSource::ColdSync {
+                        from_genesis: genesis,
+                    },
+                    fold_blocks,
+                    sync_blocks,
+                    // The safe and sync blocks, the events of `sync`, and a
+                    // parent and a fold per block.
+                    calls: 2 + self.concurrent_events_fetch as u64 + 1 + 2 * fold_blocks,
+                }
+            }
+        };
+
+        Ok(estimate)
+    }
+
+    async fn fetch_state_for_block<F: Foldable<UserData = UD> + Send + Sync + 'static>(
+        &self,
+        initial_state: &F::InitialState,
+        fold_block: QueryBlock,
+        budget: Option<Arc<RpcBudget>>,
+    ) -> Result<(BlockState<F>, ComputeSource), FoldableError<M, F>> {
+        let stack = self.push_query::<F>(initial_state)?;
+        let query = QUERY_STACK.scope(stack, self.run_query(initial_state, fold_block, budget));
+
+        let result = match self.start_user_data_snapshot() {
+            Some(snapshot) => USER_DATA_SNAPSHOT.scope(snapshot, query).await,
+            None => query.await,
+        };
+
+        #[cfg(feature = "metrics")]
+        if let Ok((_, source)) = &result {
+            crate::metrics::cache_lookup(matches!(source, ComputeSource::CacheHit));
+        }
+
+        result
+    }
+
+    /// Query stack of the current task with a query of `initial_state`
+    /// pushed, failing if it's already in the stack, or too deep.
+    fn push_query<F: Foldable + 'static>(
+        &self,
+        initial_state: &F::InitialState,
+    ) -> Result<Vec<QueryKey>, FoldableError<M, F>> {
+        let key = QueryKey {
+            env: self.id(),
+            fold: TypeId::of::<F>(),
+            initial_state: Arc::new(initial_state.clone()),
+        };
+
+        let mut stack = QUERY_STACK.try_with(Vec::clone).unwrap_or_default();
+        let running = stack.iter().any(|query| {
+            query.env == key.env
+                && query.fold == key.fold
+                && query.initial_state.downcast_ref::<F::InitialState>() == Some(initial_state)
+        });
+        ensure!(!running, CycleDetectedSnafu);
+        ensure!(
+            stack.len() < self.max_query_depth,
+            DepthExceededSnafu {
+                max_depth: self.max_query_depth
+            }
+        );
+
+        stack.push(key);
+        Ok(stack)
+    }
+
+    async fn run_query<F: Foldable<UserData = UD> + Send + Sync + 'static>(
+        &self,
+        initial_state: &F::InitialState,
+        fold_block: QueryBlock,
+        budget: Option<Arc<RpcBudget>>,
+    ) -> Result<(BlockState<F>, ComputeSource), FoldableError<M, F>> {
         let archive = self.global_archive.get_archive::<F>().await;
         let train = archive.get_train(initial_state).await;
 
+        let query = async {
+            let (block_state, source) = self
+                .compute_state_for_block(initial_state, &train, fold_block, &budget)
+                .await?;
+
+            self.roll_back_orphaned(initial_state, &train, &archive, &block_state.block, &budget)
+                .await?;
+
+            Ok::<_, FoldableError<M, F>>((block_state, source))
+        };
+
+        let (mut block_state, source) = RpcBudget::scope(budget.clone(), query).await?;
+
+        // Blocks fetched past the budget didn't fail on their own.
+        if let Some(budget) = budget.as_ref().filter(|budget| budget.exceeded()) {
+            return RpcBudgetExceededSnafu {
+                budget: budget.limit(),
+            }
+            .fail();
+        }
+
+        block_state.chain_id = Some(self.chain_id().await.context(MiddlewareSnafu)?);
+        Ok((block_state, source))
+    }
+
+    /// Block targeted by `fold_block`, failing if it's before the genesis of
+    /// `initial_state`.
+    async fn resolve_query_block<F: Foldable<UserData = UD> + Send + Sync + 'static>(
+        &self,
+        initial_state: &F::InitialState,
+        fold_block: QueryBlock,
+    ) -> Result<Arc<Block>, FoldableError<M, F>> {
         // First check if block exists in archive, returning it if so. This is
         // an optimization and can be removed. The following code will be able
         // to get the requested block regardless. By doing this, we won't need
@@ -102,6 +708,13 @@ impl<M: Middleware + 'static, UD> StateFoldEnvironment<M, UD> {
         let block = match fold_block {
             QueryBlock::Latest => self.current_block().await.context(BlockArchiveSnafu)?,
 
+            QueryBlock::Safe => self
+                .tagged_block(BlockNumber::Safe)
+                .await
+                .context(BlockArchiveSnafu)?,
+
+            QueryBlock::Pending => return PendingBlockUnsupportedSnafu {}.fail(),
+
             QueryBlock::BlockHash(hash) => self
                 .block_with_hash(&hash)
                 .await
@@ -111,108 +724,3175 @@ impl<M: Middleware + 'static, UD> StateFoldEnvironment<M, UD> {
                 self.block_with_number(n).await.context(BlockArchiveSnafu)?
             }
 
+            QueryBlock::BlockNumberAndHash(n, expected) => {
+                let block = self.block_with_number(n).await.context(BlockArchiveSnafu)?;
+
+                ensure!(
+                    block.hash == expected,
+                    HashMismatchSnafu {
+                        number: n,
+                        expected,
+                        actual: block.hash,
+                    }
+                );
+
+                block
+            }
+
             QueryBlock::BlockDepth(depth) => self
                 .block_at_depth(depth)
                 .await
                 .context(BlockArchiveSnafu)?,
 
+            QueryBlock::Confirmations(0) => return PendingBlockUnsupportedSnafu {}.fail(),
+
+            QueryBlock::Confirmations(confirmations) => {
+                let current = self
+                    .current_block_number()
+                    .await
+                    .context(BlockArchiveSnafu)?;
+
+                // As with `BlockDepth`, block zero isn't reachable.
+                ensure!(
+                    U64::from(confirmations) <= current,
+                    ConfirmationsTooHighSnafu {
+                        confirmations,
+                        current,
+                    }
+                );
+
+                self.block_at_depth(confirmations as usize - 1)
+                    .await
+                    .context(BlockArchiveSnafu)?
+            }
+
+            QueryBlock::Custom(target) => {
+                let id = self
+                    .block_resolver
+                    .resolve(self.inner_middleware.as_ref(), &target)
+                    .await
+                    .context(MiddlewareSnafu)?;
+
+                match id {
+                    Some(BlockId::Number(BlockNumber::Pending)) => {
+                        return PendingBlockUnsupportedSnafu {}.fail()
+                    }
+
+                    Some(BlockId::Hash(hash)) => self.block_with_hash(&hash).await,
+                    Some(BlockId::Number(BlockNumber::Number(n))) => {
+                        self.block_with_number(n).await
+                    }
+                    Some(BlockId::Number(tag)) => self.tagged_block(tag).await,
+                    None => return UnknownQueryTargetSnafu { target }.fail(),
+                }
+                .context(BlockArchiveSnafu)?
+            }
+
             QueryBlock::Block(b) => b,
         };
 
+        // Blocks before genesis cannot be synced, as the access layer would
+        // query an empty (inverted) range.
+        let genesis = self.fold_genesis_block::<F>(initial_state);
+        ensure!(
+            block.number >= genesis,
+            BlockBeforeGenesisSnafu {
+                block: block.number,
+                genesis,
+            }
+        );
+
+        Ok(block)
+    }
+
+    async fn compute_state_for_block<F: Foldable<UserData = UD> + Send + Sync + 'static>(
+        &self,
+        initial_state: &F::InitialState,
+        train: &Train<F>,
+        fold_block: QueryBlock,
+        budget: &Option<Arc<RpcBudget>>,
+    ) -> Result<(BlockState<F>, ComputeSource), FoldableError<M, F>> {
+        let block = self
+            .resolve_query_block::<F>(initial_state, fold_block)
+            .await?;
+
         // Check if exists in archive.
         if let Some(block_state) = train.get_block_state(Arc::clone(&block)).await {
-            return Ok(block_state);
+            return Ok((block_state, ComputeSource::CacheHit));
         }
 
+        // Held until the outcome is recorded, ending the trial of a half-open
+        // circuit.
+        let _admission = match &self.circuit_breaker {
+            Some(config) => match train.circuit_breaker().admit(config, self.clock.as_ref()) {
+                Ok(admission) => Some(admission),
+                Err(retry_in) => return CircuitOpenSnafu { retry_in }.fail(),
+            },
+            None => None,
+        };
+
         // If it's not on archive, do the actual work. This method has an
         // internal lock, which makes concurrent calls mutually exclusive, to
         // avoid replicated work.
-        train.fetch_block_state(self, block).await
+        let result = train.fetch_block_state(self, block, budget).await;
+
+        if let Some(config) = &self.circuit_breaker {
+            match &result {
+                Ok(_) => train.circuit_breaker().record_success(),
+                Err(FoldableError::InnerError { .. }) => train
+                    .circuit_breaker()
+                    .record_failure(config, self.clock.as_ref()),
+                Err(_) => {}
+            }
+        }
+
+        result
     }
-}
 
-///
-/// Internals
+    /// State of the latest block considered safe by the confirmation policy.
+    pub async fn get_latest_safe_state<F: Foldable<UserData = UD> + Send + Sync + 'static>(
+        &self,
+        initial_state: &F::InitialState,
+    ) -> Result<BlockState<F>, FoldableError<M, F>> {
+        let safe = self.safe_block_number::<F>(initial_state).await?;
+        let query = match &self.pinned_tip {
+            Some(tip) => QueryBlock::Block(
+                self.ancestor_at(tip, safe)
+                    .await
+                    .context(BlockArchiveSnafu)?,
+            ),
 
-impl<M: Middleware + 'static, UD> StateFoldEnvironment<M, UD> {
-    pub(crate) fn sync_access(&self, block: &Block) -> Arc<SyncMiddleware<M>> {
-        let middleware = SyncMiddleware::new(
-            Arc::clone(&self.inner_middleware),
-            self.genesis_block,
-            block.number,
-            self.query_limit_error_codes.clone(),
-            self.concurrent_events_fetch,
-            self.maximum_events_per_response,
-        );
+            None => QueryBlock::BlockNumber(safe),
+        };
 
-        Arc::new(middleware)
+        self.get_state_for_block(initial_state, query).await
     }
 
-    pub(crate) fn fold_access(&self, block: &Block) -> Arc<FoldMiddleware<M>> {
-        let middleware = FoldMiddleware::new(Arc::clone(&self.inner_middleware), block.hash);
-        Arc::new(middleware)
+    /// Same as `get_latest_safe_state`, under the name of its use of bringing
+    /// a cached state up to the safe tip: like any query, it folds forward
+    /// from the most recent cached ancestor, never folding a cached block
+    /// again, and syncs only if no ancestor is cached.
+    pub async fn advance_to_safe_tip<F: Foldable<UserData = UD> + Send + Sync + 'static>(
+        &self,
+        initial_state: &F::InitialState,
+    ) -> Result<BlockState<F>, FoldableError<M, F>> {
+        self.get_latest_safe_state(initial_state).await
     }
 
-    pub(crate) async fn current_block_number(&self) -> Result<U64, BlockArchiveError<M>> {
-        if let Some(a) = &self.block_archive {
-            Ok(a.latest_block().await.number)
-        } else {
-            current_block_number(self.inner_middleware.as_ref()).await
-        }
-    }
+    /// Spawns a background task keeping the latest safe state of
+    /// `initial_state` folded and cached, folding it on every item of
+    /// `new_heads`, a subscription to new blocks such as
+    /// `BlockSubscriber::subscribe_new_blocks_at_depth`, so
+    /// `get_latest_safe_state` is a cache hit. After a reorg, the new safe
+    /// block is folded. Errors are retried on the next head. The task runs
+    /// until `new_heads` ends, or the returned `Tracker` is stopped or
+    /// dropped.
+    pub fn track<F, S>(self: &Arc<Self>, initial_state: F::InitialState, new_heads: S) -> Tracker
+    where
+        F: Foldable<UserData = UD> + Send + Sync + 'static,
+        S: Stream<Item = BlockStreamItem> + Send + 'static,
+        UD: Send + Sync + 'static,
+    {
+        let env = Arc::clone(self);
 
-    pub(crate) async fn current_block(&self) -> Result<Arc<Block>, BlockArchiveError<M>> {
-        if let Some(a) = &self.block_archive {
-            Ok(a.latest_block().await)
-        } else {
-            self.block(BlockNumber::Latest).await
-        }
-    }
+        let handle = tokio::spawn(async move {
+            #[cfg(feature = "metrics")]
+            let _guard = crate::metrics::SubscriberGuard::new();
 
-    pub async fn block_with_hash(&self, hash: &H256) -> Result<Arc<Block>, BlockArchiveError<M>> {
-        if let Some(a) = &self.block_archive {
-            a.block_with_hash(hash).await
-        } else {
-            self.block(*hash).await
-        }
-    }
+            futures::pin_mut!(new_heads);
 
-    pub async fn block_with_number(&self, number: U64) -> Result<Arc<Block>, BlockArchiveError<M>> {
-        if let Some(a) = &self.block_archive {
-            a.block_with_number(number).await
-        } else {
-            self.block(number).await
-        }
+            let _ = env.get_latest_safe_state::<F>(&initial_state).await;
+            while new_heads.next().await.is_some() {
+                let _ = env.get_latest_safe_state::<F>(&initial_state).await;
+            }
+        });
+
+        Tracker::new(handle)
     }
 
-    pub(crate) async fn block_at_depth(
+    /// Folds each block of `blocks` as it arrives, sending the results, errors
+    /// included, into `sink` in order. Sending waits for room in `sink`, so a
+    /// slow consumer paces folding. Returns when `blocks` ends or `sink` is
+    /// closed.
+    pub async fn pipe<F, B>(
         &self,
-        depth: usize,
-    ) -> Result<Arc<Block>, BlockArchiveError<M>> {
-        if let Some(a) = &self.block_archive {
-            a.block_at_depth(depth).await
-        } else {
-            let current = self.current_block_number().await?;
-            self.fetch_block_at_depth(current, depth).await
+        initial_state: &F::InitialState,
+        blocks: impl Stream<Item = B>,
+        sink: mpsc::Sender<Result<BlockState<F>, FoldableError<M, F>>>,
+    ) where
+        F: Foldable<UserData = UD> + Send + Sync + 'static,
+        B: Into<QueryBlock>,
+    {
+        #[cfg(feature = "metrics")]
+        let _guard = crate::metrics::SubscriberGuard::new();
+
+        futures::pin_mut!(blocks);
+
+        while let Some(block) = blocks.next().await {
+            let result = self.get_state_for_block(initial_state, block.into()).await;
+
+            if sink.send(result).await.is_err() {
+                return;
+            }
         }
     }
 
-    async fn block<T: Into<BlockId> + Send + Sync>(
-        &self,
-        block: T,
-    ) -> Result<Arc<Block>, BlockArchiveError<M>> {
-        Ok(Arc::new(
-            fetch_block(self.inner_middleware.as_ref(), block).await?,
-        ))
+    /// Folds the blocks of a block subscription, such as
+    /// `BlockSubscriber::subscribe_new_blocks_at_depth`, emitting the state of
+    /// a block only when it differs from the last state emitted. On a `Reorg`,
+    /// the blocks of the new branch are folded in order, so a reorg changing
+    /// the state emits the corrected state. On a `HistoryGap`, the block it
+    /// resumes from is folded. Errors are emitted as they happen. The stream
+    /// ends when `blocks` does.
+    pub fn subscribe_state<'a, F>(
+        &'a self,
+        initial_state: &'a F::InitialState,
+        blocks: impl Stream<Item = BlockStreamItem> + 'a,
+    ) -> impl Stream<Item = Result<BlockState<F>, FoldableError<M, F>>> + 'a
+    where
+        F: Foldable<UserData = UD> + PartialEq + Send + Sync + 'static,
+    {
+        let blocks = blocks.flat_map(|item| {
+            futures::stream::iter(match item {
+                BlockStreamItem::NewBlock(block) => vec![block],
+                BlockStreamItem::Reorg(blocks) => blocks,
+                BlockStreamItem::Orphaned(_) => vec![],
+                BlockStreamItem::HistoryGap { to, .. } => vec![to],
+            })
+        });
+
+        // Active until the stream is dropped.
+        #[cfg(feature = "metrics")]
+        let blocks = {
+            let guard = crate::metrics::SubscriberGuard::new();
+            blocks.inspect(move |_| {
+                let _ = &guard;
+            })
+        };
+
+        futures::stream::unfold(
+            (Box::pin(blocks), None::<Arc<F>>),
+            move |(mut blocks, mut last)| async move {
+                while let Some(block) = blocks.next().await {
+                    let result = self
+                        .get_state_for_block::<F>(initial_state, QueryBlock::BlockHash(block.hash))
+                        .await;
+
+                    match result {
+                        Ok(block_state) if last.as_deref() == Some(block_state.state.as_ref()) => {}
+
+                        Ok(block_state) => {
+                            last = Some(Arc::clone(&block_state.state));
+                            return Some((Ok(block_state), (blocks, last)));
+                        }
+
+                        Err(e) => return Some((Err(e), (blocks, last))),
+                    }
+                }
+
+                None
+            },
+        )
     }
 
-    async fn fetch_block_at_depth(
+    /// Seeds the cache with a trusted `prior` state (e.g. from a peer or a
+    /// database) and gets the state of `fold_block`, folding forward from
+    /// `prior` instead of syncing. Fails with `PriorStateReorged` if the block
+    /// of `prior` is no longer canonical, in which case the caller should
+    /// resync with `get_state_for_block`, and with `ChainMismatch` if `prior`
+    /// is tagged with another chain. Untagged states, such as those received
+    /// over gRPC, fail with `UntaggedPriorState`; to trust them, tag them with
+    /// the `chain_id` of the environment.
+    pub async fn fold_from<F: Foldable<UserData = UD> + Send + Sync + 'static>(
         &self,
-        current: U64,
-        depth: usize,
+        initial_state: &F::InitialState,
+        prior: BlockState<F>,
+        fold_block: QueryBlock,
+    ) -> Result<BlockState<F>, FoldableError<M, F>> {
+        let chain_id = self.chain_id().await.context(MiddlewareSnafu)?;
+        let found = prior
+            .chain_id
+            .ok_or(snafu::NoneError)
+            .context(UntaggedPriorStateSnafu)?;
+        ensure!(
+            found == chain_id,
+            ChainMismatchSnafu {
+                expected: chain_id,
+                found
+            }
+        );
+
+        let canonical = self
+            .block_with_number(prior.block.number)
+            .await
+            .context(BlockArchiveSnafu)?;
+
+        ensure!(
+            canonical.hash == prior.block.hash,
+            PriorStateReorgedSnafu {
+                block: prior.block.hash
+            }
+        );
+
+        let archive = self.global_archive.get_archive::<F>().await;
+        let train = archive.get_train(initial_state).await;
+        train.insert_block_state(prior).await;
+
+        self.get_state_for_block(initial_state, fold_block).await
+    }
+
+    /// Replays the folds leading to the state of `fold_block`, for auditing.
+    /// The state is queried as usual, and then folded again from the earliest
+    /// cached state it descends from without gaps, recording the logs each
+    /// `fold` consumes. That base state is synced again too, recording the
+    /// logs of `sync`. Replayed states differing from the cached ones, as of
+    /// non-deterministic folds, are flagged. The replay doesn't touch the
+    /// cache, and makes the same requests as the original sync and folds.
+    pub async fn explain<F: Foldable<UserData = UD> + PartialEq + Send + Sync + 'static>(
+        &self,
+        initial_state: &F::InitialState,
+        fold_block: QueryBlock,
+    ) -> Result<FoldTrace<F>, FoldableError<M, F>> {
+        let mut base = self.get_state_for_block(initial_state, fold_block).await?;
+
+        let archive = self.global_archive.get_archive::<F>().await;
+        let train = archive.get_train(initial_state).await;
+        let cached: HashSet<H256> = train
+            .cached_blocks()
+            .await
+            .into_iter()
+            .map(|(_, hash)| hash)
+            .collect();
+
+        // Walk back the cached ancestry of the target, stacking the blocks to
+        // replay.
+        let mut stack = vec![];
+        while cached.contains(&base.block.parent_hash) {
+            let parent = self
+                .block_with_hash(&base.block.parent_hash)
+                .await
+                .context(BlockArchiveSnafu)?;
+
+            // The parent may have been evicted since listing the cache.
+            let Some(parent_state) = train.get_block_state(parent).await else {
+                break;
+            };
+
+            stack.push((Arc::clone(&base.block), Arc::clone(&base.state)));
+            base = parent_state;
+        }
+
+        let recorder = Arc::new(std::sync::Mutex::new(vec![]));
+        let genesis = self.fold_genesis_block::<F>(initial_state);
+        let access = self.sync_access_recording(genesis, &base.block, Arc::clone(&recorder));
+        let synced = F::sync(initial_state, &base.block, self, access)
+            .await
+            .context(InnerSnafu)?;
+        let base_logs = std::mem::take(&mut *recorder.lock().unwrap());
+
+        let mut state = Arc::clone(&base.state);
+        let mut steps = Vec::with_capacity(stack.len());
+
+        for (i, (block, cached)) in stack.into_iter().rev().enumerate() {
+            if i > 0 && i % self.fold_yield_interval.max(1) == 0 {
+                tokio::task::yield_now().await;
+            }
+
+            let relevant = !state.terminal() && F::relevant(&block, self);
+            let mut logs = vec![];
+
+            if relevant {
+                let recorder = Arc::new(std::sync::Mutex::new(vec![]));
+                let access = self.fold_access_recording(&block, Arc::clone(&recorder));
+                let new_state = F::fold(&state, &block, self, access)
+                    .await
+                    .context(InnerSnafu)?;
+
+                state = Arc::new(new_state);
+                logs = std::mem::take(&mut *recorder.lock().unwrap());
+            }
+
+            steps.push(FoldStep {
+                block,
+                relevant,
+                logs,
+                mismatch: *state != *cached,
+                state: Arc::clone(&state),
+            });
+        }
+
+        Ok(FoldTrace {
+            chain_id: self.chain_id().await.context(MiddlewareSnafu)?,
+            base_mismatch: synced != *base.state,
+            base_block: base.block,
+            base_state: base.state,
+            base_logs,
+            steps,
+        })
+    }
+
+    /// States of the blocks from `from` to `to`, sampled by `sample`, oldest
+    /// first, for plotting a fold's history. The range is folded forward once,
+    /// caching the state of each of its blocks, syncing on `from` first if
+    /// needed. Empty if `to` is before `from`; fails with
+    /// `SeriesRangeNotOnChain` if `from` isn't an ancestor of `to`.
+    pub async fn series<F: Foldable<UserData = UD> + Send + Sync + 'static>(
+        &self,
+        initial_state: &F::InitialState,
+        from: QueryBlock,
+        to: QueryBlock,
+        sample: SampleSpec,
+    ) -> Result<Vec<BlockState<F>>, FoldableError<M, F>> {
+        let from = self.resolve_query_block::<F>(initial_state, from).await?;
+        let to = self.resolve_query_block::<F>(initial_state, to).await?;
+        if to.number < from.number {
+            return Ok(vec![]);
+        }
+
+        // Walk back the ancestry of `to`, down to the block number of `from`.
+        let mut blocks = vec![Arc::clone(&to)];
+        let mut block = to;
+        while block.number > from.number {
+            block = self
+                .block_with_hash(&block.parent_hash)
+                .await
+                .context(BlockArchiveSnafu)?;
+            blocks.push(Arc::clone(&block));
+        }
+
+        ensure!(
+            block.hash == from.hash,
+            SeriesRangeNotOnChainSnafu {
+                from: from.hash,
+                to: blocks[0].hash
+            }
+        );
+        blocks.reverse();
+
+        let last = Arc::clone(&blocks[blocks.len() - 1]);
+        self.get_state_for_block::<F>(initial_state, QueryBlock::Block(from))
+            .await?;
+        self.get_state_for_block::<F>(initial_state, QueryBlock::Block(last))
+            .await?;
+
+        // Cached by now, unless evicted, in which case they're folded again.
+        let mut series = vec![];
+        for block in sample.sample(&blocks) {
+            let block_state = self
+                .get_state_for_block(initial_state, QueryBlock::Block(block))
+                .await?;
+            series.push(block_state);
+        }
+
+        Ok(series)
+    }
+
+    /// Whether the block `block_hash` is on the canonical chain. Cheap if the
+    /// block is within the history tracked by the block archive.
+    pub async fn is_canonical(&self, block_hash: H256) -> Result<bool, BlockArchiveError<M>> {
+        let block = self.block_with_hash(&block_hash).await?;
+        let canonical = self.block_with_number(block.number).await?;
+
+        Ok(canonical.hash == block_hash)
+    }
+
+    /// Checks whether the block `block_state` was computed at is still on the
+    /// canonical chain, instead of trusting the cache.
+    pub async fn revalidate<F>(
+        &self,
+        block_state: &BlockState<F>,
+    ) -> Result<Validity, BlockArchiveError<M>> {
+        let canonical = self.block_with_number(block_state.block.number).await?;
+
+        if canonical.hash == block_state.block.hash {
+            Ok(Validity::Canonical)
+        } else {
+            Ok(Validity::Reorged { canonical })
+        }
+    }
+
+    /// State of the circuit breaker of `initial_state`. Always `Closed` if the
+    /// circuit breaker is disabled.
+    pub async fn circuit_state<F: Foldable<UserData = UD> + Send + Sync + 'static>(
+        &self,
+        initial_state: &F::InitialState,
+    ) -> CircuitState {
+        let archive = self.global_archive.get_archive::<F>().await;
+
+        match (&self.circuit_breaker, archive.train(initial_state).await) {
+            (Some(config), Some(train)) => {
+                train.circuit_breaker().state(config, self.clock.as_ref())
+            }
+            _ => CircuitState::Closed,
+        }
+    }
+
+    /// Keys the cache of `F` on `key(initial_state)` instead of the whole
+    /// `initial_state`, so initial states differing only in fields that don't
+    /// affect the state (e.g. a label) share cached states. States are synced
+    /// from the first initial state queried for each key. Meant to be set
+    /// before querying `F`, as states cached under the previous keys are
+    /// dropped.
+    pub async fn set_initial_state_key<F, K>(
+        &self,
+        key: impl Fn(&F::InitialState) -> K + Send + Sync + 'static,
+    ) where
+        F: Foldable<UserData = UD> + Send + Sync + 'static,
+        K: std::hash::Hash + Eq + Send + Sync + 'static,
+    {
+        let key = Arc::new(move |initial_state: &F::InitialState| {
+            CacheKey::new::<F, _>(key(initial_state))
+        });
+
+        let archive = self.global_archive.get_archive::<F>().await;
+        self.global_archive
+            .set_archive::<F>(archive.rebuild(Some(key), None))
+            .await;
+    }
+
+    /// Stores the states of `F` in the caches built by `cache`, instead of in
+    /// memory. A cache is built for each initial state queried, or for each
+    /// key if set with `set_initial_state_key`. States already in a cache are
+    /// folded from, while those held by the previous caches are dropped.
+    pub async fn set_state_cache<F>(
+        &self,
+        cache: impl Fn(&F::InitialState) -> Box<dyn StateCache<F>> + Send + Sync + 'static,
+    ) where
+        F: Foldable<UserData = UD> + Send + Sync + 'static,
+    {
+        let archive = self.global_archive.get_archive::<F>().await;
+        self.global_archive
+            .set_archive::<F>(archive.rebuild(None, Some(Arc::new(cache))))
+            .await;
+    }
+
+    /// Calls `hook` whenever a query of `F` returns a state on a different
+    /// branch than the highest state previously returned for the same initial
+    /// state, before returning it. The hook receives the common ancestor of
+    /// both branches and the states of the orphaned branch, from its tip down
+    /// to the ancestor (exclusive), so side effects of those states can be
+    /// undone. Orphaned states missing from the cache are recomputed on the
+    /// old branch.
+    pub async fn on_reorg<F>(
+        &self,
+        hook: impl Fn(&F::InitialState, &Block, &[BlockState<F>]) + Send + Sync + 'static,
+    ) where
+        F: Foldable<UserData = UD> + Send + Sync + 'static,
+    {
+        let archive = self.global_archive.get_archive::<F>().await;
+        archive.set_reorg_hook(Arc::new(hook));
+    }
+
+    /// Calls `hook` whenever cached states of `F` are dropped for being
+    /// orphaned by a reorg, detected as for `on_reorg`, with the number and
+    /// hash of their blocks, sorted ascending. Meant for monitoring cache
+    /// churn.
+    pub async fn on_cache_invalidation<F>(
+        &self,
+        hook: impl Fn(&F::InitialState, &[(U64, H256)]) + Send + Sync + 'static,
+    ) where
+        F: Foldable<UserData = UD> + Send + Sync + 'static,
+    {
+        let archive = self.global_archive.get_archive::<F>().await;
+        archive.set_invalidation_hook(Arc::new(hook));
+    }
+
+    /// Number and hash of the blocks whose states of `F` are currently cached
+    /// for `initial_state`, sorted ascending.
+    pub async fn cached_blocks<F: Foldable<UserData = UD> + Send + Sync + 'static>(
+        &self,
+        initial_state: &F::InitialState,
+    ) -> Vec<(U64, H256)> {
+        let archive = self.global_archive.get_archive::<F>().await;
+
+        match archive.train(initial_state).await {
+            Some(train) => train.cached_blocks().await,
+            None => vec![],
+        }
+    }
+
+    /// Serializes the states of `F` cached for `initial_state` in `format`,
+    /// to be restored with `import_cache`, e.g. after a restart. The export
+    /// is tagged with the chain id, fetched first if no query has yet.
+    #[cfg(any(feature = "json", feature = "bincode"))]
+    pub async fn export_cache<F>(
+        &self,
+        initial_state: &F::InitialState,
+        format: CacheFormat,
+    ) -> std::result::Result<Vec<u8>, CacheExportError>
+    where
+        F: Foldable<UserData = UD> + serde::Serialize + Send + Sync + 'static,
+        M::Error: 'static,
+    {
+        let archive = self.global_archive.get_archive::<F>().await;
+
+        let entries = match archive.train(initial_state).await {
+            Some(train) => train.entries().await,
+            None => vec![],
+        };
+
+        let chain_id = self
+            .chain_id()
+            .await
+            .map_err(|e| CacheExportError::ChainIdUnavailable { source: e.into() })?;
+
+        cache_export::encode(&entries, chain_id, format)
+    }
+
+    /// Adds the states of an `export_cache` in `format` to the cache of
+    /// `initial_state`, returning how many were imported. Nothing is imported
+    /// if `data` is invalid, or if it's tagged with another chain, failing
+    /// with `ChainMismatch`. States are otherwise trusted as is, as are those
+    /// of exports of version 1, which aren't tagged; importing an export
+    /// taken on another fold or chain yields wrong query results.
+    #[cfg(any(feature = "json", feature = "bincode"))]
+    pub async fn import_cache<F>(
+        &self,
+        initial_state: &F::InitialState,
+        data: &[u8],
+        format: CacheFormat,
+    ) -> std::result::Result<usize, CacheExportError>
+    where
+        F: Foldable<UserData = UD> + serde::de::DeserializeOwned + Send + Sync + 'static,
+        M::Error: 'static,
+    {
+        let (found, entries) = cache_export::decode::<F>(data, format)?;
+        let count = entries.len();
+
+        if let Some(found) = found {
+            let expected = self
+                .chain_id()
+                .await
+                .map_err(|e| CacheExportError::ChainIdUnavailable { source: e.into() })?;
+
+            if found != expected {
+                return Err(CacheExportError::ChainMismatch { expected, found });
+            }
+        }
+
+        let archive = self.global_archive.get_archive::<F>().await;
+        let train = archive.get_train(initial_state).await;
+
+        for (block, state) in entries {
+            train
+                .insert_block_state(BlockState {
+                    block: Arc::new(block),
+                    state: Arc::new(state),
+                    chain_id: found,
+                })
+                .await;
+        }
+
+        Ok(count)
+    }
+
+    /// Advances the head of `train` to `block`. If `block` isn't a descendant
+    /// of the previous head, calls the reorg hook of `archive`, if any, with
+    /// the orphaned states, then drops them from the cache, calling its
+    /// invalidation hook, if any.
+    /// The head is advanced before the rollback, so that concurrent queries
+    /// roll back each reorg once; orphans whose states can't be computed are
+    /// skipped.
+    async fn roll_back_orphaned<F: Foldable<UserData = UD> + Send + Sync + 'static>(
+        &self,
+        initial_state: &F::InitialState,
+        train: &Train<F>,
+        archive: &Archive<F>,
+        block: &Arc<Block>,
+        budget: &Option<Arc<RpcBudget>>,
+    ) -> Result<(), FoldableError<M, F>> {
+        let (ancestor, orphaned, canonical) = loop {
+            let previous = {
+                let mut head = train.head().await;
+
+                match head.as_ref() {
+                    Some(previous) if previous.number > block.number => return Ok(()),
+                    Some(previous) if previous.hash == block.hash => return Ok(()),
+                    Some(previous) => Arc::clone(previous),
+                    None => {
+                        *head = Some(Arc::clone(block));
+                        return Ok(());
+                    }
+                }
+            };
+
+            // Walked without holding the head, which other queries may have
+            // advanced meanwhile, in which case this is retried from it.
+            let rollback = self
+                .orphaned_blocks::<F>(initial_state, &previous, block)
+                .await?;
+
+            let mut head = train.head().await;
+            if head.as_ref().map(|head| head.hash) == Some(previous.hash) {
+                *head = Some(Arc::clone(block));
+                break rollback;
+            }
+        };
+
+        if orphaned.is_empty() {
+            return Ok(());
+        }
+
+        #[cfg(feature = "metrics")]
+        crate::metrics::reorg();
+
+        if let Some(hook) = archive.reorg_hook() {
+            let mut states = Vec::with_capacity(orphaned.len());
+            for orphan in orphaned {
+                match train
+                    .fetch_block_state(self, Arc::clone(&orphan), budget)
+                    .await
+                {
+                    Ok((state, _)) => states.push(state),
+                    Err(e) => tracing::warn!(
+                        "Skipping the rollback of orphaned block `{}` ({:?}), whose state can't be computed: {}",
+                        orphan.number,
+                        orphan.hash,
+                        e
+                    ),
+                }
+            }
+
+            hook(initial_state, &ancestor, &states);
+        }
+
+        let invalidated: Vec<_> = train
+            .cached_blocks()
+            .await
+            .into_iter()
+            .filter(|(number, hash)| canonical.get(number).is_some_and(|h| h != hash))
+            .collect();
+
+        if !invalidated.is_empty() {
+            train.invalidate_non_canonical(&canonical).await;
+
+            if let Some(hook) = archive.invalidation_hook() {
+                hook(initial_state, &invalidated);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Blocks of the branch of `previous` orphaned by `block`, newest first,
+    /// along with their common ancestor, and the blocks replacing them, by
+    /// number. Blocks before the genesis of `initial_state` are ignored.
+    #[allow(clippy::type_complexity)]
+    async fn orphaned_blocks<F: Foldable<UserData = UD> + Send + Sync + 'static>(
+        &self,
+        initial_state: &F::InitialState,
+        previous: &Arc<Block>,
+        block: &Arc<Block>,
+    ) -> Result<(Arc<Block>, Vec<Arc<Block>>, HashMap<U64, H256>), FoldableError<M, F>> {
+        let mut new_branch = Arc::clone(block);
+        while new_branch.number > previous.number {
+            new_branch = self
+                .block_with_hash(&new_branch.parent_hash)
+                .await
+                .context(BlockArchiveSnafu)?;
+        }
+
+        let mut old_branch = Arc::clone(previous);
+        let mut orphaned = vec![];
+        let mut canonical = HashMap::new();
+        let genesis = self.fold_genesis_block::<F>(initial_state);
+        while old_branch.hash != new_branch.hash && old_branch.number > genesis {
+            orphaned.push(Arc::clone(&old_branch));
+            canonical.insert(new_branch.number, new_branch.hash);
+
+            old_branch = self
+                .block_with_hash(&old_branch.parent_hash)
+                .await
+                .context(BlockArchiveSnafu)?;
+            new_branch = self
+                .block_with_hash(&new_branch.parent_hash)
+                .await
+                .context(BlockArchiveSnafu)?;
+        }
+
+        Ok((old_branch, orphaned, canonical))
+    }
+}
+
+#[cfg(feature = "profiling")]
+impl<M: Middleware + 'static, UD> StateFoldEnvironment<M, UD> {
+    /// Timings of every block synced or folded since the last reset, keyed by
+    /// block hash.
+    pub fn fold_timings(&self) -> std::collections::HashMap<H256, BlockTiming> {
+        self.profiler.summary()
+    }
+
+    pub fn reset_fold_timings(&self) {
+        self.profiler.reset()
+    }
+
+    pub(crate) fn profile_fold(&self, block: &Block, elapsed: std::time::Duration) {
+        self.profiler.block(block.hash).add_fold(elapsed)
+    }
+}
+
+///
+/// Internals
+
+impl<M: Middleware + 'static, UD> StateFoldEnvironment<M, UD> {
+    #[cfg(test)]
+    pub(crate) fn sync_access(&self, block: &Block) -> Arc<SyncMiddleware<M>> {
+        self.sync_access_from(self.genesis_block, block, None)
+    }
+
+    /// Access layer for syncing on `block`, querying from `genesis`, and
+    /// spending from `budget`.
+    pub(crate) fn sync_access_from(
+        &self,
+        genesis: U64,
+        block: &Block,
+        budget: Option<Arc<RpcBudget>>,
+    ) -> Arc<SyncMiddleware<M>> {
+        Arc::new(self.sync_middleware(genesis, block, budget))
+    }
+
+    /// Access layer for replaying the sync of `block`, querying from
+    /// `genesis`, recording the logs it is returned into `recorder`.
+    fn sync_access_recording(
+        &self,
+        genesis: U64,
+        block: &Block,
+        recorder: Arc<std::sync::Mutex<Vec<ethers::types::Log>>>,
+    ) -> Arc<SyncMiddleware<M>> {
+        Arc::new(
+            self.sync_middleware(genesis, block, None)
+                .with_log_recorder(recorder),
+        )
+    }
+
+    fn sync_middleware(
+        &self,
+        genesis: U64,
+        block: &Block,
+        budget: Option<Arc<RpcBudget>>,
+    ) -> SyncMiddleware<M> {
+        let middleware = SyncMiddleware::new(
+            Arc::clone(&self.inner_middleware),
+            genesis,
+            block.number,
+            self.query_limit_error_codes.clone(),
+            self.concurrent_events_fetch,
+            self.maximum_events_per_response,
+            self.retry_policy,
+            self.batch_transport.clone(),
+        )
+        .with_budget(budget)
+        .with_gate(self.request_gate.clone())
+        .with_clock(Arc::clone(&self.clock))
+        .with_log_pagination(self.log_pagination.clone());
+
+        #[cfg(feature = "profiling")]
+        let middleware = middleware.with_profile(self.profiler.block(block.hash));
+
+        middleware
+    }
+
+    /// Genesis of `initial_state`, as declared by `F`, or the environment's
+    /// genesis.
+    pub(crate) fn fold_genesis_block<F: Foldable>(&self, initial_state: &F::InitialState) -> U64 {
+        F::genesis_block(initial_state).unwrap_or(self.genesis_block)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn fold_access(&self, block: &Block) -> Arc<FoldMiddleware<M>> {
+        self.fold_access_with_budget(block, None)
+    }
+
+    /// Same as `fold_access`, spending from `budget`.
+    pub(crate) fn fold_access_with_budget(
+        &self,
+        block: &Block,
+        budget: Option<Arc<RpcBudget>>,
+    ) -> Arc<FoldMiddleware<M>> {
+        let middleware = FoldMiddleware::new(
+            Arc::clone(&self.inner_middleware),
+            block.hash,
+            self.retry_policy,
+            self.batch_transport.clone(),
+        )
+        .with_block_number(block.number)
+        .with_budget(budget)
+        .with_gate(self.request_gate.clone())
+        .with_clock(Arc::clone(&self.clock))
+        .with_log_pagination(self.log_pagination.clone())
+        .with_log_coalescer(self.log_coalescer.clone());
+
+        #[cfg(feature = "profiling")]
+        let middleware = middleware.with_profile(self.profiler.block(block.hash));
+
+        Arc::new(middleware)
+    }
+
+    /// Access layer for folding the pending `block`, pinned to the `pending`
+    /// tag.
+    fn fold_access_pending(&self, block: &Block) -> Arc<FoldMiddleware<M>> {
+        let middleware = FoldMiddleware::new(
+            Arc::clone(&self.inner_middleware),
+            block.hash,
+            self.retry_policy,
+            self.batch_transport.clone(),
+        )
+        .with_block_number(block.number)
+        .at_pending_block()
+        .with_gate(self.request_gate.clone())
+        .with_clock(Arc::clone(&self.clock))
+        .with_log_pagination(self.log_pagination.clone());
+
+        Arc::new(middleware)
+    }
+
+    /// Access layer for replaying the fold of `block`, recording the logs it
+    /// is returned into `recorder`.
+    pub(crate) fn fold_access_recording(
+        &self,
+        block: &Block,
+        recorder: Arc<std::sync::Mutex<Vec<ethers::types::Log>>>,
+    ) -> Arc<FoldMiddleware<M>> {
+        let middleware = FoldMiddleware::new(
+            Arc::clone(&self.inner_middleware),
+            block.hash,
+            self.retry_policy,
+            self.batch_transport.clone(),
+        )
+        .with_block_number(block.number)
+        .with_gate(self.request_gate.clone())
+        .with_clock(Arc::clone(&self.clock))
+        .with_log_pagination(self.log_pagination.clone())
+        .with_log_recorder(recorder);
+
+        Arc::new(middleware)
+    }
+
+    /// Freezes the chain tip at `block`, for reproducible analysis. Every
+    /// query relative to the tip (`Latest`, `BlockDepth`, `Confirmations`,
+    /// and the safe tip of the confirmation policy) then resolves against it,
+    /// whatever the node reports, and tags the node resolves past it, such as
+    /// `safe` and `finalized`, are clamped to it. Relative blocks are those of
+    /// its branch, even once it's reorged out, and queries of explicit blocks
+    /// are unaffected. See `clear_pinned_tip`.
+    pub fn with_pinned_tip(mut self, block: Arc<Block>) -> Self {
+        self.pinned_tip = Some(block);
+        self
+    }
+
+    /// Follows the live tip again, the default.
+    pub fn clear_pinned_tip(&mut self) {
+        self.pinned_tip = None;
+    }
+
+    /// Ancestor of `tip` with `number`, such as that of the pinned tip.
+    /// Fetched by number while `tip` is on the canonical chain, and by walking
+    /// its parents once it's reorged out.
+    pub(crate) async fn ancestor_at(
+        &self,
+        tip: &Arc<Block>,
+        number: U64,
     ) -> Result<Arc<Block>, BlockArchiveError<M>> {
-        Ok(Arc::new(
-            fetch_block_at_depth(self.inner_middleware.as_ref(), current, depth).await?,
-        ))
+        if self.block_with_number(tip.number).await?.hash == tip.hash {
+            return self.block_with_number(number).await;
+        }
+
+        let mut block = Arc::clone(tip);
+        while block.number > number {
+            block = self.block_with_hash(&block.parent_hash).await?;
+        }
+
+        Ok(block)
+    }
+
+    pub(crate) async fn current_block_number(&self) -> Result<U64, BlockArchiveError<M>> {
+        if let Some(tip) = &self.pinned_tip {
+            Ok(tip.number)
+        } else if let Some(a) = &self.block_archive {
+            Ok(a.latest_block().await.number)
+        } else {
+            current_block_number(self.inner_middleware.as_ref()).await
+        }
+    }
+
+    /// Policy deciding which blocks are safe to `sync` on: the configured
+    /// `confirmation_policy`, or `ConfirmationPolicy::Depth(safety_margin)`.
+    pub fn confirmation_policy(&self) -> ConfirmationPolicy {
+        self.confirmation_policy
+            .unwrap_or(ConfirmationPolicy::Depth(self.safety_margin))
+    }
+
+    /// Number of the latest block considered safe by the confirmation policy.
+    pub(crate) async fn safe_block_number<F: Foldable + 'static>(
+        &self,
+        initial_state: &F::InitialState,
+    ) -> Result<U64, FoldableError<M, F>> {
+        let policy = self.confirmation_policy();
+
+        let depth_block = match policy.depth() {
+            Some(depth) => {
+                let current = self
+                    .current_block_number()
+                    .await
+                    .context(BlockArchiveSnafu)?;
+
+                let block = current.checked_sub(depth.into());
+                if block.is_some() || policy == ConfirmationPolicy::Either(depth) {
+                    block
+                } else if self.clamp_safety_margin {
+                    let genesis = self.fold_genesis_block::<F>(initial_state).min(current);
+                    if !self.clamp_warned.swap(true, Ordering::Relaxed) {
+                        tracing::warn!(
+                            "Safety margin `{}` clamped to genesis `{}`, the chain height being `{}`",
+                            depth,
+                            genesis,
+                            current
+                        );
+                    }
+
+                    Some(genesis)
+                } else {
+                    return SafetyMarginTooLargeSnafu {
+                        safety_margin: depth,
+                        current,
+                    }
+                    .fail();
+                }
+            }
+
+            None => None,
+        };
+
+        let finalized_block = if policy.uses_finalized() {
+            let finalized = self
+                .tagged_block(BlockNumber::Finalized)
+                .await
+                .context(BlockArchiveSnafu)?;
+
+            Some(finalized.number)
+        } else {
+            None
+        };
+
+        let safe_block = match policy {
+            ConfirmationPolicy::Depth(_) => depth_block,
+            ConfirmationPolicy::Finalized => finalized_block,
+            ConfirmationPolicy::Both(_) => std::cmp::min(depth_block, finalized_block),
+            ConfirmationPolicy::Either(_) => std::cmp::max(depth_block, finalized_block),
+        };
+
+        Ok(safe_block.expect("policy should yield a safe block"))
+    }
+
+    pub(crate) async fn current_block(&self) -> Result<Arc<Block>, BlockArchiveError<M>> {
+        if let Some(tip) = &self.pinned_tip {
+            Ok(Arc::clone(tip))
+        } else if let Some(a) = &self.block_archive {
+            Ok(a.latest_block().await)
+        } else {
+            self.block(BlockNumber::Latest).await
+        }
+    }
+
+    pub async fn block_with_hash(&self, hash: &H256) -> Result<Arc<Block>, BlockArchiveError<M>> {
+        if let Some(a) = &self.block_archive {
+            a.block_with_hash(hash).await
+        } else {
+            self.block(*hash).await
+        }
+    }
+
+    /// Latest block shared by the branches of `a` and `b`, or `None` if there's
+    /// none within `max_depth` blocks of the higher of the two. Useful for
+    /// custom rewind logic on reorgs.
+    pub async fn common_ancestor(
+        &self,
+        a: &H256,
+        b: &H256,
+        max_depth: usize,
+    ) -> Result<Option<Arc<Block>>, BlockArchiveError<M>> {
+        match &self.block_archive {
+            Some(archive) => archive.common_ancestor(a, b, max_depth).await,
+            None => common_ancestor(a, b, max_depth, |hash| self.block(hash)).await,
+        }
+    }
+
+    pub async fn block_with_number(&self, number: U64) -> Result<Arc<Block>, BlockArchiveError<M>> {
+        if let Some(a) = &self.block_archive {
+            a.block_with_number(number).await
+        } else {
+            self.block(number).await
+        }
+    }
+
+    pub(crate) async fn block_at_depth(
+        &self,
+        depth: usize,
+    ) -> Result<Arc<Block>, BlockArchiveError<M>> {
+        if let Some(tip) = &self.pinned_tip {
+            let number =
+                tip.number
+                    .checked_sub(depth.into())
+                    .ok_or(BlockArchiveError::DepthTooHigh {
+                        depth,
+                        latest: tip.number.as_usize(),
+                    })?;
+
+            self.ancestor_at(tip, number).await
+        } else if let Some(a) = &self.block_archive {
+            a.block_at_depth(depth).await
+        } else {
+            let current = self.current_block_number().await?;
+            self.fetch_block_at_depth(current, depth).await
+        }
+    }
+
+    /// Block of the tag `tag`, clamped to the pinned tip, if any.
+    async fn tagged_block(&self, tag: BlockNumber) -> Result<Arc<Block>, BlockArchiveError<M>> {
+        let block = self.block(tag).await?;
+
+        match &self.pinned_tip {
+            Some(tip) if block.number > tip.number => Ok(Arc::clone(tip)),
+            _ => Ok(block),
+        }
+    }
+
+    /// Permit of the `request_gate` for fetching a block. Fetches the gate
+    /// drops while waiting are sent regardless, as block errors have no
+    /// variant for it.
+    async fn block_permit(&self) -> Option<GatePermit> {
+        request_gate::acquire::<M>(&self.request_gate)
+            .await
+            .ok()
+            .flatten()
+    }
+
+    async fn block<T: Into<BlockId> + Send + Sync>(
+        &self,
+        block: T,
+    ) -> Result<Arc<Block>, BlockArchiveError<M>> {
+        budget::spend_current();
+        let _permit = self.block_permit().await;
+
+        Ok(Arc::new(
+            fetch_block_with_retry(
+                self.inner_middleware.as_ref(),
+                block,
+                self.incomplete_block_retry,
+                self.clock.as_ref(),
+            )
+            .await?,
+        ))
+    }
+
+    async fn fetch_block_at_depth(
+        &self,
+        current: U64,
+        depth: usize,
+    ) -> Result<Arc<Block>, BlockArchiveError<M>> {
+        budget::spend_current();
+        let _permit = self.block_permit().await;
+
+        Ok(Arc::new(
+            fetch_block_at_depth_with_retry(
+                self.inner_middleware.as_ref(),
+                current,
+                depth,
+                self.incomplete_block_retry,
+                self.clock.as_ref(),
+            )
+            .await?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::delegate_access::budget::RpcBudget;
+    use crate::error::FoldableError;
+    use crate::test_utils::mocks::{
+        BaseFeeFold, BloomFold, CanonicalFold, ChattyFold, CountingFold, DeployedFold, FoldCounts,
+        GrowingFold, IncrementFold, LabeledFold, LabeledInitialState, LogCountFold,
+        MutableUserData, NestedErrors, PingFold, ScaledFold, SelfDestructFold, SnapshotFold,
+        SumFold, WATCHED_ADDRESS,
+    };
+    use crate::{
+        AccessError, BlockResolver, Clock, ComputeSource, ManualClock, Priority, RequestGate,
+        Retryability, SampleSpec, StandardResolver, StateFoldEnvironment, Stateless, Validity,
+    };
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use eth_block_history::BlockArchiveError;
+    use eth_state_fold_test::mock_middleware::{MockError, MockMiddleware};
+    use eth_state_fold_types::ethereum_types::BloomInput;
+    use eth_state_fold_types::ethers::types::{
+        BlockId, BlockNumber, Bloom, Filter, Log, H256, U64,
+    };
+    use eth_state_fold_types::{BlockState, BlockStreamItem, QueryBlock};
+    use futures::StreamExt;
+
+    const INITIAL_VALUE: u64 = 42;
+    const SAFETY_MARGIN: usize = 8;
+
+    fn new_env(
+        m: &Arc<MockMiddleware>,
+        safety_margin: usize,
+        genesis: u64,
+    ) -> StateFoldEnvironment<MockMiddleware, ()> {
+        StateFoldEnvironment::new(
+            Arc::clone(m),
+            None,
+            safety_margin,
+            genesis.into(),
+            vec![],
+            1,
+            usize::MAX,
+            (),
+        )
+    }
+
+    #[tokio::test]
+    async fn compute_source_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+
+        let get_source = |query| async {
+            env.get_state_for_block_instrumented::<IncrementFold>(&INITIAL_VALUE, query)
+                .await
+                .unwrap()
+                .1
+        };
+
+        assert_eq!(
+            get_source(QueryBlock::Latest).await,
+            ComputeSource::ColdSync {
+                from_genesis: 0.into()
+            }
+        );
+        assert_eq!(
+            get_source(QueryBlock::Latest).await,
+            ComputeSource::CacheHit
+        );
+
+        let latest = m.get_latest_block().await.unwrap();
+        m.add_block(latest.hash).await.unwrap();
+        assert_eq!(
+            get_source(QueryBlock::Latest).await,
+            ComputeSource::IncrementalFold {
+                from_block: 128.into()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn genesis_at_tip_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 128);
+
+        let block_state = env
+            .get_state_for_block::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap();
+
+        assert_eq!(block_state.block.number, 128.into());
+        assert_eq!(block_state.state.n, 128 + INITIAL_VALUE);
+
+        // State was built by `sync` on genesis, without folding from an
+        // earlier block.
+        let train = env
+            .global_archive
+            .get_archive::<IncrementFold>()
+            .await
+            .get_train(&INITIAL_VALUE)
+            .await;
+        let parent = env.block_with_number(127.into()).await.unwrap();
+        assert!(train.get_block_state(parent).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn before_genesis_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 128);
+
+        let err = env
+            .get_state_for_block::<IncrementFold>(
+                &INITIAL_VALUE,
+                QueryBlock::BlockNumber(127.into()),
+            )
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            err,
+            FoldableError::BlockBeforeGenesis { block, genesis }
+                if block == 127.into() && genesis == 128.into()
+        ));
+    }
+
+    #[tokio::test]
+    async fn empty_chain_test() {
+        let m = MockMiddleware::new(0).await;
+
+        let env = new_env(&m, 0, 0);
+        let block_state = env
+            .get_state_for_block::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap();
+        assert_eq!(block_state.block.number, 0.into());
+        assert_eq!(block_state.state.n, INITIAL_VALUE);
+
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+        let err = env
+            .get_state_for_block::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, FoldableError::SafetyMarginTooLarge { .. }));
+    }
+
+    #[tokio::test]
+    async fn pinned_tip_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+        let pinned = env.block_with_number(100.into()).await.unwrap();
+        let mut env = env.with_pinned_tip(Arc::clone(&pinned));
+
+        let mut tip = m.get_latest_block().await.unwrap().hash;
+        for _ in 0..10 {
+            tip = m.add_block(tip).await.unwrap();
+        }
+
+        // Relative to the pin, as the chain advances.
+        let number = |block_state: BlockState<IncrementFold>| block_state.block.number;
+        let safe = env
+            .get_latest_safe_state::<IncrementFold>(&INITIAL_VALUE)
+            .await
+            .unwrap();
+        assert_eq!(number(safe), 92.into());
+
+        for (query, expected) in [
+            (QueryBlock::Latest, 100),
+            (QueryBlock::BlockDepth(3), 97),
+            (QueryBlock::Confirmations(5), 96),
+        ] {
+            let block_state = env
+                .get_state_for_block::<IncrementFold>(&INITIAL_VALUE, query)
+                .await
+                .unwrap();
+            assert_eq!(number(block_state), expected.into());
+        }
+
+        // Explicit blocks past the pin are still reachable.
+        let block_state = env
+            .get_state_for_block::<IncrementFold>(
+                &INITIAL_VALUE,
+                QueryBlock::BlockNumber(110.into()),
+            )
+            .await
+            .unwrap();
+        assert_eq!(number(block_state), 110.into());
+
+        // Relative to the branch of the pin once it's reorged out.
+        let fork = env.block_with_number(95.into()).await.unwrap();
+        let mut tip = fork.hash;
+        for _ in 0..40 {
+            tip = m.add_block(tip).await.unwrap();
+        }
+        let branch = |block_state: BlockState<IncrementFold>| block_state.block.hash;
+        let safe = env
+            .get_latest_safe_state::<IncrementFold>(&INITIAL_VALUE)
+            .await
+            .unwrap();
+        assert_eq!(
+            branch(safe),
+            m.get_block_with_number_from(92.into(), pinned.hash)
+                .await
+                .unwrap()
+                .hash
+        );
+        for depth in [0, 3] {
+            let block_state = env
+                .get_state_for_block::<IncrementFold>(&INITIAL_VALUE, QueryBlock::BlockDepth(depth))
+                .await
+                .unwrap();
+            let expected = m
+                .get_block_with_number_from((100 - depth as u64).into(), pinned.hash)
+                .await
+                .unwrap();
+            assert_eq!(branch(block_state), expected.hash);
+            assert_ne!(
+                expected.hash,
+                env.block_with_number(expected.number).await.unwrap().hash
+            );
+        }
+
+        // Cleared, following the live tip again.
+        env.clear_pinned_tip();
+        let safe = env
+            .get_latest_safe_state::<IncrementFold>(&INITIAL_VALUE)
+            .await
+            .unwrap();
+        assert_eq!(number(safe), 127.into());
+    }
+
+    #[tokio::test]
+    async fn pinned_tip_sync_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+        let pinned = env.block_with_number(120.into()).await.unwrap();
+        let env = env.with_pinned_tip(Arc::clone(&pinned));
+
+        // Reorged out below the sync block, before anything is cached.
+        let fork = env.block_with_number(100.into()).await.unwrap();
+        let mut tip = fork.hash;
+        for _ in 0..40 {
+            tip = m.add_block(tip).await.unwrap();
+        }
+
+        // Synced on the branch of the pin.
+        let block_state = env
+            .get_state_for_block::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap();
+        assert_eq!(block_state.block.hash, pinned.hash);
+
+        let sync_block = m
+            .get_block_with_number_from(112.into(), pinned.hash)
+            .await
+            .unwrap();
+        assert_ne!(
+            sync_block.hash,
+            env.block_with_number(112.into()).await.unwrap().hash
+        );
+        let cached = env.cached_blocks::<IncrementFold>(&INITIAL_VALUE).await;
+        assert_eq!(cached[0], (sync_block.number, sync_block.hash));
+        assert_eq!(cached.len(), 9);
+    }
+
+    #[tokio::test]
+    async fn series_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+
+        let series = env
+            .series::<IncrementFold>(
+                &INITIAL_VALUE,
+                QueryBlock::BlockNumber(100.into()),
+                QueryBlock::BlockNumber(110.into()),
+                SampleSpec::EveryNBlocks(2),
+            )
+            .await
+            .unwrap();
+
+        let points: Vec<_> = series
+            .iter()
+            .map(|block_state| (block_state.block.number.as_u64(), block_state.state.n))
+            .collect();
+        let expected: Vec<_> = (100..=110)
+            .step_by(2)
+            .map(|number| (number, number + INITIAL_VALUE))
+            .collect();
+        assert_eq!(points, expected);
+
+        // Every block of the range is cached.
+        let cached = env.cached_blocks::<IncrementFold>(&INITIAL_VALUE).await;
+        assert!((100..=110).all(|number| cached.iter().any(|(n, _)| *n == number.into())));
+
+        // Empty reversed ranges, and failing ranges across branches.
+        let series = env
+            .series::<IncrementFold>(
+                &INITIAL_VALUE,
+                QueryBlock::BlockNumber(110.into()),
+                QueryBlock::BlockNumber(100.into()),
+                SampleSpec::EveryBlock,
+            )
+            .await
+            .unwrap();
+        assert!(series.is_empty());
+
+        let to = m.get_block_with_number(110.into()).await.unwrap().hash;
+        let parent = m.get_block_with_number(104.into()).await.unwrap().hash;
+        let uncle = m.add_block(parent).await.unwrap();
+        let err = env
+            .series::<IncrementFold>(
+                &INITIAL_VALUE,
+                QueryBlock::BlockHash(uncle),
+                QueryBlock::BlockHash(to),
+                SampleSpec::EveryBlock,
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, FoldableError::SeriesRangeNotOnChain { .. }));
+    }
+
+    #[tokio::test]
+    async fn clamp_safety_margin_test() {
+        let m = MockMiddleware::new(2).await;
+        let mut env = new_env(&m, 4, 0);
+        let err = env
+            .get_latest_safe_state::<IncrementFold>(&INITIAL_VALUE)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, FoldableError::SafetyMarginTooLarge { .. }));
+
+        // Synced on genesis, folded up to the tip.
+        env.clamp_safety_margin = true;
+        let block_state = env
+            .get_latest_safe_state::<IncrementFold>(&INITIAL_VALUE)
+            .await
+            .unwrap();
+        assert_eq!(block_state.block.number, 0.into());
+        assert_eq!(block_state.state.n, INITIAL_VALUE);
+
+        let block_state = env
+            .get_state_for_block::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap();
+        assert_eq!(block_state.block.number, 2.into());
+        assert_eq!(block_state.state.n, 2 + INITIAL_VALUE);
+
+        // Clamped to the environment's genesis.
+        let mut env = new_env(&m, 4, 1);
+        env.clamp_safety_margin = true;
+        let block_state = env
+            .get_latest_safe_state::<IncrementFold>(&INITIAL_VALUE)
+            .await
+            .unwrap();
+        assert_eq!(block_state.block.number, 1.into());
+
+        // Or to that of the fold, if it declares one.
+        let mut env = new_env(&m, 4, 0);
+        env.clamp_safety_margin = true;
+        let block_state = env
+            .get_latest_safe_state::<DeployedFold>(&1.into())
+            .await
+            .unwrap();
+        assert_eq!(block_state.block.number, 1.into());
+    }
+
+    #[tokio::test]
+    async fn cached_blocks_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+
+        assert!(env
+            .cached_blocks::<IncrementFold>(&INITIAL_VALUE)
+            .await
+            .is_empty());
+
+        for n in [124u64, 128, 126] {
+            env.get_state_for_block::<IncrementFold>(
+                &INITIAL_VALUE,
+                QueryBlock::BlockNumber(n.into()),
+            )
+            .await
+            .unwrap();
+        }
+
+        // Synced on block `128 - SAFETY_MARGIN`, folded up to block 128.
+        let mut expected = vec![];
+        for n in 120u64..=128 {
+            let block = env.block_with_number(n.into()).await.unwrap();
+            expected.push((block.number, block.hash));
+        }
+
+        assert_eq!(
+            env.cached_blocks::<IncrementFold>(&INITIAL_VALUE).await,
+            expected
+        );
+        assert!(env
+            .cached_blocks::<IncrementFold>(&(INITIAL_VALUE + 1))
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn initial_state_key_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+
+        let a = LabeledInitialState {
+            value: INITIAL_VALUE,
+            label: "a",
+        };
+        let b = LabeledInitialState {
+            value: INITIAL_VALUE,
+            label: "b",
+        };
+
+        // Keyed on the whole initial state by default.
+        env.get_state_for_block::<LabeledFold>(&a, QueryBlock::Latest)
+            .await
+            .unwrap();
+        assert!(env.cached_blocks::<LabeledFold>(&b).await.is_empty());
+
+        // Ignoring the label, both initial states share the cache.
+        env.set_initial_state_key::<LabeledFold, _>(|s| s.value)
+            .await;
+        assert!(env.cached_blocks::<LabeledFold>(&a).await.is_empty());
+
+        let state_a = env
+            .get_state_for_block::<LabeledFold>(&a, QueryBlock::Latest)
+            .await
+            .unwrap()
+            .state;
+        let state_b = env
+            .get_state_for_block::<LabeledFold>(&b, QueryBlock::Latest)
+            .await
+            .unwrap()
+            .state;
+
+        assert!(Arc::ptr_eq(&state_a, &state_b));
+        assert_eq!(
+            env.cached_blocks::<LabeledFold>(&a).await,
+            env.cached_blocks::<LabeledFold>(&b).await
+        );
+    }
+
+    #[tokio::test]
+    async fn fold_from_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+
+        let block = env.block_with_number(5.into()).await.unwrap();
+        let prior = BlockState {
+            state: Arc::new(IncrementFold {
+                low_hash: block.hash.to_low_u64_be(),
+                n: 5 + INITIAL_VALUE,
+                initial_state: INITIAL_VALUE,
+            }),
+            block,
+            chain_id: Some(1337.into()),
+        };
+
+        let block_state = env
+            .fold_from(&INITIAL_VALUE, prior, QueryBlock::BlockNumber(10.into()))
+            .await
+            .unwrap();
+        assert_eq!(block_state.state.n, 10 + INITIAL_VALUE);
+
+        // Folded from the prior state, without syncing.
+        let cached = env.cached_blocks::<IncrementFold>(&INITIAL_VALUE).await;
+        let cached: Vec<_> = cached.into_iter().map(|(n, _)| n.as_u64()).collect();
+        assert_eq!(cached, (5..=10).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn fold_from_reorged_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+
+        // Uncle of block 5, with the main chain extended afterwards.
+        let latest = m.get_latest_block().await.unwrap();
+        let base = env.block_with_number(4.into()).await.unwrap();
+        let uncle = m.add_block(base.hash).await.unwrap();
+        let block = Arc::new(m.get_block(uncle).await.unwrap());
+        m.add_block(latest.hash).await.unwrap();
+
+        let prior = BlockState {
+            state: Arc::new(IncrementFold {
+                low_hash: block.hash.to_low_u64_be(),
+                n: 5 + INITIAL_VALUE,
+                initial_state: INITIAL_VALUE,
+            }),
+            block,
+            chain_id: Some(1337.into()),
+        };
+
+        let err = env
+            .fold_from(&INITIAL_VALUE, prior, QueryBlock::BlockNumber(10.into()))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            FoldableError::PriorStateReorged { block } if block == uncle
+        ));
+    }
+
+    #[tokio::test]
+    async fn fold_from_chain_mismatch_test() {
+        let m = MockMiddleware::new(128).await;
+        let other = MockMiddleware::new(128).await;
+        other.set_chain_id(5.into()).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+        let other_env = new_env(&other, SAFETY_MARGIN, 0);
+
+        // States are tagged with the chain of their environment.
+        let prior = other_env
+            .get_state_for_block::<IncrementFold>(&INITIAL_VALUE, QueryBlock::BlockNumber(5.into()))
+            .await
+            .unwrap();
+        assert_eq!(prior.chain_id, Some(5.into()));
+
+        let err = env
+            .fold_from(
+                &INITIAL_VALUE,
+                prior.clone(),
+                QueryBlock::BlockNumber(10.into()),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            FoldableError::ChainMismatch { expected, found }
+                if expected == 1337.into() && found == 5.into()
+        ));
+        assert!(env
+            .cached_blocks::<IncrementFold>(&INITIAL_VALUE)
+            .await
+            .is_empty());
+
+        // Untagged states are trusted only once tagged.
+        let prior = BlockState {
+            chain_id: None,
+            ..prior
+        };
+        let err = env
+            .fold_from(
+                &INITIAL_VALUE,
+                prior.clone(),
+                QueryBlock::BlockNumber(10.into()),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, FoldableError::UntaggedPriorState {}));
+
+        let prior = BlockState {
+            chain_id: Some(env.chain_id().await.unwrap()),
+            ..prior
+        };
+        let block_state = env
+            .fold_from(&INITIAL_VALUE, prior, QueryBlock::BlockNumber(10.into()))
+            .await
+            .unwrap();
+        assert_eq!(block_state.chain_id, Some(1337.into()));
+    }
+
+    #[tokio::test]
+    async fn revalidate_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+
+        let block_state = env
+            .get_state_for_block::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap();
+        assert!(env.is_canonical(block_state.block.hash).await.unwrap());
+        assert_eq!(
+            env.revalidate(&block_state).await.unwrap(),
+            Validity::Canonical
+        );
+
+        // Reorg out block 128.
+        let uncle = m.add_block(block_state.block.parent_hash).await.unwrap();
+        m.add_block(uncle).await.unwrap();
+
+        assert!(!env.is_canonical(block_state.block.hash).await.unwrap());
+        assert!(env.is_canonical(uncle).await.unwrap());
+        assert!(matches!(
+            env.revalidate(&block_state).await.unwrap(),
+            Validity::Reorged { canonical } if canonical.hash == uncle
+        ));
+    }
+
+    #[tokio::test]
+    async fn reorg_hook_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+
+        let rollbacks = Arc::new(std::sync::Mutex::new(vec![]));
+        let hook_rollbacks = Arc::clone(&rollbacks);
+        env.on_reorg::<IncrementFold>(move |_, ancestor, orphaned| {
+            let orphaned: Vec<_> = orphaned
+                .iter()
+                .map(|s| (s.block.number.as_u64(), s.state.n))
+                .collect();
+            hook_rollbacks
+                .lock()
+                .unwrap()
+                .push((ancestor.number.as_u64(), orphaned));
+        })
+        .await;
+
+        let get_latest = || async {
+            env.get_state_for_block::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Latest)
+                .await
+                .unwrap()
+        };
+
+        let block_state = get_latest().await;
+        m.add_block(block_state.block.hash).await.unwrap();
+        get_latest().await;
+        assert!(rollbacks.lock().unwrap().is_empty());
+
+        // Reorg out blocks 128 and 129.
+        let mut tip = block_state.block.parent_hash;
+        for _ in 0..3 {
+            tip = m.add_block(tip).await.unwrap();
+        }
+
+        let block_state = get_latest().await;
+        assert_eq!(block_state.block.number, 130.into());
+        assert_eq!(
+            *rollbacks.lock().unwrap(),
+            vec![(
+                127,
+                vec![(129, 129 + INITIAL_VALUE), (128, 128 + INITIAL_VALUE)]
+            )]
+        );
+
+        // Neither older blocks nor the same head are reorgs.
+        env.get_state_for_block::<IncrementFold>(
+            &INITIAL_VALUE,
+            QueryBlock::BlockNumber(100.into()),
+        )
+        .await
+        .unwrap();
+        get_latest().await;
+        assert_eq!(rollbacks.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reorg_hook_unavailable_orphans_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+
+        let rollbacks = Arc::new(std::sync::Mutex::new(vec![]));
+        let hook_rollbacks = Arc::clone(&rollbacks);
+        env.on_reorg::<CanonicalFold>(move |_, ancestor, orphaned| {
+            let orphaned: Vec<_> = orphaned.iter().map(|s| s.block.number.as_u64()).collect();
+            hook_rollbacks
+                .lock()
+                .unwrap()
+                .push((ancestor.number.as_u64(), orphaned));
+        })
+        .await;
+
+        env.get_state_for_block::<CanonicalFold>(&(), QueryBlock::BlockNumber(110.into()))
+            .await
+            .unwrap();
+
+        // Reorg out blocks 101 to 128, of which only 110 has a state.
+        let mut tip = m.get_block_with_number(100.into()).await.unwrap().hash;
+        for _ in 0..30 {
+            tip = m.add_block(tip).await.unwrap();
+        }
+
+        // The orphans that can no longer be synced are skipped.
+        let get_latest = || async {
+            env.get_state_for_block::<CanonicalFold>(&(), QueryBlock::Latest)
+                .await
+                .unwrap()
+        };
+        assert_eq!(get_latest().await.block.hash, tip);
+        assert_eq!(*rollbacks.lock().unwrap(), vec![(100, vec![110])]);
+
+        // The head still advanced.
+        get_latest().await;
+        assert_eq!(rollbacks.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn fold_genesis_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+        let deployment = U64::from(100);
+
+        env.get_state_for_block::<DeployedFold>(&deployment, QueryBlock::Latest)
+            .await
+            .unwrap();
+
+        let requests = m.log_requests().await;
+        assert!(!requests.is_empty());
+        for filter in requests {
+            assert_eq!(filter.get_from_block(), Some(deployment));
+        }
+
+        let err = env
+            .get_state_for_block::<DeployedFold>(&deployment, QueryBlock::BlockNumber(99.into()))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            FoldableError::BlockBeforeGenesis { block, genesis }
+                if block == 99.into() && genesis == deployment
+        ));
+
+        // Folds without a declared genesis use the environment's.
+        let state = env
+            .get_state_for_block::<IncrementFold>(
+                &INITIAL_VALUE,
+                QueryBlock::BlockNumber(99.into()),
+            )
+            .await
+            .unwrap();
+        assert_eq!(state.state.n, 99 + INITIAL_VALUE);
+    }
+
+    #[tokio::test]
+    async fn rpc_budget_test() {
+        let m = MockMiddleware::new(16).await;
+        let logs = (1..=16u64)
+            .map(|n| Log {
+                block_number: Some(n.into()),
+                log_index: Some(n.into()),
+                ..Default::default()
+            })
+            .collect();
+        m.set_logs(logs).await;
+
+        // Responses of at most two logs, so syncing needs many partitions.
+        let mut env =
+            StateFoldEnvironment::new(Arc::clone(&m), None, 0, 0.into(), vec![], 1, 2, ());
+        env.rpc_budget = Some(4);
+
+        let err = env
+            .get_state_for_block::<DeployedFold>(&U64::zero(), QueryBlock::Latest)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            FoldableError::RpcBudgetExceeded { budget: 4 }
+        ));
+        assert!(env
+            .cached_blocks::<DeployedFold>(&U64::zero())
+            .await
+            .is_empty());
+
+        let block_state = env
+            .get_state_for_block_with_budget::<DeployedFold>(
+                &U64::zero(),
+                QueryBlock::Latest,
+                Some(64),
+            )
+            .await
+            .unwrap();
+        assert_eq!(block_state.state.logs, 16);
+    }
+
+    #[tokio::test]
+    async fn rpc_budget_retries_test() {
+        use eth_state_fold_types::ethers::providers::Middleware;
+
+        let m = MockMiddleware::new(16).await;
+        let mut env = new_env(&m, 0, 0);
+        env.retry_policy.classifier = |_: &MockError| Retryability::Retry;
+        env.retry_policy.backoff = Duration::ZERO;
+
+        let block = m.get_latest_block().await.unwrap();
+        let get_logs = |limit| {
+            let access = env.fold_access_with_budget(&block, Some(Arc::new(RpcBudget::new(limit))));
+            async move { access.get_logs(&Filter::new()).await }
+        };
+
+        // Each retry spends the budget, and none is attempted past it.
+        m.fail_next_requests(1).await;
+        assert!(get_logs(2).await.is_ok());
+        assert_eq!(m.log_requests().await.len(), 2);
+
+        m.fail_next_requests(2).await;
+        assert!(matches!(
+            get_logs(2).await,
+            Err(AccessError::RpcBudgetExceeded {})
+        ));
+        assert_eq!(m.log_requests().await.len(), 4);
+
+        // So do the blocks fetched by the environment, such as the latest one.
+        env.get_state_for_block::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap();
+        let err = env
+            .get_state_for_block_with_budget::<IncrementFold>(
+                &INITIAL_VALUE,
+                QueryBlock::Latest,
+                Some(0),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            FoldableError::RpcBudgetExceeded { budget: 0 }
+        ));
+        assert!(env
+            .get_state_for_block_with_budget::<IncrementFold>(
+                &INITIAL_VALUE,
+                QueryBlock::Latest,
+                Some(1),
+            )
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn pipe_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 100);
+
+        let blocks = futures::stream::iter([
+            QueryBlock::BlockNumber(120.into()),
+            QueryBlock::BlockNumber(121.into()),
+            QueryBlock::BlockNumber(99.into()),
+            QueryBlock::BlockNumber(128.into()),
+        ]);
+
+        // A single slot, so each state is received before the next is folded.
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let pipe = env.pipe::<IncrementFold, _>(&INITIAL_VALUE, blocks, tx);
+
+        let receive = async {
+            let mut received = vec![];
+            while let Some(result) = rx.recv().await {
+                received.push(result.map(|s| s.state.n));
+            }
+            received
+        };
+
+        let ((), received) = futures::join!(pipe, receive);
+
+        assert_eq!(received.len(), 4);
+        assert_eq!(received[0].as_ref().unwrap(), &(120 + INITIAL_VALUE));
+        assert_eq!(received[1].as_ref().unwrap(), &(121 + INITIAL_VALUE));
+        assert!(matches!(
+            received[2],
+            Err(FoldableError::BlockBeforeGenesis { .. })
+        ));
+        assert_eq!(received[3].as_ref().unwrap(), &(128 + INITIAL_VALUE));
+    }
+
+    #[tokio::test]
+    async fn fold_namespace_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+
+        let increment = env
+            .get_state_for_block::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap();
+        let scaled = env
+            .get_state_for_block::<ScaledFold>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap();
+
+        assert_eq!(increment.state.n, 128 + INITIAL_VALUE);
+        assert_eq!(scaled.state.n, 128 * INITIAL_VALUE);
+    }
+
+    #[tokio::test]
+    async fn block_tags_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+        m.set_safe_block(124.into()).await;
+
+        let block_state = env
+            .get_state_for_block::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Safe)
+            .await
+            .unwrap();
+        assert_eq!(block_state.block.number, 124.into());
+        assert_eq!(block_state.state.n, 124 + INITIAL_VALUE);
+
+        let err = env
+            .get_state_for_block::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Pending)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, FoldableError::PendingBlockUnsupported {}));
+    }
+
+    #[tokio::test]
+    async fn pending_block_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+        let latest = m.get_latest_block().await.unwrap();
+
+        let pending = env
+            .get_state_for_pending_block::<IncrementFold>(&INITIAL_VALUE)
+            .await
+            .unwrap();
+        assert_eq!(pending.parent.block.hash, latest.hash);
+        assert_eq!(pending.block.number, latest.number + 1);
+        assert_eq!(pending.block.hash, H256::zero());
+        assert_eq!(pending.state.n, latest.number.as_u64() + 1 + INITIAL_VALUE);
+
+        // Only its parent is cached.
+        let cached = env.cached_blocks::<IncrementFold>(&INITIAL_VALUE).await;
+        assert!(cached.iter().any(|(_, hash)| *hash == latest.hash));
+        assert!(cached.iter().all(|(number, _)| *number <= latest.number));
+
+        // Folded again as the pending block changes.
+        let tip = m.add_block(latest.hash).await.unwrap();
+        let pending = env
+            .get_state_for_pending_block::<IncrementFold>(&INITIAL_VALUE)
+            .await
+            .unwrap();
+        assert_eq!(pending.parent.block.hash, tip);
+        assert_eq!(pending.state.n, latest.number.as_u64() + 2 + INITIAL_VALUE);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pending_logs_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+        let latest = m.get_latest_block().await.unwrap();
+
+        // Pending logs lack their number and index, as nodes may answer them.
+        m.set_logs(vec![Log {
+            block_hash: Some(latest.hash),
+            block_number: Some(latest.number),
+            log_index: Some(0.into()),
+            ..Default::default()
+        }])
+        .await;
+        m.set_pending_logs(vec![Log::default(); 3]).await;
+
+        let pending = env
+            .get_state_for_pending_block::<Stateless<LogCountFold>>(&())
+            .await
+            .unwrap();
+        assert_eq!(pending.parent.state.state.logs, 1);
+        assert_eq!(pending.state.state.logs, 3);
+
+        // Fails if a block is mined while folding, moving the pending block
+        // onto another parent.
+        m.set_log_latency(|_| Duration::from_secs(1)).await;
+        let (result, tip) = tokio::join!(
+            env.get_state_for_pending_block::<Stateless<LogCountFold>>(&()),
+            async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                m.add_block(latest.hash).await.unwrap()
+            }
+        );
+        assert!(matches!(
+            result,
+            Err(FoldableError::PendingBlockMoved { expected, found })
+                if expected == latest.hash && found == tip
+        ));
+    }
+
+    #[tokio::test]
+    async fn track_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = Arc::new(new_env(&m, SAFETY_MARGIN, 0));
+        let (heads, new_heads) = futures::channel::mpsc::unbounded();
+        let tracker = env.track::<IncrementFold, _>(INITIAL_VALUE, new_heads);
+
+        for _ in 0..4 {
+            let latest = m.get_latest_block().await.unwrap();
+            let hash = m.add_block(latest.hash).await.unwrap();
+            let safe = latest.number + 1 - SAFETY_MARGIN;
+
+            let head = env.block_with_hash(&hash).await.unwrap();
+            heads
+                .unbounded_send(BlockStreamItem::NewBlock(head))
+                .unwrap();
+
+            // Wait for the tracker to fold the new safe block.
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while env
+                    .cached_blocks::<IncrementFold>(&INITIAL_VALUE)
+                    .await
+                    .last()
+                    .map(|(number, _)| *number)
+                    != Some(safe)
+                {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            })
+            .await
+            .expect("tracker didn't fold the new safe block");
+
+            let (block_state, source) = env
+                .get_state_for_block_instrumented::<IncrementFold>(
+                    &INITIAL_VALUE,
+                    QueryBlock::BlockNumber(safe),
+                )
+                .await
+                .unwrap();
+            assert_eq!(source, ComputeSource::CacheHit);
+            assert_eq!(block_state.state.n, safe.as_u64() + INITIAL_VALUE);
+        }
+
+        tracker.stop().await;
+
+        let latest = m.get_latest_block().await.unwrap();
+        let hash = m.add_block(latest.hash).await.unwrap();
+        let head = env.block_with_hash(&hash).await.unwrap();
+        assert!(heads
+            .unbounded_send(BlockStreamItem::NewBlock(head))
+            .is_err());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let (_, source) = env
+            .get_state_for_block_instrumented::<IncrementFold>(
+                &INITIAL_VALUE,
+                QueryBlock::BlockNumber(latest.number + 1 - SAFETY_MARGIN),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(source, ComputeSource::IncrementalFold { .. }));
+    }
+
+    #[tokio::test]
+    async fn confirmations_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+
+        // The latest block has one confirmation, as `BlockDepth(0)`.
+        for confirmations in [1, 4, 100, 128] {
+            let block_state = env
+                .get_state_for_block::<IncrementFold>(
+                    &INITIAL_VALUE,
+                    QueryBlock::Confirmations(confirmations),
+                )
+                .await
+                .unwrap();
+            assert_eq!(block_state.block.number, (129 - confirmations).into());
+            assert_eq!(block_state.state.n, 129 - confirmations + INITIAL_VALUE);
+
+            let depth = env
+                .get_state_for_block::<IncrementFold>(
+                    &INITIAL_VALUE,
+                    QueryBlock::BlockDepth(confirmations as usize - 1),
+                )
+                .await
+                .unwrap();
+            assert_eq!(depth.block, block_state.block);
+        }
+
+        // The pending block has none.
+        let err = env
+            .get_state_for_block::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Confirmations(0))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, FoldableError::PendingBlockUnsupported {}));
+
+        let err = env
+            .get_state_for_block::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Confirmations(129))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            FoldableError::ConfirmationsTooHigh { confirmations, current }
+                if confirmations == 129 && current == 128.into()
+        ));
+    }
+
+    #[tokio::test]
+    async fn incomplete_block_test() {
+        let m = MockMiddleware::new(128).await;
+        let mut env = new_env(&m, SAFETY_MARGIN, 0);
+        let clock = Arc::new(ManualClock::new());
+        env.clock = clock.clone();
+        let delay = env.incomplete_block_retry.delay;
+
+        // Each retry waits for the clock to move past its delay.
+        m.return_incomplete_blocks(2).await;
+        let start = clock.now();
+        let block_state = {
+            let query = env.get_state_for_block::<IncrementFold>(
+                &INITIAL_VALUE,
+                QueryBlock::BlockNumber(100.into()),
+            );
+            futures::pin_mut!(query);
+
+            loop {
+                tokio::select! {
+                    result = &mut query => break result.unwrap(),
+                    _ = tokio::task::yield_now() => clock.advance(delay),
+                }
+            }
+        };
+        assert!(clock.now() - start >= 2 * delay);
+        assert_eq!(block_state.block.number, 100.into());
+        assert_eq!(block_state.state.n, 100 + INITIAL_VALUE);
+
+        env.incomplete_block_retry.max_retries = 0;
+        m.return_incomplete_blocks(1).await;
+        let err = env
+            .get_state_for_block::<IncrementFold>(
+                &INITIAL_VALUE,
+                QueryBlock::BlockNumber(101.into()),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            FoldableError::BlockArchiveError {
+                source: BlockArchiveError::BlockIncomplete { .. }
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn common_ancestor_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+
+        let fork = m.get_block_with_number(124.into()).await.unwrap().hash;
+        let a = m.add_block(fork).await.unwrap();
+        let mut b = fork;
+        for _ in 0..3 {
+            b = m.add_block(b).await.unwrap();
+        }
+
+        let ancestor = env.common_ancestor(&a, &b, 8).await.unwrap();
+        assert_eq!(ancestor.unwrap().hash, fork);
+        let ancestor = env.common_ancestor(&a, &b, 2).await.unwrap();
+        assert!(ancestor.is_none());
+    }
+
+    #[tokio::test]
+    async fn block_resolver_test() {
+        use eth_state_fold_types::ethers::providers::Middleware;
+        struct VerifiedResolver;
+
+        #[async_trait::async_trait]
+        impl BlockResolver<MockMiddleware> for VerifiedResolver {
+            async fn resolve(
+                &self,
+                middleware: &MockMiddleware,
+                target: &str,
+            ) -> Result<Option<BlockId>, <MockMiddleware as Middleware>::Error> {
+                if target != "verified" {
+                    return StandardResolver.resolve(middleware, target).await;
+                }
+
+                Ok(Some(BlockNumber::Number(100.into()).into()))
+            }
+        }
+
+        let m = MockMiddleware::new(128).await;
+        let mut env = new_env(&m, SAFETY_MARGIN, 0);
+
+        let query = |target: &str| QueryBlock::Custom(target.to_owned());
+
+        let err = env
+            .get_state_for_block::<IncrementFold>(&INITIAL_VALUE, query("verified"))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            FoldableError::UnknownQueryTarget { target } if target == "verified"
+        ));
+
+        env.block_resolver = Arc::new(VerifiedResolver);
+
+        let block_state = env
+            .get_state_for_block::<IncrementFold>(&INITIAL_VALUE, query("verified"))
+            .await
+            .unwrap();
+        assert_eq!(block_state.block.number, 100.into());
+        assert_eq!(block_state.state.n, 100 + INITIAL_VALUE);
+
+        let block_state = env
+            .get_state_for_block::<IncrementFold>(&INITIAL_VALUE, query("latest"))
+            .await
+            .unwrap();
+        assert_eq!(block_state.block.number, 128.into());
+    }
+
+    #[tokio::test]
+    async fn base_fee_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+
+        for n in 118..=120 {
+            let hash = m.get_block_with_number(n.into()).await.unwrap().hash;
+            m.set_block_fees(hash, Some(n.into()), 21_000.into()).await;
+        }
+
+        let block_state = env
+            .get_state_for_block::<BaseFeeFold>(&(), QueryBlock::BlockNumber(118.into()))
+            .await
+            .unwrap();
+        assert_eq!(block_state.block.base_fee_per_gas, Some(118.into()));
+        assert_eq!(block_state.block.gas_used, Some(21_000.into()));
+        assert_eq!(block_state.state.total, 118.into());
+
+        let block_state = env
+            .get_state_for_block::<BaseFeeFold>(&(), QueryBlock::Latest)
+            .await
+            .unwrap();
+        assert_eq!(block_state.block.base_fee_per_gas, None);
+        assert_eq!(block_state.state.total, (118 + 119 + 120).into());
+    }
+
+    #[tokio::test]
+    async fn advance_to_safe_tip_test() {
+        let m = MockMiddleware::new(13).await;
+        let env = StateFoldEnvironment::new(
+            Arc::clone(&m),
+            None,
+            SAFETY_MARGIN,
+            0.into(),
+            vec![],
+            1,
+            usize::MAX,
+            FoldCounts::default(),
+        );
+        let counts = || {
+            let counts = env.user_data();
+            (
+                counts.syncs.load(Ordering::SeqCst),
+                counts.folds.load(Ordering::SeqCst),
+            )
+        };
+
+        // Synced on the safe block 5.
+        let block_state = env.advance_to_safe_tip::<CountingFold>(&()).await.unwrap();
+        assert_eq!(block_state.block.number, 5.into());
+        assert_eq!(counts(), (1, 0));
+
+        // Folded from 5 up to the new safe block 9.
+        let mut tip = m.get_latest_block().await.unwrap().hash;
+        for _ in 0..4 {
+            tip = m.add_block(tip).await.unwrap();
+        }
+        let block_state = env.advance_to_safe_tip::<CountingFold>(&()).await.unwrap();
+        assert_eq!(block_state.block.number, 9.into());
+        assert_eq!(counts(), (1, 4));
+
+        // Already at the safe tip.
+        let block_state = env.advance_to_safe_tip::<CountingFold>(&()).await.unwrap();
+        assert_eq!(block_state.block.number, 9.into());
+        assert_eq!(counts(), (1, 4));
+    }
+
+    #[tokio::test]
+    async fn block_header_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+
+        let mut bloom = Bloom::zero();
+        bloom.accrue(BloomInput::Raw(WATCHED_ADDRESS.as_bytes()));
+        for n in [120, 124] {
+            let hash = m.get_block_with_number(n.into()).await.unwrap().hash;
+            m.set_logs_bloom(hash, bloom).await;
+            m.set_block_fees(hash, Some(n.into()), 21_000.into()).await;
+        }
+
+        // Synced on 120, folded on 124, and cached.
+        for n in [120, 124, 124] {
+            let block_state = env
+                .get_state_for_block::<IncrementFold>(
+                    &INITIAL_VALUE,
+                    QueryBlock::BlockNumber(n.into()),
+                )
+                .await
+                .unwrap();
+
+            let block = m.get_block_with_number(n.into()).await.unwrap();
+            let parent = m.get_block_with_number((n - 1).into()).await.unwrap();
+            assert_eq!(block_state.block.hash, block.hash);
+            assert_eq!(block_state.block.parent_hash, parent.hash);
+            assert_eq!(block_state.block.timestamp, block.timestamp);
+            assert_eq!(block_state.block.logs_bloom, bloom);
+            assert_eq!(block_state.block.base_fee_per_gas, Some(n.into()));
+            assert_eq!(block_state.block.gas_used, Some(21_000.into()));
+        }
+    }
+
+    #[tokio::test]
+    async fn terminal_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+        m.self_destruct(WATCHED_ADDRESS, 124.into()).await;
+
+        // Synced on 120, folded up to 124, where it turns terminal.
+        let block_state = env
+            .get_state_for_block::<SelfDestructFold>(&WATCHED_ADDRESS, QueryBlock::Latest)
+            .await
+            .unwrap();
+        assert_eq!(block_state.block.number, 128.into());
+        assert!(!block_state.state.alive);
+        assert_eq!(block_state.state.checks, 5);
+
+        // Blocks past the terminal one aren't cached, except the leaf.
+        let cached: Vec<u64> = env
+            .cached_blocks::<SelfDestructFold>(&WATCHED_ADDRESS)
+            .await
+            .into_iter()
+            .map(|(n, _)| n.as_u64())
+            .collect();
+        assert_eq!(cached, vec![120, 121, 122, 123, 124, 128]);
+
+        let state_at = |n: u64| {
+            let env = &env;
+            async move {
+                env.get_state_for_block::<SelfDestructFold>(
+                    &WATCHED_ADDRESS,
+                    QueryBlock::BlockNumber(n.into()),
+                )
+                .await
+                .unwrap()
+                .state
+            }
+        };
+
+        assert!(state_at(123).await.alive);
+        let terminal = state_at(124).await;
+        assert!(!terminal.alive);
+
+        // Later blocks carry the terminal state, without folding it.
+        for n in 125..=128 {
+            assert!(Arc::ptr_eq(&state_at(n).await, &terminal));
+        }
+    }
+
+    #[tokio::test]
+    async fn relevant_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+
+        let mut bloom = Bloom::zero();
+        bloom.accrue(BloomInput::Raw(WATCHED_ADDRESS.as_bytes()));
+        for n in [122, 125] {
+            let hash = m.get_block_with_number(n.into()).await.unwrap().hash;
+            m.set_logs_bloom(hash, bloom).await;
+        }
+
+        // Synced on 120, folding only 122 and 125.
+        let block_state = env
+            .get_state_for_block::<BloomFold>(&(), QueryBlock::Latest)
+            .await
+            .unwrap();
+        assert_eq!(block_state.block.number, 128.into());
+        assert_eq!(block_state.state.folds, 2);
+
+        // Skipped blocks carry the previous state, under their own block.
+        for (n, folds) in [(121, 0), (122, 1), (124, 1), (125, 2), (127, 2)] {
+            let block_state = env
+                .get_state_for_block::<BloomFold>(&(), QueryBlock::BlockNumber(n.into()))
+                .await
+                .unwrap();
+            assert_eq!(block_state.block.number, n.into());
+            assert_eq!(block_state.state.folds, folds);
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_invalidation_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+
+        env.get_state_for_block::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap();
+        let cached = env.cached_blocks::<IncrementFold>(&INITIAL_VALUE).await;
+        assert_eq!(cached.len(), 9);
+
+        // Installing the hook keeps the states already cached.
+        let invalidations = Arc::new(std::sync::Mutex::new(vec![]));
+        let hook_invalidations = Arc::clone(&invalidations);
+        env.on_cache_invalidation::<IncrementFold>(move |initial_state, blocks| {
+            hook_invalidations
+                .lock()
+                .unwrap()
+                .push((*initial_state, blocks.to_vec()));
+        })
+        .await;
+        assert_eq!(
+            env.cached_blocks::<IncrementFold>(&INITIAL_VALUE).await,
+            cached
+        );
+
+        // Fork on block 124, orphaning the cached states of 125 to 128.
+        let mut tip = m.get_block_with_number(124.into()).await.unwrap().hash;
+        for _ in 0..5 {
+            tip = m.add_block(tip).await.unwrap();
+        }
+
+        env.get_state_for_block::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap();
+
+        let orphaned = cached[5..].to_vec();
+        assert_eq!(
+            *invalidations.lock().unwrap(),
+            vec![(INITIAL_VALUE, orphaned.clone())]
+        );
+
+        let cached = env.cached_blocks::<IncrementFold>(&INITIAL_VALUE).await;
+        assert!(orphaned.iter().all(|block| !cached.contains(block)));
+        assert_eq!(cached.last().unwrap().1, tip);
+
+        // Queries on the same branch invalidate nothing.
+        let latest = m.get_latest_block().await.unwrap();
+        m.add_block(latest.hash).await.unwrap();
+        env.get_state_for_block::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap();
+        assert_eq!(invalidations.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn orphan_eviction_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+
+        env.get_state_for_block::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap();
+        let cached = env.cached_blocks::<IncrementFold>(&INITIAL_VALUE).await;
+
+        // Orphaned states are dropped without any hook installed.
+        let mut tip = m.get_block_with_number(124.into()).await.unwrap().hash;
+        for _ in 0..5 {
+            tip = m.add_block(tip).await.unwrap();
+        }
+
+        env.get_state_for_block::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap();
+
+        let now_cached = env.cached_blocks::<IncrementFold>(&INITIAL_VALUE).await;
+        assert!(cached[5..].iter().all(|block| !now_cached.contains(block)));
+        assert_eq!(now_cached.last().unwrap().1, tip);
+    }
+
+    #[tokio::test]
+    async fn estimate_cost_test() {
+        let m = MockMiddleware::new(128).await;
+        let mut env = new_env(&m, SAFETY_MARGIN, 0);
+
+        let estimate = env
+            .estimate_cost::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap();
+        assert_eq!(
+            estimate.source,
+            ComputeSource::ColdSync {
+                from_genesis: 0.into()
+            }
+        );
+        assert_eq!(estimate.fold_blocks, 8);
+        assert_eq!(estimate.sync_blocks, 120);
+
+        // The query sends no more requests than estimated, block fetches
+        // included, as it runs within a budget of the estimate.
+        env.rpc_budget = Some(estimate.calls as usize);
+        let (_, source) = env
+            .get_state_for_block_instrumented::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap();
+        assert_eq!(source, estimate.source);
+        assert!(m.log_requests().await.len() as u64 <= estimate.calls);
+
+        let estimate = env
+            .estimate_cost::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap();
+        assert_eq!(estimate.source, ComputeSource::CacheHit);
+        assert_eq!(estimate.calls, 0);
+
+        let mut tip = m.get_latest_block().await.unwrap().hash;
+        for _ in 0..10 {
+            tip = m.add_block(tip).await.unwrap();
+        }
+
+        let estimate = env
+            .estimate_cost::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap();
+        assert_eq!(
+            estimate.source,
+            ComputeSource::IncrementalFold {
+                from_block: 128.into()
+            }
+        );
+        assert_eq!(estimate.fold_blocks, 10);
+        assert_eq!(estimate.sync_blocks, 0);
+
+        // Estimating doesn't fold.
+        assert_eq!(
+            env.cached_blocks::<IncrementFold>(&INITIAL_VALUE)
+                .await
+                .len(),
+            9
+        );
+
+        let log_requests = m.log_requests().await.len();
+        env.rpc_budget = Some(estimate.calls as usize);
+        let (_, source) = env
+            .get_state_for_block_instrumented::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap();
+        assert_eq!(source, estimate.source);
+        assert!((m.log_requests().await.len() - log_requests) as u64 <= estimate.calls);
+    }
+
+    #[cfg(any(feature = "json", feature = "bincode"))]
+    async fn cache_round_trip(format: crate::CacheFormat) {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+
+        let expected = env
+            .get_state_for_block::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap();
+        let data = env
+            .export_cache::<IncrementFold>(&INITIAL_VALUE, format)
+            .await
+            .unwrap();
+
+        let restored = new_env(&m, SAFETY_MARGIN, 0);
+        let imported = restored
+            .import_cache::<IncrementFold>(&INITIAL_VALUE, &data, format)
+            .await
+            .unwrap();
+        assert_eq!(imported, 9);
+        assert_eq!(
+            restored
+                .cached_blocks::<IncrementFold>(&INITIAL_VALUE)
+                .await,
+            env.cached_blocks::<IncrementFold>(&INITIAL_VALUE).await
+        );
+
+        let (block_state, source) = restored
+            .get_state_for_block_instrumented::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap();
+        assert_eq!(source, ComputeSource::CacheHit);
+        assert_eq!(block_state.block, expected.block);
+        assert_eq!(block_state.state, expected.state);
+
+        // Rejected on other chains.
+        let other = MockMiddleware::new(128).await;
+        other.set_chain_id(5.into()).await;
+        let err = new_env(&other, SAFETY_MARGIN, 0)
+            .import_cache::<IncrementFold>(&INITIAL_VALUE, &data, format)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, crate::CacheExportError::ChainMismatch { .. }));
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn json_cache_test() {
+        use crate::{CacheExportError, CacheFormat};
+
+        cache_round_trip(CacheFormat::Json).await;
+
+        // Exports taken before any query are tagged too.
+        let m = MockMiddleware::new(128).await;
+        let other = MockMiddleware::new(128).await;
+        other.set_chain_id(5.into()).await;
+        let data = new_env(&other, SAFETY_MARGIN, 0)
+            .export_cache::<IncrementFold>(&INITIAL_VALUE, CacheFormat::Json)
+            .await
+            .unwrap();
+        let err = new_env(&m, SAFETY_MARGIN, 0)
+            .import_cache::<IncrementFold>(&INITIAL_VALUE, &data, CacheFormat::Json)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, CacheExportError::ChainMismatch { .. }));
+
+        // Exports of version 1, without the chain id, are still imported.
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+        env.get_state_for_block::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap();
+        let data = env
+            .export_cache::<IncrementFold>(&INITIAL_VALUE, CacheFormat::Json)
+            .await
+            .unwrap();
+        let header = b"SFCACHE".len();
+        let (_, entries): (serde_json::Value, serde_json::Value) =
+            serde_json::from_slice(&data[header + 2..]).unwrap();
+        let mut v1 = data[..header].to_vec();
+        v1.extend([1, data[header + 1]]);
+        v1.extend(serde_json::to_vec(&entries).unwrap());
+
+        let imported = new_env(&m, SAFETY_MARGIN, 0)
+            .import_cache::<IncrementFold>(&INITIAL_VALUE, &v1, CacheFormat::Json)
+            .await
+            .unwrap();
+        assert_eq!(imported, 9);
+    }
+
+    #[cfg(feature = "bincode")]
+    #[tokio::test]
+    async fn bincode_cache_test() {
+        cache_round_trip(crate::CacheFormat::Bincode).await;
+    }
+
+    #[cfg(all(feature = "json", feature = "bincode"))]
+    #[tokio::test]
+    async fn cache_format_mismatch_test() {
+        use crate::{CacheExportError, CacheFormat};
+
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+        env.get_state_for_block::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap();
+
+        let data = env
+            .export_cache::<IncrementFold>(&INITIAL_VALUE, CacheFormat::Json)
+            .await
+            .unwrap();
+
+        let restored = new_env(&m, SAFETY_MARGIN, 0);
+        let err = restored
+            .import_cache::<IncrementFold>(&INITIAL_VALUE, &data, CacheFormat::Bincode)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            CacheExportError::FormatMismatch {
+                expected: CacheFormat::Bincode,
+                found: 1
+            }
+        ));
+
+        let err = restored
+            .import_cache::<IncrementFold>(&INITIAL_VALUE, b"{}", CacheFormat::Json)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, CacheExportError::NotACacheExport {}));
+        assert!(restored
+            .cached_blocks::<IncrementFold>(&INITIAL_VALUE)
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn user_data_snapshot_test() {
+        let m = MockMiddleware::new(128).await;
+        let mut env = StateFoldEnvironment::new(
+            Arc::clone(&m),
+            None,
+            SAFETY_MARGIN,
+            0.into(),
+            vec![],
+            1,
+            usize::MAX,
+            MutableUserData::default(),
+        );
+        *env.live_user_data().value.lock().unwrap() = 7;
+
+        // Every fold reads the user data, then mutates the live one, which
+        // folds see without snapshots.
+        let block_state = env
+            .get_state_for_block::<SnapshotFold>(&(), QueryBlock::Latest)
+            .await
+            .unwrap();
+        assert_eq!(block_state.state.seen, (7..16).collect::<Vec<_>>());
+        assert_eq!(*env.user_data().value.lock().unwrap(), 16);
+
+        let mut tip = m.get_latest_block().await.unwrap().hash;
+        for _ in 0..3 {
+            tip = m.add_block(tip).await.unwrap();
+        }
+
+        // With snapshots, every fold of a query sees the user data as it
+        // started.
+        env.enable_user_data_snapshots();
+        let block_state = env
+            .get_state_for_block::<SnapshotFold>(&(), QueryBlock::Latest)
+            .await
+            .unwrap();
+        assert_eq!(block_state.state.seen[SAFETY_MARGIN + 1..], [16; 3]);
+
+        // Outside of queries, the user data is live.
+        assert_eq!(*env.user_data().value.lock().unwrap(), 19);
+    }
+
+    #[tokio::test]
+    async fn number_and_hash_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+        let block = env.block_with_number(100.into()).await.unwrap();
+
+        let block_state = env
+            .get_state_for_block::<IncrementFold>(&INITIAL_VALUE, (block.number, block.hash).into())
+            .await
+            .unwrap();
+        assert_eq!(block_state.block.hash, block.hash);
+        assert_eq!(block_state.state.n, 100 + INITIAL_VALUE);
+
+        let err = env
+            .get_state_for_block::<IncrementFold>(
+                &INITIAL_VALUE,
+                QueryBlock::BlockNumberAndHash(101.into(), block.hash),
+            )
+            .await
+            .unwrap_err();
+        let canonical = env.block_with_number(101.into()).await.unwrap();
+        assert!(matches!(
+            err,
+            FoldableError::HashMismatch { number, expected, actual }
+                if number == 101.into() && expected == block.hash && actual == canonical.hash
+        ));
+    }
+
+    #[tokio::test]
+    async fn priority_test() {
+        let m = MockMiddleware::new(128).await;
+        let gate = RequestGate::new(1);
+        let mut env = new_env(&m, SAFETY_MARGIN, 0);
+        env.request_gate = Some(gate.clone());
+        let env = Arc::new(env);
+
+        let finished = Arc::new(std::sync::Mutex::new(vec![]));
+        let query = |requests: usize, priority: Priority| {
+            let env = Arc::clone(&env);
+            let finished = Arc::clone(&finished);
+            tokio::spawn(async move {
+                env.get_state_for_block_with_priority::<ChattyFold>(
+                    &requests,
+                    QueryBlock::Latest,
+                    priority,
+                )
+                .await
+                .unwrap();
+                finished.lock().unwrap().push(priority);
+            })
+        };
+
+        // A long background sync queues up before the interactive queries.
+        let permit = gate.acquire(Priority::Normal).await;
+        let queries = [
+            query(32, Priority::Low),
+            query(2, Priority::High),
+            query(3, Priority::High),
+        ];
+        while gate.waiting() < 3 {
+            tokio::task::yield_now().await;
+        }
+
+        drop(permit);
+        for query in queries {
+            query.await.unwrap();
+        }
+
+        assert_eq!(
+            *finished.lock().unwrap(),
+            vec![Priority::High, Priority::High, Priority::Low]
+        );
+
+        // Block fetches wait for the gate too.
+        let permit = gate.acquire(Priority::Normal).await;
+        let fetch = env.block(BlockNumber::Latest);
+        tokio::pin!(fetch);
+        assert!(tokio::time::timeout(Duration::from_millis(10), &mut fetch)
+            .await
+            .is_err());
+        drop(permit);
+        assert!(fetch.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn nested_query_test() {
+        let m = MockMiddleware::new(128).await;
+        let query = |max_query_depth, n| {
+            let m = Arc::clone(&m);
+            async move {
+                let mut env = StateFoldEnvironment::new(
+                    m,
+                    None,
+                    SAFETY_MARGIN,
+                    0.into(),
+                    vec![],
+                    1,
+                    usize::MAX,
+                    NestedErrors::default(),
+                );
+                env.max_query_depth = max_query_depth;
+
+                let err = env
+                    .get_state_for_block::<PingFold>(&n, QueryBlock::Latest)
+                    .await
+                    .unwrap_err();
+                assert!(matches!(err, FoldableError::InnerError { .. }));
+
+                let errors = env.user_data().lock().unwrap().clone();
+                errors[0].clone()
+            }
+        };
+
+        // Ping(0) -> Pong(0) -> Ping(0).
+        let cycle = FoldableError::<MockMiddleware, PingFold>::CycleDetected {}.to_string();
+        assert_eq!(query(16, 0).await, cycle);
+
+        // Ping(3) -> Pong(3) -> Ping(2) -> ... -> Ping(0), within the limit.
+        assert_eq!(query(16, 3).await, cycle);
+
+        // Ping(3) -> Pong(3) -> Ping(2) -> Pong(2) -> Ping(1), past it.
+        let depth = FoldableError::<MockMiddleware, PingFold>::DepthExceeded { max_depth: 4 };
+        assert_eq!(query(4, 3).await, depth.to_string());
+    }
+
+    #[tokio::test]
+    async fn consistent_snapshot_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = StateFoldEnvironment::new(
+            Arc::clone(&m),
+            None,
+            SAFETY_MARGIN,
+            0.into(),
+            vec![],
+            1,
+            usize::MAX,
+            Arc::clone(&m),
+        );
+
+        // Every sync and fold produces a block.
+        let states = env
+            .consistent_snapshot::<GrowingFold>(&[1, 2, 3], QueryBlock::Latest)
+            .await
+            .unwrap();
+        let latest = m.get_latest_block().await.unwrap();
+        assert!(latest.number > 128.into());
+
+        assert_eq!(states.len(), 3);
+        for (state, label) in states.iter().zip([1, 2, 3]) {
+            assert_eq!(state.block.number, 128.into());
+            assert_eq!(state.state.label, label);
+        }
+
+        // Separate queries of the latest block see the tip advancing.
+        let first = env
+            .get_state_for_block::<GrowingFold>(&4, QueryBlock::Latest)
+            .await
+            .unwrap();
+        let second = env
+            .get_state_for_block::<GrowingFold>(&5, QueryBlock::Latest)
+            .await
+            .unwrap();
+        assert!(first.block.number < second.block.number);
+    }
+
+    #[tokio::test]
+    async fn explain_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+
+        // Synced on 120, and folded up to 124.
+        let trace = env
+            .explain::<IncrementFold>(&INITIAL_VALUE, QueryBlock::BlockNumber(124.into()))
+            .await
+            .unwrap();
+        assert_eq!(trace.base_block.number, 120.into());
+        assert_eq!(trace.base_state.n, 120 + INITIAL_VALUE);
+
+        let steps: Vec<_> = trace
+            .steps
+            .iter()
+            .map(|step| (step.block.number.as_u64(), step.state.n))
+            .collect();
+        assert_eq!(
+            steps,
+            (121..=124)
+                .map(|n| (n, n + INITIAL_VALUE))
+                .collect::<Vec<_>>()
+        );
+        assert!(trace
+            .steps
+            .iter()
+            .all(|step| step.relevant && step.logs.is_empty()));
+        assert_eq!(trace.state().block.number, 124.into());
+        assert!(trace.base_logs.is_empty());
+        assert!(!trace.mismatched());
+
+        // Later blocks are traced from the same base.
+        let trace = env
+            .explain::<IncrementFold>(&INITIAL_VALUE, QueryBlock::Latest)
+            .await
+            .unwrap();
+        assert_eq!(trace.base_block.number, 120.into());
+        assert_eq!(trace.steps.len(), 8);
+
+        #[cfg(feature = "json")]
+        {
+            let json = serde_json::to_value(&trace).unwrap();
+            assert_eq!(json["steps"].as_array().unwrap().len(), 8);
+            assert_eq!(json["steps"][7]["state"]["n"], 128 + INITIAL_VALUE);
+        }
+
+        // The logs of the sync are recorded, and replayed states differing
+        // from the cached ones flagged.
+        let mut logs = vec![];
+        for n in [110, 122] {
+            let block = m.get_block_with_number(n.into()).await.unwrap();
+            logs.push(Log {
+                block_hash: Some(block.hash),
+                block_number: Some(block.number),
+                log_index: Some(0.into()),
+                ..Default::default()
+            });
+        }
+        m.set_logs(logs.clone()).await;
+        env.get_state_for_block::<SumFold>(&(), QueryBlock::Latest)
+            .await
+            .unwrap();
+
+        m.set_logs(vec![logs[0].clone()]).await;
+        let trace = env
+            .explain::<SumFold>(&(), QueryBlock::Latest)
+            .await
+            .unwrap();
+        assert_eq!(trace.base_logs, vec![logs[0].clone()]);
+        assert!(!trace.base_mismatch);
+
+        let mismatches: Vec<_> = trace
+            .steps
+            .iter()
+            .filter(|step| step.mismatch)
+            .map(|step| step.block.number.as_u64())
+            .collect();
+        assert_eq!(mismatches, (122..=128).collect::<Vec<_>>());
+        assert!(trace.mismatched());
+    }
+
+    #[tokio::test]
+    async fn subscribe_state_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = new_env(&m, SAFETY_MARGIN, 0);
+
+        let mut bloom = Bloom::zero();
+        bloom.accrue(BloomInput::Raw(WATCHED_ADDRESS.as_bytes()));
+        for n in [122, 125] {
+            let hash = m.get_block_with_number(n.into()).await.unwrap().hash;
+            m.set_logs_bloom(hash, bloom).await;
+        }
+
+        let mut items = vec![];
+        for n in 121..=128 {
+            let block = env.block_with_number(n.into()).await.unwrap();
+            items.push(BlockStreamItem::NewBlock(block));
+        }
+
+        // Reorg dropping 125 and its successors, so `BloomFold` folds once
+        // fewer.
+        let base = m.get_block_with_number(124.into()).await.unwrap().hash;
+        let first = m.add_block(base).await.unwrap();
+        let second = m.add_block(first).await.unwrap();
+        let mut reorg = vec![];
+        for hash in [first, second] {
+            reorg.push(env.block_with_hash(&hash).await.unwrap());
+        }
+        items.push(BlockStreamItem::Reorg(reorg));
+
+        // Changes on every block, the reorged ones included.
+        let states: Vec<_> = env
+            .subscribe_state::<IncrementFold>(&INITIAL_VALUE, futures::stream::iter(items.clone()))
+            .map(|result| result.unwrap().block.number.as_u64())
+            .collect()
+            .await;
+        assert_eq!(
+            states,
+            vec![121, 122, 123, 124, 125, 126, 127, 128, 125, 126]
+        );
+
+        // Changes on 122 and 125 only, and back on the reorg.
+        let states: Vec<_> = env
+            .subscribe_state::<BloomFold>(&(), futures::stream::iter(items))
+            .map(|result| {
+                let block_state = result.unwrap();
+                (block_state.block.number.as_u64(), block_state.state.folds)
+            })
+            .collect()
+            .await;
+        assert_eq!(states, vec![(121, 0), (122, 1), (125, 2), (125, 1)]);
     }
 }
@@ -0,0 +1,78 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use eth_state_fold_types::ethers::types::{Log, U256};
+use eth_state_fold_types::{Block, BlockState};
+
+use std::sync::Arc;
+
+/// Replay of the folds leading to a state, as returned by
+/// `StateFoldEnvironment::explain`.
+#[derive(Debug)]
+#[cfg_attr(any(feature = "json", feature = "bincode"), derive(serde::Serialize))]
+pub struct FoldTrace<F> {
+    /// Earliest cached state the target descends from without gaps, either
+    /// synced or seeded, which the replay folds from.
+    pub base_block: Arc<Block>,
+    pub base_state: Arc<F>,
+
+    /// Logs returned to `sync` by the access layer when syncing the base state
+    /// again, in request order.
+    pub base_logs: Vec<Log>,
+
+    /// Whether syncing the base state again gave another state, as when it
+    /// was seeded with a different one, or `sync` isn't deterministic.
+    pub base_mismatch: bool,
+
+    /// Blocks from the one after `base_block` to the target, oldest first.
+    pub steps: Vec<FoldStep<F>>,
+
+    /// Id of the chain the trace was replayed on.
+    pub chain_id: U256,
+}
+
+/// A block of a `FoldTrace`.
+#[derive(Debug)]
+#[cfg_attr(any(feature = "json", feature = "bincode"), derive(serde::Serialize))]
+pub struct FoldStep<F> {
+    pub block: Arc<Block>,
+
+    /// Whether `fold` ran on the block. Irrelevant blocks, and those after a
+    /// terminal state, keep the previous state.
+    pub relevant: bool,
+
+    /// Logs returned to `fold` by the access layer, in request order.
+    pub logs: Vec<Log>,
+
+    /// State after the block.
+    pub state: Arc<F>,
+
+    /// Whether `state` differs from the cached state of the block, as when
+    /// `fold` isn't deterministic. Later steps are folded from the replayed
+    /// state, so they no longer explain the cached ones.
+    pub mismatch: bool,
+}
+
+impl<F> FoldTrace<F> {
+    /// Whether any replayed state differs from the cached one.
+    pub fn mismatched(&self) -> bool {
+        self.base_mismatch || self.steps.iter().any(|step| step.mismatch)
+    }
+
+    /// Final state of the trace.
+    pub fn state(&self) -> BlockState<F> {
+        match self.steps.last() {
+            Some(step) => BlockState {
+                block: Arc::clone(&step.block),
+                state: Arc::clone(&step.state),
+                chain_id: Some(self.chain_id),
+            },
+
+            None => BlockState {
+                block: Arc::clone(&self.base_block),
+                state: Arc::clone(&self.base_state),
+                chain_id: Some(self.chain_id),
+            },
+        }
+    }
+}
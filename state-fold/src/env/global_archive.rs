@@ -12,14 +12,12 @@ use std::sync::Arc;
 use tokio::sync::RwLock;
 
 pub(crate) struct GlobalArchive {
-    safety_margin: usize,
     archives: RwLock<HashMap<TypeId, Arc<dyn Any + Send + Sync + 'static>>>,
 }
 
 impl GlobalArchive {
-    pub fn new(safety_margin: usize) -> Self {
+    pub fn new() -> Self {
         Self {
-            safety_margin,
             archives: RwLock::new(HashMap::new()),
         }
     }
@@ -32,7 +30,7 @@ impl GlobalArchive {
             return archive.clone().downcast::<Archive<F>>().unwrap();
         }
 
-        let new_archive = Arc::new(Archive::new(self.safety_margin));
+        let new_archive = Arc::new(Archive::new());
         self.archives
             .write()
             .await
@@ -40,6 +38,17 @@ impl GlobalArchive {
 
         new_archive
     }
+
+    /// Replaces the archive of `F` with `archive`, discarding its cache.
+    pub(crate) async fn set_archive<F>(&self, archive: Archive<F>)
+    where
+        F: Foldable + Send + Sync + 'static,
+    {
+        self.archives
+            .write()
+            .await
+            .insert(TypeId::of::<Archive<F>>(), Arc::new(archive));
+    }
 }
 
 #[cfg(test)]
@@ -50,7 +59,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_dyn_type() {
-        let global_archive = GlobalArchive::new(42);
+        let global_archive = GlobalArchive::new();
         assert_eq!(0, global_archive.archives.read().await.len());
 
         let archive1 = global_archive.get_archive::<IncrementFold>().await;
@@ -2,8 +2,39 @@
 // SPDX-License-Identifier: Apache-2.0 (see LICENSE)
 
 mod archive;
+mod block_resolver;
+#[cfg(any(feature = "json", feature = "bincode"))]
+mod cache_export;
+mod cache_key;
+mod capabilities;
+mod circuit_breaker;
+mod compute_source;
+mod confirmation_policy;
+mod cost_estimate;
 mod environment;
+mod fold_trace;
 mod global_archive;
+mod pending_block_state;
+mod sample_spec;
+mod state_cache;
+mod tracker;
 mod train;
+mod user_data;
+mod validity;
 
+pub use block_resolver::{BlockResolver, StandardResolver};
+#[cfg(any(feature = "json", feature = "bincode"))]
+pub use cache_export::{CacheExportError, CacheFormat};
+pub use capabilities::ProviderCapabilities;
+pub use circuit_breaker::{CircuitBreakerConfig, CircuitState};
+pub use compute_source::ComputeSource;
+pub use confirmation_policy::ConfirmationPolicy;
+pub use cost_estimate::CostEstimate;
 pub use environment::StateFoldEnvironment;
+pub use fold_trace::{FoldStep, FoldTrace};
+pub use pending_block_state::PendingBlockState;
+pub use sample_spec::SampleSpec;
+pub use state_cache::{MemoryStateCache, StateCache};
+pub use tracker::Tracker;
+pub use user_data::UserDataRef;
+pub use validity::Validity;
@@ -0,0 +1,21 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use eth_state_fold_types::{Block, BlockState};
+
+use std::sync::Arc;
+
+/// Provisional state at the node's pending block, as returned by
+/// `StateFoldEnvironment::get_state_for_pending_block`. Kept apart from
+/// `BlockState`, as it is neither reorg-stable nor deterministic, and is never
+/// cached.
+#[derive(Debug)]
+pub struct PendingBlockState<F> {
+    /// State of the parent of the pending block, cached as usual.
+    pub parent: BlockState<F>,
+
+    /// The pending block. Its hash is zero if the node doesn't give one, and
+    /// its logs bloom is full if the node doesn't give one either.
+    pub block: Arc<Block>,
+    pub state: Arc<F>,
+}
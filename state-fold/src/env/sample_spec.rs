@@ -0,0 +1,58 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use eth_state_fold_types::Block;
+
+use std::sync::Arc;
+
+/// Blocks of a range sampled by `StateFoldEnvironment::series`, always starting
+/// with the first block of the range.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleSpec {
+    EveryBlock,
+
+    /// Every `n`th block, counting from the first one.
+    EveryNBlocks(u64),
+
+    /// The first block at or after each multiple of `secs` seconds since the
+    /// first block, by timestamp. Intervals without blocks aren't sampled.
+    EveryDuration(u64),
+}
+
+impl SampleSpec {
+    /// Sampled blocks of `blocks`, consecutive blocks in ascending order.
+    pub(crate) fn sample(&self, blocks: &[Arc<Block>]) -> Vec<Arc<Block>> {
+        let Some(first) = blocks.first() else {
+            return vec![];
+        };
+
+        match *self {
+            Self::EveryBlock | Self::EveryNBlocks(0 | 1) | Self::EveryDuration(0) => {
+                blocks.to_vec()
+            }
+
+            Self::EveryNBlocks(n) => blocks
+                .iter()
+                .filter(|block| (block.number - first.number).as_u64() % n == 0)
+                .cloned()
+                .collect(),
+
+            Self::EveryDuration(secs) => {
+                let mut next = first.timestamp;
+                blocks
+                    .iter()
+                    .filter(|block| {
+                        if block.timestamp < next {
+                            return false;
+                        }
+
+                        let elapsed = block.timestamp - first.timestamp;
+                        next = first.timestamp + (elapsed / secs + 1) * secs;
+                        true
+                    })
+                    .cloned()
+                    .collect()
+            }
+        }
+    }
+}
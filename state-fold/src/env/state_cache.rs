@@ -0,0 +1,225 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use crate::Foldable;
+
+use eth_state_fold_types::ethers::types::{H256, U64};
+use eth_state_fold_types::Block;
+
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// Storage of the folded states of an initial state, keyed by block.
+///
+/// The environment uses an in-memory cache by default, which can be replaced
+/// with `StateFoldEnvironment::set_state_cache`, e.g. to share states across
+/// service instances or keep them across restarts. Backends storing states out
+/// of process have to serialize them; such backends should be implemented for
+/// `F: Foldable + Serialize + DeserializeOwned`. Note `Block` is serializable.
+#[async_trait]
+pub trait StateCache<F: Foldable>: Send + Sync {
+    /// State of `block`, if cached.
+    async fn get(&self, block: &Block) -> Option<Arc<F>>;
+
+    /// Caches the state of `block`, replacing any previous one.
+    async fn put(&self, block: Arc<Block>, state: Arc<F>);
+
+    /// Drops the states of every block numbered below `number`.
+    async fn invalidate_below(&self, number: U64);
+
+    /// Drops the states of blocks at the heights in `canonical` whose hash
+    /// differs from the canonical hash at that height.
+    async fn invalidate_non_canonical(&self, canonical: &HashMap<U64, H256>);
+
+    /// Number and hash of every block with a cached state, in any order.
+    async fn blocks(&self) -> Vec<(U64, H256)>;
+
+    /// Every cached state with its block, in any order.
+    async fn entries(&self) -> Vec<(Arc<Block>, Arc<F>)>;
+}
+
+/// Default `StateCache`, keeping states in memory.
+pub struct MemoryStateCache<F> {
+    states: RwLock<HashMap<Arc<Block>, Arc<F>>>,
+}
+
+impl<F> MemoryStateCache<F> {
+    pub fn new() -> Self {
+        Self {
+            states: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+impl<F> Default for MemoryStateCache<F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl<F: Foldable> StateCache<F> for MemoryStateCache<F> {
+    async fn get(&self, block: &Block) -> Option<Arc<F>> {
+        self.states.read().await.get(block).cloned()
+    }
+
+    async fn put(&self, block: Arc<Block>, state: Arc<F>) {
+        self.states.write().await.insert(block, state);
+    }
+
+    async fn invalidate_below(&self, number: U64) {
+        self.states
+            .write()
+            .await
+            .retain(|block, _| block.number >= number);
+    }
+
+    async fn invalidate_non_canonical(&self, canonical: &HashMap<U64, H256>) {
+        self.states.write().await.retain(|block, _| {
+            canonical
+                .get(&block.number)
+                .is_none_or(|hash| *hash == block.hash)
+        });
+    }
+
+    async fn blocks(&self) -> Vec<(U64, H256)> {
+        self.states
+            .read()
+            .await
+            .keys()
+            .map(|block| (block.number, block.hash))
+            .collect()
+    }
+
+    async fn entries(&self) -> Vec<(Arc<Block>, Arc<F>)> {
+        self.states
+            .read()
+            .await
+            .iter()
+            .map(|(block, state)| (Arc::clone(block), Arc::clone(state)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StateCache;
+    use crate::test_utils::mocks::IncrementFold;
+    use crate::{ComputeSource, StateFoldEnvironment};
+
+    use eth_state_fold_test::mock_middleware::MockMiddleware;
+    use eth_state_fold_types::ethers::types::{H256, U64};
+    use eth_state_fold_types::{Block, QueryBlock};
+
+    use async_trait::async_trait;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    type States = HashMap<H256, (Arc<Block>, Arc<IncrementFold>)>;
+
+    /// Backend shared by environments, counting the states put into it.
+    #[derive(Clone, Default)]
+    struct SharedCache {
+        states: Arc<Mutex<States>>,
+        puts: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl StateCache<IncrementFold> for SharedCache {
+        async fn get(&self, block: &Block) -> Option<Arc<IncrementFold>> {
+            let states = self.states.lock().unwrap();
+            states.get(&block.hash).map(|(_, state)| Arc::clone(state))
+        }
+
+        async fn put(&self, block: Arc<Block>, state: Arc<IncrementFold>) {
+            self.puts.fetch_add(1, Ordering::SeqCst);
+            self.states
+                .lock()
+                .unwrap()
+                .insert(block.hash, (block, state));
+        }
+
+        async fn invalidate_below(&self, number: U64) {
+            let mut states = self.states.lock().unwrap();
+            states.retain(|_, (block, _)| block.number >= number);
+        }
+
+        async fn invalidate_non_canonical(&self, canonical: &HashMap<U64, H256>) {
+            let mut states = self.states.lock().unwrap();
+            states
+                .retain(|hash, (block, _)| canonical.get(&block.number).is_none_or(|h| h == hash));
+        }
+
+        async fn blocks(&self) -> Vec<(U64, H256)> {
+            let states = self.states.lock().unwrap();
+            states
+                .values()
+                .map(|(block, _)| (block.number, block.hash))
+                .collect()
+        }
+
+        async fn entries(&self) -> Vec<(Arc<Block>, Arc<IncrementFold>)> {
+            let states = self.states.lock().unwrap();
+            states.values().cloned().collect()
+        }
+    }
+
+    async fn new_env(
+        m: &Arc<MockMiddleware>,
+        cache: &SharedCache,
+    ) -> StateFoldEnvironment<MockMiddleware, ()> {
+        let env =
+            StateFoldEnvironment::new(Arc::clone(m), None, 8, 0.into(), vec![], 1, usize::MAX, ());
+
+        let cache = cache.clone();
+        env.set_state_cache::<IncrementFold>(move |_| Box::new(cache.clone()))
+            .await;
+
+        env
+    }
+
+    #[tokio::test]
+    async fn custom_cache_test() {
+        let m = MockMiddleware::new(128).await;
+        let cache = SharedCache::default();
+
+        // Synced on block 120, and folded up to block 128.
+        let env = new_env(&m, &cache).await;
+        let (_, source) = env
+            .get_state_for_block_instrumented::<IncrementFold>(&0, QueryBlock::Latest)
+            .await
+            .unwrap();
+        assert!(matches!(source, ComputeSource::ColdSync { .. }));
+        assert_eq!(cache.puts.load(Ordering::SeqCst), 9);
+        assert_eq!(env.cached_blocks::<IncrementFold>(&0).await.len(), 9);
+
+        // A new environment, sharing the backend, folds from its states.
+        let env = new_env(&m, &cache).await;
+        let (_, source) = env
+            .get_state_for_block_instrumented::<IncrementFold>(&0, QueryBlock::Latest)
+            .await
+            .unwrap();
+        assert_eq!(source, ComputeSource::CacheHit);
+
+        let latest = m.get_latest_block().await.unwrap();
+        m.add_block(latest.hash).await.unwrap();
+        let (block_state, source) = env
+            .get_state_for_block_instrumented::<IncrementFold>(&0, QueryBlock::Latest)
+            .await
+            .unwrap();
+        assert_eq!(
+            source,
+            ComputeSource::IncrementalFold {
+                from_block: 128.into()
+            }
+        );
+        assert_eq!(block_state.state.n, 129);
+        assert_eq!(cache.puts.load(Ordering::SeqCst), 10);
+
+        cache.invalidate_below(125.into()).await;
+        assert_eq!(cache.blocks().await.len(), 5);
+    }
+}
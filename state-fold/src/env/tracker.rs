@@ -0,0 +1,34 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use tokio::task::JoinHandle;
+
+/// Handle of a background task started by `StateFoldEnvironment::track`,
+/// keeping the latest safe state of an initial state folded and cached. The
+/// task stops when the handle is stopped or dropped.
+#[derive(Debug)]
+pub struct Tracker {
+    handle: JoinHandle<()>,
+}
+
+impl Tracker {
+    pub(crate) fn new(handle: JoinHandle<()>) -> Self {
+        Self { handle }
+    }
+
+    /// Stops the background task, waiting for it to finish.
+    pub async fn stop(mut self) {
+        self.handle.abort();
+        let _ = (&mut self.handle).await;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+}
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
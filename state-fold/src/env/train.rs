@@ -1,62 +1,148 @@
 // (c) Cartesi and individual authors (see AUTHORS)
 // SPDX-License-Identifier: Apache-2.0 (see LICENSE)
 
+use crate::delegate_access::budget::RpcBudget;
 use crate::error::*;
 use crate::Foldable;
 
-use super::StateFoldEnvironment;
+use super::circuit_breaker::CircuitBreaker;
+use super::state_cache::StateCache;
+use super::{ComputeSource, StateFoldEnvironment};
 
 use eth_state_fold_types::Block;
 use eth_state_fold_types::BlockState;
 
 use eth_state_fold_types::ethers;
-use ethers::core::types::U64;
+use ethers::core::types::{H256, U64};
 use ethers::providers::Middleware;
 
 use snafu::ResultExt;
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{Mutex, MutexGuard, RwLock};
 
+/// Cache of the states of a fold for an initial state. Its states are
+/// untagged, each environment tagging those it returns with its chain id.
 pub(crate) struct Train<F>
 where
     F: Foldable,
 {
     initial_state: F::InitialState,
-    safety_margin: usize,
-    state_tree: RwLock<HashMap<Arc<Block>, Arc<F>>>,
+    states: Box<dyn StateCache<F>>,
     earliest_block: RwLock<U64>,
     fetch_mutex: Mutex<()>,
+    circuit_breaker: CircuitBreaker,
+
+    /// Highest block whose state was returned, used to detect reorgs.
+    head: Mutex<Option<Arc<Block>>>,
 }
 
 impl<F> Train<F>
 where
     F: Foldable + Send + Sync + 'static,
 {
-    pub fn new(initial_state: F::InitialState, safety_margin: usize) -> Self {
+    #[cfg(test)]
+    pub fn new(initial_state: F::InitialState) -> Self {
         Self {
             initial_state,
-            safety_margin,
-            state_tree: RwLock::new(HashMap::new()),
+            states: Box::new(super::state_cache::MemoryStateCache::new()),
             earliest_block: RwLock::new(U64::max_value()),
             fetch_mutex: Mutex::new(()),
+            circuit_breaker: CircuitBreaker::default(),
+            head: Mutex::new(None),
+        }
+    }
+
+    /// Creates a train storing its states in `states`, which may already hold
+    /// states (e.g. from a previous run) to fold from.
+    pub async fn with_cache(
+        initial_state: F::InitialState,
+        states: Box<dyn StateCache<F>>,
+    ) -> Self {
+        let earliest_block = states
+            .blocks()
+            .await
+            .into_iter()
+            .map(|(number, _)| number)
+            .min()
+            .unwrap_or_else(U64::max_value);
+
+        Self {
+            initial_state,
+            states,
+            earliest_block: RwLock::new(earliest_block),
+            fetch_mutex: Mutex::new(()),
+            circuit_breaker: CircuitBreaker::default(),
+            head: Mutex::new(None),
         }
     }
 
+    pub fn circuit_breaker(&self) -> &CircuitBreaker {
+        &self.circuit_breaker
+    }
+
+    pub async fn head(&self) -> MutexGuard<'_, Option<Arc<Block>>> {
+        self.head.lock().await
+    }
+
     pub async fn get_block_state(&self, block: Arc<Block>) -> Option<BlockState<F>> {
-        self.state_tree
-            .read()
+        self.states.get(&block).await.map(|state| BlockState {
+            block,
+            state,
+            chain_id: None,
+        })
+    }
+
+    /// Number and hash of every block with a cached state, sorted ascending.
+    pub async fn cached_blocks(&self) -> Vec<(U64, H256)> {
+        let mut blocks = self.states.blocks().await;
+        blocks.sort_unstable();
+        blocks
+    }
+
+    /// Every cached state, sorted by block number.
+    #[cfg(any(feature = "json", feature = "bincode"))]
+    pub async fn entries(&self) -> Vec<BlockState<F>> {
+        let mut entries: Vec<_> = self
+            .states
+            .entries()
             .await
-            .get(&block)
-            .cloned()
-            .map(|state| BlockState { block, state })
+            .into_iter()
+            .map(|(block, state)| BlockState {
+                block,
+                state,
+                chain_id: None,
+            })
+            .collect();
+
+        entries.sort_unstable_by_key(|entry| (entry.block.number, entry.block.hash));
+        entries
+    }
+
+    /// Drops the states of blocks at the heights in `canonical` whose hash
+    /// differs from the canonical one.
+    pub async fn invalidate_non_canonical(&self, canonical: &HashMap<U64, H256>) {
+        self.states.invalidate_non_canonical(canonical).await
+    }
+
+    /// Adds a known state to the train, which can then be folded from.
+    pub async fn insert_block_state(&self, block_state: BlockState<F>) {
+        let number = block_state.block.number;
+
+        self.states.put(block_state.block, block_state.state).await;
+
+        let mut earliest_block = self.earliest_block.write().await;
+        *earliest_block = std::cmp::min(*earliest_block, number);
     }
 
+    /// Gets the state of `block`, folding or syncing if not cached. Also
+    /// returns how the state was obtained.
     pub async fn fetch_block_state<M: Middleware + 'static>(
         &self,
         env: &StateFoldEnvironment<M, F::UserData>,
         block: Arc<Block>,
-    ) -> Result<BlockState<F>, FoldableError<M, F>> {
+        budget: &Option<Arc<RpcBudget>>,
+    ) -> Result<(BlockState<F>, ComputeSource), FoldableError<M, F>> {
         // We assume this function will be called close to the latest block
         // and on the "main" chain, instead of on old blocks on "uncle chains".
         // As such we make multiple concurrent calls to fetch mutually
@@ -67,11 +153,29 @@ where
         let _guard = self.fetch_mutex.lock().await;
 
         if let Some(state) = self.get_block_state(Arc::clone(&block)).await {
-            return Ok(state);
+            return Ok((state, ComputeSource::CacheHit));
+        }
+
+        let result = self.fold_to_leaf(env, block, budget).await?;
+
+        if let Some(retention) = env.state_retention {
+            self.drop_states_below(result.0.block.number.saturating_sub(retention.into()))
+                .await;
+        }
+
+        Ok(result)
+    }
+
+    /// Drops the states of blocks numbered below `number`, which are then
+    /// synced again when queried.
+    async fn drop_states_below(&self, number: U64) {
+        let mut earliest_block = self.earliest_block.write().await;
+        if *earliest_block >= number {
+            return;
         }
 
-        let block_state = self.fold_to_leaf(env, block).await?;
-        Ok(block_state)
+        self.states.invalidate_below(number).await;
+        *earliest_block = number;
     }
 }
 
@@ -84,7 +188,8 @@ where
         &self,
         env: &StateFoldEnvironment<M, F::UserData>,
         leaf_block: Arc<Block>,
-    ) -> Result<BlockState<F>, FoldableError<M, F>> {
+        budget: &Option<Arc<RpcBudget>>,
+    ) -> Result<(BlockState<F>, ComputeSource), FoldableError<M, F>> {
         // Build stack of blocks to be processed. We are, in essence, searching
         // for an ancestral block that exists in the archive, saving in a stack
         // the "lineage" of blocks from leaf to this ancestral block.
@@ -113,7 +218,9 @@ where
                 // build an ancestral block by syncing. We call our
                 // `sync_to_margin` helper function, which will get us the
                 // block `leaf - safety_margin` inside the archive.
-                let sync_block = self.sync_to_margin(env, Arc::clone(&leaf_block)).await?;
+                let sync_block = self
+                    .sync_to_margin(env, Arc::clone(&leaf_block), budget)
+                    .await?;
 
                 // The function `sync_to_margin` has added an accumualtor to
                 // the train, using at least `safety_margin` from the current
@@ -146,15 +253,17 @@ where
                 // stack has all the blocks we have to fold on.
 
                 if ancestor_block.number <= sync_block.number {
-                    // ancestor_block = sync_block;
+                    // The stack is folded from the sync block, not from the
+                    // ancestor we've walked past it to.
                     let margin = leaf_block.number - sync_block.number;
                     stack.truncate(margin.as_usize());
+                    ancestor_block = sync_block;
                     break;
                 }
             }
 
             // Check if we've reached a block that we've processed before.
-            if self.state_tree.read().await.contains_key(&ancestor_block) {
+            if self.states.get(&ancestor_block).await.is_some() {
                 break;
             } else {
                 // If we haven't, add it to the stack.
@@ -168,103 +277,148 @@ where
                 .context(BlockArchiveSnafu)?;
         }
 
+        let source = if has_synced {
+            ComputeSource::ColdSync {
+                from_genesis: env.fold_genesis_block::<F>(&self.initial_state),
+            }
+        } else {
+            ComputeSource::IncrementalFold {
+                from_block: ancestor_block.number,
+            }
+        };
+
         // Process each block whose hash was pushed into the stack, in LIFO
         // order.
-        for block in stack.into_iter().rev() {
+        for (i, block) in stack.into_iter().rev().enumerate() {
+            // Folds may not await on anything that yields, so periodically
+            // give other tasks a chance to run.
+            if i > 0 && i % env.fold_yield_interval.max(1) == 0 {
+                tokio::task::yield_now().await;
+            }
+
             // Compute new state. We can guarantee the previous state is in the
             // archive, either because it is the ancestral block we found in
             // the previous step, or because we inserted it in the previous
             // iteration of this loop.
             let new_state = {
-                let state_tree = self.state_tree.read().await;
-                let previous_state = state_tree
+                let previous_state = self
+                    .states
                     .get(&ancestor_block)
+                    .await
                     .ok_or(snafu::NoneError)
                     .context(BlockUnavailableSnafu)?;
 
-                let new_state = F::fold(previous_state, &block, env, env.fold_access(&block))
-                    .await
-                    .context(InnerSnafu)?;
+                // A terminal state is that of every later block, so it's
+                // cached under the leaf alone, without walking the rest.
+                if previous_state.terminal() {
+                    self.states
+                        .put(Arc::clone(&leaf_block), previous_state)
+                        .await;
+                    break;
+                }
+
+                // Irrelevant blocks share the previous state, cached under
+                // their own block.
+                if !F::relevant(&block, env) {
+                    previous_state
+                } else {
+                    #[cfg(any(feature = "profiling", feature = "metrics"))]
+                    let start = env.clock.now();
+
+                    let access = env.fold_access_with_budget(&block, budget.clone());
+                    let new_state = F::fold(&previous_state, &block, env, access).await;
+                    check_budget(budget)?;
+                    let new_state = new_state.context(InnerSnafu)?;
 
-                Arc::new(new_state)
+                    #[cfg(any(feature = "profiling", feature = "metrics"))]
+                    let elapsed = env.clock.now().duration_since(start);
+
+                    #[cfg(feature = "profiling")]
+                    env.profile_fold(&block, elapsed);
+
+                    #[cfg(feature = "metrics")]
+                    crate::metrics::fold_duration("fold", elapsed);
+
+                    Arc::new(new_state)
+                }
             };
 
-            // Add new state to state_tree.
-            self.state_tree
-                .write()
-                .await
-                .insert(Arc::clone(&block), new_state);
+            // Add new state to the cache.
+            self.states.put(Arc::clone(&block), new_state).await;
 
             // Update ancestor block
             ancestor_block = block;
         }
 
-        let state = Arc::clone(
-            self.state_tree
-                .read()
-                .await
-                .get(&leaf_block)
-                .ok_or(snafu::NoneError)
-                .context(BlockUnavailableSnafu)?,
-        );
+        let state = self
+            .states
+            .get(&leaf_block)
+            .await
+            .ok_or(snafu::NoneError)
+            .context(BlockUnavailableSnafu)?;
 
-        Ok(BlockState {
+        let block_state = BlockState {
             state,
             block: leaf_block,
-        })
+            chain_id: None,
+        };
+
+        Ok((block_state, source))
     }
 
     async fn sync_to_margin<M: Middleware + 'static>(
         &self,
         env: &StateFoldEnvironment<M, F::UserData>,
         leaf_block: Arc<Block>,
+        budget: &Option<Arc<RpcBudget>>,
     ) -> Result<Arc<Block>, FoldableError<M, F>> {
-        // Calculate sync block. If `leaf_block` in within the `safety_margin`,
-        // then use `leaf_block`. Otherwise, use current block minus
-        // `safety_margin`.
+        // Calculate sync block. If `leaf_block` is already safe according to
+        // the environment's confirmation policy, then use `leaf_block`.
+        // Otherwise, use the latest safe block, but never a block before
+        // genesis. As such, if genesis is not yet safe (e.g. starting exactly
+        // at the deployment block), we sync on genesis itself.
         let sync_block = {
-            let current: U64 = env
-                .current_block_number()
-                .await
-                .context(BlockArchiveSnafu)?;
-
-            assert!(
-                current > self.safety_margin.into(),
-                "Safety margin greater than blocks in blockchain"
-            );
-            let minimum_sync_block = current - self.safety_margin;
+            let minimum_sync_block = env.safe_block_number::<F>(&self.initial_state).await?;
+            let genesis = env.fold_genesis_block::<F>(&self.initial_state);
+            let minimum_sync_block = std::cmp::max(minimum_sync_block, genesis);
 
             if leaf_block.number <= minimum_sync_block {
                 leaf_block
             } else {
-                // NOTE: There's the assumption that we are asking for a block
-                // on the main path. Otherwise, `get_block_with_number` will
-                // yield an unexpected result.
-                env.block_with_number(minimum_sync_block)
+                // On the branch of `leaf_block`, which may be off the main
+                // chain, e.g. past a pinned tip that has been reorged out.
+                env.ancestor_at(&leaf_block, minimum_sync_block)
                     .await
                     .context(BlockArchiveSnafu)?
             }
         };
 
         // Now create the state with user defined `sync`.
+        let genesis = env.fold_genesis_block::<F>(&self.initial_state);
         let sync_state = {
-            let state = F::sync(
-                &self.initial_state,
-                &sync_block,
-                env,
-                env.sync_access(&sync_block),
-            )
-            .await
-            .context(InnerSnafu)?;
+            #[cfg(any(feature = "profiling", feature = "metrics"))]
+            let start = env.clock.now();
+
+            let access = env.sync_access_from(genesis, &sync_block, budget.clone());
+            let state = F::sync(&self.initial_state, &sync_block, env, access).await;
+
+            check_budget(budget)?;
+            let state = state.context(InnerSnafu)?;
+
+            #[cfg(any(feature = "profiling", feature = "metrics"))]
+            let elapsed = env.clock.now().duration_since(start);
+
+            #[cfg(feature = "profiling")]
+            env.profile_fold(&sync_block, elapsed);
+
+            #[cfg(feature = "metrics")]
+            crate::metrics::fold_duration("sync", elapsed);
 
             Arc::new(state)
         };
 
         // Insert it into the archive.
-        self.state_tree
-            .write()
-            .await
-            .insert(Arc::clone(&sync_block), sync_state);
+        self.states.put(Arc::clone(&sync_block), sync_state).await;
 
         // Finally, update the earliest block with the minimum value between
         // itself and sync block number. Note that it has the initial value of
@@ -283,11 +437,27 @@ where
     }
 }
 
+/// Fails if a request was attempted past `budget`, in which case the result of
+/// the `sync` or `fold` that attempted it is discarded, as it may have seen
+/// missing data instead of failing.
+fn check_budget<M: Middleware, F: Foldable>(
+    budget: &Option<Arc<RpcBudget>>,
+) -> Result<(), FoldableError<M, F>> {
+    match budget {
+        Some(budget) if budget.exceeded() => RpcBudgetExceededSnafu {
+            budget: budget.limit(),
+        }
+        .fail(),
+        _ => Ok(()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::Train;
     use crate::test_utils::mocks::IncrementFold;
-    use crate::StateFoldEnvironment;
+    use crate::{ConfirmationPolicy, StateFoldEnvironment};
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::Arc;
 
     use eth_state_fold_test::mock_middleware::MockMiddleware;
@@ -303,7 +473,7 @@ mod tests {
         Arc<MockMiddleware>,
         StateFoldEnvironment<MockMiddleware, ()>,
     ) {
-        let train = Train::<IncrementFold>::new(INITIAL_VALUE, SAFETY_MARGIN);
+        let train = Train::<IncrementFold>::new(INITIAL_VALUE);
         let m = MockMiddleware::new(128).await;
 
         let env = StateFoldEnvironment::new(
@@ -330,9 +500,10 @@ mod tests {
         assert_eq!(*train.earliest_block.read().await, U64::max_value());
 
         let state = train
-            .fetch_block_state(&env, latest_block.clone())
+            .fetch_block_state(&env, latest_block.clone(), &None)
             .await
             .unwrap()
+            .0
             .state;
 
         assert_eq!(
@@ -350,6 +521,138 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn finalized_policy_test() {
+        let (train, m, mut env) = instantiate_all().await;
+        env.confirmation_policy = Some(ConfirmationPolicy::Finalized);
+        m.set_finalized_block(100.into()).await;
+
+        let latest_block = Arc::new(m.get_latest_block().await.unwrap());
+        let state = train
+            .fetch_block_state(&env, latest_block.clone(), &None)
+            .await
+            .unwrap()
+            .0
+            .state;
+
+        assert_eq!(state.n, 128 + INITIAL_VALUE);
+        assert_eq!(*train.earliest_block.read().await, U64::from(100));
+    }
+
+    #[tokio::test]
+    async fn fold_yield_test() {
+        let train = Train::<IncrementFold>::new(INITIAL_VALUE);
+        let m = MockMiddleware::new(1024).await;
+        let mut env = StateFoldEnvironment::new(
+            Arc::clone(&m),
+            None,
+            512,
+            0.into(),
+            vec![],
+            1,
+            usize::MAX,
+            (),
+        );
+        env.fold_yield_interval = 16;
+
+        // Runs on the same single-threaded runtime as the fold.
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let ticker = tokio::spawn({
+            let ticks = Arc::clone(&ticks);
+            async move {
+                loop {
+                    ticks.fetch_add(1, Ordering::SeqCst);
+                    tokio::task::yield_now().await;
+                }
+            }
+        });
+
+        let latest_block = Arc::new(m.get_latest_block().await.unwrap());
+        let state = train
+            .fetch_block_state(&env, latest_block, &None)
+            .await
+            .unwrap()
+            .0
+            .state;
+        assert_eq!(state.n, 1024 + INITIAL_VALUE);
+
+        // Folded 512 blocks, yielding every 16 of them.
+        assert!(ticks.load(Ordering::SeqCst) >= 512 / 16 - 1);
+        ticker.abort();
+    }
+
+    #[tokio::test]
+    async fn deep_fork_test() {
+        let (train, m, env) = instantiate_all().await;
+
+        // States cached from 92 to 100.
+        let block = Arc::new(m.get_block_with_number(100.into()).await.unwrap());
+        train.fetch_block_state(&env, block, &None).await.unwrap();
+
+        // Fork below the earliest cached state, reaching past it by more than
+        // the safety margin, so that the walk to an ancestor crosses the sync
+        // block.
+        let mut hash = m.get_block_with_number(80.into()).await.unwrap().hash;
+        for _ in 0..30 {
+            hash = m.add_block(hash).await.unwrap();
+        }
+
+        let leaf_block = Arc::new(m.get_latest_block().await.unwrap());
+        assert_eq!(leaf_block.hash, hash);
+
+        let state = train
+            .fetch_block_state(&env, leaf_block, &None)
+            .await
+            .unwrap()
+            .0
+            .state;
+
+        assert_eq!(
+            state.as_ref().clone(),
+            IncrementFold {
+                low_hash: hash.to_low_u64_be(),
+                n: 110 + INITIAL_VALUE,
+                initial_state: INITIAL_VALUE,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn state_retention_test() {
+        let (train, m, mut env) = instantiate_all().await;
+        env.state_retention = Some(4);
+        let numbers = |cached: Vec<(U64, H256)>| -> Vec<u64> {
+            cached.into_iter().map(|(n, _)| n.as_u64()).collect()
+        };
+
+        // Synced on 120, folded up to 128, keeping 124 and up.
+        let block = Arc::new(m.get_latest_block().await.unwrap());
+        train.fetch_block_state(&env, block, &None).await.unwrap();
+        assert_eq!(
+            numbers(train.cached_blocks().await),
+            (124..=128).collect::<Vec<_>>()
+        );
+        assert_eq!(*train.earliest_block.read().await, U64::from(124));
+
+        // Folded forward, dropping states as they fall behind.
+        let mut hash = m.get_latest_block().await.unwrap().hash;
+        for _ in 0..2 {
+            hash = m.add_block(hash).await.unwrap();
+        }
+        let block = Arc::new(m.get_latest_block().await.unwrap());
+        train.fetch_block_state(&env, block, &None).await.unwrap();
+        assert_eq!(
+            numbers(train.cached_blocks().await),
+            (126..=130).collect::<Vec<_>>()
+        );
+
+        // Dropped states are synced again.
+        let block = Arc::new(m.get_block_with_number(110.into()).await.unwrap());
+        let (block_state, source) = train.fetch_block_state(&env, block, &None).await.unwrap();
+        assert_eq!(block_state.state.n, 110 + INITIAL_VALUE);
+        assert!(matches!(source, crate::ComputeSource::ColdSync { .. }));
+    }
+
     #[tokio::test]
     async fn straight_blockchain_test() {
         let (train, m, env) = instantiate_all().await;
@@ -359,9 +662,10 @@ mod tests {
             assert!(train.get_block_state(block.clone()).await.is_none());
 
             let state = train
-                .fetch_block_state(&env, block.clone())
+                .fetch_block_state(&env, block.clone(), &None)
                 .await
                 .unwrap()
+                .0
                 .state;
 
             assert_eq!(
@@ -381,9 +685,10 @@ mod tests {
             assert!(train.get_block_state(block.clone()).await.is_none());
 
             let state = train
-                .fetch_block_state(&env, block.clone())
+                .fetch_block_state(&env, block.clone(), &None)
                 .await
                 .unwrap()
+                .0
                 .state;
 
             assert_eq!(
@@ -403,9 +708,10 @@ mod tests {
             assert!(train.get_block_state(block.clone()).await.is_none());
 
             let state = train
-                .fetch_block_state(&env, block.clone())
+                .fetch_block_state(&env, block.clone(), &None)
                 .await
                 .unwrap()
+                .0
                 .state;
 
             assert_eq!(
@@ -424,9 +730,10 @@ mod tests {
             let block = Arc::new(m.get_block_with_number(i.into()).await.unwrap());
 
             let state = train
-                .fetch_block_state(&env, block.clone())
+                .fetch_block_state(&env, block.clone(), &None)
                 .await
                 .unwrap()
+                .0
                 .state;
 
             assert_eq!(
@@ -464,9 +771,10 @@ mod tests {
             let block = Arc::new(m.get_block_with_number_from(i.into(), tip_a).await.unwrap());
 
             let state = train
-                .fetch_block_state(&env, block.clone())
+                .fetch_block_state(&env, block.clone(), &None)
                 .await
                 .unwrap()
+                .0
                 .state;
 
             assert_eq!(
@@ -484,9 +792,10 @@ mod tests {
             let block = Arc::new(m.get_block_with_number_from(i.into(), tip_b).await.unwrap());
 
             let state = train
-                .fetch_block_state(&env, block.clone())
+                .fetch_block_state(&env, block.clone(), &None)
                 .await
                 .unwrap()
+                .0
                 .state;
 
             assert_eq!(
@@ -504,9 +813,10 @@ mod tests {
             let block = Arc::new(m.get_block_with_number_from(i.into(), tip_c).await.unwrap());
 
             let state = train
-                .fetch_block_state(&env, block.clone())
+                .fetch_block_state(&env, block.clone(), &None)
                 .await
                 .unwrap()
+                .0
                 .state;
 
             assert_eq!(
@@ -524,9 +834,10 @@ mod tests {
             let block = Arc::new(m.get_block_with_number_from(i.into(), tip_d).await.unwrap());
 
             let state = train
-                .fetch_block_state(&env, block.clone())
+                .fetch_block_state(&env, block.clone(), &None)
                 .await
                 .unwrap()
+                .0
                 .state;
 
             assert_eq!(
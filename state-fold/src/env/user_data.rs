@@ -0,0 +1,25 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// User data of an environment, as returned by
+/// `StateFoldEnvironment::user_data`: the snapshot of the current query, if
+/// snapshots are enabled, or the live user data otherwise.
+#[derive(Debug)]
+pub enum UserDataRef<'a, UD> {
+    Live(&'a UD),
+    Snapshot(Arc<UD>),
+}
+
+impl<UD> Deref for UserDataRef<'_, UD> {
+    type Target = UD;
+
+    fn deref(&self) -> &UD {
+        match self {
+            Self::Live(user_data) => user_data,
+            Self::Snapshot(user_data) => user_data,
+        }
+    }
+}
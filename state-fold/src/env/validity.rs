@@ -0,0 +1,17 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use eth_state_fold_types::Block;
+
+use std::sync::Arc;
+
+/// Whether the block of a state is still on the canonical chain, as checked by
+/// `StateFoldEnvironment::revalidate`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Validity {
+    /// The block is canonical.
+    Canonical,
+
+    /// The block has been reorged out in favour of `canonical`.
+    Reorged { canonical: Arc<Block> },
+}
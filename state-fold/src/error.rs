@@ -7,6 +7,7 @@ use eth_block_history::BlockArchiveError;
 use eth_state_fold_types::ethers;
 
 use ethers::providers::{FromErr, Middleware};
+use ethers::types::{H256, U256, U64};
 
 use snafu::Snafu;
 
@@ -28,6 +29,72 @@ pub enum FoldableError<M: Middleware + 'static, F: Foldable + 'static> {
     #[snafu(display("Requested log unavailable"))]
     LogUnavailable {},
 
+    #[snafu(display("Requested block `{}` is before genesis block `{}`", block, genesis))]
+    BlockBeforeGenesis { block: U64, genesis: U64 },
+
+    #[snafu(display(
+        "Safety margin `{}` greater than blocks in blockchain `{}`",
+        safety_margin,
+        current
+    ))]
+    SafetyMarginTooLarge { safety_margin: usize, current: U64 },
+
+    #[snafu(display(
+        "Confirmations `{}` greater than blocks in blockchain `{}`",
+        confirmations,
+        current
+    ))]
+    ConfirmationsTooHigh { confirmations: u64, current: U64 },
+
+    #[snafu(display(
+        "Canonical block `{}` has hash `{}`, expected `{}`",
+        number,
+        actual,
+        expected
+    ))]
+    HashMismatch {
+        number: U64,
+        expected: H256,
+        actual: H256,
+    },
+
+    #[snafu(display("Query target `{}` unknown to the block resolver", target))]
+    UnknownQueryTarget { target: String },
+
+    #[snafu(display("Pending block cannot be folded, as it is not reorg-stable"))]
+    PendingBlockUnsupported {},
+
+    #[snafu(display(
+        "Pending block moved from parent `{}` to `{}` while folding, retry",
+        expected,
+        found
+    ))]
+    PendingBlockMoved { expected: H256, found: H256 },
+
+    #[snafu(display("Prior state block `{}` is no longer canonical, resync", block))]
+    PriorStateReorged { block: H256 },
+
+    #[snafu(display("State of chain `{}` used on chain `{}`", found, expected))]
+    ChainMismatch { expected: U256, found: U256 },
+
+    #[snafu(display("Prior state is not tagged with a chain id"))]
+    UntaggedPriorState {},
+
+    #[snafu(display("Series start `{}` is not an ancestor of its end `{}`", from, to))]
+    SeriesRangeNotOnChain { from: H256, to: H256 },
+
+    #[snafu(display("Circuit open after repeated fold failures, retry in {:?}", retry_in))]
+    CircuitOpen { retry_in: std::time::Duration },
+
+    #[snafu(display("Query exceeded its budget of `{}` RPC calls", budget))]
+    RpcBudgetExceeded { budget: usize },
+
+    #[snafu(display("Query nested in a fold queries an enclosing fold's state"))]
+    CycleDetected {},
+
+    #[snafu(display("Queries nested in folds deeper than `{}`", max_depth))]
+    DepthExceeded { max_depth: usize },
+
     #[snafu(display("Partition error: {:?}", sources))]
     PartitionError { sources: Vec<M::Error> },
 }
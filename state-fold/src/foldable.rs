@@ -9,6 +9,7 @@ use eth_state_fold_types::{BlockState, QueryBlock};
 
 use eth_state_fold_types::ethers;
 use ethers::providers::Middleware;
+use ethers::types::U64;
 
 use async_trait::async_trait;
 use std::sync::Arc;
@@ -19,6 +20,35 @@ pub trait Foldable: Send + Sync + std::fmt::Debug + Sized {
     type Error: std::error::Error;
     type UserData: Send + Sync;
 
+    /// First block relevant to `initial_state` (e.g. the deployment block of
+    /// its contract). If set, states are synced no earlier than this block,
+    /// and `sync` queries start from it instead of the environment's genesis.
+    /// Defaults to `None`, using the environment's genesis.
+    fn genesis_block(_initial_state: &Self::InitialState) -> Option<U64> {
+        None
+    }
+
+    /// Whether `fold` has to run on `block`, e.g. by checking its logs bloom.
+    /// If `false`, the previous state is carried forward unchanged as the
+    /// state of `block`. Skipped blocks must not change the state, so that
+    /// folding stays consistent with `sync`, which sees the whole range.
+    /// Defaults to `true`.
+    fn relevant<M: Middleware + 'static>(
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, Self::UserData>,
+    ) -> bool {
+        true
+    }
+
+    /// Whether this state is final, e.g. its contract has self-destructed,
+    /// which can be checked with `FoldMiddleware::is_contract_alive`. Terminal
+    /// states aren't folded any further: they are carried forward unchanged
+    /// as the state of every later block, like on irrelevant blocks. Defaults
+    /// to `false`.
+    fn terminal(&self) -> bool {
+        false
+    }
+
     async fn sync<M: Middleware + 'static>(
         initial_state: &Self::InitialState,
         block: &Block,
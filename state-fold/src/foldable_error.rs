@@ -0,0 +1,154 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+/// Declares an error enum for a `Foldable`, with an `Access` variant holding
+/// the `AccessError`s of the access layers, as an `ErasedAccessError`, along
+/// with the given variants, each wrapping an error. Implements `Display`,
+/// `Error`, with each variant's error as its `source`, and `From` for
+/// `AccessError<M>` of any middleware and for each wrapped error, so `?`
+/// converts them. Wrapped error types must be distinct. Access errors display
+/// the blocks they were accessing.
+///
+/// ```
+/// use eth_state_fold::FoldMiddleware;
+/// use eth_state_fold_types::ethers::abi::{AbiDecode, AbiError};
+/// use eth_state_fold_types::ethers::providers::Middleware;
+/// use eth_state_fold_types::ethers::types::{Filter, U256};
+///
+/// eth_state_fold::foldable_error! {
+///     pub enum MyFoldError {
+///         Decode(AbiError),
+///     }
+/// }
+///
+/// // Sum of the amounts logged in the block.
+/// async fn total<M: Middleware + 'static>(
+///     access: &FoldMiddleware<M>,
+/// ) -> Result<U256, MyFoldError> {
+///     let mut total = U256::zero();
+///     for log in access.get_logs(&Filter::new()).await? {
+///         total += U256::decode(&log.data)?;
+///     }
+///
+///     Ok(total)
+/// }
+///
+/// let err = MyFoldError::from(U256::decode([0u8; 4]).unwrap_err());
+/// assert!(err.to_string().starts_with("Decode error: "));
+/// ```
+#[macro_export]
+macro_rules! foldable_error {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident {
+            $( $(#[$variant_meta:meta])* $variant:ident($error:ty) ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug)]
+        $vis enum $name {
+            Access($crate::ErasedAccessError),
+            $( $(#[$variant_meta])* $variant($error), )*
+        }
+
+        impl ::std::fmt::Display for $name {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+                match self {
+                    Self::Access(e) => write!(f, "Access error: {}", e),
+                    $( Self::$variant(e) => write!(f, "{} error: {}", stringify!($variant), e), )*
+                }
+            }
+        }
+
+        impl ::std::error::Error for $name {
+            fn source(&self) -> Option<&(dyn ::std::error::Error + 'static)> {
+                match self {
+                    Self::Access(e) => Some(e),
+                    $( Self::$variant(e) => Some(e), )*
+                }
+            }
+        }
+
+        impl<M> From<$crate::AccessError<M>> for $name
+        where
+            M: $crate::__private::Middleware + 'static,
+            M::Error: 'static,
+        {
+            fn from(error: $crate::AccessError<M>) -> Self {
+                Self::Access(error.into())
+            }
+        }
+
+        $(
+            impl From<$error> for $name {
+                fn from(error: $error) -> Self {
+                    Self::$variant(error)
+                }
+            }
+        )*
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AccessError, AccessedBlocks, StateFoldEnvironment};
+
+    use eth_state_fold_test::mock_middleware::{MockError, MockMiddleware};
+    use eth_state_fold_types::ethers;
+    use ethers::providers::Middleware;
+    use ethers::types::{Filter, U64};
+
+    use std::error::Error;
+    use std::sync::Arc;
+
+    crate::foldable_error! {
+        /// Error of a sample fold.
+        enum SampleError {
+            Parse(std::num::ParseIntError),
+        }
+    }
+
+    #[tokio::test]
+    async fn access_error_test() {
+        let m = MockMiddleware::new(16).await;
+        let env = StateFoldEnvironment::new(Arc::clone(&m), None, 4, 0.into(), vec![], 4, 2, ());
+        let block = env.block_with_number(U64::from(10)).await.unwrap();
+
+        let fold = || async {
+            let access = env.fold_access(&block);
+            let logs = access.get_logs(&Filter::new()).await?;
+            let n: u64 = "42".parse()?;
+            Ok::<_, SampleError>(logs.len() as u64 + n)
+        };
+        assert_eq!(fold().await.unwrap(), 42);
+
+        m.fail_next_requests(1).await;
+        let err = fold().await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Access error: Ethers provider error at block {:?}: MockError",
+                block.hash
+            )
+        );
+
+        // The original error and its source survive the wrap.
+        let SampleError::Access(access) = &err else {
+            panic!("unexpected error {:?}", err);
+        };
+        assert!(matches!(
+            access.downcast_ref::<MockMiddleware>(),
+            Some(AccessError::EthersProviderError {
+                source: MockError,
+                blocks: AccessedBlocks::Block(hash),
+            }) if *hash == block.hash
+        ));
+
+        let source = err.source().and_then(Error::source).unwrap();
+        assert!(source.is::<MockError>());
+
+        let err = SampleError::from("x".parse::<u64>().unwrap_err());
+        assert!(err.to_string().starts_with("Parse error: "));
+        assert!(err.source().unwrap().is::<std::num::ParseIntError>());
+    }
+}
@@ -8,13 +8,48 @@ pub mod config;
 pub mod error;
 pub mod utils;
 
+#[cfg(feature = "profiling")]
+pub mod profiling;
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+
+#[cfg(feature = "metrics")]
+pub mod metrics;
+
 mod delegate_access;
+mod dependent;
 mod env;
 mod foldable;
+mod foldable_error;
+mod merged;
+mod or_default;
+mod stateless;
 
-pub use delegate_access::{AccessError, FoldMiddleware, SyncMiddleware};
-pub use env::StateFoldEnvironment;
+pub use delegate_access::{
+    decode_call, decode_event, default_classifier, AccessError, AccessedBlocks, Batch,
+    BatchRequest, BatchResponse, BatchTransport, DecodedLogs, ErasedAccessError, FoldMiddleware,
+    LogCoalescer, LogPage, LogPagination, Priority, RequestGate, RetryPolicy, Retryability,
+    SyncMiddleware, UnrecognizedLogs,
+};
+pub use dependent::{DependencyError, Dependent, DependentFoldable};
+pub use env::{
+    BlockResolver, CircuitBreakerConfig, CircuitState, ComputeSource, ConfirmationPolicy,
+    CostEstimate, FoldStep, FoldTrace, MemoryStateCache, PendingBlockState, ProviderCapabilities,
+    SampleSpec, StandardResolver, StateCache, StateFoldEnvironment, Tracker, UserDataRef, Validity,
+};
+#[cfg(any(feature = "json", feature = "bincode"))]
+pub use env::{CacheExportError, CacheFormat};
+pub use eth_block_history::{Clock, ManualClock, TokioClock};
 pub use foldable::Foldable;
+pub use merged::{MergeableFoldable, Merged, MergedError};
+pub use or_default::{Applicable, OrDefault, OrDefaultInitialState};
+pub use stateless::{Stateless, StatelessFoldable};
+
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
 
-#[cfg(test)]
-mod test_utils;
+#[doc(hidden)]
+pub mod __private {
+    pub use eth_state_fold_types::ethers::providers::Middleware;
+}
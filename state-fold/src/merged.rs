@@ -0,0 +1,230 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use crate::delegate_access::budget;
+use crate::{FoldMiddleware, Foldable, StateFoldEnvironment, SyncMiddleware};
+
+use eth_state_fold_types::ethers;
+use eth_state_fold_types::Block;
+use ethers::providers::Middleware;
+use ethers::types::U64;
+
+use async_trait::async_trait;
+use futures::stream::{FuturesUnordered, StreamExt};
+use snafu::Snafu;
+use std::sync::Arc;
+
+/// Fold whose syncs over consecutive ranges of blocks can be merged, for
+/// `Merged`.
+pub trait MergeableFoldable: Foldable {
+    /// Merges `earlier`, synced over a range of blocks, with `later`, synced
+    /// over the range right after it, into the state synced over both. Each
+    /// range is synced from the same initial state, on its last block, with a
+    /// `SyncMiddleware` querying only that range, so `sync` must depend only
+    /// on the events of its range (e.g. a sum or a set union over them). Must
+    /// be associative.
+    fn merge(earlier: Self, later: Self) -> Self;
+}
+
+/// Error of a `Merged` fold.
+#[derive(Debug, Snafu)]
+pub enum MergedError<F: Foldable + 'static> {
+    #[snafu(display("{}", source))]
+    Inner { source: F::Error },
+
+    /// Failure fetching the last block of a partition, with its middleware
+    /// type erased.
+    #[snafu(display("Partition block error: {}", source))]
+    PartitionBlock {
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+}
+
+/// Adapter of `F` that splits its syncs into `sync_partitions` consecutive
+/// ranges of blocks, syncs them concurrently and merges the results in order.
+/// Folds are those of `F`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Merged<F: MergeableFoldable> {
+    pub state: F,
+}
+
+#[async_trait]
+impl<F> Foldable for Merged<F>
+where
+    F: MergeableFoldable + 'static,
+{
+    type InitialState = F::InitialState;
+    type Error = MergedError<F>;
+    type UserData = F::UserData;
+
+    fn genesis_block(initial_state: &Self::InitialState) -> Option<U64> {
+        F::genesis_block(initial_state)
+    }
+
+    fn relevant<M: Middleware + 'static>(
+        block: &Block,
+        env: &StateFoldEnvironment<M, Self::UserData>,
+    ) -> bool {
+        F::relevant(block, env)
+    }
+
+    fn terminal(&self) -> bool {
+        self.state.terminal()
+    }
+
+    async fn sync<M: Middleware + 'static>(
+        initial_state: &Self::InitialState,
+        block: &Block,
+        env: &StateFoldEnvironment<M, Self::UserData>,
+        access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        let genesis = env.fold_genesis_block::<F>(initial_state).as_u64();
+        let blocks = block.number.as_u64().saturating_sub(genesis) + 1;
+        let partitions = env.sync_partitions.clamp(1, blocks as usize) as u64;
+
+        if partitions == 1 {
+            let state = F::sync(initial_state, block, env, access)
+                .await
+                .map_err(|source| MergedError::Inner { source })?;
+
+            return Ok(Self { state });
+        }
+
+        // Ranges of nearly equal length, the earlier ones one block longer
+        // when it doesn't divide evenly. Each spends the budget of the query.
+        let mut syncs = FuturesUnordered::new();
+        let mut from = genesis;
+
+        for i in 0..partitions {
+            let to = from + blocks / partitions + u64::from(i < blocks % partitions) - 1;
+
+            syncs.push(async move {
+                let partition_block = if to == block.number.as_u64() {
+                    Arc::new(block.clone())
+                } else {
+                    env.block_with_number(to.into())
+                        .await
+                        .map_err(|e| MergedError::PartitionBlock { source: e.into() })?
+                };
+
+                let access = env.sync_access_from(from.into(), &partition_block, budget::current());
+                F::sync(initial_state, &partition_block, env, access)
+                    .await
+                    .map(|state| (i, state))
+                    .map_err(|source| MergedError::Inner { source })
+            });
+
+            from = to + 1;
+        }
+
+        let mut states: Vec<Option<F>> = (0..partitions).map(|_| None).collect();
+        while let Some(result) = syncs.next().await {
+            let (i, state) = result?;
+            states[i as usize] = Some(state);
+        }
+
+        let state = states
+            .into_iter()
+            .flatten()
+            .reduce(F::merge)
+            .expect("syncs of at least two partitions");
+
+        Ok(Self { state })
+    }
+
+    async fn fold<M: Middleware + 'static>(
+        previous_state: &Self,
+        block: &Block,
+        env: &StateFoldEnvironment<M, Self::UserData>,
+        access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        let state = F::fold(&previous_state.state, block, env, access)
+            .await
+            .map_err(|source| MergedError::Inner { source })?;
+
+        Ok(Self { state })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Merged;
+    use crate::test_utils::mocks::SumFold;
+    use crate::StateFoldEnvironment;
+
+    use eth_state_fold_test::mock_middleware::MockMiddleware;
+    use eth_state_fold_types::ethers;
+    use eth_state_fold_types::QueryBlock;
+    use ethers::types::Log;
+    use std::sync::Arc;
+
+    const SAFETY_MARGIN: usize = 8;
+
+    #[tokio::test]
+    async fn merged_sync_test() {
+        let m = MockMiddleware::new(128).await;
+        let mut logs = vec![];
+        for n in (3..=128).step_by(5) {
+            let block = m.get_block_with_number(n.into()).await.unwrap();
+            logs.push(Log {
+                block_hash: Some(block.hash),
+                block_number: Some(n.into()),
+                log_index: Some(n.into()),
+                ..Default::default()
+            });
+        }
+        m.set_logs(logs).await;
+        let expected: u64 = (3..=128).step_by(5).sum();
+
+        let mut sums = vec![];
+        for partitions in [1, 3, 4, 7] {
+            let mut env = StateFoldEnvironment::new(
+                Arc::clone(&m),
+                None,
+                SAFETY_MARGIN,
+                0.into(),
+                vec![],
+                1,
+                usize::MAX,
+                (),
+            );
+            env.sync_partitions = partitions;
+            let requests = m.log_requests().await.len();
+
+            let state = env
+                .get_state_for_block::<Merged<SumFold>>(&(), QueryBlock::Latest)
+                .await
+                .unwrap()
+                .state;
+            sums.push(state.state.sum);
+
+            // A request per partition, and one per folded block.
+            assert_eq!(
+                m.log_requests().await.len() - requests,
+                partitions + SAFETY_MARGIN
+            );
+        }
+
+        assert_eq!(sums, vec![expected; 4]);
+
+        // Unmerged, the sync is a single request.
+        let env = StateFoldEnvironment::new(
+            Arc::clone(&m),
+            None,
+            SAFETY_MARGIN,
+            0.into(),
+            vec![],
+            1,
+            usize::MAX,
+            (),
+        );
+        let requests = m.log_requests().await.len();
+        let state = env
+            .get_state_for_block::<SumFold>(&(), QueryBlock::Latest)
+            .await
+            .unwrap()
+            .state;
+        assert_eq!(state.sum, expected);
+        assert_eq!(m.log_requests().await.len() - requests, 1 + SAFETY_MARGIN);
+    }
+}
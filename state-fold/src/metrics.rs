@@ -0,0 +1,190 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+//! Standard metrics of the environment, emitted through the `metrics` crate
+//! to the recorder installed by the application, such as a Prometheus
+//! exporter. Available with the `metrics` feature; without it, nothing is
+//! measured. The names and labels below are stable.
+
+use std::time::Duration;
+
+/// Histogram of the durations of `sync` and `fold` calls, in seconds,
+/// labeled by `kind`, either `sync` or `fold`.
+pub const FOLD_DURATION_SECONDS: &str = "state_fold_fold_duration_seconds";
+
+/// Counter of the requests sent through the access layers, labeled by
+/// `method`, such as `eth_getLogs`, or `batch` for JSON-RPC batches.
+pub const RPC_CALLS_TOTAL: &str = "state_fold_rpc_calls_total";
+
+/// Counter of queries answered from the cache.
+pub const CACHE_HITS_TOTAL: &str = "state_fold_cache_hits_total";
+
+/// Counter of queries that had to fold or sync.
+pub const CACHE_MISSES_TOTAL: &str = "state_fold_cache_misses_total";
+
+/// Counter of reorgs orphaning states returned by earlier queries, counted
+/// once per initial state they orphan states of.
+pub const REORGS_TOTAL: &str = "state_fold_reorgs_total";
+
+/// Gauge of the active subscribers of the environments to blocks: their
+/// `subscribe_state` streams, `pipe` calls and `track` tasks. Those of a
+/// `BlockSubscriber` are reported by `BlockSubscriber::subscriber_count`.
+pub const ACTIVE_SUBSCRIBERS: &str = "state_fold_active_subscribers";
+
+pub(crate) fn fold_duration(kind: &'static str, elapsed: Duration) {
+    ::metrics::histogram!(FOLD_DURATION_SECONDS, "kind" => kind).record(elapsed.as_secs_f64());
+}
+
+pub(crate) fn rpc_call(method: &'static str) {
+    ::metrics::counter!(RPC_CALLS_TOTAL, "method" => method).increment(1);
+}
+
+pub(crate) fn cache_lookup(hit: bool) {
+    let name = if hit {
+        CACHE_HITS_TOTAL
+    } else {
+        CACHE_MISSES_TOTAL
+    };
+    ::metrics::counter!(name).increment(1);
+}
+
+pub(crate) fn reorg() {
+    ::metrics::counter!(REORGS_TOTAL).increment(1);
+}
+
+/// Counts a subscriber as active until dropped.
+#[derive(Debug)]
+pub(crate) struct SubscriberGuard(());
+
+impl SubscriberGuard {
+    pub fn new() -> Self {
+        ::metrics::gauge!(ACTIVE_SUBSCRIBERS).increment(1.0);
+        Self(())
+    }
+}
+
+impl Drop for SubscriberGuard {
+    fn drop(&mut self) {
+        ::metrics::gauge!(ACTIVE_SUBSCRIBERS).decrement(1.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::mocks::IncrementFold;
+    use crate::StateFoldEnvironment;
+
+    use eth_state_fold_test::mock_middleware::MockMiddleware;
+    use eth_state_fold_types::ethers::providers::Middleware;
+    use eth_state_fold_types::ethers::types::Filter;
+    use eth_state_fold_types::{BlockStreamItem, QueryBlock};
+    use futures::StreamExt;
+
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder, Snapshotter};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    /// Values of the metrics recorded since the last snapshot, keyed by name
+    /// and labels. Histograms are counted by samples.
+    fn snapshot(snapshotter: &Snapshotter) -> HashMap<String, f64> {
+        snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| {
+                let (name, labels) = key.key().clone().into_parts();
+                let labels: Vec<_> = labels
+                    .iter()
+                    .map(|label| format!("{}={}", label.key(), label.value()))
+                    .collect();
+
+                let value = match value {
+                    DebugValue::Counter(value) => value as f64,
+                    DebugValue::Gauge(value) => value.into_inner(),
+                    DebugValue::Histogram(samples) => samples.len() as f64,
+                };
+
+                (format!("{}{:?}", name.as_str(), labels), value)
+            })
+            .collect()
+    }
+
+    fn value(snapshot: &HashMap<String, f64>, name: &str, labels: &[&str]) -> f64 {
+        snapshot
+            .get(&format!("{name}{labels:?}"))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    // Recorded on the thread of the test only, so other tests don't count.
+    #[test]
+    fn metrics_test() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        ::metrics::with_local_recorder(&recorder, || {
+            runtime.block_on(async {
+                let m = MockMiddleware::new(128).await;
+                let env = StateFoldEnvironment::new(
+                    Arc::clone(&m),
+                    None,
+                    8,
+                    0.into(),
+                    vec![],
+                    1,
+                    usize::MAX,
+                    (),
+                );
+
+                // Synced on block 120, folded up to 128, and then cached.
+                let query = || env.get_state_for_block::<IncrementFold>(&0, QueryBlock::Latest);
+                let block_state = query().await.unwrap();
+                query().await.unwrap();
+
+                let values = snapshot(&snapshotter);
+                assert_eq!(value(&values, FOLD_DURATION_SECONDS, &["kind=sync"]), 1.0);
+                assert_eq!(value(&values, FOLD_DURATION_SECONDS, &["kind=fold"]), 8.0);
+                assert_eq!(value(&values, CACHE_MISSES_TOTAL, &[]), 1.0);
+                assert_eq!(value(&values, CACHE_HITS_TOTAL, &[]), 1.0);
+
+                let latest = m.get_latest_block().await.unwrap();
+                env.fold_access(&latest)
+                    .get_logs(&Filter::new())
+                    .await
+                    .unwrap();
+                let values = snapshot(&snapshotter);
+                assert_eq!(
+                    value(&values, RPC_CALLS_TOTAL, &["method=eth_getLogs"]),
+                    1.0
+                );
+
+                // Reorg out block 128, with no hook installed.
+                let tip = m.add_block(block_state.block.parent_hash).await.unwrap();
+                m.add_block(tip).await.unwrap();
+                query().await.unwrap();
+                assert_eq!(value(&snapshot(&snapshotter), REORGS_TOTAL, &[]), 1.0);
+
+                let blocks = futures::stream::iter([BlockStreamItem::NewBlock(Arc::new(latest))]);
+                let mut states = Box::pin(env.subscribe_state::<IncrementFold>(&0, blocks));
+                states.next().await.unwrap().unwrap();
+
+                // Piping counts as a subscriber too, until it returns.
+                let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+                let blocks = futures::stream::pending::<QueryBlock>();
+                let pipe = env.pipe::<IncrementFold, _>(&0, blocks, tx);
+                futures::pin_mut!(pipe);
+                let polled = futures::poll!(pipe.as_mut());
+                assert!(polled.is_pending());
+                drop(states);
+                rx.close();
+
+                assert_eq!(value(&snapshot(&snapshotter), ACTIVE_SUBSCRIBERS, &[]), 1.0);
+            })
+        });
+    }
+}
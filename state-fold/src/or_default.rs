@@ -0,0 +1,272 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use crate::delegate_access::budget;
+use crate::{FoldMiddleware, Foldable, StateFoldEnvironment, SyncMiddleware};
+
+use eth_state_fold_types::ethers;
+use eth_state_fold_types::Block;
+use ethers::providers::Middleware;
+use ethers::types::U64;
+
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// Fold whose errors may mean that its initial state doesn't apply at the
+/// block (e.g. its contract isn't deployed yet, or has been destroyed), for
+/// `OrDefault`.
+pub trait Applicable: Foldable {
+    /// Whether `error`, of syncing or folding, means the initial state
+    /// doesn't apply at the block, rather than a failure.
+    fn not_applicable(error: &Self::Error) -> bool;
+}
+
+/// Adapter of `F` that, when syncing or folding from the `primary` initial
+/// state fails with an error `F` classifies as not applicable (e.g. the
+/// contract isn't deployed yet at that block), syncs from the `fallback`
+/// initial state instead of failing. Useful for folds over contracts that
+/// appear partway through the queried range.
+///
+/// While on the fallback, each fold first tries syncing from the primary
+/// initial state, switching back to it once it applies. That's as costly as a
+/// sync, so the primary should declare its `genesis_block`, before which it
+/// isn't tried.
+#[derive(Clone, PartialEq, Eq)]
+pub struct OrDefault<F: Foldable> {
+    pub state: F,
+    pub initial_state: OrDefaultInitialState<F::InitialState>,
+
+    /// Whether `state` descends from the fallback initial state.
+    pub fallback: bool,
+}
+
+impl<F: Foldable> std::fmt::Debug for OrDefault<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OrDefault")
+            .field("state", &self.state)
+            .field("fallback", &self.fallback)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Initial state of `OrDefault<F>`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct OrDefaultInitialState<S> {
+    pub primary: S,
+    pub fallback: S,
+}
+
+impl<S> OrDefaultInitialState<S> {
+    pub fn new(primary: S, fallback: S) -> Self {
+        Self { primary, fallback }
+    }
+}
+
+impl<F> OrDefault<F>
+where
+    F: Applicable + 'static,
+    F::Error: Send,
+{
+    /// State synced from the primary initial state at `block`, if it applies.
+    async fn primary<M: Middleware + 'static>(
+        initial_state: &OrDefaultInitialState<F::InitialState>,
+        block: &Block,
+        env: &StateFoldEnvironment<M, F::UserData>,
+        access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Option<Self>, F::Error> {
+        let primary = &initial_state.primary;
+        if F::genesis_block(primary).is_some_and(|genesis| block.number < genesis) {
+            return Ok(None);
+        }
+
+        match F::sync(primary, block, env, access).await {
+            Ok(state) => Ok(Some(Self {
+                state,
+                initial_state: initial_state.clone(),
+                fallback: false,
+            })),
+
+            Err(e) if F::not_applicable(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// State synced from the fallback initial state at `block`.
+    async fn fallback<M: Middleware + 'static>(
+        initial_state: &OrDefaultInitialState<F::InitialState>,
+        block: &Block,
+        env: &StateFoldEnvironment<M, F::UserData>,
+        access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, F::Error> {
+        Ok(Self {
+            state: F::sync(&initial_state.fallback, block, env, access).await?,
+            initial_state: initial_state.clone(),
+            fallback: true,
+        })
+    }
+
+    /// Access for syncing at `block` while folding, spending the budget of
+    /// the query.
+    fn sync_access<M: Middleware + 'static>(
+        initial_state: &OrDefaultInitialState<F::InitialState>,
+        block: &Block,
+        env: &StateFoldEnvironment<M, F::UserData>,
+    ) -> Arc<SyncMiddleware<M>> {
+        let genesis = env.fold_genesis_block::<Self>(initial_state);
+        env.sync_access_from(genesis, block, budget::current())
+    }
+}
+
+#[async_trait]
+impl<F> Foldable for OrDefault<F>
+where
+    F: Applicable + 'static,
+    F::Error: Send,
+{
+    type InitialState = OrDefaultInitialState<F::InitialState>;
+    type Error = F::Error;
+    type UserData = F::UserData;
+
+    /// Earliest of the genesis blocks of `primary` and `fallback`.
+    fn genesis_block(initial_state: &Self::InitialState) -> Option<U64> {
+        let primary = F::genesis_block(&initial_state.primary)?;
+        let fallback = F::genesis_block(&initial_state.fallback)?;
+        Some(primary.min(fallback))
+    }
+
+    fn relevant<M: Middleware + 'static>(
+        block: &Block,
+        env: &StateFoldEnvironment<M, Self::UserData>,
+    ) -> bool {
+        F::relevant(block, env)
+    }
+
+    /// Only states of the primary are terminal, as the primary is still
+    /// tried on the fallback.
+    fn terminal(&self) -> bool {
+        !self.fallback && self.state.terminal()
+    }
+
+    async fn sync<M: Middleware + 'static>(
+        initial_state: &Self::InitialState,
+        block: &Block,
+        env: &StateFoldEnvironment<M, Self::UserData>,
+        access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        match Self::primary(initial_state, block, env, Arc::clone(&access)).await? {
+            Some(state) => Ok(state),
+            None => Self::fallback(initial_state, block, env, access).await,
+        }
+    }
+
+    async fn fold<M: Middleware + 'static>(
+        previous_state: &Self,
+        block: &Block,
+        env: &StateFoldEnvironment<M, Self::UserData>,
+        access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        let initial_state = &previous_state.initial_state;
+
+        if previous_state.fallback {
+            let sync_access = Self::sync_access(initial_state, block, env);
+            if let Some(state) = Self::primary(initial_state, block, env, sync_access).await? {
+                return Ok(state);
+            }
+        }
+
+        match F::fold(&previous_state.state, block, env, access).await {
+            Ok(state) => Ok(Self {
+                state,
+                initial_state: initial_state.clone(),
+                fallback: previous_state.fallback,
+            }),
+
+            Err(e) if !previous_state.fallback && F::not_applicable(&e) => {
+                let sync_access = Self::sync_access(initial_state, block, env);
+                Self::fallback(initial_state, block, env, sync_access).await
+            }
+
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OrDefault, OrDefaultInitialState};
+    use crate::error::FoldableError;
+    use crate::test_utils::mocks::{ContractFold, FlakyFold};
+    use crate::StateFoldEnvironment;
+
+    use eth_state_fold_test::mock_middleware::MockMiddleware;
+    use eth_state_fold_types::QueryBlock;
+
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn or_default_test() {
+        let m = MockMiddleware::new(128).await;
+        let env = StateFoldEnvironment::new(m, None, 8, 0.into(), vec![], 1, usize::MAX, ());
+
+        // Deployed at block 100, destroyed at block 124.
+        let initial_state =
+            OrDefaultInitialState::new((100.into(), Some(124.into())), (0.into(), None));
+        let query = |number: u64| {
+            env.get_state_for_block::<OrDefault<ContractFold>>(
+                &initial_state,
+                QueryBlock::BlockNumber(number.into()),
+            )
+        };
+
+        // Before deployment.
+        let state = query(90).await.unwrap().state;
+        assert!(state.fallback);
+        assert_eq!(state.state.deployment, 0.into());
+
+        // Folded on the fallback from block 90, then back on the primary once
+        // deployed.
+        let state = query(110).await.unwrap().state;
+        assert!(!state.fallback);
+        assert_eq!(state.state.deployment, 100.into());
+
+        // Back on the fallback once the fold of the primary doesn't apply.
+        let state = env
+            .get_state_for_block::<OrDefault<ContractFold>>(&initial_state, QueryBlock::Latest)
+            .await
+            .unwrap()
+            .state;
+        assert!(state.fallback);
+        assert_eq!(state.state.deployment, 0.into());
+
+        // Never destroyed, queried before deployment, then at the latest
+        // block.
+        let initial_state = OrDefaultInitialState::new((100.into(), None), (0.into(), None));
+        for (query, fallback) in [
+            (QueryBlock::BlockNumber(90.into()), true),
+            (QueryBlock::Latest, false),
+        ] {
+            let state = env
+                .get_state_for_block::<OrDefault<ContractFold>>(&initial_state, query)
+                .await
+                .unwrap()
+                .state;
+            assert_eq!(state.fallback, fallback);
+        }
+    }
+
+    #[tokio::test]
+    async fn or_default_error_test() {
+        let m = MockMiddleware::new(128).await;
+        let failing = Arc::new(AtomicBool::new(true));
+        let env = StateFoldEnvironment::new(m, None, 8, 0.into(), vec![], 1, usize::MAX, failing);
+
+        // Errors not classified as not applicable still fail.
+        let initial_state = OrDefaultInitialState::new((), ());
+        let err = env
+            .get_state_for_block::<OrDefault<FlakyFold>>(&initial_state, QueryBlock::Latest)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, FoldableError::InnerError { .. }));
+    }
+}
@@ -0,0 +1,160 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use crate::Clock;
+
+use eth_state_fold_types::ethers::types::H256;
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Time spent processing a block, as collected by the `profiling` feature.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockTiming {
+    /// Time spent in the `fold` (or `sync`) call of the block, RPC included.
+    pub fold_duration: Duration,
+
+    /// Time spent in requests made through the access layer of the block.
+    pub rpc_duration: Duration,
+
+    /// Number of requests made through the access layer of the block.
+    pub rpc_calls: usize,
+}
+
+impl BlockTiming {
+    /// Time spent in user code, i.e. outside of requests.
+    pub fn compute_duration(&self) -> Duration {
+        self.fold_duration.saturating_sub(self.rpc_duration)
+    }
+}
+
+/// Timing of a single block, shared with its access layer.
+#[derive(Debug, Default)]
+pub(crate) struct BlockProfile(Mutex<BlockTiming>);
+
+impl BlockProfile {
+    pub fn add_fold(&self, elapsed: Duration) {
+        self.0.lock().unwrap().fold_duration += elapsed;
+    }
+
+    /// Runs `request`, adding its duration on `clock` to this block.
+    pub async fn time_rpc<T>(&self, clock: &dyn Clock, request: impl Future<Output = T>) -> T {
+        let start = clock.now();
+        let result = request.await;
+
+        let mut timing = self.0.lock().unwrap();
+        timing.rpc_duration += clock.now().duration_since(start);
+        timing.rpc_calls += 1;
+
+        result
+    }
+}
+
+/// Runs `request`, timing it on `clock` if there's a profile.
+pub(crate) async fn time_rpc<T>(
+    profile: &Option<Arc<BlockProfile>>,
+    clock: &dyn Clock,
+    request: impl Future<Output = T>,
+) -> T {
+    match profile {
+        Some(profile) => profile.time_rpc(clock, request).await,
+        None => request.await,
+    }
+}
+
+/// Timings of every processed block, keyed by block hash.
+#[derive(Debug, Default)]
+pub(crate) struct Profiler {
+    blocks: Mutex<HashMap<H256, Arc<BlockProfile>>>,
+}
+
+impl Profiler {
+    pub fn block(&self, hash: H256) -> Arc<BlockProfile> {
+        Arc::clone(self.blocks.lock().unwrap().entry(hash).or_default())
+    }
+
+    pub fn summary(&self) -> HashMap<H256, BlockTiming> {
+        self.blocks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(hash, profile)| (*hash, *profile.0.lock().unwrap()))
+            .collect()
+    }
+
+    pub fn reset(&self) {
+        self.blocks.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::mocks::IncrementFold;
+    use crate::{ManualClock, StateFoldEnvironment};
+    use std::sync::Arc;
+
+    use eth_state_fold_test::mock_middleware::MockMiddleware;
+    use eth_state_fold_types::ethers::providers::Middleware;
+    use eth_state_fold_types::ethers::types::Filter;
+    use eth_state_fold_types::QueryBlock;
+
+    #[tokio::test]
+    async fn profiling_test() {
+        let m = MockMiddleware::new(128).await;
+        let env =
+            StateFoldEnvironment::new(Arc::clone(&m), None, 8, 0.into(), vec![], 1, usize::MAX, ());
+
+        env.get_state_for_block::<IncrementFold>(&0, QueryBlock::Latest)
+            .await
+            .unwrap();
+
+        // Synced on block 120, and folded up to block 128.
+        let timings = env.fold_timings();
+        assert_eq!(timings.len(), 9);
+        for n in 120..=128 {
+            let block = m.get_block_with_number(n.into()).await.unwrap();
+            let timing = timings[&block.hash];
+            assert!(!timing.fold_duration.is_zero());
+            assert_eq!(timing.rpc_calls, 0);
+        }
+
+        let latest = m.get_latest_block().await.unwrap();
+        let access = env.fold_access(&latest);
+        access.get_logs(&Filter::new()).await.unwrap();
+        access.batch().execute().await.unwrap();
+
+        let timing = env.fold_timings()[&latest.hash];
+        assert_eq!(timing.rpc_calls, 1);
+        assert!(!timing.rpc_duration.is_zero());
+
+        env.reset_fold_timings();
+        assert!(env.fold_timings().is_empty());
+    }
+
+    #[tokio::test]
+    async fn profiling_clock_test() {
+        let m = MockMiddleware::new(128).await;
+        let mut env =
+            StateFoldEnvironment::new(Arc::clone(&m), None, 8, 0.into(), vec![], 1, usize::MAX, ());
+        env.clock = Arc::new(ManualClock::new());
+
+        env.get_state_for_block::<IncrementFold>(&0, QueryBlock::Latest)
+            .await
+            .unwrap();
+        let latest = m.get_latest_block().await.unwrap();
+        env.fold_access(&latest)
+            .get_logs(&Filter::new())
+            .await
+            .unwrap();
+
+        // Timed on the environment's clock, which never moved.
+        let timings = env.fold_timings();
+        assert_eq!(timings.len(), 9);
+        assert!(timings
+            .values()
+            .all(|timing| timing.fold_duration.is_zero() && timing.rpc_duration.is_zero()));
+        assert_eq!(timings[&latest.hash].rpc_calls, 1);
+    }
+}
@@ -0,0 +1,170 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use crate::delegate_access::budget;
+use crate::{FoldMiddleware, Foldable, StateFoldEnvironment, SyncMiddleware};
+
+use eth_state_fold_types::ethers;
+use eth_state_fold_types::Block;
+use ethers::providers::Middleware;
+use ethers::types::U64;
+
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// State that depends only on the block it's computed at, such as a snapshot
+/// of contract storage, with no carry-over from the previous block. Queried
+/// through `Stateless<Self>`, whose `sync` and `fold` both run `compute`, so
+/// they can't diverge. States are cached and reorg-handled like any other.
+#[async_trait]
+pub trait StatelessFoldable: Send + Sync + std::fmt::Debug + Sized {
+    type InitialState: Clone + PartialEq + Eq + std::hash::Hash + Send + Sync;
+    type Error: std::error::Error;
+    type UserData: Send + Sync;
+
+    /// Same as `Foldable::genesis_block`.
+    fn genesis_block(_initial_state: &Self::InitialState) -> Option<U64> {
+        None
+    }
+
+    /// Same as `Foldable::relevant`.
+    fn relevant<M: Middleware + 'static>(
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, Self::UserData>,
+    ) -> bool {
+        true
+    }
+
+    /// Same as `Foldable::terminal`.
+    fn terminal(&self) -> bool {
+        false
+    }
+
+    /// Computes the state of `block`, with an `access` pinned to it, the same
+    /// whether the state is synced or folded.
+    async fn compute<M: Middleware + 'static>(
+        initial_state: &Self::InitialState,
+        block: &Block,
+        env: &StateFoldEnvironment<M, Self::UserData>,
+        access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error>;
+}
+
+/// Adapter folding a `StatelessFoldable` by computing each block from scratch.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Stateless<F: StatelessFoldable> {
+    pub state: F,
+    pub initial_state: F::InitialState,
+}
+
+impl<F: StatelessFoldable> std::fmt::Debug for Stateless<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Stateless")
+            .field("state", &self.state)
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl<F> Foldable for Stateless<F>
+where
+    F: StatelessFoldable + 'static,
+{
+    type InitialState = F::InitialState;
+    type Error = F::Error;
+    type UserData = F::UserData;
+
+    fn genesis_block(initial_state: &Self::InitialState) -> Option<U64> {
+        F::genesis_block(initial_state)
+    }
+
+    fn relevant<M: Middleware + 'static>(
+        block: &Block,
+        env: &StateFoldEnvironment<M, Self::UserData>,
+    ) -> bool {
+        F::relevant(block, env)
+    }
+
+    fn terminal(&self) -> bool {
+        self.state.terminal()
+    }
+
+    async fn sync<M: Middleware + 'static>(
+        initial_state: &Self::InitialState,
+        block: &Block,
+        env: &StateFoldEnvironment<M, Self::UserData>,
+        _access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        // Rather than the `SyncMiddleware`, whose logs span the whole range
+        // synced, so `compute` sees the same block as when folding.
+        let access = env.fold_access_with_budget(block, budget::current());
+
+        Ok(Self {
+            state: F::compute(initial_state, block, env, access).await?,
+            initial_state: initial_state.clone(),
+        })
+    }
+
+    async fn fold<M: Middleware + 'static>(
+        previous_state: &Self,
+        block: &Block,
+        env: &StateFoldEnvironment<M, Self::UserData>,
+        access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        let initial_state = &previous_state.initial_state;
+
+        Ok(Self {
+            state: F::compute(initial_state, block, env, access).await?,
+            initial_state: initial_state.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Stateless;
+    use crate::test_utils::mocks::LogCountFold;
+    use crate::StateFoldEnvironment;
+
+    use eth_state_fold_test::mock_middleware::MockMiddleware;
+    use eth_state_fold_types::ethers::providers::Middleware;
+    use eth_state_fold_types::ethers::types::Log;
+    use eth_state_fold_types::QueryBlock;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn stateless_test() {
+        let m = MockMiddleware::new(128).await;
+        let mut logs = vec![];
+        for (n, count) in [(100, 2), (120, 1), (124, 3), (128, 1)] {
+            let block = m.get_block(n).await.unwrap().unwrap();
+            for i in 0..count {
+                logs.push(Log {
+                    block_hash: block.hash,
+                    block_number: Some(n.into()),
+                    log_index: Some(i.into()),
+                    ..Default::default()
+                });
+            }
+        }
+        m.set_logs(logs).await;
+
+        let env =
+            StateFoldEnvironment::new(Arc::clone(&m), None, 8, 0.into(), vec![], 1, usize::MAX, ());
+
+        // Synced at block 120, folded up to 128.
+        let query = |block| env.get_state_for_block::<Stateless<LogCountFold>>(&(), block);
+        let state = query(QueryBlock::Latest).await.unwrap().state;
+        assert_eq!(state.state.number, 128.into());
+        assert_eq!(state.state.logs, 1);
+
+        for (n, count) in [(100, 2), (101, 0), (120, 1), (123, 0), (124, 3)] {
+            let state = query(QueryBlock::BlockNumber(n.into()))
+                .await
+                .unwrap()
+                .state;
+            assert_eq!(state.state.number, n.into());
+            assert_eq!(state.state.logs, count, "block {n}");
+        }
+    }
+}
@@ -0,0 +1,180 @@
+// (c) Cartesi and individual authors (see AUTHORS)
+// SPDX-License-Identifier: Apache-2.0 (see LICENSE)
+
+use crate::{Foldable, StateFoldEnvironment};
+
+use eth_state_fold_test::mock_middleware::MockMiddleware;
+
+use eth_state_fold_types::ethers;
+use eth_state_fold_types::{Block, QueryBlock};
+use ethers::providers::Middleware;
+use ethers::types::{H256, U64};
+
+use async_trait::async_trait;
+use std::sync::Arc;
+
+const INITIAL_BLOCKS: u64 = 16;
+const STEPS: usize = 32;
+const MAX_REORG_DEPTH: u64 = 6;
+const MAX_EXTENSION: u64 = 4;
+const SAFETY_MARGIN: usize = 2;
+
+/// Mock chain that can be forked at will, such as `MockMiddleware` from the
+/// `eth-state-fold-test` crate.
+#[async_trait]
+pub trait ReorgChain: Middleware + Sized + 'static {
+    /// Creates a chain with `block_count` blocks after genesis, whose hashes
+    /// are derived from `seed`.
+    async fn new_seeded(seed: u64, block_count: u64) -> Arc<Self>;
+
+    async fn latest_block(&self) -> Block;
+
+    /// Canonical block numbered `number`, if any.
+    async fn block_with_number(&self, number: U64) -> Option<Block>;
+
+    /// Adds a child of `parent_hash`, making it the latest block.
+    async fn add_block(&self, parent_hash: H256) -> Option<H256>;
+}
+
+/// Generates a random chain from `seed` on a `ReorgChain`, extending it
+/// and reorging it at random depths. After every step, the state fetched by a
+/// long-lived environment, folding from its cache across the reorgs, is
+/// checked against the state computed by `sync` alone on the same canonical
+/// block. Panics on the first mismatch, reporting the seed and block.
+///
+/// Runs of the same seed generate the same chain, so failures are
+/// reproducible.
+pub async fn fuzz_reorgs<F, C>(initial_state: F::InitialState, seed: u64)
+where
+    F: Foldable + PartialEq + Send + Sync + 'static,
+    F::UserData: Default,
+    C: ReorgChain,
+{
+    let mut rng = SplitMix64(seed);
+    let m = C::new_seeded(seed, INITIAL_BLOCKS).await;
+    let env = new_env::<F, C>(&m, SAFETY_MARGIN);
+
+    for _ in 0..STEPS {
+        let tip = m.latest_block().await;
+
+        // Either extend the chain, or fork it from a block behind the tip.
+        let depth = if rng.next() & 1 == 0 {
+            0
+        } else {
+            1 + rng.next() % std::cmp::min(MAX_REORG_DEPTH, tip.number.as_u64())
+        };
+
+        let fork_point = m.block_with_number(tip.number - depth).await.unwrap();
+
+        let mut hash = fork_point.hash;
+        for _ in 0..depth + 1 + rng.next() % MAX_EXTENSION {
+            hash = m.add_block(hash).await.unwrap();
+        }
+
+        // Check the tip and a random block behind it.
+        let tip = m.latest_block().await;
+        let behind = rng.next() % (MAX_REORG_DEPTH + 1);
+        let numbers = [tip.number, tip.number.saturating_sub(behind.into())];
+
+        for number in numbers {
+            let folded = env
+                .get_state_for_block::<F>(&initial_state, QueryBlock::BlockNumber(number))
+                .await
+                .unwrap_or_else(|e| {
+                    panic!("seed {}: fold of block {} failed: {}", seed, number, e)
+                });
+
+            let synced = synced_state::<F, C>(&m, &initial_state, number).await;
+
+            assert!(
+                folded.state.as_ref() == synced.as_ref(),
+                "seed {}: folded and synced states of block {} differ: {:?} != {:?}",
+                seed,
+                number,
+                folded.state,
+                synced
+            );
+        }
+    }
+}
+
+/// State of block `number` computed by `sync` on it, without folding.
+async fn synced_state<F, C>(m: &Arc<C>, initial_state: &F::InitialState, number: U64) -> Arc<F>
+where
+    F: Foldable + Send + Sync + 'static,
+    F::UserData: Default,
+    C: ReorgChain,
+{
+    let env = new_env::<F, C>(m, 0);
+
+    env.get_state_for_block::<F>(initial_state, QueryBlock::BlockNumber(number))
+        .await
+        .unwrap_or_else(|e| panic!("sync of block {} failed: {}", number, e))
+        .state
+}
+
+fn new_env<F: Foldable, C: ReorgChain>(
+    m: &Arc<C>,
+    safety_margin: usize,
+) -> StateFoldEnvironment<C, F::UserData>
+where
+    F::UserData: Default,
+{
+    StateFoldEnvironment::new(
+        Arc::clone(m),
+        None,
+        safety_margin,
+        0.into(),
+        vec![],
+        1,
+        usize::MAX,
+        F::UserData::default(),
+    )
+}
+
+#[async_trait]
+impl ReorgChain for MockMiddleware {
+    async fn new_seeded(seed: u64, block_count: u64) -> Arc<Self> {
+        MockMiddleware::new_seeded(seed, block_count).await
+    }
+
+    async fn latest_block(&self) -> Block {
+        self.get_latest_block().await.unwrap()
+    }
+
+    async fn block_with_number(&self, number: U64) -> Option<Block> {
+        MockMiddleware::get_block_with_number(self, number).await
+    }
+
+    async fn add_block(&self, parent_hash: H256) -> Option<H256> {
+        MockMiddleware::add_block(self, parent_hash).await
+    }
+}
+
+/// Small deterministic generator, so runs are reproducible from the seed.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fuzz_reorgs;
+    use crate::test_utils::mocks::IncrementFold;
+
+    use eth_state_fold_test::mock_middleware::MockMiddleware;
+
+    #[tokio::test]
+    async fn fuzz_reorgs_test() {
+        for seed in 0..64 {
+            fuzz_reorgs::<IncrementFold, MockMiddleware>(42, seed).await;
+        }
+    }
+}
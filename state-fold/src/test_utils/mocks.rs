@@ -1,15 +1,22 @@
 // (c) Cartesi and individual authors (see AUTHORS)
 // SPDX-License-Identifier: Apache-2.0 (see LICENSE)
 
-use crate::{FoldMiddleware, Foldable, StateFoldEnvironment, SyncMiddleware};
+use crate::error::FoldableError;
+use crate::{
+    Applicable, DependencyError, Dependent, DependentFoldable, FoldMiddleware, Foldable,
+    MergeableFoldable, StateFoldEnvironment, StatelessFoldable, SyncMiddleware,
+};
 
-use eth_state_fold_test::mock_middleware::MockError;
+use eth_state_fold_test::mock_middleware::{MockError, MockMiddleware};
 
+use eth_state_fold_types::ethereum_types::BloomInput;
 use eth_state_fold_types::ethers;
-use eth_state_fold_types::Block;
+use eth_state_fold_types::{Block, QueryBlock};
 use ethers::providers::Middleware;
+use ethers::types::{Address, Filter, U256, U64};
 
 use async_trait::async_trait;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 
 #[derive(Clone, Debug)]
@@ -41,6 +48,7 @@ impl Foldable for MockFold {
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(test, derive(serde::Serialize, serde::Deserialize))]
 pub(crate) struct IncrementFold {
     pub(crate) low_hash: u64,
     pub(crate) n: u64,
@@ -84,3 +92,962 @@ impl Foldable for IncrementFold {
         })
     }
 }
+
+/// Shares the `InitialState` type of `IncrementFold`, but folds differently.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ScaledFold {
+    pub(crate) n: u64,
+    pub(crate) factor: u64,
+}
+
+#[async_trait]
+impl Foldable for ScaledFold {
+    type InitialState = u64;
+    type Error = MockError;
+    type UserData = ();
+
+    async fn sync<M: Middleware>(
+        initial_state: &Self::InitialState,
+        block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        _access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        Ok(Self {
+            n: block.number.as_u64() * initial_state,
+            factor: *initial_state,
+        })
+    }
+
+    async fn fold<M: Middleware>(
+        previous_state: &Self,
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        _access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        Ok(Self {
+            n: previous_state.n + previous_state.factor,
+            factor: previous_state.factor,
+        })
+    }
+}
+
+/// Fails to sync and fold on blocks no longer canonical, like on nodes
+/// pruning the data of orphaned blocks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CanonicalFold;
+
+impl CanonicalFold {
+    async fn ensure_canonical<M: Middleware + 'static>(
+        block: &Block,
+        env: &StateFoldEnvironment<M, ()>,
+    ) -> Result<Self, MockError> {
+        match env.is_canonical(block.hash).await {
+            Ok(true) => Ok(Self),
+            _ => Err(MockError),
+        }
+    }
+}
+
+#[async_trait]
+impl Foldable for CanonicalFold {
+    type InitialState = ();
+    type Error = MockError;
+    type UserData = ();
+
+    async fn sync<M: Middleware + 'static>(
+        _initial_state: &Self::InitialState,
+        block: &Block,
+        env: &StateFoldEnvironment<M, ()>,
+        _access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        Self::ensure_canonical(block, env).await
+    }
+
+    async fn fold<M: Middleware + 'static>(
+        _previous_state: &Self,
+        block: &Block,
+        env: &StateFoldEnvironment<M, ()>,
+        _access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        Self::ensure_canonical(block, env).await
+    }
+}
+
+/// Fails to sync and fold while its user data is set.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct FlakyFold;
+
+#[async_trait]
+impl Foldable for FlakyFold {
+    type InitialState = ();
+    type Error = MockError;
+    type UserData = Arc<AtomicBool>;
+
+    async fn sync<M: Middleware + 'static>(
+        _initial_state: &Self::InitialState,
+        _block: &Block,
+        env: &StateFoldEnvironment<M, Arc<AtomicBool>>,
+        _access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        if env.user_data().load(Ordering::SeqCst) {
+            Err(MockError)
+        } else {
+            Ok(Self)
+        }
+    }
+
+    async fn fold<M: Middleware + 'static>(
+        _previous_state: &Self,
+        _block: &Block,
+        env: &StateFoldEnvironment<M, Arc<AtomicBool>>,
+        _access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        if env.user_data().load(Ordering::SeqCst) {
+            Err(MockError)
+        } else {
+            Ok(Self)
+        }
+    }
+}
+
+impl Applicable for FlakyFold {
+    fn not_applicable(_error: &MockError) -> bool {
+        false
+    }
+}
+
+/// Initial state with a label that doesn't affect the state.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct LabeledInitialState {
+    pub(crate) value: u64,
+    pub(crate) label: &'static str,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct LabeledFold {
+    pub(crate) n: u64,
+}
+
+#[async_trait]
+impl Foldable for LabeledFold {
+    type InitialState = LabeledInitialState;
+    type Error = MockError;
+    type UserData = ();
+
+    async fn sync<M: Middleware>(
+        initial_state: &Self::InitialState,
+        block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        _access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        Ok(Self {
+            n: block.number.as_u64() + initial_state.value,
+        })
+    }
+
+    async fn fold<M: Middleware>(
+        previous_state: &Self,
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        _access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        Ok(Self {
+            n: previous_state.n + 1,
+        })
+    }
+}
+
+/// Records the chain tip, read through the raw middleware, instead of the
+/// block being processed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TipFold {
+    pub(crate) tip: u64,
+}
+
+#[async_trait]
+impl Foldable for TipFold {
+    type InitialState = ();
+    type Error = MockError;
+    type UserData = ();
+
+    async fn sync<M: Middleware>(
+        _initial_state: &Self::InitialState,
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        let tip = access.raw_middleware().get_block_number().await;
+        Ok(Self {
+            tip: tip.map_err(|_| MockError)?.as_u64(),
+        })
+    }
+
+    async fn fold<M: Middleware>(
+        _previous_state: &Self,
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        let tip = access.raw_middleware().get_block_number().await;
+        Ok(Self {
+            tip: tip.map_err(|_| MockError)?.as_u64(),
+        })
+    }
+}
+
+/// Counts the logs of the chain since its `InitialState`, declared as its
+/// genesis.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct DeployedFold {
+    pub(crate) logs: usize,
+}
+
+#[async_trait]
+impl Foldable for DeployedFold {
+    type InitialState = U64;
+    type Error = MockError;
+    type UserData = ();
+
+    fn genesis_block(initial_state: &Self::InitialState) -> Option<U64> {
+        Some(*initial_state)
+    }
+
+    async fn sync<M: Middleware + 'static>(
+        _initial_state: &Self::InitialState,
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        let logs = access.get_logs(&Filter::new()).await;
+        Ok(Self {
+            logs: logs.map_err(|_| MockError)?.len(),
+        })
+    }
+
+    async fn fold<M: Middleware>(
+        previous_state: &Self,
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        _access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        Ok(previous_state.clone())
+    }
+}
+
+/// Sums the base fees of the blocks folded since sync, pre-London blocks
+/// counting as zero.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct BaseFeeFold {
+    pub(crate) total: U256,
+}
+
+#[async_trait]
+impl Foldable for BaseFeeFold {
+    type InitialState = ();
+    type Error = MockError;
+    type UserData = ();
+
+    async fn sync<M: Middleware>(
+        _initial_state: &Self::InitialState,
+        block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        _access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        Ok(Self {
+            total: block.base_fee_per_gas.unwrap_or_default(),
+        })
+    }
+
+    async fn fold<M: Middleware>(
+        previous_state: &Self,
+        block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        _access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        Ok(Self {
+            total: previous_state.total + block.base_fee_per_gas.unwrap_or_default(),
+        })
+    }
+}
+
+/// Address whose presence in a block's logs bloom makes it relevant to
+/// `BloomFold`.
+pub(crate) const WATCHED_ADDRESS: Address = Address::repeat_byte(0xaa);
+
+/// Counts the `fold` calls since sync, only folding blocks whose logs bloom
+/// may contain `WATCHED_ADDRESS`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct BloomFold {
+    pub(crate) folds: usize,
+}
+
+#[async_trait]
+impl Foldable for BloomFold {
+    type InitialState = ();
+    type Error = MockError;
+    type UserData = ();
+
+    fn relevant<M: Middleware + 'static>(
+        block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+    ) -> bool {
+        block
+            .logs_bloom
+            .contains_input(BloomInput::Raw(WATCHED_ADDRESS.as_bytes()))
+    }
+
+    async fn sync<M: Middleware>(
+        _initial_state: &Self::InitialState,
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        _access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        Ok(Self { folds: 0 })
+    }
+
+    async fn fold<M: Middleware>(
+        previous_state: &Self,
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        _access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        Ok(Self {
+            folds: previous_state.folds + 1,
+        })
+    }
+}
+
+/// User data mutated by the folds reading it.
+#[derive(Debug, Default)]
+pub(crate) struct MutableUserData {
+    pub(crate) value: std::sync::Mutex<u64>,
+}
+
+impl Clone for MutableUserData {
+    fn clone(&self) -> Self {
+        Self {
+            value: std::sync::Mutex::new(*self.value.lock().unwrap()),
+        }
+    }
+}
+
+/// Records the `value` of the user data seen by each sync and fold,
+/// incrementing the live `value` after every read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct SnapshotFold {
+    pub(crate) seen: Vec<u64>,
+}
+
+impl SnapshotFold {
+    fn read<M: Middleware + 'static>(env: &StateFoldEnvironment<M, MutableUserData>) -> u64 {
+        let seen = *env.user_data().value.lock().unwrap();
+        *env.live_user_data().value.lock().unwrap() += 1;
+        seen
+    }
+}
+
+#[async_trait]
+impl Foldable for SnapshotFold {
+    type InitialState = ();
+    type Error = MockError;
+    type UserData = MutableUserData;
+
+    async fn sync<M: Middleware + 'static>(
+        _initial_state: &Self::InitialState,
+        _block: &Block,
+        env: &StateFoldEnvironment<M, MutableUserData>,
+        _access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        Ok(Self {
+            seen: vec![Self::read(env)],
+        })
+    }
+
+    async fn fold<M: Middleware + 'static>(
+        previous_state: &Self,
+        _block: &Block,
+        env: &StateFoldEnvironment<M, MutableUserData>,
+        _access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        let mut seen = previous_state.seen.clone();
+        seen.push(Self::read(env));
+        Ok(Self { seen })
+    }
+}
+
+/// Contract deployed at the first block of its `InitialState`, and destroyed
+/// at the second, if any, failing to sync or fold outside of them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ContractFold {
+    pub(crate) deployment: U64,
+    pub(crate) destruction: Option<U64>,
+}
+
+impl ContractFold {
+    fn deployed(&self, block: &Block) -> Result<Self, MockError> {
+        let destroyed = self.destruction.is_some_and(|n| block.number >= n);
+        if block.number < self.deployment || destroyed {
+            return Err(MockError);
+        }
+
+        Ok(self.clone())
+    }
+}
+
+#[async_trait]
+impl Foldable for ContractFold {
+    type InitialState = (U64, Option<U64>);
+    type Error = MockError;
+    type UserData = ();
+
+    async fn sync<M: Middleware>(
+        initial_state: &Self::InitialState,
+        block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        _access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        let (deployment, destruction) = *initial_state;
+        Self {
+            deployment,
+            destruction,
+        }
+        .deployed(block)
+    }
+
+    async fn fold<M: Middleware>(
+        previous_state: &Self,
+        block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        _access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        previous_state.deployed(block)
+    }
+}
+
+impl Applicable for ContractFold {
+    fn not_applicable(_error: &MockError) -> bool {
+        true
+    }
+}
+
+/// Sends as many requests as its `InitialState` when syncing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ChattyFold {
+    pub(crate) requests: usize,
+}
+
+#[async_trait]
+impl Foldable for ChattyFold {
+    type InitialState = usize;
+    type Error = MockError;
+    type UserData = ();
+
+    async fn sync<M: Middleware + 'static>(
+        initial_state: &Self::InitialState,
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        for _ in 0..*initial_state {
+            access
+                .get_logs(&Filter::new())
+                .await
+                .map_err(|_| MockError)?;
+        }
+
+        Ok(Self {
+            requests: *initial_state,
+        })
+    }
+
+    async fn fold<M: Middleware>(
+        previous_state: &Self,
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        _access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        Ok(previous_state.clone())
+    }
+}
+
+/// Sums the block numbers of its logs, syncing in merged partitions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct SumFold {
+    pub(crate) sum: u64,
+}
+
+impl SumFold {
+    fn sum(logs: Vec<ethers::types::Log>) -> u64 {
+        logs.iter()
+            .filter_map(|log| log.block_number)
+            .map(|number| number.as_u64())
+            .sum()
+    }
+}
+
+#[async_trait]
+impl Foldable for SumFold {
+    type InitialState = ();
+    type Error = MockError;
+    type UserData = ();
+
+    async fn sync<M: Middleware + 'static>(
+        _initial_state: &Self::InitialState,
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        let logs = access.get_logs(&Filter::new()).await;
+        Ok(Self {
+            sum: Self::sum(logs.map_err(|_| MockError)?),
+        })
+    }
+
+    async fn fold<M: Middleware + 'static>(
+        previous_state: &Self,
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        let logs = access.get_logs(&Filter::new()).await;
+        Ok(Self {
+            sum: previous_state.sum + Self::sum(logs.map_err(|_| MockError)?),
+        })
+    }
+}
+
+impl MergeableFoldable for SumFold {
+    fn merge(earlier: Self, later: Self) -> Self {
+        Self {
+            sum: earlier.sum + later.sum,
+        }
+    }
+}
+
+/// Number of logs of each block, computed from scratch at every block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct LogCountFold {
+    pub(crate) number: U64,
+    pub(crate) logs: usize,
+}
+
+#[async_trait]
+impl StatelessFoldable for LogCountFold {
+    type InitialState = ();
+    type Error = MockError;
+    type UserData = ();
+
+    async fn compute<M: Middleware + 'static>(
+        _initial_state: &Self::InitialState,
+        block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        let logs = access
+            .get_logs(&Filter::new())
+            .await
+            .map_err(|_| MockError)?;
+
+        Ok(Self {
+            number: block.number,
+            logs: logs.len(),
+        })
+    }
+}
+
+/// Twice the `n` of `IncrementFold` of the same initial state.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct DoubledFold {
+    pub(crate) value: u64,
+}
+
+#[async_trait]
+impl DependentFoldable for DoubledFold {
+    type InitialState = u64;
+    type Error = MockError;
+    type UserData = ();
+    type Dependency = IncrementFold;
+
+    fn dependency_initial_state(initial_state: &u64) -> u64 {
+        *initial_state
+    }
+
+    async fn sync<M: Middleware + 'static>(
+        _initial_state: &Self::InitialState,
+        dependency: &IncrementFold,
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        _access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        Ok(Self {
+            value: 2 * dependency.n,
+        })
+    }
+
+    async fn fold<M: Middleware + 'static>(
+        previous_state: &Self,
+        dependency: &IncrementFold,
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        _access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        assert_eq!(previous_state.value + 2, 2 * dependency.n);
+
+        Ok(Self {
+            value: 2 * dependency.n,
+        })
+    }
+}
+
+/// One more than `DoubledFold` of the same initial state.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct OffsetFold {
+    pub(crate) value: u64,
+}
+
+#[async_trait]
+impl DependentFoldable for OffsetFold {
+    type InitialState = u64;
+    type Error = MockError;
+    type UserData = ();
+    type Dependency = Dependent<DoubledFold>;
+
+    fn dependency_initial_state(initial_state: &u64) -> u64 {
+        *initial_state
+    }
+
+    async fn sync<M: Middleware + 'static>(
+        _initial_state: &Self::InitialState,
+        dependency: &Dependent<DoubledFold>,
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        _access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        Ok(Self {
+            value: dependency.state.value + 1,
+        })
+    }
+
+    async fn fold<M: Middleware + 'static>(
+        _previous_state: &Self,
+        dependency: &Dependent<DoubledFold>,
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        _access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        Ok(Self {
+            value: dependency.state.value + 1,
+        })
+    }
+}
+
+/// Queries `Dependent<CyclicFold>` of the same initial state when syncing,
+/// whose dependency is back on it, recording whether the cycle was detected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CycleFold {
+    pub(crate) cycle_detected: bool,
+}
+
+#[async_trait]
+impl Foldable for CycleFold {
+    type InitialState = u64;
+    type Error = MockError;
+    type UserData = ();
+
+    async fn sync<M: Middleware + 'static>(
+        initial_state: &Self::InitialState,
+        block: &Block,
+        env: &StateFoldEnvironment<M, ()>,
+        _access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        let query = QueryBlock::BlockNumber(block.number);
+        let err = env
+            .get_state_for_block::<Dependent<CyclicFold>>(initial_state, query)
+            .await
+            .unwrap_err();
+
+        let cycle_detected = match err {
+            FoldableError::InnerError {
+                source: DependencyError::DependencyQuery { source },
+            } => matches!(
+                source.downcast_ref::<FoldableError<M, CycleFold>>(),
+                Some(FoldableError::CycleDetected {})
+            ),
+            _ => false,
+        };
+
+        Ok(Self { cycle_detected })
+    }
+
+    async fn fold<M: Middleware + 'static>(
+        previous_state: &Self,
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        _access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        Ok(previous_state.clone())
+    }
+}
+
+/// Depends on `CycleFold`, and is irrelevant to every block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CyclicFold;
+
+#[async_trait]
+impl DependentFoldable for CyclicFold {
+    type InitialState = u64;
+    type Error = MockError;
+    type UserData = ();
+    type Dependency = CycleFold;
+
+    fn dependency_initial_state(initial_state: &u64) -> u64 {
+        *initial_state
+    }
+
+    fn relevant<M: Middleware + 'static>(
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+    ) -> bool {
+        false
+    }
+
+    async fn sync<M: Middleware + 'static>(
+        _initial_state: &Self::InitialState,
+        _dependency: &CycleFold,
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        _access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        unreachable!("the dependency of `CyclicFold` is a cycle")
+    }
+
+    async fn fold<M: Middleware + 'static>(
+        _previous_state: &Self,
+        _dependency: &CycleFold,
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        _access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        unreachable!("the dependency of `CyclicFold` is a cycle")
+    }
+}
+
+/// Displays of the errors of the queries nested in `PingFold` and `PongFold`,
+/// innermost first.
+pub(crate) type NestedErrors = std::sync::Mutex<Vec<String>>;
+
+/// Queries `PongFold` of the same initial state when syncing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct PingFold;
+
+/// Queries `PingFold` of the previous initial state, of the same one at `0`,
+/// when syncing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct PongFold;
+
+async fn query_nested<M, F>(
+    initial_state: &F::InitialState,
+    block: &Block,
+    env: &StateFoldEnvironment<M, NestedErrors>,
+) -> Result<(), MockError>
+where
+    M: Middleware + 'static,
+    F: Foldable<UserData = NestedErrors> + 'static,
+{
+    let query = QueryBlock::BlockNumber(block.number);
+    if let Err(e) = env.get_state_for_block::<F>(initial_state, query).await {
+        env.user_data().lock().unwrap().push(e.to_string());
+        return Err(MockError);
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+impl Foldable for PingFold {
+    type InitialState = u64;
+    type Error = MockError;
+    type UserData = NestedErrors;
+
+    async fn sync<M: Middleware + 'static>(
+        initial_state: &Self::InitialState,
+        block: &Block,
+        env: &StateFoldEnvironment<M, NestedErrors>,
+        _access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        query_nested::<M, PongFold>(initial_state, block, env).await?;
+        Ok(Self)
+    }
+
+    async fn fold<M: Middleware + 'static>(
+        previous_state: &Self,
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, NestedErrors>,
+        _access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        Ok(previous_state.clone())
+    }
+}
+
+#[async_trait]
+impl Foldable for PongFold {
+    type InitialState = u64;
+    type Error = MockError;
+    type UserData = NestedErrors;
+
+    async fn sync<M: Middleware + 'static>(
+        initial_state: &Self::InitialState,
+        block: &Block,
+        env: &StateFoldEnvironment<M, NestedErrors>,
+        _access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        let previous = initial_state.saturating_sub(1);
+        query_nested::<M, PingFold>(&previous, block, env).await?;
+        Ok(Self)
+    }
+
+    async fn fold<M: Middleware + 'static>(
+        previous_state: &Self,
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, NestedErrors>,
+        _access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        Ok(previous_state.clone())
+    }
+}
+
+/// Labeled by its `InitialState`, producing a block on the mock chain at every
+/// sync and fold.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct GrowingFold {
+    pub(crate) label: u64,
+}
+
+impl GrowingFold {
+    async fn grow(m: &MockMiddleware) -> Result<(), MockError> {
+        let latest = m.get_latest_block().await.ok_or(MockError)?;
+        m.add_block(latest.hash).await.ok_or(MockError)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Foldable for GrowingFold {
+    type InitialState = u64;
+    type Error = MockError;
+    type UserData = Arc<MockMiddleware>;
+
+    async fn sync<M: Middleware + 'static>(
+        initial_state: &Self::InitialState,
+        _block: &Block,
+        env: &StateFoldEnvironment<M, Self::UserData>,
+        _access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        Self::grow(&env.user_data()).await?;
+        Ok(Self {
+            label: *initial_state,
+        })
+    }
+
+    async fn fold<M: Middleware + 'static>(
+        previous_state: &Self,
+        _block: &Block,
+        env: &StateFoldEnvironment<M, Self::UserData>,
+        _access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        Self::grow(&env.user_data()).await?;
+        Ok(previous_state.clone())
+    }
+}
+
+/// Numbers of syncs and folds of `CountingFold`.
+#[derive(Debug, Default)]
+pub(crate) struct FoldCounts {
+    pub(crate) syncs: AtomicUsize,
+    pub(crate) folds: AtomicUsize,
+}
+
+/// Counts its syncs and folds in the user data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CountingFold;
+
+#[async_trait]
+impl Foldable for CountingFold {
+    type InitialState = ();
+    type Error = MockError;
+    type UserData = FoldCounts;
+
+    async fn sync<M: Middleware + 'static>(
+        _initial_state: &Self::InitialState,
+        _block: &Block,
+        env: &StateFoldEnvironment<M, FoldCounts>,
+        _access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        env.user_data().syncs.fetch_add(1, Ordering::SeqCst);
+        Ok(Self)
+    }
+
+    async fn fold<M: Middleware + 'static>(
+        _previous_state: &Self,
+        _block: &Block,
+        env: &StateFoldEnvironment<M, FoldCounts>,
+        _access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        env.user_data().folds.fetch_add(1, Ordering::SeqCst);
+        Ok(Self)
+    }
+}
+
+/// Tracks whether the contract at its `InitialState` has code, becoming
+/// terminal once it self-destructs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct SelfDestructFold {
+    pub(crate) address: Address,
+    pub(crate) alive: bool,
+
+    /// Number of blocks it checked the contract on.
+    pub(crate) checks: usize,
+}
+
+#[async_trait]
+impl Foldable for SelfDestructFold {
+    type InitialState = Address;
+    type Error = MockError;
+    type UserData = ();
+
+    fn terminal(&self) -> bool {
+        !self.alive
+    }
+
+    async fn sync<M: Middleware + 'static>(
+        initial_state: &Self::InitialState,
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        access: Arc<SyncMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        Ok(Self {
+            address: *initial_state,
+            alive: access
+                .is_contract_alive(*initial_state)
+                .await
+                .map_err(|_| MockError)?,
+            checks: 1,
+        })
+    }
+
+    async fn fold<M: Middleware + 'static>(
+        previous_state: &Self,
+        _block: &Block,
+        _env: &StateFoldEnvironment<M, ()>,
+        access: Arc<FoldMiddleware<M>>,
+    ) -> Result<Self, Self::Error> {
+        Ok(Self {
+            address: previous_state.address,
+            alive: access
+                .is_contract_alive(previous_state.address)
+                .await
+                .map_err(|_| MockError)?,
+            checks: previous_state.checks + 1,
+        })
+    }
+}
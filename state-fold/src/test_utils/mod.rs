@@ -1,7 +1,16 @@
 // (c) Cartesi and individual authors (see AUTHORS)
 // SPDX-License-Identifier: Apache-2.0 (see LICENSE)
 
+//! Utilities for testing `Foldable` implementations against mock chains.
+//! Available with the `test-utils` feature.
+
+#[cfg(test)]
 pub(crate) mod mocks;
 
+#[cfg(test)]
 mod utils;
+#[cfg(test)]
 pub(crate) use utils::set_value_get_block;
+
+mod fuzz;
+pub use fuzz::{fuzz_reorgs, ReorgChain};
@@ -43,6 +43,13 @@ pub struct MessageMalformedError {
     reason: String,
 }
 
+#[derive(Debug, Snafu)]
+#[snafu(display("message `{}` cannot represent `{}`", message, value))]
+pub struct MessageUnsupportedError {
+    message: String,
+    value: String,
+}
+
 #[derive(Debug, Snafu)]
 pub enum MessageConversionError {
     #[snafu(display("NilError error: {}", source,))]
@@ -50,6 +57,9 @@ pub enum MessageConversionError {
 
     #[snafu(display("MalformedError error: {}", source,))]
     MalformedError { source: MessageMalformedError },
+
+    #[snafu(display("UnsupportedError error: {}", source,))]
+    UnsupportedError { source: MessageUnsupportedError },
 }
 
 #[derive(Debug, Snafu)]
@@ -101,9 +111,11 @@ impl<T: DeserializeOwned> TryFrom<GrpcBlockState> for BlockState<T> {
         )
         .context(DeserializeSnafu)?;
 
+        // The gRPC interface doesn't carry the chain id.
         Ok(Self {
             block,
             state: Arc::new(state),
+            chain_id: None,
         })
     }
 }
@@ -241,20 +253,32 @@ impl TryFrom<BlockStreamResponse> for BlockStreamItem {
     }
 }
 
-impl From<BlockStreamItem> for BlockStreamResponse {
-    fn from(i: BlockStreamItem) -> Self {
-        Self {
-            response: Some(i.into()),
-        }
+impl TryFrom<BlockStreamItem> for BlockStreamResponse {
+    type Error = MessageConversionError;
+
+    fn try_from(i: BlockStreamItem) -> Result<Self, Self::Error> {
+        Ok(Self {
+            response: Some(i.try_into()?),
+        })
     }
 }
 
-impl From<BlockStreamItem> for GrpcBlockStreamResponse {
-    fn from(i: BlockStreamItem) -> Self {
-        match i {
+impl TryFrom<BlockStreamItem> for GrpcBlockStreamResponse {
+    type Error = MessageConversionError;
+
+    fn try_from(i: BlockStreamItem) -> Result<Self, Self::Error> {
+        Ok(match i {
             BlockStreamItem::NewBlock(b) => GrpcBlockStreamResponse::NewBlock(b.into()),
             BlockStreamItem::Reorg(bs) => GrpcBlockStreamResponse::ReorganizedBlocks(bs.into()),
-        }
+
+            BlockStreamItem::Orphaned(_) | BlockStreamItem::HistoryGap { .. } => {
+                return Err(MessageUnsupportedError {
+                    message: "BlockStreamItem".to_owned(),
+                    value: format!("{:?}", i),
+                })
+                .context(UnsupportedSnafu)
+            }
+        })
     }
 }
 
@@ -325,11 +349,15 @@ impl TryFrom<Id> for QueryBlock {
     }
 }
 
-impl From<QueryBlock> for GrpcQueryBlock {
-    fn from(b: QueryBlock) -> Self {
+impl TryFrom<QueryBlock> for GrpcQueryBlock {
+    type Error = MessageConversionError;
+
+    fn try_from(b: QueryBlock) -> Result<Self, Self::Error> {
         let id = match b {
             QueryBlock::BlockDepth(d) => Some(Id::Depth(d as u64)),
 
+            QueryBlock::Confirmations(n) if n > 0 => Some(Id::Depth(n - 1)),
+
             QueryBlock::BlockHash(h) => Some(Id::BlockHash(h.into())),
 
             QueryBlock::BlockNumber(n) => Some(Id::BlockNumber(n.as_u64())),
@@ -337,9 +365,21 @@ impl From<QueryBlock> for GrpcQueryBlock {
             QueryBlock::Block(b) => Some(Id::BlockHash(b.hash.into())),
 
             QueryBlock::Latest => None,
+
+            QueryBlock::Safe
+            | QueryBlock::Pending
+            | QueryBlock::Confirmations(_)
+            | QueryBlock::Custom(_)
+            | QueryBlock::BlockNumberAndHash(..) => {
+                return Err(MessageUnsupportedError {
+                    message: "QueryBlock".to_owned(),
+                    value: format!("{:?}", b),
+                })
+                .context(UnsupportedSnafu)
+            }
         };
 
-        Self { id }
+        Ok(Self { id })
     }
 }
 
@@ -392,6 +432,9 @@ impl TryFrom<GrpcBlock> for Arc<Block> {
                 .context(NilSnafu)?
                 .try_into()
                 .context(MalformedSnafu)?,
+
+            base_fee_per_gas: None,
+            gas_used: None,
         };
 
         Ok(Arc::new(ret))
@@ -131,10 +131,19 @@ where
             .await
             .map_err(|e| Status::unavailable(format!("{:?}", e)))?;
 
-        let stream = stream.map(|x| {
-            x.map(|x| x.into())
-                .map_err(|e| Status::unavailable(format!("{:?}", e)))
-        });
+        let stream =
+            stream.map(
+                |x| match x.map_err(|e| Status::unavailable(format!("{:?}", e)))? {
+                    BlockStreamItem::HistoryGap { from, to } => Err(Status::data_loss(format!(
+                        "history gap from block `{}` to `{}`, resync required",
+                        from.number, to.number
+                    ))),
+
+                    item => item
+                        .try_into()
+                        .map_err(|e| Status::internal(format!("{:?}", e))),
+                },
+            );
 
         Ok(Response::new(Box::pin(stream)))
     }
@@ -273,6 +282,18 @@ where
                         Ok(StateStreamResponse { response })
                     }
 
+                    // Not requested by `subscribe_new_blocks_at_depth`.
+                    Ok(BlockStreamItem::Orphaned(_)) => {
+                        Err(Status::internal("unexpected orphaned block"))
+                    }
+
+                    Ok(BlockStreamItem::HistoryGap { from, to }) => {
+                        Err(Status::data_loss(format!(
+                            "history gap from block `{}` to `{}`, resync required",
+                            from.number, to.number
+                        )))
+                    }
+
                     Err(e) => Err(Status::unavailable(format!("{:?}", e))),
                 }
             }